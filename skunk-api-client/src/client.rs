@@ -13,18 +13,38 @@ use futures_util::{
     Future,
     FutureExt,
 };
+use skunk_api_protocol::{
+    flow::MessageId,
+    interrupt::Continue,
+    tls::{
+        CachedCert,
+        GetCertsResponse,
+        GetPinnedHostsResponse,
+        PinnedHost,
+        PurgeCertsResponse,
+        RemovePinnedHostsResponse,
+    },
+};
 use skunk_util::trigger;
-use tokio::sync::watch;
+use tokio::sync::{
+    mpsc,
+    watch,
+};
 use tracing::Instrument;
 use url::Url;
 
 use crate::{
+    interrupt::Interrupt,
     socket::{
         Command,
         Reactor,
         ReactorHandle,
     },
-    util::platform::spawn_local,
+    util::{
+        platform::spawn_local,
+        ResponseExt,
+    },
+    Error,
     Status,
 };
 
@@ -71,6 +91,64 @@ impl Client {
     pub async fn flows(&self) {
         todo!();
     }
+
+    /// Receive messages that were paused by the proxy.
+    ///
+    /// Each interrupt must be answered with [`Client::continue_interrupt`],
+    /// otherwise the proxied connection stays paused. Only the most recent
+    /// receiver gets interrupts. If there is no receiver, paused messages are
+    /// forwarded unchanged.
+    pub async fn interrupts(&mut self) -> mpsc::Receiver<Interrupt> {
+        let (interrupt_tx, interrupt_rx) = mpsc::channel(16);
+        self.send_command(Command::SubscribeInterrupts { interrupt_tx })
+            .await;
+        interrupt_rx
+    }
+
+    /// Continue a paused message.
+    pub async fn continue_interrupt(&mut self, message_id: MessageId, action: Continue) {
+        self.send_command(Command::Continue { message_id, action })
+            .await;
+    }
+
+    /// List the cached forged certificates, from least to most recently used.
+    pub async fn certs(&self) -> Result<Vec<CachedCert>, Error> {
+        let url = self.base_url.clone().push("tls").push("certs").finish();
+        let response: GetCertsResponse = self.client.get(url).send().await?.msgpack().await?;
+        Ok(response.certs)
+    }
+
+    /// Remove the cached forged certificates for `host`, or all certificates
+    /// if `host` is `None`. Returns the number of removed certificates.
+    pub async fn purge_certs(&self, host: Option<&str>) -> Result<usize, Error> {
+        let mut url = self.base_url.clone().push("tls").push("certs").finish();
+        if let Some(host) = host {
+            url.query_pairs_mut().append_pair("host", host);
+        }
+        let response: PurgeCertsResponse = self.client.delete(url).send().await?.msgpack().await?;
+        Ok(response.purged)
+    }
+
+    /// List the hosts whose connections are passed through, because a client
+    /// rejected our certificate.
+    pub async fn pinned_hosts(&self) -> Result<Vec<PinnedHost>, Error> {
+        let url = self.base_url.clone().push("tls").push("pinned").finish();
+        let response: GetPinnedHostsResponse = self.client.get(url).send().await?.msgpack().await?;
+        Ok(response.hosts)
+    }
+
+    /// Remove the pinned `host`, or all pinned hosts if `host` is `None`, so
+    /// their connections are decrypted again. Returns the number of removed
+    /// hosts.
+    pub async fn remove_pinned_hosts(&self, host: Option<&str>) -> Result<usize, Error> {
+        let mut url = self.base_url.clone().push("tls").push("pinned").finish();
+        if let Some(host) = host {
+            url.query_pairs_mut().append_pair("host", host);
+        }
+        let response: RemovePinnedHostsResponse =
+            self.client.delete(url).send().await?.msgpack().await?;
+        Ok(response.removed)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -0,0 +1,19 @@
+use skunk_api_protocol::{
+    flow::{
+        FlowId,
+        MessageId,
+    },
+    interrupt::Intercepted,
+};
+
+/// A message that was paused by the proxy.
+///
+/// Continue it with
+/// [`Client::continue_interrupt`][crate::Client::continue_interrupt].
+#[derive(Clone, Debug)]
+pub struct Interrupt {
+    pub message_id: MessageId,
+    pub flow_id: FlowId,
+    pub prompt: Option<String>,
+    pub message: Intercepted,
+}
@@ -3,6 +3,7 @@
 mod client;
 mod error;
 mod flow;
+mod interrupt;
 mod socket;
 mod util;
 
@@ -12,6 +13,7 @@ pub use self::{
         Connection,
     },
     error::Error,
+    interrupt::Interrupt,
 };
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
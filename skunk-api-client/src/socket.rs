@@ -26,6 +26,8 @@ use serde::{
     Serialize,
 };
 use skunk_api_protocol::{
+    flow::MessageId,
+    interrupt::Continue,
     socket::{
         ClientHello,
         ClientMessage,
@@ -44,6 +46,7 @@ use url::Url;
 
 use crate::{
     flow,
+    interrupt::Interrupt,
     util::platform::{
         interval,
         sleep,
@@ -87,6 +90,7 @@ pub(crate) struct Reactor {
     reload_tx: trigger::Sender,
     status_tx: watch::Sender<Status>,
     flows_tx: HashMap<SubscriptionId, mpsc::Sender<flow::Event>>,
+    interrupts_tx: Option<mpsc::Sender<Interrupt>>,
 }
 
 impl Reactor {
@@ -102,6 +106,7 @@ impl Reactor {
             reload_tx,
             status_tx,
             flows_tx: HashMap::new(),
+            interrupts_tx: None,
         };
 
         let handle = ReactorHandle {
@@ -245,12 +250,42 @@ impl<'a> ReactorConnection<'a> {
                         .await?;
                 }
             }
-            ServerMessage::Interrupt { message_id } => {
-                // todo: for now we'll just send a Continue back
-                // eventually we want to send the interrupt to the user with a oneshot channel.
-                self.socket
-                    .send(&ClientMessage::Continue { message_id })
-                    .await?;
+            ServerMessage::Interrupt {
+                message_id,
+                flow_id,
+                prompt,
+                message,
+            } => {
+                let interrupt = Interrupt {
+                    message_id,
+                    flow_id,
+                    prompt,
+                    message,
+                };
+
+                let forwarded = if let Some(interrupts_tx) = &self.reactor.interrupts_tx {
+                    if interrupts_tx.send(interrupt).await.is_ok() {
+                        true
+                    }
+                    else {
+                        // the interrupts receiver has been dropped.
+                        self.reactor.interrupts_tx = None;
+                        false
+                    }
+                }
+                else {
+                    false
+                };
+
+                if !forwarded {
+                    // nobody is handling interrupts, so we just let the message continue.
+                    self.socket
+                        .send(&ClientMessage::Continue {
+                            message_id,
+                            action: Continue::Forward,
+                        })
+                        .await?;
+                }
             }
         }
 
@@ -265,6 +300,14 @@ impl<'a> ReactorConnection<'a> {
             } => {
                 self.reactor.flows_tx.insert(subscription_id, event_tx);
             }
+            Command::SubscribeInterrupts { interrupt_tx } => {
+                self.reactor.interrupts_tx = Some(interrupt_tx);
+            }
+            Command::Continue { message_id, action } => {
+                self.socket
+                    .send(&ClientMessage::Continue { message_id, action })
+                    .await?;
+            }
         }
 
         Ok(())
@@ -277,6 +320,13 @@ pub(crate) enum Command {
         subscription_id: SubscriptionId,
         event_tx: mpsc::Sender<flow::Event>,
     },
+    SubscribeInterrupts {
+        interrupt_tx: mpsc::Sender<Interrupt>,
+    },
+    Continue {
+        message_id: MessageId,
+        action: Continue,
+    },
 }
 
 /// Wrapper around [`reqwest_websocket::WebSocket`] that sends and receives
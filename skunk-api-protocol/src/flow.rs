@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use chrono::{
     DateTime,
     FixedOffset,
@@ -27,6 +29,8 @@ pub struct GetFlowsRequest {
     pub after: Option<DateTime<FixedOffset>>,
     pub before: Option<DateTime<FixedOffset>>,
     pub limit: Option<usize>,
+    /// Only return flows of the user that authenticated with the proxy.
+    pub username: Option<String>,
     pub subscribe: Option<Subscribe>,
 }
 
@@ -143,3 +147,95 @@ pub enum MessageKind {
     Request,
     Response,
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
+pub struct ArtifactId(pub Uuid);
+
+/// A blob of data (e.g. a HTTP body) associated with a message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Artifact {
+    pub artifact_id: ArtifactId,
+    pub message_id: Option<MessageId>,
+    pub mime_type: Option<String>,
+    pub file_name: Option<String>,
+    pub timestamp: DateTime<FixedOffset>,
+}
+
+/// [`MessageData`] for HTTP requests.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<ArtifactId>,
+}
+
+/// [`MessageData`] for HTTP responses.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<ArtifactId>,
+}
+
+/// [`MessageData`] for WebSocket frames.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebSocketFrame {
+    pub direction: WebSocketDirection,
+    pub opcode: String,
+    pub fin: bool,
+    pub payload: Option<ArtifactId>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebSocketDirection {
+    FromClient,
+    FromServer,
+}
+
+/// [`MessageData`] for DNS queries.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DnsQuery {
+    pub name: String,
+    pub query_type: String,
+}
+
+/// [`MessageData`] for DNS answers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DnsAnswer {
+    pub response_code: String,
+    pub addresses: Vec<IpAddr>,
+    pub ttl: u32,
+
+    /// The answer was made up by a rule, instead of coming from the upstream
+    /// resolver.
+    pub spoofed: bool,
+}
+
+/// [`MessageData`] for UDP datagrams.
+///
+/// Datagrams from the client are [`MessageKind::Request`]s, and datagrams to
+/// the client are [`MessageKind::Response`]s.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UdpDatagram {
+    /// The destination of a datagram from the client, or the source of a
+    /// datagram to the client.
+    pub address: String,
+
+    pub payload: Option<ArtifactId>,
+
+    /// The decoded DNS message, if this is a DNS datagram.
+    pub dns: Option<DnsMessage>,
+}
+
+/// A decoded DNS message in a [`UdpDatagram`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsMessage {
+    Query(DnsQuery),
+    Answer(DnsAnswer),
+}
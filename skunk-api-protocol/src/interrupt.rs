@@ -0,0 +1,55 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// A message that was paused by an `interrupt` effect, and is waiting for the
+/// user to continue it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Intercepted {
+    HttpRequest(InterceptedHttpRequest),
+    HttpResponse(InterceptedHttpResponse),
+}
+
+/// A paused HTTP request.
+///
+/// The body is sent in full, so that it can be edited.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InterceptedHttpRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// A paused HTTP response.
+///
+/// The body is sent in full, so that it can be edited.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InterceptedHttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// How a paused message should be continued.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum Continue {
+    /// Forward the message unchanged.
+    #[default]
+    Forward,
+
+    /// Forward an edited version of the message.
+    ///
+    /// The edited message must be of the same kind as the paused message.
+    Modify(Intercepted),
+
+    /// Don't forward the message, but reply with this response instead.
+    ///
+    /// For a paused request, the request is not sent to the server. For a
+    /// paused response, this replaces the response from the server.
+    Respond(InterceptedHttpResponse),
+
+    /// Drop the message and close the connection.
+    Drop,
+}
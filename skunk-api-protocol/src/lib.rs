@@ -4,9 +4,11 @@ use semver::Version;
 mod axum;
 pub mod error;
 pub mod flow;
+pub mod interrupt;
 pub mod socket;
 #[cfg(feature = "sqlx")]
 mod sqlx;
+pub mod tls;
 pub mod util;
 
 pub const PROTOCOL_VERSION: Version = Version::new(0, 1, 0);
@@ -7,9 +7,16 @@ use serde::{
 };
 use uuid::Uuid;
 
-use crate::flow::{
-    self,
-    MessageId,
+use crate::{
+    flow::{
+        self,
+        FlowId,
+        MessageId,
+    },
+    interrupt::{
+        Continue,
+        Intercepted,
+    },
 };
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -43,9 +50,13 @@ pub enum ServerMessage {
         subscription_id: SubscriptionId,
         event: flow::Event,
     },
+    /// A message was paused by an `interrupt` effect. The proxy waits until a
+    /// client sends [`ClientMessage::Continue`] with the same `message_id`.
     Interrupt {
         message_id: MessageId,
-        // todo: request/response/etc.
+        flow_id: FlowId,
+        prompt: Option<String>,
+        message: Intercepted,
     },
 }
 
@@ -59,6 +70,6 @@ pub enum ClientMessage {
     Stop,
     Continue {
         message_id: MessageId,
-        // todo: modified request/response/etc.
+        action: Continue,
     },
 }
@@ -0,0 +1,89 @@
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::util::{
+    api_request,
+    api_response,
+};
+
+/// A certificate in the cache of forged certificates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedCert {
+    /// The server name the client sent, or the address it connected to.
+    pub host: String,
+
+    /// Hex-encoded SHA-256 fingerprint of the certificate the server presented.
+    pub upstream_fingerprint: String,
+}
+
+/// Lists the cached certificates, from least to most recently used.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetCertsResponse {
+    pub certs: Vec<CachedCert>,
+}
+
+api_response!(GetCertsResponse);
+
+/// Removes cached certificates, so they're forged again on the next
+/// connection.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PurgeCertsRequest {
+    /// Only remove the certificates for this host. If `None`, all certificates
+    /// are removed.
+    pub host: Option<String>,
+}
+
+api_request!(PurgeCertsRequest);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PurgeCertsResponse {
+    /// Number of removed certificates.
+    pub purged: usize,
+}
+
+api_response!(PurgeCertsResponse);
+
+/// A host whose connections are passed through, because a client rejected our
+/// certificate, e.g. because it pins certificates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PinnedHost {
+    /// The server name the client sent, or the address it connected to.
+    pub host: String,
+
+    /// When the client rejected our certificate.
+    pub detected_at: DateTime<Utc>,
+
+    /// How the client rejected our certificate, e.g. the alert it sent.
+    pub reason: String,
+}
+
+/// Lists the pinned hosts, in the order they were detected.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetPinnedHostsResponse {
+    pub hosts: Vec<PinnedHost>,
+}
+
+api_response!(GetPinnedHostsResponse);
+
+/// Removes pinned hosts, so their connections are decrypted again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemovePinnedHostsRequest {
+    /// Only remove this host. If `None`, all hosts are removed.
+    pub host: Option<String>,
+}
+
+api_request!(RemovePinnedHostsRequest);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemovePinnedHostsResponse {
+    /// Number of removed hosts.
+    pub removed: usize,
+}
+
+api_response!(RemovePinnedHostsResponse);
@@ -11,6 +11,7 @@ use axum::{
     routing,
     Router,
 };
+use bytes::Bytes;
 use chrono::{
     DateTime,
     FixedOffset,
@@ -21,6 +22,7 @@ use skunk_api_protocol::{
         NoSuchSocket,
     },
     flow::{
+        Artifact,
         Event,
         Flow,
         FlowId,
@@ -71,6 +73,7 @@ async fn get_flows(
             request.after,
             request.before,
             request.limit,
+            request.username.as_deref(),
             subscribe,
         )
         .await?;
@@ -112,8 +115,21 @@ impl Flows {
     }
 
     pub async fn emit_message(&self, message: Message) -> Result<(), Error> {
+        self.emit_message_with_artifacts(message, &[]).await
+    }
+
+    /// Emits a message together with artifacts (e.g. HTTP bodies) that belong
+    /// to it.
+    pub async fn emit_message_with_artifacts(
+        &self,
+        message: Message,
+        artifacts: &[(Artifact, Bytes)],
+    ) -> Result<(), Error> {
         let mut transaction = self.flow_store.transaction().await?;
         transaction.insert_message(&message).await?;
+        for (artifact, data) in artifacts {
+            transaction.insert_artifact(artifact, data).await?;
+        }
         let mut subscriptions = self.subscriptions.write().await;
         transaction.commit().await?;
         subscriptions.flow_message(&message).await?;
@@ -133,6 +149,7 @@ impl Flows {
         after: Option<DateTime<FixedOffset>>,
         before: Option<DateTime<FixedOffset>>,
         limit: Option<usize>,
+        username: Option<&str>,
         subscribe: Option<(socket::Sender, SubscriptionId)>,
     ) -> Result<Vec<Flow>, Error> {
         let subscriptions = if subscribe.is_some() {
@@ -152,7 +169,9 @@ impl Flows {
             _ => unreachable!(),
         }
 
-        let flows = transaction.get_flows(parent, after, before, limit).await?;
+        let flows = transaction
+            .get_flows(parent, after, before, limit, username)
+            .await?;
 
         transaction.commit().await?;
 
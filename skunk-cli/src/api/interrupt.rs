@@ -0,0 +1,119 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use skunk_api_protocol::{
+    flow::{
+        FlowId,
+        MessageId,
+    },
+    interrupt::{
+        Continue,
+        Intercepted,
+    },
+    socket::{
+        ServerMessage,
+        SocketId,
+    },
+};
+use tokio::sync::oneshot;
+
+use super::socket;
+
+/// Messages paused by an `interrupt` effect.
+///
+/// Interrupts are sent to all connected websockets. The first client to answer
+/// with a `Continue` message decides how the message continues.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupts {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    sockets: HashMap<SocketId, socket::Sender>,
+    pending: HashMap<MessageId, Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    continue_tx: oneshot::Sender<Continue>,
+    sockets: HashSet<SocketId>,
+}
+
+impl Interrupts {
+    pub fn connect_socket(&self, sender: socket::Sender) {
+        let mut inner = self.inner.lock();
+        inner.sockets.insert(sender.socket_id(), sender);
+    }
+
+    /// Removes a socket. Pending interrupts that no other socket has seen are
+    /// continued unchanged.
+    pub fn disconnect_socket(&self, socket_id: SocketId) {
+        let mut inner = self.inner.lock();
+        inner.sockets.remove(&socket_id);
+        inner.pending.retain(|_, pending| {
+            pending.sockets.remove(&socket_id);
+            !pending.sockets.is_empty()
+        });
+    }
+
+    /// Pauses a message until a client continues it.
+    ///
+    /// If no client is connected, the message is forwarded unchanged.
+    pub async fn interrupt(
+        &self,
+        message_id: MessageId,
+        flow_id: FlowId,
+        prompt: Option<String>,
+        message: Intercepted,
+    ) -> Continue {
+        let (continue_tx, continue_rx) = oneshot::channel();
+
+        let sockets = {
+            let mut inner = self.inner.lock();
+            inner.sockets.retain(|_, sender| !sender.is_closed());
+            if inner.sockets.is_empty() {
+                tracing::warn!(?message_id, "No client connected to handle interrupt");
+                return Continue::Forward;
+            }
+            let socket_ids = inner.sockets.keys().copied().collect();
+            inner.pending.insert(
+                message_id,
+                Pending {
+                    continue_tx,
+                    sockets: socket_ids,
+                },
+            );
+            inner.sockets.values().cloned().collect::<Vec<_>>()
+        };
+
+        for mut sender in sockets {
+            let message = ServerMessage::Interrupt {
+                message_id,
+                flow_id,
+                prompt: prompt.clone(),
+                message: message.clone(),
+            };
+            if let Err(socket::Closed) = sender.send_message(message).await {
+                self.disconnect_socket(sender.socket_id());
+            }
+        }
+
+        // if all sockets went away, the sender is dropped and we just forward the
+        // message.
+        continue_rx.await.unwrap_or_default()
+    }
+
+    /// Continues a paused message. Returns `false` if there is no such message,
+    /// e.g. because another client already continued it.
+    pub fn resume(&self, message_id: MessageId, action: Continue) -> bool {
+        let pending = self.inner.lock().pending.remove(&message_id);
+        pending.is_some_and(|pending| pending.continue_tx.send(action).is_ok())
+    }
+}
@@ -1,6 +1,8 @@
 mod capture;
 mod flow;
+mod interrupt;
 mod socket;
+mod tls;
 
 use std::{
     collections::HashMap,
@@ -21,8 +23,11 @@ use axum::{
     routing,
     Router,
 };
-use flow::Flows;
 use parking_lot::RwLock;
+use skunk::protocol::tls::{
+    cert_cache::CertCache,
+    pinned::PinnedHosts,
+};
 use skunk_api_protocol::{
     error::{
         ApiError,
@@ -30,9 +35,12 @@ use skunk_api_protocol::{
     },
     socket::SocketId,
 };
-use skunk_flow_store::FlowStore;
 use skunk_util::trigger;
 
+pub use self::{
+    flow::Flows,
+    interrupt::Interrupts,
+};
 use crate::env::{
     config::TlsConfig,
     Environment,
@@ -60,7 +68,10 @@ pub fn builder(env: Environment) -> Builder {
     Builder {
         env,
         reload_ui: Default::default(),
-        flow_store: None,
+        flows: None,
+        interrupts: None,
+        cert_cache: None,
+        pinned_hosts: None,
     }
 }
 
@@ -68,7 +79,10 @@ pub fn builder(env: Environment) -> Builder {
 pub struct Builder {
     env: Environment,
     reload_ui: trigger::Receiver,
-    flow_store: Option<FlowStore>,
+    flows: Option<Flows>,
+    interrupts: Option<Interrupts>,
+    cert_cache: Option<Arc<CertCache>>,
+    pinned_hosts: Option<Arc<PinnedHosts>>,
 }
 
 impl Builder {
@@ -78,8 +92,23 @@ impl Builder {
         reload_tx
     }
 
-    pub fn with_flow_store(mut self, flow_store: FlowStore) -> Self {
-        self.flow_store = Some(flow_store);
+    pub fn with_flows(mut self, flows: Flows) -> Self {
+        self.flows = Some(flows);
+        self
+    }
+
+    pub fn with_interrupts(mut self, interrupts: Interrupts) -> Self {
+        self.interrupts = Some(interrupts);
+        self
+    }
+
+    pub fn with_cert_cache(mut self, cert_cache: Arc<CertCache>) -> Self {
+        self.cert_cache = Some(cert_cache);
+        self
+    }
+
+    pub fn with_pinned_hosts(mut self, pinned_hosts: Arc<PinnedHosts>) -> Self {
+        self.pinned_hosts = Some(pinned_hosts);
         self
     }
 }
@@ -90,13 +119,17 @@ impl Builder {
             env: self.env,
             sockets: Arc::new(RwLock::new(HashMap::new())),
             reload_ui: Arc::new(self.reload_ui),
-            flows: Flows::new(self.flow_store),
+            flows: self.flows.unwrap_or_else(|| Flows::new(None)),
+            interrupts: self.interrupts.unwrap_or_default(),
+            cert_cache: self.cert_cache,
+            pinned_hosts: self.pinned_hosts,
         };
 
         Router::default()
             .route("/ws", routing::get(socket::handle))
             .nest("/flow", flow::router())
             .nest("/capture", capture::router())
+            .nest("/tls", tls::router())
             .route("/feralsec-root-cert.pem", routing::get(get_tls_root_cert))
             .fallback(|| async { "404 - Not found" })
             .with_state(context)
@@ -109,14 +142,24 @@ pub struct Context {
     sockets: Arc<RwLock<HashMap<SocketId, socket::Sender>>>,
     reload_ui: Arc<trigger::Receiver>,
     flows: Flows,
+    interrupts: Interrupts,
+    cert_cache: Option<Arc<CertCache>>,
+    pinned_hosts: Option<Arc<PinnedHosts>>,
 }
 
 impl Context {
     pub fn connect_socket(&self, sender: socket::Sender) {
+        self.interrupts.connect_socket(sender.clone());
         let mut sockets = self.sockets.write();
         sockets.insert(sender.socket_id(), sender);
     }
 
+    pub fn disconnect_socket(&self, socket_id: SocketId) {
+        self.interrupts.disconnect_socket(socket_id);
+        let mut sockets = self.sockets.write();
+        sockets.remove(&socket_id);
+    }
+
     pub fn socket(&self, id: SocketId) -> Result<socket::Sender, NoSuchSocket> {
         let sockets = self.sockets.read();
 
@@ -40,15 +40,18 @@ pub(super) async fn handle(
     let span = tracing::info_span!("websocket");
     ws.on_upgrade(move |socket| {
         async move {
+            let socket_id = SocketId(Uuid::new_v4());
             let reactor = Reactor {
                 socket: socket.into(),
-                context,
-                socket_id: SocketId(Uuid::new_v4()),
+                context: context.clone(),
+                socket_id,
             };
 
             if let Err(e) = reactor.run().await {
                 tracing::error!("{e:?}");
             }
+
+            context.disconnect_socket(socket_id);
         }
         .instrument(span)
     })
@@ -147,7 +150,11 @@ impl Reactor {
             }
             ClientMessage::Start => todo!(),
             ClientMessage::Stop => todo!(),
-            ClientMessage::Continue { .. } => todo!(),
+            ClientMessage::Continue { message_id, action } => {
+                if !self.context.interrupts.resume(message_id, action) {
+                    tracing::debug!(?message_id, "interrupt already continued");
+                }
+            }
         }
 
         Ok(())
@@ -0,0 +1,93 @@
+use axum::{
+    extract::{
+        Query,
+        State,
+    },
+    routing,
+    Router,
+};
+use skunk_api_protocol::tls::{
+    CachedCert,
+    GetCertsResponse,
+    GetPinnedHostsResponse,
+    PinnedHost,
+    PurgeCertsRequest,
+    PurgeCertsResponse,
+    RemovePinnedHostsRequest,
+    RemovePinnedHostsResponse,
+};
+
+use super::Context;
+
+pub(super) fn router() -> Router<Context> {
+    Router::new()
+        .route("/certs", routing::get(get_certs).delete(purge_certs))
+        .route(
+            "/pinned",
+            routing::get(get_pinned_hosts).delete(remove_pinned_hosts),
+        )
+}
+
+async fn get_certs(State(context): State<Context>) -> GetCertsResponse {
+    let certs = context
+        .cert_cache
+        .iter()
+        .flat_map(|cert_cache| cert_cache.keys())
+        .map(|key| {
+            CachedCert {
+                host: key.host,
+                upstream_fingerprint: key.upstream_fingerprint.to_string(),
+            }
+        })
+        .collect();
+
+    GetCertsResponse { certs }
+}
+
+async fn purge_certs(
+    State(context): State<Context>,
+    Query(request): Query<PurgeCertsRequest>,
+) -> PurgeCertsResponse {
+    let purged = context
+        .cert_cache
+        .as_ref()
+        .map_or(0, |cert_cache| cert_cache.purge(request.host.as_deref()));
+
+    if purged > 0 {
+        tracing::info!(host = ?request.host, purged, "Purged cached certificates");
+    }
+
+    PurgeCertsResponse { purged }
+}
+
+async fn get_pinned_hosts(State(context): State<Context>) -> GetPinnedHostsResponse {
+    let hosts = context
+        .pinned_hosts
+        .iter()
+        .flat_map(|pinned_hosts| pinned_hosts.hosts())
+        .map(|pinned| {
+            PinnedHost {
+                host: pinned.host,
+                detected_at: pinned.detected_at.into(),
+                reason: pinned.reason,
+            }
+        })
+        .collect();
+
+    GetPinnedHostsResponse { hosts }
+}
+
+async fn remove_pinned_hosts(
+    State(context): State<Context>,
+    Query(request): Query<RemovePinnedHostsRequest>,
+) -> RemovePinnedHostsResponse {
+    let removed = context.pinned_hosts.as_ref().map_or(0, |pinned_hosts| {
+        pinned_hosts.remove(request.host.as_deref())
+    });
+
+    if removed > 0 {
+        tracing::info!(host = ?request.host, removed, "Removed pinned hosts");
+    }
+
+    RemovePinnedHostsResponse { removed }
+}
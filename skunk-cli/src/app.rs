@@ -1,15 +1,16 @@
 use color_eyre::eyre::Error;
 use semver::Version;
 use semver_macro::env_version;
-use skunk::protocol::tls;
 
 use crate::env::{
     args::{
+        CaCommand,
         Command,
+        NameConstraintArgs,
         Options,
+        PinnedCommand,
         ProxyArgs,
     },
-    config::TlsConfig,
     Environment,
 };
 
@@ -29,43 +30,48 @@ impl App {
     /// Runs the given command-line command.
     pub async fn run(&mut self, command: Command) -> Result<(), Error> {
         match command {
+            Command::Ca(command) => {
+                crate::ca::run(self.environment.clone(), command).await?;
+            }
             Command::GenerateCert { force } => {
-                self.generate_ca(force).await?;
+                let command = CaCommand::Generate {
+                    force,
+                    name_constraints: NameConstraintArgs {
+                        permitted: vec![],
+                        excluded: vec![],
+                    },
+                };
+                crate::ca::run(self.environment.clone(), command).await?;
             }
             Command::Proxy(args) => {
                 self.proxy(args).await?;
             }
+            Command::Pinned(command) => {
+                self.pinned(command).await?;
+            }
         }
 
         Ok(())
     }
 
-    /// Generates the CA.
-    async fn generate_ca(&self, force: bool) -> Result<(), Error> {
-        let tls_config = self
-            .environment
-            .get_untracked::<TlsConfig>("tls")
-            .await?
-            .unwrap_or_default();
-        let ca = tls::Ca::generate().await?;
-        let key_file = self.environment.config_relative_path(&tls_config.key_file);
-        let cert_file = self.environment.config_relative_path(&tls_config.cert_file);
+    /// Lists or removes pinned hosts.
+    async fn pinned(&self, command: PinnedCommand) -> Result<(), Error> {
+        let pinned_hosts = self.environment.pinned_hosts()?;
 
-        if !force {
-            if key_file.exists() {
-                tracing::error!(key_file = %key_file.display(), "Key file already exists. Aborting. Run with --force to overwrite existing files.");
-                return Ok(());
+        match command {
+            PinnedCommand::List => {
+                for pinned in pinned_hosts.hosts() {
+                    let detected_at: chrono::DateTime<chrono::Local> = pinned.detected_at.into();
+                    println!("{}\t{}\t{}", pinned.host, detected_at, pinned.reason);
+                }
             }
-            if cert_file.exists() {
-                tracing::error!(cert_file = %cert_file.display(), "Cert file already exists. Aborting. Run with --force to overwrite existing files.");
-                return Ok(());
+            PinnedCommand::Remove { host, .. } => {
+                let removed = pinned_hosts.remove(host.as_deref());
+                tracing::info!(removed, "Removed pinned hosts.");
             }
         }
 
-        ca.save(&key_file, &cert_file)?;
-
-        tracing::info!(key_file = %key_file.display(), "Key file saved.");
-        tracing::info!(cert_file = %cert_file.display(), "Cert file saved.");
+        pinned_hosts.close().await;
 
         Ok(())
     }
@@ -0,0 +1,361 @@
+//! Management of the certificate authority used to intercept TLS traffic.
+
+use std::{
+    io::{
+        IsTerminal,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use base64::Engine;
+use color_eyre::eyre::{
+    bail,
+    Error,
+};
+use sha1::{
+    Digest,
+    Sha1,
+};
+use skunk::protocol::tls::{
+    self,
+    cert_cache::Fingerprint,
+    name_constraints,
+};
+
+use crate::env::{
+    args::{
+        CaCommand,
+        ExportFormat,
+        NameConstraintArgs,
+    },
+    config::TlsConfig,
+    Environment,
+};
+
+/// Runs a `ca` subcommand.
+pub async fn run(environment: Environment, command: CaCommand) -> Result<(), Error> {
+    let files = CaFiles::from_environment(&environment).await?;
+
+    match command {
+        CaCommand::Generate {
+            force,
+            name_constraints,
+        } => {
+            if !force && !files.check_not_exist() {
+                return Ok(());
+            }
+            let name_constraints = name_constraints.parse()?;
+            let ca = tls::Ca::generate_with_name_constraints(name_constraints).await?;
+            files.save(&ca)?;
+        }
+        CaCommand::Import {
+            key,
+            cert,
+            pkcs12,
+            password_file,
+            force,
+        } => {
+            let ca = match (key, cert, pkcs12) {
+                (Some(key), Some(cert), None) => tls::Ca::open(key, cert)?,
+                (None, None, Some(pkcs12)) => {
+                    let password = import_password(password_file.as_deref())?;
+                    tls::Ca::open_pkcs12(pkcs12, &password)?
+                }
+                _ => bail!("Either --key and --cert, or --pkcs12 are required"),
+            };
+            if !force && !files.check_not_exist() {
+                return Ok(());
+            }
+            files.save(&ca)?;
+        }
+        CaCommand::Export {
+            format,
+            output,
+            password_file,
+            include_key,
+        } => {
+            if include_key && format != ExportFormat::Pkcs12 {
+                bail!("The key can only be exported as PKCS#12");
+            }
+            let ca = files.open()?;
+            let data = match format {
+                ExportFormat::Pem => ca.root_cert_pem().into_bytes(),
+                ExportFormat::Der => ca.root_cert().to_vec(),
+                ExportFormat::Pkcs12 => {
+                    let password = export_password(password_file.as_deref(), include_key)?;
+                    ca.to_pkcs12(&password, include_key)?
+                }
+                ExportFormat::Mobileconfig => mobileconfig(&ca).into_bytes(),
+            };
+            if let Some(output) = output {
+                std::fs::write(&output, data)?;
+                tracing::info!(output = %output.display(), ?format, "Root certificate exported.");
+            }
+            else {
+                std::io::stdout().write_all(&data)?;
+            }
+        }
+        CaCommand::Fingerprint => {
+            let ca = files.open()?;
+            let cert = ca.root_cert();
+            println!("SHA-256: {}", colon_hex(&Fingerprint::of(cert).0));
+            println!("SHA-1:   {}", colon_hex(&Sha1::digest(cert.as_ref())));
+        }
+        CaCommand::Rotate { name_constraints } => {
+            let old_ca = files.open()?;
+            let name_constraints = match name_constraints.parse()? {
+                Some(name_constraints) => Some(name_constraints),
+                None => old_ca.name_constraints().cloned(),
+            };
+            let ca = tls::Ca::generate_with_name_constraints(name_constraints).await?;
+
+            // the new files are written next to the old ones first, so that a failure
+            // leaves the old CA in place.
+            let new_files = CaFiles {
+                key_file: with_suffix(&files.key_file, ".new"),
+                cert_file: with_suffix(&files.cert_file, ".new"),
+            };
+            ca.save(&new_files.key_file, &new_files.cert_file)?;
+
+            for file in [&files.key_file, &files.cert_file] {
+                let backup = with_suffix(file, ".old");
+                std::fs::rename(file, &backup)?;
+                tracing::info!(file = %backup.display(), "Old file kept.");
+            }
+            for (new_file, file) in [
+                (&new_files.key_file, &files.key_file),
+                (&new_files.cert_file, &files.cert_file),
+            ] {
+                std::fs::rename(new_file, file)?;
+            }
+            tracing::info!(key_file = %files.key_file.display(), "Key file saved.");
+            tracing::info!(cert_file = %files.cert_file.display(), "Cert file saved.");
+            tracing::info!("CA rotated. Clients need to trust the new root certificate.");
+        }
+    }
+
+    Ok(())
+}
+
+/// Paths of the CA's files, from the `[tls]` section of the configuration.
+struct CaFiles {
+    key_file: PathBuf,
+    cert_file: PathBuf,
+}
+
+impl CaFiles {
+    async fn from_environment(environment: &Environment) -> Result<Self, Error> {
+        let tls_config = environment
+            .get_untracked::<TlsConfig>("tls")
+            .await?
+            .unwrap_or_default();
+        Ok(Self {
+            key_file: environment.config_relative_path(&tls_config.key_file),
+            cert_file: environment.config_relative_path(&tls_config.cert_file),
+        })
+    }
+
+    fn open(&self) -> Result<tls::Ca, Error> {
+        Ok(tls::Ca::open(&self.key_file, &self.cert_file)?)
+    }
+
+    /// Checks that the files don't exist yet, and logs an error if they do.
+    fn check_not_exist(&self) -> bool {
+        if self.key_file.exists() {
+            tracing::error!(key_file = %self.key_file.display(), "Key file already exists. Aborting. Run with --force to overwrite existing files.");
+            return false;
+        }
+        if self.cert_file.exists() {
+            tracing::error!(cert_file = %self.cert_file.display(), "Cert file already exists. Aborting. Run with --force to overwrite existing files.");
+            return false;
+        }
+        true
+    }
+
+    fn save(&self, ca: &tls::Ca) -> Result<(), Error> {
+        ca.save(&self.key_file, &self.cert_file)?;
+        tracing::info!(key_file = %self.key_file.display(), "Key file saved.");
+        tracing::info!(cert_file = %self.cert_file.display(), "Cert file saved.");
+        Ok(())
+    }
+}
+
+impl NameConstraintArgs {
+    fn parse(&self) -> Result<Option<name_constraints::NameConstraints>, Error> {
+        Ok(name_constraints::from_subtrees(
+            &self.permitted,
+            &self.excluded,
+        )?)
+    }
+}
+
+/// Environment variable the password of PKCS#12 files is read from.
+const PASSWORD_ENV: &str = "SKUNK_CA_PASSWORD";
+
+/// Reads the password of a PKCS#12 file from `password_file` or the
+/// environment.
+///
+/// Passwords aren't accepted as arguments, since those are visible to other
+/// users.
+fn read_password(password_file: Option<&Path>) -> Result<Option<String>, Error> {
+    if let Some(password_file) = password_file {
+        let password = std::fs::read_to_string(password_file)?;
+        Ok(Some(password.trim_end_matches(['\r', '\n']).to_owned()))
+    }
+    else {
+        Ok(std::env::var(PASSWORD_ENV).ok())
+    }
+}
+
+/// Gets the password of an imported PKCS#12 file with [`read_password`], or by
+/// prompting for it. Without a terminal, the password is empty.
+fn import_password(password_file: Option<&Path>) -> Result<String, Error> {
+    if let Some(password) = read_password(password_file)? {
+        Ok(password)
+    }
+    else if std::io::stdin().is_terminal() {
+        Ok(rpassword::prompt_password("Password: ")?)
+    }
+    else {
+        Ok(String::new())
+    }
+}
+
+/// Gets the password for an exported PKCS#12 file with [`read_password`], or
+/// by prompting for it.
+///
+/// Without the key, the file can be exported without a password.
+fn export_password(password_file: Option<&Path>, include_key: bool) -> Result<String, Error> {
+    let password = if let Some(password) = read_password(password_file)? {
+        password
+    }
+    else if !include_key {
+        String::new()
+    }
+    else if std::io::stdin().is_terminal() {
+        let password = rpassword::prompt_password("Password: ")?;
+        if rpassword::prompt_password("Repeat password: ")? != password {
+            bail!("Passwords don't match");
+        }
+        password
+    }
+    else {
+        bail!("Exporting the key requires a password. Use --password-file or set {PASSWORD_ENV}.");
+    };
+
+    if include_key && password.is_empty() {
+        bail!("Exporting the key requires a non-empty password");
+    }
+    Ok(password)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn colon_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Creates an Apple configuration profile that installs the root certificate.
+///
+/// The UUIDs are derived from the certificate, so installing the profile for
+/// the same certificate again replaces it.
+fn mobileconfig(ca: &tls::Ca) -> String {
+    let cert = ca.root_cert();
+    let fingerprint = Fingerprint::of(cert).0;
+    let uuid = |bytes: &[u8]| {
+        uuid::Builder::from_random_bytes(bytes.try_into().unwrap())
+            .into_uuid()
+            .to_string()
+            .to_uppercase()
+    };
+    let profile_uuid = uuid(&fingerprint[..16]);
+    let payload_uuid = uuid(&fingerprint[16..]);
+    let cert = base64::engine::general_purpose::STANDARD.encode(cert.as_ref());
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>PayloadContent</key>
+	<array>
+		<dict>
+			<key>PayloadCertificateFileName</key>
+			<string>skunk-root-ca.cer</string>
+			<key>PayloadContent</key>
+			<data>{cert}</data>
+			<key>PayloadDisplayName</key>
+			<string>skunk root CA</string>
+			<key>PayloadIdentifier</key>
+			<string>com.apple.security.root.{payload_uuid}</string>
+			<key>PayloadType</key>
+			<string>com.apple.security.root</string>
+			<key>PayloadUUID</key>
+			<string>{payload_uuid}</string>
+			<key>PayloadVersion</key>
+			<integer>1</integer>
+		</dict>
+	</array>
+	<key>PayloadDisplayName</key>
+	<string>skunk root CA</string>
+	<key>PayloadIdentifier</key>
+	<string>skunk.ca.{profile_uuid}</string>
+	<key>PayloadType</key>
+	<string>Configuration</string>
+	<key>PayloadUUID</key>
+	<string>{profile_uuid}</string>
+	<key>PayloadVersion</key>
+	<integer>1</integer>
+</dict>
+</plist>
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_formats_colon_hex() {
+        assert_eq!(colon_hex(&[]), "");
+        assert_eq!(colon_hex(&[0x0a]), "0A");
+        assert_eq!(colon_hex(&[0x00, 0xab, 0xff]), "00:AB:FF");
+    }
+
+    #[tokio::test]
+    async fn it_creates_mobileconfig_profiles() {
+        let ca = tls::Ca::generate().await.unwrap();
+        let profile = mobileconfig(&ca);
+
+        let cert = base64::engine::general_purpose::STANDARD.encode(ca.root_cert().as_ref());
+        assert!(profile.contains(&format!("<data>{cert}</data>")));
+
+        let fingerprint = Fingerprint::of(ca.root_cert()).0;
+        let payload_uuid = uuid::Builder::from_random_bytes(fingerprint[16..].try_into().unwrap())
+            .into_uuid()
+            .to_string()
+            .to_uppercase();
+        assert!(profile.contains(&format!(
+            "<string>com.apple.security.root.{payload_uuid}</string>"
+        )));
+
+        // the same certificate gives the same profile, so it replaces the installed
+        // one.
+        assert_eq!(mobileconfig(&ca), profile);
+        let other = tls::Ca::generate().await.unwrap();
+        assert_ne!(mobileconfig(&other), profile);
+    }
+}
@@ -57,8 +57,11 @@ impl Args {
 
 #[derive(Debug, Parser)]
 pub enum Command {
-    /// Generates key and root certificate for the certificate authority used to
-    /// intercept TLS traffic.
+    /// Manages the certificate authority used to intercept TLS traffic.
+    #[clap(subcommand)]
+    Ca(CaCommand),
+    /// Same as `ca generate`.
+    #[clap(hide = true)]
     GenerateCert {
         /// Overwrite existing files.
         #[clap(short, long)]
@@ -66,6 +69,118 @@ pub enum Command {
     },
     /// Example command to log (possibly decrypted) HTTP traffic to console.
     Proxy(ProxyArgs),
+    /// Lists or removes hosts whose connections are passed through, because a
+    /// client rejected our certificate.
+    ///
+    /// While the proxy is running, use the API instead.
+    #[clap(subcommand)]
+    Pinned(PinnedCommand),
+}
+
+#[derive(Debug, Parser)]
+pub enum CaCommand {
+    /// Generates key and root certificate.
+    Generate {
+        /// Overwrite existing files.
+        #[clap(short, long)]
+        force: bool,
+
+        #[clap(flatten)]
+        name_constraints: NameConstraintArgs,
+    },
+    /// Imports an existing key and root certificate.
+    Import {
+        /// The key file, PEM or DER encoded.
+        #[clap(long, requires = "cert", required_unless_present = "pkcs12")]
+        key: Option<PathBuf>,
+
+        /// The certificate file, PEM or DER encoded.
+        #[clap(long, requires = "key")]
+        cert: Option<PathBuf>,
+
+        /// A PKCS#12 file containing both key and certificate.
+        #[clap(long, conflicts_with_all = ["key", "cert"])]
+        pkcs12: Option<PathBuf>,
+
+        /// File containing the password of the PKCS#12 file. Without it, the
+        /// password is read from `SKUNK_CA_PASSWORD`, or prompted for.
+        #[clap(long, value_name = "FILE", conflicts_with_all = ["key", "cert"])]
+        password_file: Option<PathBuf>,
+
+        /// Overwrite existing files.
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Exports the root certificate, e.g. to install it on a device.
+    Export {
+        #[clap(short, long, value_enum, default_value_t = ExportFormat::Pem)]
+        format: ExportFormat,
+
+        /// File to write to. Defaults to stdout.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// File containing the password of the PKCS#12 file. Without it, the
+        /// password is read from `SKUNK_CA_PASSWORD`, or prompted for when
+        /// exporting the key.
+        #[clap(long, value_name = "FILE")]
+        password_file: Option<PathBuf>,
+
+        /// Also export the key. Only possible with PKCS#12, and requires a
+        /// password.
+        #[clap(long)]
+        include_key: bool,
+    },
+    /// Prints the fingerprints of the root certificate.
+    Fingerprint,
+    /// Replaces key and root certificate with new ones. The old files are kept
+    /// with an `.old` extension.
+    ///
+    /// Without any name constraints, the old CA's name constraints are used.
+    Rotate {
+        #[clap(flatten)]
+        name_constraints: NameConstraintArgs,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub struct NameConstraintArgs {
+    /// Only allow the CA to sign certificates for this domain and its
+    /// subdomains, or IP subnet (e.g. `10.0.0.0/8`).
+    #[clap(long = "permit", value_name("NAME"))]
+    pub permitted: Vec<String>,
+
+    /// Don't allow the CA to sign certificates for this domain and its
+    /// subdomains, or IP subnet.
+    #[clap(long = "exclude", value_name("NAME"))]
+    pub excluded: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// PEM encoded certificate.
+    Pem,
+    /// DER encoded certificate.
+    Der,
+    /// PKCS#12 file.
+    Pkcs12,
+    /// Apple configuration profile for iOS and macOS.
+    Mobileconfig,
+}
+
+#[derive(Debug, Parser)]
+pub enum PinnedCommand {
+    /// Lists the pinned hosts.
+    List,
+    /// Removes pinned hosts, so their connections are decrypted again.
+    Remove {
+        /// Only remove this host.
+        host: Option<String>,
+
+        /// Remove all hosts.
+        #[clap(long, conflicts_with = "host", required_unless_present = "host")]
+        all: bool,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -73,20 +188,31 @@ pub struct ProxyArgs {
     #[clap(flatten)]
     pub socks: SocksArgs,
 
+    #[clap(flatten)]
+    pub http: HttpArgs,
+
     #[clap(flatten)]
     pub pcap: PcapArgs,
 
     #[clap(flatten)]
     pub api: ApiArgs,
 
+    #[clap(flatten)]
+    pub tls: TlsArgs,
+
     #[clap(long)]
     pub no_graceful_shutdown: bool,
 
+    /// Rules file to apply to intercepted traffic.
+    #[clap(long, value_name("PATH"))]
+    pub rules: Option<PathBuf>,
+
     /// Only intercept specified addresses.
     ///
     /// `host:port` pairs. Multiple can be specified. This can be used to only
     /// selectively inspect traffic. By default all traffic is inspected.
-    /// Currently only ports 80 and 443 are supported.
+    /// TLS and HTTP are detected on any port, other protocols are passed
+    /// through.
     pub filter: Vec<TcpAddress>,
 }
 
@@ -106,8 +232,9 @@ pub struct SocksArgs {
     pub bind_address: SocketAddr,
 
     /// Username for the SOCKS proxy. If this is specified, --socks-password
-    /// needs to be specified as well.
-    #[clap(id = "socks_username", value_name("PASSWORD"), long = "socks-username")]
+    /// needs to be specified as well. More users can be added in the `[socks]`
+    /// section of the configuration.
+    #[clap(id = "socks_username", value_name("USERNAME"), long = "socks-username")]
     pub username: Option<String>,
 
     /// Password for the SOCKS proxy. If this is specified, --socks-username
@@ -132,6 +259,22 @@ impl SocksArgs {
     }
 }
 
+#[derive(Debug, Parser)]
+pub struct HttpArgs {
+    /// Enable HTTP proxy
+    #[clap(id = "http_enabled", long = "http")]
+    pub enabled: bool,
+
+    /// Bind address for the HTTP proxy.
+    #[clap(
+        id = "http_bind_address",
+        value_name("ADDRESS"),
+        long = "http-bind-address",
+        default_value = "127.0.0.1:8888"
+    )]
+    pub bind_address: SocketAddr,
+}
+
 #[derive(Debug, Parser)]
 pub struct PcapArgs {
     #[clap(id = "pcap_enabled", long = "pcap")]
@@ -146,6 +289,33 @@ pub struct PcapArgs {
 
     #[clap(id = "pcap_ap", long = "pcap-ap")]
     pub ap: bool,
+
+    /// DNS server that queries from the virtual network are forwarded to.
+    ///
+    /// By default the name server from `/etc/resolv.conf` is used. If there is
+    /// none, names are resolved by the operating system.
+    #[clap(
+        id = "pcap_dns_upstream",
+        value_name("ADDRESS"),
+        long = "pcap-dns-upstream"
+    )]
+    pub dns_upstream: Option<SocketAddr>,
+}
+
+#[derive(Debug, Parser)]
+pub struct TlsArgs {
+    /// Append the secrets of intercepted TLS connections to this file.
+    ///
+    /// This uses the NSS key log format, which can be used by e.g. Wireshark
+    /// to decrypt captured traffic. Overrides `key_log_file` in the `[tls]`
+    /// section of the configuration.
+    #[clap(
+        id = "tls_key_log_file",
+        value_name("PATH"),
+        long = "tls-key-log-file",
+        env = "SSLKEYLOGFILE"
+    )]
+    pub key_log_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Parser)]
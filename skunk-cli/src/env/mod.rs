@@ -12,7 +12,9 @@ use std::{
 
 use config::{
     ConfigFile,
+    SocksConfig,
     TlsConfig,
+    UpstreamConfig,
 };
 use murmur3::murmur3_x64_128;
 use serde::Deserialize;
@@ -25,6 +27,7 @@ pub use self::{
     },
     error::Error,
 };
+use crate::upstream::Upstreams;
 
 /// Default configuration directory relative to the OS's local configuration
 /// directory (e.g. `~/.config`` on Linux).
@@ -96,15 +99,89 @@ impl Environment {
         self.data_dir.join(path)
     }
 
-    pub async fn tls_context(&self) -> Result<tls::Context, crate::Error> {
-        let tls_config = self
+    /// Opens the table of hosts whose clients pin certificates.
+    pub fn pinned_hosts(&self) -> Result<tls::pinned::PinnedHosts, tls::Error> {
+        tls::pinned::PinnedHosts::open(self.data_relative_path("pinned-hosts.yml"))
+    }
+
+    /// Creates the TLS context from the `[tls]` section of the configuration.
+    ///
+    /// `key_log_file` overrides the key log file from the configuration.
+    pub async fn tls_context(
+        &self,
+        key_log_file: Option<&Path>,
+    ) -> Result<tls::Context, crate::Error> {
+        let mut tls_config = self
             .get_untracked::<TlsConfig>("tls")
             .await?
             .unwrap_or_default();
         let key_file = self.config_relative_path(&tls_config.key_file);
         let cert_file = self.config_relative_path(&tls_config.cert_file);
         let ca = tls::Ca::open(key_file, cert_file)?;
-        Ok(tls::Context::new(ca).await?)
+
+        let cert_cache = if tls_config.persist_cert_cache {
+            tls::cert_cache::CertCache::open(
+                self.data_relative_path("cert-cache"),
+                tls_config.cert_cache_capacity,
+                &ca,
+            )
+            .await?
+        }
+        else {
+            tls::cert_cache::CertCache::new(tls_config.cert_cache_capacity).await?
+        };
+        // CA bundles and client certificates are relative to the configuration
+        // directory.
+        for policy in std::iter::once(&mut tls_config.upstream.default).chain(
+            tls_config
+                .upstream
+                .hosts
+                .iter_mut()
+                .map(|host| &mut host.policy),
+        ) {
+            if let Some(ca_bundle) = &mut policy.ca_bundle {
+                *ca_bundle = self.config_relative_path(&ca_bundle);
+            }
+            if let Some(client_cert) = &mut policy.client_cert {
+                client_cert.relative_to(&self.config_dir);
+            }
+        }
+
+        let mut context = tls::Context::with_cert_cache(ca, cert_cache)?
+            .with_imitation_policies(tls_config.imitation)
+            .with_upstream_policies(&tls_config.upstream)?
+            .with_request_client_cert(tls_config.request_client_cert)
+            .with_pinned_hosts(self.pinned_hosts()?);
+
+        let key_log_file = key_log_file.map(ToOwned::to_owned).or_else(|| {
+            tls_config
+                .key_log_file
+                .map(|path| self.config_relative_path(path))
+        });
+        if let Some(key_log_file) = key_log_file {
+            tracing::info!(path = %key_log_file.display(), "Logging TLS secrets");
+            context = context.with_key_log_file(tls::key_log::KeyLogFile::open(key_log_file)?);
+        }
+
+        Ok(context)
+    }
+
+    /// Creates the upstream proxies from the `[upstream]` section of the
+    /// configuration.
+    pub async fn upstreams(&self) -> Result<Upstreams, crate::Error> {
+        let upstream_config = self
+            .get_untracked::<UpstreamConfig>("upstream")
+            .await?
+            .unwrap_or_default();
+        Upstreams::from_config(&upstream_config)
+    }
+
+    /// Returns the `[socks]` section of the configuration.
+    pub async fn socks_config(&self) -> Result<SocksConfig, Error> {
+        Ok(self
+            .get_untracked::<SocksConfig>("socks")
+            .await?
+            .unwrap_or_default())
     }
 }
 
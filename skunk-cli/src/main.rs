@@ -2,8 +2,10 @@
 
 mod api;
 mod app;
+mod ca;
 mod env;
 mod proxy;
+mod upstream;
 mod util;
 
 use clap::Parser;
@@ -1,51 +1,180 @@
 use std::{
     collections::HashSet,
+    fmt::Display,
+    fs::File,
+    hash::Hash,
+    io::Write,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    },
+    path::Path,
     sync::Arc,
 };
 
+use ::http::{
+    header::{
+        CONTENT_LENGTH,
+        TRANSFER_ENCODING,
+    },
+    request,
+    response,
+    HeaderName,
+    HeaderValue,
+    Method,
+    StatusCode,
+};
 use axum::Router;
+use bytes::Bytes;
+use chrono::{
+    DateTime,
+    FixedOffset,
+    Utc,
+};
 use color_eyre::eyre::Error;
+use http_body_util::{
+    combinators::UnsyncBoxBody,
+    BodyExt,
+    Full,
+};
+use indexmap::{
+    IndexMap,
+    IndexSet,
+};
+use parking_lot::Mutex;
+use serde::Serialize;
 use skunk::{
-    address::TcpAddress,
-    connect::{
-        Connect,
-        ConnectTcp,
+    address::{
+        HostAddress,
+        TcpAddress,
+        UdpAddress,
     },
+    connect::Connect,
     protocol::{
-        http,
+        http::{
+            self,
+            body::{
+                Incoming,
+                Tee,
+            },
+            HeaderMap,
+            Request,
+            Response,
+            Upgraded,
+            Uri,
+        },
+        inet,
+        sniff,
         tls,
+        websocket::{
+            self,
+            Frame,
+            Opcode,
+        },
     },
     proxy::{
+        fn_proxy,
+        http as http_proxy,
         pcap::{
             self,
+            dns,
             interface::Interface,
+            NetworkConfig,
             VirtualNetwork,
         },
-        socks::server as socks,
+        socks,
         DestinationAddress,
         Passthrough,
         Proxy,
     },
+    rule::{
+        compiler::Config as RulesConfig,
+        engine::{
+            DnsInfo,
+            HttpInfo,
+            Rules,
+            RulesEvaluator,
+            TlsInfo,
+            WebSocketInfo,
+        },
+        file::{
+            DefaultEffects,
+            Direction,
+            LogEffect,
+            LogTarget,
+        },
+    },
 };
+use skunk_api_protocol::{
+    flow::{
+        Artifact,
+        ArtifactId,
+        DnsAnswer,
+        DnsMessage,
+        DnsQuery,
+        Flow,
+        FlowId,
+        HttpRequest,
+        HttpResponse,
+        Message,
+        MessageData,
+        MessageId,
+        MessageKind,
+        Metadata,
+        UdpDatagram,
+        WebSocketDirection,
+        WebSocketFrame,
+    },
+    interrupt::{
+        Continue,
+        Intercepted,
+        InterceptedHttpRequest,
+        InterceptedHttpResponse,
+    },
+};
+use skunk_flow_store::FlowStore;
 use skunk_util::error::ResultExt;
 use tokio::{
-    net::TcpStream,
+    io::{
+        AsyncRead,
+        AsyncWrite,
+    },
+    net::UdpSocket,
     task::JoinSet,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
+use uuid::Uuid;
 
 use crate::{
+    api::{
+        Flows,
+        Interrupts,
+    },
     env::{
         args::ProxyArgs,
         Environment,
     },
+    upstream::{
+        Outgoing,
+        Upstreams,
+    },
     util::{
         serve_ui::ServeUi,
         shutdown::cancel_on_ctrlc_or_sigterm,
     },
 };
 
+/// Maximum number of bytes of a streamed HTTP body that are recorded.
+const MAX_RECORDED_BODY_SIZE: usize = 1024 * 1024;
+
+/// Maximum number of destinations of a UDP association for which rule results,
+/// resolved addresses and whether they're recorded are remembered. The oldest
+/// destinations are forgotten first.
+const MAX_UDP_DESTINATIONS: usize = 1024;
+
 pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error> {
     let pcap_interface = if args.pcap.enabled {
         fn print_interfaces() -> Result<(), Error> {
@@ -75,7 +204,9 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
     };
 
     // create TLS context
-    let tls = environment.tls_context().await?;
+    let tls = environment
+        .tls_context(args.tls.key_log_file.as_deref())
+        .await?;
 
     // target filters
     let filter = Arc::new(if args.filter.is_empty() {
@@ -87,6 +218,42 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
         Filter::Set(args.filter.into_iter().collect())
     });
 
+    // flow store. all intercepted traffic is recorded here.
+    let flow_store = FlowStore::create(environment.data_relative_path("flows.db")).await?;
+    let flows = Flows::new(Some(flow_store));
+
+    // rules
+    let (rules, rules_log) = if let Some(path) = &args.rules {
+        tracing::info!(path = %path.display(), "Loading rules");
+        let rules = Rules::from_file(
+            path,
+            &RulesConfig {
+                with_user_interaction: args.api.enabled,
+            },
+        )?;
+        let rules_log = RulesLog::open(environment.data_relative_path("rules.log"))?;
+        (Some(rules), Some(rules_log))
+    }
+    else {
+        (None, None)
+    };
+
+    // upstream proxies. rules can select them with `upstream` effects.
+    let upstreams = environment.upstreams().await?.with_rules(rules.clone());
+
+    // messages paused by `interrupt` effects are continued through the API.
+    let interrupts = args.api.enabled.then(Interrupts::default);
+
+    let context = Context {
+        tls,
+        upstreams,
+        filter,
+        flows: flows.clone(),
+        rules,
+        rules_log,
+        interrupts: interrupts.clone(),
+    };
+
     // shutdown token
     let shutdown = if args.no_graceful_shutdown {
         CancellationToken::default()
@@ -99,12 +266,18 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
 
     if args.socks.enabled {
         let shutdown = shutdown.clone();
+        let context = context.clone();
+        let socks_config = environment.socks_config().await?;
 
         join_set.spawn(async move {
-            // run the SOCKS server. `proxy` will handle connections. The default
-            // [`Connect`][skunk::connect::Connect] (i.e.
-            // [`ConnectTcp`][skunk::connect::ConnectTcp]) is used.
-            let mut listener = args.socks.builder()?.listen().await?;
+            // run the SOCKS server. `proxy` will handle connections. Connections to the
+            // destination are made through the upstream proxies.
+            let mut listener = args
+                .socks
+                .builder()?
+                .with_users(socks_config.users)
+                .listen()
+                .await?;
             tracing::info!("SOCKS server listening on: {}", args.socks.bind_address);
 
             let mut join_set = JoinSet::default();
@@ -115,18 +288,73 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
                     request_res = listener.next() => request_res?,
                 };
 
-                match ConnectTcp.connect(request.destination_address()).await {
+                let request = match request {
+                    socks::server::Request::Connect(request) => request,
+                    socks::server::Request::Associate(request) => {
+                        // we relay datagrams ourselves, since the upstream proxies only tunnel TCP
+                        // connections. so we can't relay them, if connections go through a proxy.
+                        if !context.upstreams.default_is_direct() {
+                            tracing::warn!("Rejecting UDP association, since connections are made through an upstream proxy");
+                            request.reject(socks::v5::RejectReason::CommandNotSupported);
+                            continue;
+                        }
+                        let client_address = *request.client_address();
+                        let Ok(association) = request.accept().await.log_error()
+                        else {
+                            continue;
+                        };
+                        let context = context.clone();
+                        let shutdown = shutdown.clone();
+
+                        join_set.spawn(async move {
+                            tokio::select! {
+                                _ = shutdown.cancelled() => {},
+                                result = relay_udp(context, association, client_address) => {
+                                    let _ = result.log_error();
+                                }
+                            }
+                        });
+                        continue;
+                    }
+                    socks::server::Request::Bind(request) => {
+                        // the peer connects to us directly, since the upstream proxies can't
+                        // listen for us.
+                        let client_address = *request.client_address();
+                        let Ok(binding) = request.accept().await.log_error()
+                        else {
+                            continue;
+                        };
+                        let context = context.clone();
+                        let shutdown = shutdown.clone();
+
+                        join_set.spawn(async move {
+                            tokio::select! {
+                                _ = shutdown.cancelled() => {},
+                                result = proxy_bind(context, binding, client_address) => {
+                                    let _ = result.log_error();
+                                }
+                            }
+                        });
+                        continue;
+                    }
+                };
+
+                match context
+                    .upstreams
+                    .connect(request.destination_address())
+                    .await
+                {
                     Ok(outgoing) => {
-                        let bind_address = outgoing.local_addr().unwrap().into();
+                        let bind_address = outgoing.get_ref().local_addr().unwrap().into();
                         let incoming = request.accept(bind_address).await?;
-                        let tls = tls.clone();
-                        let filter = filter.clone();
+                        let username = incoming.username().map(ToOwned::to_owned);
+                        let context = context.clone();
                         let shutdown = shutdown.clone();
 
                         join_set.spawn(async move {
                             tokio::select! {
                                 _ = shutdown.cancelled() => {},
-                                result = proxy(tls, filter, incoming, outgoing) => {
+                                result = proxy(context, incoming, outgoing, username, None) => {
                                     let _ = result.log_error();
                                 }
                             }
@@ -144,10 +372,36 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
         });
     }
 
+    if args.http.enabled {
+        let shutdown = shutdown.clone();
+        let context = context.clone();
+
+        join_set.spawn(async move {
+            // run the HTTP proxy. `CONNECT` tunnels and plain HTTP requests are both passed
+            // to `proxy`.
+            tracing::info!("HTTP proxy listening on: {}", args.http.bind_address);
+
+            http_proxy::Builder::default()
+                .with_bind_address(args.http.bind_address)
+                .with_graceful_shutdown(shutdown)
+                .with_connect(context.upstreams.clone())
+                .with_proxy(fn_proxy(
+                    move |incoming: http_proxy::Incoming, outgoing: Outgoing| {
+                        proxy(context.clone(), incoming, outgoing, None, None)
+                    },
+                ))
+                .serve()
+                .await?;
+
+            Ok::<(), Error>(())
+        });
+    }
+
     if let Some(interface) = pcap_interface {
         join_set.spawn({
             let shutdown = shutdown.clone();
             let interface = interface.clone();
+            let context = context.clone();
             async move {
                 let _hostapd = if args.pcap.ap {
                     let country_code = std::env::var("HOSTAPD_CC")
@@ -168,8 +422,78 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
                     None
                 };
 
-                let _network = VirtualNetwork::new(&interface)?;
-                shutdown.cancelled().await;
+                // run the virtual network. TCP connections from clients on the network are
+                // terminated by us and passed to `proxy`, just like connections accepted by
+                // the SOCKS server.
+                let network = VirtualNetwork::new(&interface)?;
+                let network_config = NetworkConfig::default();
+                let mut listener = network.tcp_listener().await;
+
+                let mut join_set = JoinSet::default();
+
+                // hand out addresses to clients on the network.
+                join_set.spawn(pcap::dhcp::run(
+                    network.clone(),
+                    network_config.clone(),
+                    shutdown.clone(),
+                ));
+
+                // answer DNS queries from clients on the network. this also lets the network
+                // know the host names clients connect to.
+                let upstream = args
+                    .pcap
+                    .dns_upstream
+                    .map_or_else(dns::Upstream::default, dns::Upstream::Server);
+                join_set.spawn(dns::run(
+                    network.clone(),
+                    network_config,
+                    upstream,
+                    dns::fn_handler({
+                        let context = context.clone();
+                        move |query, resolver| resolve(context.clone(), query, resolver)
+                    }),
+                    shutdown.clone(),
+                ));
+
+                loop {
+                    let incoming = tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        incoming_opt = listener.accept() => {
+                            let Some(incoming) = incoming_opt else { break; };
+                            incoming
+                        }
+                    };
+
+                    let context = context.clone();
+                    let shutdown = shutdown.clone();
+
+                    join_set.spawn(async move {
+                        // the client already completed the handshake with us, so if we can't
+                        // connect to the destination, all we can do is close the connection.
+                        let outgoing =
+                            match context.upstreams.connect(incoming.destination_address()).await {
+                                Ok(outgoing) => outgoing,
+                                Err(error) => {
+                                    tracing::debug!(
+                                        destination_address = %incoming.destination_address(),
+                                        ?error,
+                                        "Failed to connect"
+                                    );
+                                    return;
+                                }
+                            };
+
+                        tokio::select! {
+                            _ = shutdown.cancelled() => {},
+                            result = proxy(context, incoming, outgoing, None, None) => {
+                                let _ = result.log_error();
+                            }
+                        }
+                    });
+                }
+
+                while join_set.join_next().await.is_some() {}
+
                 Ok::<(), Error>(())
             }
         });
@@ -177,7 +501,11 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
 
     if args.api.enabled {
         let shutdown = shutdown.clone();
-        let mut api_builder = super::api::builder(environment.clone());
+        let mut api_builder = super::api::builder(environment.clone())
+            .with_flows(flows)
+            .with_interrupts(interrupts.unwrap_or_default())
+            .with_cert_cache(context.tls.cert_cache().clone())
+            .with_pinned_hosts(context.tls.pinned_hosts().clone());
         let serve_ui = ServeUi::from_environment(&environment, &mut api_builder).await?;
 
         join_set.spawn(async move {
@@ -206,50 +534,618 @@ pub async fn run(environment: Environment, args: ProxyArgs) -> Result<(), Error>
     Ok(())
 }
 
+/// State shared by all proxied connections.
+#[derive(Clone, Debug)]
+struct Context {
+    tls: tls::Context,
+    upstreams: Upstreams,
+    filter: Arc<Filter>,
+    flows: Flows,
+    rules: Option<Rules>,
+    rules_log: Option<RulesLog>,
+    interrupts: Option<Interrupts>,
+}
+
+impl Context {
+    /// Applies the effects that fired for a connection or message.
+    fn apply_effects(&self, effects: Vec<DefaultEffects>, destination: &impl Display) -> Action {
+        let mut action = Action::Continue;
+
+        for effect in effects {
+            match effect {
+                DefaultEffects::Log(log) => self.log(&log, destination),
+                DefaultEffects::Interrupt(interrupt) => {
+                    if action == Action::Continue {
+                        action = Action::Interrupt {
+                            prompt: interrupt.prompt,
+                        };
+                    }
+                }
+                DefaultEffects::Drop => action = Action::Drop,
+                DefaultEffects::Replace(_) | DefaultEffects::Inject(_) => {
+                    tracing::warn!(%destination, "Replace and inject effects only apply to WebSocket frames");
+                }
+                DefaultEffects::Resolve(_) => {
+                    tracing::warn!(%destination, "Resolve effects only apply to DNS queries");
+                }
+                // the upstream proxy was already selected when the connection was made.
+                DefaultEffects::Upstream(_) => {}
+            }
+        }
+
+        action
+    }
+
+    /// Applies the effects that fired for a connection or message that isn't
+    /// an HTTP request or response, and so can't be interrupted. Returns
+    /// whether it is dropped.
+    fn is_dropped(&self, effects: Vec<DefaultEffects>, destination: &impl Display) -> bool {
+        match self.apply_effects(effects, destination) {
+            Action::Continue => false,
+            Action::Interrupt { .. } => {
+                tracing::warn!(%destination, "Only HTTP requests and responses can be interrupted");
+                false
+            }
+            Action::Drop => true,
+        }
+    }
+
+    /// Returns whether datagrams to `destination` are relayed. Rules are
+    /// evaluated like for a TCP connection to it. Since we can't relay
+    /// datagrams through an upstream proxy, we only relay them, if
+    /// connections to the destination are made directly.
+    fn relays_datagrams_to(&self, destination: &TcpAddress) -> bool {
+        if !self.upstreams.is_direct(destination) {
+            tracing::debug!(%destination, "Discarding datagrams, since connections go through an upstream proxy");
+            return false;
+        }
+
+        let Some(rules) = &self.rules
+        else {
+            return true;
+        };
+        let effects = rules.evaluator().set_tcp(destination);
+        if self.is_dropped(effects, destination) {
+            tracing::info!(%destination, "Dropping datagrams");
+            return false;
+        }
+        true
+    }
+
+    /// Applies the effects that fired for a WebSocket frame. Returns the frames
+    /// that are forwarded in its place.
+    ///
+    /// `drop` and `inject` effects apply to whole messages, so that fragmented
+    /// messages stay intact: whether a message is dropped is decided by its
+    /// first frame, and frames are only injected after its last frame. Control
+    /// frames are never dropped and nothing is injected after them.
+    fn apply_frame_effects(
+        &self,
+        effects: Vec<DefaultEffects>,
+        destination: &TcpAddress,
+        message: &mut WebSocketMessage,
+        mut frame: Frame,
+    ) -> Vec<Frame> {
+        let mut injected = vec![];
+        let mut other = vec![];
+
+        for effect in effects {
+            match effect {
+                DefaultEffects::Replace(replace) => {
+                    if let Some(text) = frame.as_text() {
+                        let text = replace
+                            .pattern
+                            .replace_all(text, &replace.with)
+                            .into_owned();
+                        frame.payload = text.into();
+                    }
+                    else if frame.is_compressed() {
+                        tracing::debug!(%destination, "Can't replace text in compressed frame");
+                    }
+                }
+                DefaultEffects::Inject(inject) => injected.push(Frame::text(inject.text)),
+                effect => other.push(effect),
+            }
+        }
+
+        let is_dropped = self.is_dropped(other, destination);
+
+        if frame.opcode.is_control() {
+            // control frames can be sent in the middle of a fragmented message, and are
+            // needed to keep the connection alive and to close it.
+            if is_dropped || !injected.is_empty() {
+                tracing::debug!(%destination, opcode = %frame.opcode, "Control frames can't be dropped or followed by injected frames");
+            }
+            return vec![frame];
+        }
+
+        if frame.opcode != Opcode::Continuation {
+            message.is_dropped = is_dropped;
+            if is_dropped {
+                tracing::info!(%destination, "Dropping message");
+            }
+        }
+        message.injected.extend(injected);
+
+        let fin = frame.fin;
+        let mut frames = if message.is_dropped {
+            vec![]
+        }
+        else {
+            vec![frame]
+        };
+        if fin {
+            frames.append(&mut message.injected);
+        }
+        frames
+    }
+
+    /// Applies the effects that fired for a DNS query. Returns the answer, if
+    /// the query shouldn't be forwarded to the upstream resolver.
+    fn apply_dns_effects(&self, effects: Vec<DefaultEffects>, name: &str) -> Option<dns::Answer> {
+        let mut addresses: Option<Vec<_>> = None;
+        let mut other = vec![];
+
+        for effect in effects {
+            match effect {
+                DefaultEffects::Resolve(resolve) => {
+                    addresses
+                        .get_or_insert_with(Vec::new)
+                        .extend(resolve.addresses)
+                }
+                effect => other.push(effect),
+            }
+        }
+
+        if self.is_dropped(other, &name) {
+            tracing::info!(name, "Dropping DNS query");
+            return Some(dns::Answer::name_error());
+        }
+        addresses.map(dns::Answer::spoofed)
+    }
+
+    /// Pauses a message until the user continues it through the API.
+    async fn interrupt(
+        &self,
+        message_id: MessageId,
+        flow_id: FlowId,
+        prompt: Option<String>,
+        message: Intercepted,
+    ) -> Continue {
+        if let Some(interrupts) = &self.interrupts {
+            tracing::info!(?message_id, "Waiting for user to continue message");
+            interrupts
+                .interrupt(message_id, flow_id, prompt, message)
+                .await
+        }
+        else {
+            Continue::Forward
+        }
+    }
+
+    fn log(&self, log: &LogEffect, destination: &impl Display) {
+        let name = log.name.as_deref().unwrap_or("rule");
+        let message = log.message.as_deref().unwrap_or("matched");
+
+        match log.target {
+            LogTarget::User => tracing::info!(%destination, name, "{message}"),
+            LogTarget::File => {
+                if let Some(rules_log) = &self.rules_log {
+                    rules_log.write(destination, name, message);
+                }
+            }
+        }
+    }
+}
+
+/// The message that is currently sent in one direction of a WebSocket
+/// connection.
+#[derive(Debug, Default)]
+struct WebSocketMessage {
+    /// Whether the frames of the message are dropped.
+    is_dropped: bool,
+
+    /// Frames that are injected after the last frame of the message.
+    injected: Vec<Frame>,
+}
+
+/// What to do with a connection or message, after rules have been applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Action {
+    Continue,
+    Interrupt { prompt: Option<String> },
+    Drop,
+}
+
+/// Log file for the `log` effect with `target: file`.
+#[derive(Clone, Debug)]
+struct RulesLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl RulesLog {
+    fn open(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn write(&self, destination: &impl Display, name: &str, message: &str) {
+        let mut file = self.file.lock();
+        let _ = writeln!(
+            file,
+            "{} {destination} {name}: {message}",
+            Utc::now().to_rfc3339()
+        )
+        .log_error();
+    }
+}
+
 /// Proxy connections.
 ///
 /// This will first check if the connection matches any filters. Then it will
-/// decide using the port whether to decrypt TLS for that connection. Finally it
-/// will run a HTTP server and client to proxy HTTP requests.
-async fn proxy(
-    tls: tls::Context,
-    filter: Arc<Filter>,
-    incoming: socks::Incoming,
-    outgoing: TcpStream,
-) -> Result<(), skunk::Error> {
-    let destination_address = incoming.destination_address();
+/// peek at the first bytes the client sends to decide whether to decrypt TLS
+/// for that connection. Finally it will run a HTTP server and client to proxy
+/// HTTP requests. Connections that don't speak HTTP (with or without TLS) are
+/// passed through.
+///
+/// The connection is recorded as a flow for each protocol layer (TCP, TLS and
+/// HTTP), and each request and response is recorded as a message in the HTTP
+/// flow. The `username` of a client that authenticated with the proxy is
+/// stored in the metadata of the TCP flow. The TCP flow is a child of `parent`,
+/// if given.
+///
+/// If rules are loaded, they're evaluated as soon as the destination address,
+/// the TLS handshake, and each HTTP request and response are known.
+async fn proxy<I>(
+    context: Context,
+    incoming: I,
+    outgoing: Outgoing,
+    username: Option<String>,
+    parent: Option<FlowId>,
+) -> Result<(), skunk::Error>
+where
+    I: AsyncRead + AsyncWrite + DestinationAddress + Send + Unpin + 'static,
+{
+    let destination_address = incoming.destination_address().clone();
+
+    let mut evaluator = context.rules.as_ref().map(Rules::evaluator);
+    if let Some(evaluator) = &mut evaluator {
+        let effects = evaluator.set_tcp(&destination_address);
+        if context.is_dropped(effects, &destination_address) {
+            tracing::info!(destination = %destination_address, "Dropping connection");
+            return Ok(());
+        }
+    }
 
-    if filter.matches(destination_address) {
+    if context.filter.matches(&destination_address) {
         let span = tracing::info_span!("connection", destination = %destination_address);
+        let flows = &context.flows;
 
-        let is_tls = destination_address.port == 443;
-        let (incoming, outgoing) = tls.maybe_decrypt(incoming, outgoing, is_tls).await?;
+        let mut metadata = Metadata::default();
+        let _ = metadata
+            .insert("destination".to_owned(), &destination_address)
+            .log_error();
+        if let Some(username) = &username {
+            // the user that authenticated with the SOCKS server.
+            let _ = metadata
+                .insert("username".to_owned(), &username)
+                .log_error();
+        }
+        let tcp_flow = begin_flow(flows, parent, "tcp", metadata).await;
 
-        http::proxy(incoming, outgoing, |request, send_request| {
-            let span = tracing::info_span!(
-                parent: &span,
-                "request",
-                method = %request.method(),
-                uri = %request.uri()
-            );
+        // peek at the first bytes the client sends to decide how to handle the
+        // connection.
+        let (protocol, incoming) = match sniff::Protocol::detect(incoming).await {
+            Ok(detected) => detected,
+            Err(error) => {
+                end_flows(flows, &[Some(tcp_flow)]).await;
+                return Err(error.into());
+            }
+        };
+        tracing::debug!(destination = %destination_address, ?protocol, "Detected protocol");
+        if protocol == sniff::Protocol::Unknown {
+            let result = Passthrough.proxy(incoming, outgoing).await;
+            end_flows(flows, &[Some(tcp_flow)]).await;
+            return Ok(result?);
+        }
 
-            async move {
-                // log request
-                tracing::info!("Request");
+        let is_tls = protocol == sniff::Protocol::Tls;
+        let decrypted = match context
+            .tls
+            .maybe_decrypt(incoming, outgoing, &destination_address, is_tls)
+            .await
+        {
+            Ok(decrypted) => decrypted,
+            Err(error) => {
+                end_flows(flows, &[Some(tcp_flow)]).await;
+                return Err(error.into());
+            }
+        };
+        let (incoming, outgoing) = match decrypted {
+            tls::Decrypted::Decrypted(incoming, outgoing) => (incoming, outgoing),
+            tls::Decrypted::Pinned(pinned) => {
+                // the client pins certificates, so we can only record what we saw of the
+                // handshake.
+                tracing::debug!(destination = %destination_address, host = %pinned.host, "Passing through connection of client that pins certificates");
+                let mut metadata = Metadata::default();
+                let _ = metadata
+                    .insert("pinned_host".to_owned(), &pinned.host)
+                    .log_error();
+                if let Some(client_hello) = &pinned.client_hello {
+                    let _ = metadata
+                        .insert("client_hello".to_owned(), client_hello)
+                        .log_error();
+                    let _ = metadata
+                        .insert("ja3".to_owned(), &client_hello.ja3())
+                        .log_error();
+                    let _ = metadata
+                        .insert("ja4".to_owned(), &client_hello.ja4())
+                        .log_error();
+                }
+                let tls_flow = begin_flow(flows, Some(tcp_flow), "tls", metadata).await;
+                let result = Passthrough.proxy(pinned.incoming, pinned.outgoing).await;
+                end_flows(flows, &[Some(tls_flow), Some(tcp_flow)]).await;
+                return Ok(result?);
+            }
+        };
+
+        let tls_flow = if is_tls {
+            let mut metadata = Metadata::default();
+            if let Some(alpn_protocol) = outgoing
+                .get_tls_connection()
+                .and_then(|connection| connection.alpn_protocol())
+            {
+                let _ = metadata
+                    .insert(
+                        "alpn_protocol".to_owned(),
+                        &String::from_utf8_lossy(alpn_protocol),
+                    )
+                    .log_error();
+            }
+            if let Some(connection) = outgoing.get_tls_connection() {
+                let chain = connection.peer_certificates().unwrap_or_default();
+                let _ = metadata
+                    .insert(
+                        "upstream_chain".to_owned(),
+                        &tls::upstream::CertificateInfo::chain(chain),
+                    )
+                    .log_error();
+            }
+            if let Some(chain) = incoming
+                .get_tls_connection()
+                .and_then(|connection| connection.peer_certificates())
+            {
+                let _ = metadata
+                    .insert(
+                        "client_chain".to_owned(),
+                        &tls::upstream::CertificateInfo::chain(chain),
+                    )
+                    .log_error();
+            }
+            if let Some(verification) = outgoing.verification() {
+                let _ = metadata
+                    .insert("upstream_verification".to_owned(), verification)
+                    .log_error();
+            }
+            if let Some(client_hello) = incoming.client_hello() {
+                let _ = metadata
+                    .insert("client_hello".to_owned(), client_hello)
+                    .log_error();
+                let _ = metadata
+                    .insert("ja3".to_owned(), &client_hello.ja3())
+                    .log_error();
+                let _ = metadata
+                    .insert("ja4".to_owned(), &client_hello.ja4())
+                    .log_error();
+            }
+            if let Some(key_log) = incoming.key_log() {
+                let _ = metadata
+                    .insert("key_log".to_owned(), &key_log.lines())
+                    .log_error();
+            }
+            Some(begin_flow(flows, Some(tcp_flow), "tls", metadata).await)
+        }
+        else {
+            None
+        };
+
+        if let Some(evaluator) = &mut evaluator {
+            let mut tls_info = match (incoming.get_tls_connection(), outgoing.get_tls_connection())
+            {
+                (Some(incoming), Some(outgoing)) => TlsInfo::from_connections(incoming, outgoing),
+                _ => TlsInfo::default(),
+            };
+            if let Some(client_hello) = incoming.client_hello() {
+                tls_info = tls_info.with_client_hello(client_hello);
+            }
+            let effects = evaluator.set_tls(&tls_info);
+            if context.is_dropped(effects, &destination_address) {
+                tracing::info!(destination = %destination_address, "Dropping connection");
+                end_flows(flows, &[tls_flow, Some(tcp_flow)]).await;
+                return Ok(());
+            }
+        }
+
+        // the decrypted stream might not be HTTP. for unencrypted connections this
+        // just detects HTTP again from the bytes that were already read.
+        let (protocol, incoming) = match sniff::Protocol::detect(incoming).await {
+            Ok(detected) => detected,
+            Err(error) => {
+                end_flows(flows, &[tls_flow, Some(tcp_flow)]).await;
+                return Err(error.into());
+            }
+        };
+        let Some(protocol) = http::Protocol::from_sniffed(protocol)
+        else {
+            tracing::debug!(destination = %destination_address, "Decrypted connection is not HTTP");
+            let result = Passthrough.proxy(incoming, outgoing).await;
+            end_flows(flows, &[tls_flow, Some(tcp_flow)]).await;
+            return Ok(result?);
+        };
 
-                let response = send_request.send(request).await?;
+        let http_flow = begin_flow(
+            flows,
+            Some(tls_flow.unwrap_or(tcp_flow)),
+            "http",
+            Metadata::default(),
+        )
+        .await;
 
-                // log response
-                tracing::info!(
-                    status = %response.status(),
-                    "Response"
+        let result = http::proxy(
+            incoming,
+            outgoing,
+            protocol,
+            |request, send_request: http::SendRequest<ProxyBody>| {
+                let span = tracing::info_span!(
+                    parent: &span,
+                    "request",
+                    method = %request.method(),
+                    uri = %request.uri()
                 );
+                let context = context.clone();
+                let evaluator = evaluator.clone();
+                let destination_address = destination_address.clone();
+
+                async move {
+                    let (mut parts, body) = request.into_parts();
+                    let mut body = ProxiedBody::Streamed(body);
+                    tracing::info!("Request");
+
+                    let message_id = MessageId(Uuid::new_v4());
+                    let method = parts.method.to_string();
+                    let url = request_url(&parts.uri, &parts.headers, is_tls, &destination_address);
+                    let mut synthesized = None;
+
+                    let mut request_evaluator = evaluator.clone();
+                    if let Some(evaluator) = &mut request_evaluator {
+                        let effects = evaluator.set_http(&HttpInfo {
+                            direction: Direction::Request,
+                            method: method.clone(),
+                            url: url.clone(),
+                            headers: header_list(&parts.headers),
+                        });
+                        match context.apply_effects(effects, &destination_address) {
+                            Action::Continue => {}
+                            Action::Interrupt { prompt } => {
+                                // the user wants to see the whole request, so we have to buffer it.
+                                let buffered = body.buffer().await?;
+                                let intercepted = Intercepted::HttpRequest(intercepted_request(
+                                    &parts, &buffered,
+                                ));
+                                body = match context
+                                    .interrupt(message_id, http_flow, prompt, intercepted)
+                                    .await
+                                {
+                                    Continue::Forward => ProxiedBody::Buffered(buffered),
+                                    Continue::Modify(Intercepted::HttpRequest(modified)) => {
+                                        ProxiedBody::Buffered(modify_request(&mut parts, modified)?)
+                                    }
+                                    Continue::Modify(Intercepted::HttpResponse(_)) => {
+                                        return Err(invalid_edit("expected an edited request"));
+                                    }
+                                    Continue::Respond(response) => {
+                                        synthesized = Some(response);
+                                        ProxiedBody::Buffered(buffered)
+                                    }
+                                    Continue::Drop => return Err(dropped()),
+                                };
+                            }
+                            Action::Drop => return Err(dropped()),
+                        }
+                    }
 
-                Ok(response)
+                    let body =
+                        record_request(&context.flows, http_flow, message_id, &parts, body).await;
+
+                    let (mut parts, mut body) = if let Some(response) = synthesized {
+                        let version = parts.version;
+                        let (mut parts, ()) = Response::new(()).into_parts();
+                        parts.version = version;
+                        let body = modify_response(&mut parts, response)?;
+                        (parts, ProxiedBody::Buffered(body))
+                    }
+                    else {
+                        let response = send_request.send(Request::from_parts(parts, body)).await?;
+                        let (parts, body) = response.into_parts();
+                        (parts, ProxiedBody::Streamed(body))
+                    };
+                    tracing::info!(
+                        status = %parts.status,
+                        "Response"
+                    );
+
+                    let message_id = MessageId(Uuid::new_v4());
+
+                    // effects that fired for the request don't fire again for its response.
+                    let response_evaluator = evaluator
+                        .zip(request_evaluator)
+                        .map(|(evaluator, request)| evaluator.for_response(&request));
+                    if let Some(mut evaluator) = response_evaluator {
+                        let effects = evaluator.set_http(&HttpInfo {
+                            direction: Direction::Response,
+                            method,
+                            url,
+                            headers: header_list(&parts.headers),
+                        });
+                        match context.apply_effects(effects, &destination_address) {
+                            Action::Continue => {}
+                            Action::Interrupt { prompt } => {
+                                let buffered = body.buffer().await?;
+                                let intercepted = Intercepted::HttpResponse(intercepted_response(
+                                    &parts, &buffered,
+                                ));
+                                body = match context
+                                    .interrupt(message_id, http_flow, prompt, intercepted)
+                                    .await
+                                {
+                                    Continue::Forward => ProxiedBody::Buffered(buffered),
+                                    Continue::Modify(Intercepted::HttpResponse(modified))
+                                    | Continue::Respond(modified) => {
+                                        ProxiedBody::Buffered(modify_response(
+                                            &mut parts, modified,
+                                        )?)
+                                    }
+                                    Continue::Modify(Intercepted::HttpRequest(_)) => {
+                                        return Err(invalid_edit("expected an edited response"));
+                                    }
+                                    Continue::Drop => return Err(dropped()),
+                                };
+                            }
+                            Action::Drop => return Err(dropped()),
+                        }
+                    }
+
+                    let body =
+                        record_response(&context.flows, http_flow, message_id, &parts, body).await;
+
+                    Ok(Response::from_parts(parts, body))
+                }
+                .instrument(span)
+            },
+        )
+        .await;
+
+        // continue with the upgraded connection, e.g. a WebSocket
+        let result = match result {
+            Ok(Some(upgraded)) => {
+                proxy_upgraded(
+                    &context,
+                    evaluator,
+                    http_flow,
+                    &destination_address,
+                    upgraded,
+                )
+                .await
             }
-            .instrument(span)
-        })
-        .await?;
+            Ok(None) => Ok(()),
+            Err(error) => Err(error),
+        };
+
+        end_flows(flows, &[Some(http_flow), tls_flow, Some(tcp_flow)]).await;
+
+        result?;
     }
     else {
         Passthrough.proxy(incoming, outgoing).await?;
@@ -258,6 +1154,797 @@ async fn proxy(
     Ok::<_, skunk::Error>(())
 }
 
+/// Body of HTTP requests and responses sent by the proxy.
+type ProxyBody = UnsyncBoxBody<Bytes, http::Error>;
+
+/// A HTTP body on its way through the proxy.
+enum ProxiedBody {
+    /// The body was read completely, e.g. because it was interrupted.
+    Buffered(Bytes),
+
+    /// The body is streamed through as it arrives.
+    Streamed(Incoming),
+}
+
+impl ProxiedBody {
+    /// Reads the whole body.
+    async fn buffer(self) -> Result<Bytes, skunk::Error> {
+        match self {
+            Self::Buffered(body) => Ok(body),
+            Self::Streamed(body) => Ok(body.collect().await.map_err(http::Error::from)?.to_bytes()),
+        }
+    }
+}
+
+/// Proxies a connection after a protocol upgrade.
+///
+/// WebSocket frames are recorded as messages in a `websocket` flow, and rules
+/// are applied to each frame. Other protocols are passed through.
+async fn proxy_upgraded<I, O>(
+    context: &Context,
+    evaluator: Option<RulesEvaluator>,
+    parent: FlowId,
+    destination_address: &TcpAddress,
+    upgraded: Upgraded<I, O>,
+) -> Result<(), skunk::Error>
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin,
+    O: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let is_websocket = upgraded
+        .protocol
+        .as_deref()
+        .is_some_and(|protocol| protocol.eq_ignore_ascii_case("websocket"));
+
+    if !is_websocket {
+        tracing::debug!(protocol = ?upgraded.protocol, "Passing through upgraded connection");
+        Passthrough
+            .proxy(upgraded.incoming, upgraded.outgoing)
+            .await?;
+        return Ok(());
+    }
+
+    let flows = &context.flows;
+    let websocket_flow = begin_flow(flows, Some(parent), "websocket", Metadata::default()).await;
+    let messages = &<[Mutex<WebSocketMessage>; 2]>::default();
+
+    let result = websocket::proxy(
+        upgraded.incoming,
+        upgraded.outgoing,
+        |direction, frame: Frame| {
+            let evaluator = evaluator.clone();
+
+            async move {
+                tracing::debug!(?direction, opcode = %frame.opcode, "Frame");
+
+                let frames = if let Some(mut evaluator) = evaluator {
+                    let effects =
+                        evaluator.set_websocket(&WebSocketInfo::from_frame(direction, &frame));
+                    let message = match direction {
+                        websocket::Direction::FromClient => &messages[0],
+                        websocket::Direction::FromServer => &messages[1],
+                    };
+                    context.apply_frame_effects(
+                        effects,
+                        destination_address,
+                        &mut message.lock(),
+                        frame,
+                    )
+                }
+                else {
+                    vec![frame]
+                };
+
+                for frame in &frames {
+                    record_frame(flows, websocket_flow, direction, frame).await;
+                }
+
+                Ok(frames)
+            }
+        },
+    )
+    .await;
+
+    end_flows(flows, &[Some(websocket_flow)]).await;
+
+    result
+}
+
+/// The error used to abort a connection, when a `drop` effect fires for a
+/// HTTP request or response, or the user drops an interrupted message.
+fn dropped() -> skunk::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "dropped by rule").into()
+}
+
+/// The error used to abort a connection, when the user continues an
+/// interrupted message with an invalid edit.
+fn invalid_edit(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> skunk::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error).into()
+}
+
+fn intercepted_request(parts: &request::Parts, body: &Bytes) -> InterceptedHttpRequest {
+    InterceptedHttpRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        headers: header_list(&parts.headers),
+        body: body.to_vec(),
+    }
+}
+
+fn intercepted_response(parts: &response::Parts, body: &Bytes) -> InterceptedHttpResponse {
+    InterceptedHttpResponse {
+        status: parts.status.as_u16(),
+        headers: header_list(&parts.headers),
+        body: body.to_vec(),
+    }
+}
+
+/// Applies the user's edits to a request. Returns the new body.
+fn modify_request(
+    parts: &mut request::Parts,
+    modified: InterceptedHttpRequest,
+) -> Result<Bytes, skunk::Error> {
+    parts.method = Method::from_bytes(modified.method.as_bytes()).map_err(invalid_edit)?;
+    parts.uri = modified.uri.parse::<Uri>().map_err(invalid_edit)?;
+    parts.headers = edited_headers(modified.headers)?;
+    Ok(modified.body.into())
+}
+
+/// Applies the user's edits to a response. Returns the new body.
+fn modify_response(
+    parts: &mut response::Parts,
+    modified: InterceptedHttpResponse,
+) -> Result<Bytes, skunk::Error> {
+    parts.status = StatusCode::from_u16(modified.status).map_err(invalid_edit)?;
+    parts.headers = edited_headers(modified.headers)?;
+    Ok(modified.body.into())
+}
+
+/// Parses edited headers.
+///
+/// The body might have changed, so `content-length` and `transfer-encoding`
+/// are removed and determined by hyper instead.
+fn edited_headers(headers: Vec<(String, String)>) -> Result<HeaderMap, skunk::Error> {
+    let mut header_map = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(invalid_edit)?;
+        let value = HeaderValue::from_str(&value).map_err(invalid_edit)?;
+        header_map.append(name, value);
+    }
+    header_map.remove(CONTENT_LENGTH);
+    header_map.remove(TRANSFER_ENCODING);
+    Ok(header_map)
+}
+
+/// Reconstructs the full URL of a request, which is usually in origin-form.
+fn request_url(
+    uri: &Uri,
+    headers: &HeaderMap,
+    is_tls: bool,
+    destination_address: &TcpAddress,
+) -> String {
+    if uri.scheme().is_some() {
+        uri.to_string()
+    }
+    else {
+        let scheme = if is_tls { "https" } else { "http" };
+        let host = headers
+            .get("host")
+            .and_then(|host| host.to_str().ok())
+            .map_or_else(|| destination_address.to_string(), ToOwned::to_owned);
+        format!("{scheme}://{host}{uri}")
+    }
+}
+
+/// Answers DNS queries from clients on the virtual network.
+///
+/// Each query is recorded as a flow, with the query and the answer as
+/// messages. If rules are loaded, `resolve` effects answer the query with
+/// their addresses, and `drop` effects answer that the name doesn't exist.
+async fn resolve(context: Context, query: dns::Query, resolver: dns::Resolver) -> dns::Answer {
+    let flows = &context.flows;
+
+    let mut metadata = Metadata::default();
+    let _ = metadata
+        .insert("client".to_owned(), &query.client)
+        .log_error();
+    let dns_flow = begin_flow(flows, None, "dns", metadata).await;
+
+    let data = DnsQuery {
+        name: query.name.clone(),
+        query_type: query.query_type.to_string(),
+    };
+    emit_dns_message(flows, dns_flow, MessageKind::Request, &data).await;
+
+    let answer = context.rules.as_ref().and_then(|rules| {
+        let effects = rules.evaluator().set_dns(&DnsInfo::from_query(&query));
+        context.apply_dns_effects(effects, &query.name)
+    });
+    let answer = match answer {
+        Some(answer) => answer,
+        None => resolver.resolve(&query).await,
+    };
+
+    let data = DnsAnswer {
+        response_code: answer.response_code().to_string(),
+        addresses: answer.addresses().to_vec(),
+        ttl: answer.ttl(),
+        spoofed: answer.is_spoofed(),
+    };
+    emit_dns_message(flows, dns_flow, MessageKind::Response, &data).await;
+
+    end_flows(flows, &[Some(dns_flow)]).await;
+
+    answer
+}
+
+/// Waits for the peer of a SOCKS BIND request and proxies its connection.
+///
+/// The BIND request is recorded as a `bind` flow. The connection from the peer
+/// is handled by [`proxy`] like any other connection, with the peer as its
+/// destination, and is recorded as a child of the `bind` flow.
+async fn proxy_bind(
+    context: Context,
+    binding: socks::server::Binding,
+    client_address: SocketAddr,
+) -> Result<(), skunk::Error> {
+    let flows = &context.flows;
+
+    let mut metadata = Metadata::default();
+    let _ = metadata
+        .insert("client".to_owned(), &client_address)
+        .log_error();
+    let _ = metadata
+        .insert("destination".to_owned(), binding.destination_address())
+        .log_error();
+    if let Ok(bind_address) = binding.bind_address() {
+        let _ = metadata
+            .insert("bind_address".to_owned(), &bind_address)
+            .log_error();
+    }
+    let username = binding.username().map(ToOwned::to_owned);
+    if let Some(username) = &username {
+        let _ = metadata
+            .insert("username".to_owned(), &username)
+            .log_error();
+    }
+    let bind_flow = begin_flow(flows, None, "bind", metadata).await;
+
+    let result = match binding.accept().await.log_error() {
+        Ok(Some((incoming, peer))) => {
+            proxy(
+                context.clone(),
+                incoming,
+                Outgoing::Plain(peer),
+                username,
+                Some(bind_flow),
+            )
+            .await
+        }
+        // the client closed the connection before the peer connected, the peer didn't
+        // connect in time, or accepting failed.
+        _ => Ok(()),
+    };
+
+    end_flows(flows, &[Some(bind_flow)]).await;
+
+    result
+}
+
+/// Relays the datagrams of a SOCKS UDP association.
+///
+/// The association is recorded as a UDP flow, with each datagram to or from a
+/// destination matching the filter as a message. Datagrams are sent to their
+/// destinations directly, from sockets that belong to the association.
+///
+/// Rules are evaluated once for each destination, like for the destination of
+/// a TCP connection. Datagrams to destinations that are dropped by a rule, or
+/// for which an upstream proxy is selected, are discarded.
+async fn relay_udp(
+    context: Context,
+    mut association: socks::server::Association,
+    client_address: SocketAddr,
+) -> Result<(), socks::error::Error> {
+    let flows = &context.flows;
+
+    let mut metadata = Metadata::default();
+    let _ = metadata
+        .insert("client".to_owned(), &client_address)
+        .log_error();
+    if let Some(username) = association.username() {
+        let _ = metadata
+            .insert("username".to_owned(), &username)
+            .log_error();
+    }
+    let udp_flow = begin_flow(flows, None, "udp", metadata).await;
+
+    let result = async {
+        let outgoing_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        // IPv6 might not be available.
+        let outgoing_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+
+        let mut resolved = IndexMap::new();
+        let mut relayed = IndexMap::new();
+        let mut recorded = IndexSet::new();
+        let mut buf_v4 = vec![0; 0x10000];
+        let mut buf_v6 = vec![0; 0x10000];
+
+        loop {
+            let (n_read, source_address, buf) = tokio::select! {
+                datagram = association.receive() => {
+                    let Some(datagram) = datagram? else { break; };
+                    let destination = TcpAddress::new(
+                        datagram.destination_address.host.clone(),
+                        datagram.destination_address.port,
+                    );
+                    let relay = match relayed.get(&datagram.destination_address) {
+                        Some(relay) => *relay,
+                        None => {
+                            let relay = context.relays_datagrams_to(&destination);
+                            insert_bounded(&mut relayed, datagram.destination_address.clone(), relay);
+                            relay
+                        }
+                    };
+                    if !relay {
+                        continue;
+                    }
+
+                    let record = context.filter.matches(&destination);
+                    if record {
+                        record_datagram(
+                            flows,
+                            udp_flow,
+                            MessageKind::Request,
+                            &datagram.destination_address,
+                            &datagram.data,
+                        )
+                        .await;
+                    }
+
+                    let Some(destination_address) =
+                        resolve_udp(&mut resolved, &datagram.destination_address).await
+                    else {
+                        tracing::debug!(destination = %datagram.destination_address, "Failed to resolve destination of datagram");
+                        continue;
+                    };
+                    if record && !recorded.contains(&destination_address) {
+                        if recorded.len() >= MAX_UDP_DESTINATIONS {
+                            recorded.shift_remove_index(0);
+                        }
+                        recorded.insert(destination_address);
+                    }
+                    let socket = match destination_address {
+                        SocketAddr::V4(_) => Some(&outgoing_v4),
+                        SocketAddr::V6(_) => outgoing_v6.as_ref(),
+                    };
+                    if let Some(socket) = socket {
+                        // like a router, we don't care if sending fails.
+                        let _ = socket.send_to(&datagram.data, destination_address).await;
+                    }
+                    continue;
+                }
+                result = outgoing_v4.recv_from(&mut buf_v4) => {
+                    let (n_read, source_address) = result?;
+                    (n_read, source_address, &buf_v4)
+                }
+                result = recv_from_opt(outgoing_v6.as_ref(), &mut buf_v6) => {
+                    let (n_read, source_address) = result?;
+                    (n_read, source_address, &buf_v6)
+                }
+            };
+
+            let record = recorded.contains(&source_address);
+            let source_address = UdpAddress::from(source_address);
+            let data = &buf[..n_read];
+            if record {
+                record_datagram(
+                    flows,
+                    udp_flow,
+                    MessageKind::Response,
+                    &source_address,
+                    data,
+                )
+                .await;
+            }
+            association.send(&source_address, data).await?;
+        }
+
+        Ok::<(), socks::error::Error>(())
+    }
+    .await;
+
+    end_flows(flows, &[Some(udp_flow)]).await;
+
+    result
+}
+
+/// Receives a datagram from `socket`, if there is one. Otherwise this never
+/// completes.
+async fn recv_from_opt(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr), std::io::Error> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves the destination address of a datagram. Host names are only
+/// resolved once per association.
+async fn resolve_udp(
+    resolved: &mut IndexMap<String, IpAddr>,
+    address: &UdpAddress,
+) -> Option<SocketAddr> {
+    let ip_address = match &address.host {
+        HostAddress::IpAddress(ip_address) => *ip_address,
+        HostAddress::DnsName(name) => {
+            if let Some(ip_address) = resolved.get(name) {
+                *ip_address
+            }
+            else {
+                let ip_address = tokio::net::lookup_host((name.as_str(), address.port))
+                    .await
+                    .ok()?
+                    .next()?
+                    .ip();
+                insert_bounded(resolved, name.clone(), ip_address);
+                ip_address
+            }
+        }
+    };
+    Some((ip_address, address.port).into())
+}
+
+/// Inserts a destination of a UDP association that isn't in `map` yet,
+/// forgetting the oldest one if there are already [`MAX_UDP_DESTINATIONS`].
+fn insert_bounded<K: Hash + Eq, V>(map: &mut IndexMap<K, V>, key: K, value: V) {
+    if map.len() >= MAX_UDP_DESTINATIONS {
+        map.shift_remove_index(0);
+    }
+    map.insert(key, value);
+}
+
+/// Records a datagram. Datagrams from or to the DNS port are decoded.
+async fn record_datagram(
+    flows: &Flows,
+    flow_id: FlowId,
+    kind: MessageKind,
+    address: &UdpAddress,
+    data: &[u8],
+) {
+    let message_id = MessageId(Uuid::new_v4());
+    let timestamp = Utc::now().into();
+    let artifact = artifact(message_id, timestamp, None, &Bytes::copy_from_slice(data));
+    let data = UdpDatagram {
+        address: address.to_string(),
+        payload: artifact.as_ref().map(|(artifact, _)| artifact.artifact_id),
+        dns: (address.port == dns::SERVER_PORT)
+            .then(|| decode_dns(data))
+            .flatten(),
+    };
+    emit_message(
+        flows,
+        Message {
+            message_id,
+            flow_id,
+            kind,
+            timestamp,
+            data: MessageData::default(),
+            metadata: Metadata::default(),
+        },
+        &data,
+        artifact,
+    )
+    .await;
+}
+
+/// Decodes a DNS query or answer from the payload of a datagram.
+fn decode_dns(data: &[u8]) -> Option<DnsMessage> {
+    let message = inet::dns::Message::parse(data).ok()?;
+    let question = message.questions.first()?;
+
+    let dns_message = match message.header.flags.qr {
+        inet::dns::Qr::Query => {
+            DnsMessage::Query(DnsQuery {
+                name: question.qname.as_str().to_owned(),
+                query_type: question.qtype.to_string(),
+            })
+        }
+        inet::dns::Qr::Reply => {
+            let (addresses, ttl) = message.answer_addresses();
+            DnsMessage::Answer(DnsAnswer {
+                response_code: message.header.flags.rcode.to_string(),
+                addresses,
+                ttl: ttl.unwrap_or_default(),
+                spoofed: false,
+            })
+        }
+    };
+
+    Some(dns_message)
+}
+
+/// Records a DNS query or answer.
+async fn emit_dns_message<T: Serialize>(
+    flows: &Flows,
+    flow_id: FlowId,
+    kind: MessageKind,
+    data: &T,
+) {
+    emit_message(
+        flows,
+        Message {
+            message_id: MessageId(Uuid::new_v4()),
+            flow_id,
+            kind,
+            timestamp: Utc::now().into(),
+            data: MessageData::default(),
+            metadata: Metadata::default(),
+        },
+        data,
+        None,
+    )
+    .await;
+}
+
+/// Begins a new flow.
+///
+/// Failing to record the flow is logged, but doesn't interrupt the proxied
+/// connection.
+async fn begin_flow(
+    flows: &Flows,
+    parent: Option<FlowId>,
+    protocol: &str,
+    metadata: Metadata,
+) -> FlowId {
+    let flow = Flow {
+        flow_id: FlowId(Uuid::new_v4()),
+        parent,
+        protocol: Some(protocol.to_owned()),
+        timestamp: Utc::now().into(),
+        metadata,
+    };
+    let _ = flows.begin_flow(&flow).await.log_error();
+    flow.flow_id
+}
+
+/// Records a HTTP request as it was sent to the server.
+///
+/// Returns the body to send. See [`record_http_message`].
+async fn record_request(
+    flows: &Flows,
+    flow_id: FlowId,
+    message_id: MessageId,
+    parts: &request::Parts,
+    body: ProxiedBody,
+) -> ProxyBody {
+    let data = HttpRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        version: format!("{:?}", parts.version),
+        headers: header_list(&parts.headers),
+        body: None,
+    };
+    record_http_message(
+        flows,
+        flow_id,
+        message_id,
+        MessageKind::Request,
+        &parts.headers,
+        body,
+        move |artifact_id| {
+            HttpRequest {
+                body: artifact_id,
+                ..data
+            }
+        },
+    )
+    .await
+}
+
+/// Records a HTTP response as it was sent to the client.
+///
+/// Returns the body to send. See [`record_http_message`].
+async fn record_response(
+    flows: &Flows,
+    flow_id: FlowId,
+    message_id: MessageId,
+    parts: &response::Parts,
+    body: ProxiedBody,
+) -> ProxyBody {
+    let data = HttpResponse {
+        status: parts.status.as_u16(),
+        version: format!("{:?}", parts.version),
+        headers: header_list(&parts.headers),
+        body: None,
+    };
+    record_http_message(
+        flows,
+        flow_id,
+        message_id,
+        MessageKind::Response,
+        &parts.headers,
+        body,
+        move |artifact_id| {
+            HttpResponse {
+                body: artifact_id,
+                ..data
+            }
+        },
+    )
+    .await
+}
+
+/// Records a HTTP message with its body.
+///
+/// Buffered bodies are recorded right away. Streamed bodies are passed
+/// through, and the message is recorded once the body was sent, with at most
+/// [`MAX_RECORDED_BODY_SIZE`] bytes of it. If the body was longer, the message
+/// has `body_truncated` set in its metadata.
+async fn record_http_message<T, F>(
+    flows: &Flows,
+    flow_id: FlowId,
+    message_id: MessageId,
+    kind: MessageKind,
+    headers: &HeaderMap,
+    body: ProxiedBody,
+    data: F,
+) -> ProxyBody
+where
+    T: Serialize + Send + Sync,
+    F: FnOnce(Option<ArtifactId>) -> T + Send + 'static,
+{
+    let timestamp = Utc::now().into();
+    let mime_type = headers
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+
+    let emit = {
+        let flows = flows.clone();
+        move |body: Bytes, truncated: bool| {
+            async move {
+                let artifact = artifact(message_id, timestamp, mime_type, &body);
+                let data = data(artifact.as_ref().map(|(artifact, _)| artifact.artifact_id));
+                let mut metadata = Metadata::default();
+                if truncated {
+                    let _ = metadata
+                        .insert("body_truncated".to_owned(), &true)
+                        .log_error();
+                }
+                emit_message(
+                    &flows,
+                    Message {
+                        message_id,
+                        flow_id,
+                        kind,
+                        timestamp,
+                        data: MessageData::default(),
+                        metadata,
+                    },
+                    &data,
+                    artifact,
+                )
+                .await;
+            }
+        }
+    };
+
+    match body {
+        ProxiedBody::Buffered(body) => {
+            emit(body.clone(), false).await;
+            Full::new(body)
+                .map_err(|error| match error {})
+                .boxed_unsync()
+        }
+        ProxiedBody::Streamed(body) => {
+            let (body, copy_rx) = Tee::new(body, MAX_RECORDED_BODY_SIZE);
+            tokio::spawn(async move {
+                if let Ok(copy) = copy_rx.await {
+                    emit(copy.data, copy.truncated).await;
+                }
+            });
+            body.map_err(http::Error::from).boxed_unsync()
+        }
+    }
+}
+
+/// Records a WebSocket frame as it was forwarded.
+async fn record_frame(
+    flows: &Flows,
+    flow_id: FlowId,
+    direction: websocket::Direction,
+    frame: &Frame,
+) {
+    let message_id = MessageId(Uuid::new_v4());
+    let timestamp = Utc::now().into();
+    let mime_type = (frame.opcode == Opcode::Text).then(|| "text/plain".to_owned());
+    let artifact = artifact(message_id, timestamp, mime_type, &frame.payload);
+    let data = WebSocketFrame {
+        direction: match direction {
+            websocket::Direction::FromClient => WebSocketDirection::FromClient,
+            websocket::Direction::FromServer => WebSocketDirection::FromServer,
+        },
+        opcode: frame.opcode.to_string(),
+        fin: frame.fin,
+        payload: artifact.as_ref().map(|(artifact, _)| artifact.artifact_id),
+    };
+    emit_message(
+        flows,
+        Message {
+            message_id,
+            flow_id,
+            kind: MessageKind::Other,
+            timestamp,
+            data: MessageData::default(),
+            metadata: Metadata::default(),
+        },
+        &data,
+        artifact,
+    )
+    .await;
+}
+
+/// Ends flows in the given order.
+async fn end_flows(flows: &Flows, flow_ids: &[Option<FlowId>]) {
+    for flow_id in flow_ids.iter().flatten() {
+        let _ = flows.end_flow(*flow_id).await.log_error();
+    }
+}
+
+/// Emits a message with `data` as its [`MessageData`].
+///
+/// Like with [`begin_flow`] errors are only logged.
+async fn emit_message<T: Serialize>(
+    flows: &Flows,
+    mut message: Message,
+    data: &T,
+    artifact: Option<(Artifact, Bytes)>,
+) {
+    let Ok(data) = MessageData::from_value(data).log_error()
+    else {
+        return;
+    };
+    message.data = data;
+
+    let _ = flows
+        .emit_message_with_artifacts(message, artifact.as_slice())
+        .await
+        .log_error();
+}
+
+/// Creates an artifact for some data, if it's not empty.
+fn artifact(
+    message_id: MessageId,
+    timestamp: DateTime<FixedOffset>,
+    mime_type: Option<String>,
+    data: &Bytes,
+) -> Option<(Artifact, Bytes)> {
+    (!data.is_empty()).then(|| {
+        let artifact = Artifact {
+            artifact_id: ArtifactId(Uuid::new_v4()),
+            message_id: Some(message_id),
+            mime_type,
+            file_name: None,
+            timestamp,
+        };
+        (artifact, data.clone())
+    })
+}
+
+fn header_list(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_owned(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
 /// A simple filter to decide which target addresses should be intercepted.
 #[derive(Clone, Debug)]
 enum Filter {
@@ -267,10 +1954,6 @@ enum Filter {
 
 impl Filter {
     pub fn matches(&self, address: &TcpAddress) -> bool {
-        if address.port != 80 && address.port != 443 {
-            return false;
-        }
-
         match self {
             Filter::All => true,
             Filter::Set(targets) => targets.contains(address),
@@ -0,0 +1,21 @@
+-- the original table references `flow(message_id)`, which doesn't exist, so
+-- sqlite refuses any row in it while foreign keys are enforced. the artifacts
+-- can't be copied back and are dropped.
+
+DROP TABLE artifact;
+
+CREATE TABLE artifact (
+    artifact_id UUID NOT NULL PRIMARY KEY,
+    message_id UUID,
+    mime_type TEXT,
+    file_name TEXT,
+    timestamp DATETIME NOT NULL,
+    hash BLOB NOT NULL,
+
+    FOREIGN KEY(message_id) REFERENCES flow(message_id),
+    FOREIGN KEY(hash) REFERENCES artifact_blob(hash)
+);
+
+CREATE INDEX index_artifact_mime_type ON artifact(mime_type);
+CREATE INDEX index_artifact_file_name ON artifact(file_name);
+CREATE INDEX index_artifact_timestamp ON artifact(timestamp);
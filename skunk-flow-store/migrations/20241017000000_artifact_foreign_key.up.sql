@@ -0,0 +1,35 @@
+-- the artifact table referenced `flow(message_id)`, which doesn't exist. since
+-- sqlite can't alter constraints, we have to recreate the table and copy the
+-- artifacts over. artifacts whose message doesn't exist are kept, but detached
+-- from it.
+
+CREATE TABLE artifact_new (
+    artifact_id UUID NOT NULL PRIMARY KEY,
+    message_id UUID,
+    mime_type TEXT,
+    file_name TEXT,
+    timestamp DATETIME NOT NULL,
+    hash BLOB NOT NULL,
+
+    FOREIGN KEY(message_id) REFERENCES message(message_id),
+    FOREIGN KEY(hash) REFERENCES artifact_blob(hash)
+);
+
+INSERT INTO artifact_new (artifact_id, message_id, mime_type, file_name, timestamp, hash)
+SELECT
+    artifact_id,
+    CASE WHEN message_id IN (SELECT message_id FROM message) THEN message_id END,
+    mime_type,
+    file_name,
+    timestamp,
+    hash
+FROM artifact;
+
+DROP TABLE artifact;
+
+ALTER TABLE artifact_new RENAME TO artifact;
+
+CREATE INDEX index_artifact_message_id ON artifact(message_id);
+CREATE INDEX index_artifact_mime_type ON artifact(mime_type);
+CREATE INDEX index_artifact_file_name ON artifact(file_name);
+CREATE INDEX index_artifact_timestamp ON artifact(timestamp);
@@ -10,7 +10,12 @@ use serde::{
     Deserialize,
     Serialize,
 };
+use sha2::{
+    Digest,
+    Sha256,
+};
 use skunk_api_protocol::flow::{
+    Artifact,
     Flow,
     FlowId,
     Message,
@@ -192,12 +197,52 @@ impl<'a> Transaction<'a> {
         Ok(())
     }
 
+    /// Inserts an artifact and its data.
+    ///
+    /// The data is stored content-addressed, so identical blobs are only stored
+    /// once.
+    pub async fn insert_artifact(&mut self, artifact: &Artifact, data: &[u8]) -> Result<(), Error> {
+        let hash = Sha256::digest(data).to_vec();
+        let size = data.len() as i64;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO artifact_blob (hash, size, data)
+            VALUES (?, ?, ?)
+            ON CONFLICT(hash) DO NOTHING
+            "#,
+            hash,
+            size,
+            data,
+        )
+        .execute(self.transaction.as_mut())
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO artifact (artifact_id, message_id, mime_type, file_name, timestamp, hash)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+            artifact.artifact_id,
+            artifact.message_id,
+            artifact.mime_type,
+            artifact.file_name,
+            artifact.timestamp,
+            hash,
+        )
+        .execute(self.transaction.as_mut())
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn get_flows(
         &mut self,
         parent_id: Option<FlowId>,
         after: Option<DateTime<FixedOffset>>,
         before: Option<DateTime<FixedOffset>>,
         limit: Option<usize>,
+        username: Option<&str>,
     ) -> Result<Vec<Flow>, Error> {
         // note: a negative value in the LIMIT clause will cause sqlite to ignore the
         // limit
@@ -220,6 +265,8 @@ impl<'a> Transaction<'a> {
                 (?2 > timestamp OR ?2 IS NULL)
                 AND
                 (timestamp < ?3 OR ?3 IS NULL)
+                AND
+                (json_extract(metadata, '$.username') = ?5 OR ?5 IS NULL)
             ORDER BY timestamp ASC
             LIMIT ?4
             "#,
@@ -227,6 +274,7 @@ impl<'a> Transaction<'a> {
             after,
             before,
             limit,
+            username,
         )
         .fetch_all(self.transaction.as_mut())
         .await?
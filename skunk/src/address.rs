@@ -5,6 +5,10 @@ use std::{
     cmp::Ordering,
     convert::Infallible,
     fmt::Display,
+    hash::{
+        Hash,
+        Hasher,
+    },
     net::{
         IpAddr,
         Ipv4Addr,
@@ -165,6 +169,15 @@ impl UdpAddress {
     }
 }
 
+impl From<SocketAddr> for UdpAddress {
+    fn from(value: SocketAddr) -> Self {
+        Self {
+            host: value.ip().into(),
+            port: value.port(),
+        }
+    }
+}
+
 /// Failed to parse [`TcpAddress`].
 #[derive(Debug, thiserror::Error)]
 #[error("invalid tcp address: {0}")]
@@ -207,7 +220,7 @@ impl<'de> Deserialize<'de> for UdpAddress {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Ports {
     Single(Port),
     /// A range of ports. Represented in string form by `min .. max`. We use
@@ -334,6 +347,12 @@ impl PartialEq for Port {
 
 impl Eq for Port {}
 
+impl Hash for Port {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.number.hash(state);
+    }
+}
+
 impl PartialOrd for Port {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
@@ -0,0 +1,399 @@
+//! Connect through an upstream HTTP proxy with `CONNECT` requests.
+//!
+//! [RFC 9110](https://datatracker.ietf.org/doc/html/rfc9110#name-connect)
+
+use std::{
+    fmt::Debug,
+    net::IpAddr,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+use base64::Engine;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+    ReadBuf,
+};
+
+use super::{
+    Connect,
+    ConnectTcp,
+};
+use crate::address::{
+    HostAddress,
+    TcpAddress,
+};
+
+/// Max size of a response head (status line and headers) we accept.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Max number of headers we accept in a response.
+const MAX_HEADERS: usize = 128;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid response from proxy")]
+    InvalidResponse(#[from] httparse::Error),
+
+    #[error("response head too large")]
+    ResponseHeadTooLarge,
+
+    #[error("proxy refused to open tunnel: {status} {reason}")]
+    Refused { status: u16, reason: String },
+
+    #[error("invalid server name for TLS: {host}")]
+    InvalidServerName { host: HostAddress },
+}
+
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Io(error) => error,
+            _ => std::io::Error::other(value),
+        }
+    }
+}
+
+/// Username and password for `Basic` authentication with the proxy.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Connect by opening a tunnel with a `CONNECT` request on an upstream HTTP
+/// proxy.
+///
+/// The connection to the proxy itself can be TLS encrypted (i.e. a HTTPS
+/// proxy). DNS names are resolved by the proxy.
+#[derive(Clone, Debug)]
+pub struct ConnectHttp<C = ConnectTcp> {
+    proxy_address: TcpAddress,
+    credentials: Option<Credentials>,
+    #[cfg(feature = "tls")]
+    tls: Option<std::sync::Arc<rustls::ClientConfig>>,
+    connect: C,
+}
+
+impl ConnectHttp {
+    /// Connect through the HTTP proxy at `proxy_address`, without
+    /// authentication.
+    pub fn new(proxy_address: TcpAddress) -> Self {
+        Self {
+            proxy_address,
+            credentials: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            connect: ConnectTcp,
+        }
+    }
+}
+
+impl<C> ConnectHttp<C> {
+    /// Authenticate with username and password.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Connect to the proxy with TLS, using `client_config`.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, client_config: std::sync::Arc<rustls::ClientConfig>) -> Self {
+        self.tls = Some(client_config);
+        self
+    }
+
+    /// Connect to the proxy with TLS, verifying its certificate with the
+    /// natively installed root certificates.
+    #[cfg(feature = "tls")]
+    pub fn with_native_tls(self) -> Result<Self, crate::protocol::tls::Error> {
+        let roots = crate::protocol::tls::native_certificates()?;
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(self.with_tls(client_config.into()))
+    }
+
+    /// Specify how the connection to the proxy is established. Defaults to
+    /// [`ConnectTcp`].
+    pub fn with_connect<D>(self, connect: D) -> ConnectHttp<D> {
+        ConnectHttp {
+            proxy_address: self.proxy_address,
+            credentials: self.credentials,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            connect,
+        }
+    }
+
+    pub fn proxy_address(&self) -> &TcpAddress {
+        &self.proxy_address
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Returns whether the connection to the proxy is TLS encrypted.
+    #[cfg(feature = "tls")]
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+}
+
+impl<C> ConnectHttp<C>
+where
+    C: Connect + Sync,
+{
+    async fn connect_to_proxy(&self) -> Result<MaybeTlsStream<C::Connection>, Error> {
+        let socket = self.connect.connect(&self.proxy_address).await?;
+
+        #[cfg(feature = "tls")]
+        if let Some(client_config) = &self.tls {
+            let server_name = match &self.proxy_address.host {
+                HostAddress::IpAddress(ip_address) => (*ip_address).into(),
+                HostAddress::DnsName(name) => {
+                    rustls::pki_types::ServerName::try_from(name.clone()).map_err(|_| {
+                        Error::InvalidServerName {
+                            host: self.proxy_address.host.clone(),
+                        }
+                    })?
+                }
+            };
+            let stream = tokio_rustls::TlsConnector::from(client_config.clone())
+                .connect(server_name, socket)
+                .await?;
+            return Ok(MaybeTlsStream::Tls(Box::new(stream)));
+        }
+
+        Ok(MaybeTlsStream::Plain(socket))
+    }
+}
+
+impl<C> Connect for ConnectHttp<C>
+where
+    C: Connect + Sync,
+{
+    type Connection = MaybeTlsStream<C::Connection>;
+
+    async fn connect(&self, address: &TcpAddress) -> Result<Self::Connection, std::io::Error> {
+        let result = async {
+            let socket = self.connect_to_proxy().await?;
+            open_tunnel(socket, address, self.credentials.as_ref()).await
+        }
+        .await;
+
+        result.map_err(|error| {
+            tracing::debug!(proxy_address = %self.proxy_address, %address, ?error, "CONNECT request failed");
+            error.into()
+        })
+    }
+}
+
+/// Sends a `CONNECT` request for `destination_address` to the proxy on
+/// `socket`.
+///
+/// On success the socket is returned, which is then connected to the
+/// destination.
+pub async fn open_tunnel<S>(
+    mut socket: S,
+    destination_address: &TcpAddress,
+    credentials: Option<&Credentials>,
+) -> Result<S, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // IPv6 addresses need brackets in the authority-form.
+    let authority = match &destination_address.host {
+        HostAddress::IpAddress(IpAddr::V6(ip_address)) => {
+            format!("[{ip_address}]:{}", destination_address.port)
+        }
+        host => format!("{host}:{}", destination_address.port),
+    };
+
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nhost: {authority}\r\n");
+    if let Some(credentials) = credentials {
+        let token = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", credentials.username, credentials.password));
+        request.push_str(&format!("proxy-authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    socket.write_all(request.as_bytes()).await?;
+    socket.flush().await?;
+
+    // we read the response head byte by byte, so that we don't read any data
+    // that the destination sends after it.
+    let mut head = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(Error::ResponseHeadTooLarge);
+        }
+        head.push(socket.read_u8().await?);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    if response.parse(&head)?.is_partial() {
+        return Err(Error::InvalidResponse(httparse::Error::Status));
+    }
+
+    let status = response.code.unwrap_or_default();
+    if (200..300).contains(&status) {
+        Ok(socket)
+    }
+    else {
+        Err(Error::Refused {
+            status,
+            reason: response.reason.unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+/// A connection to the proxy that might be TLS encrypted.
+#[derive(Debug)]
+pub enum MaybeTlsStream<S> {
+    Plain(S),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<S>>),
+}
+
+impl<S> MaybeTlsStream<S> {
+    /// Returns the underlying connection.
+    pub fn get_ref(&self) -> &S {
+        match self {
+            Self::Plain(inner) => inner,
+            #[cfg(feature = "tls")]
+            Self::Tls(inner) => inner.get_ref().0,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(inner) => Pin::new(inner).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(inner) => Pin::new(inner).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(inner) => Pin::new(inner).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(inner) => Pin::new(inner).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(inner) => Pin::new(inner).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    async fn read_request_head(server: &mut tokio::io::DuplexStream) -> String {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(server.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_opens_a_tunnel() {
+        let (client, mut server) = duplex(1024);
+
+        let server = tokio::spawn(async move {
+            let head = read_request_head(&mut server).await;
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .await
+                .unwrap();
+            head
+        });
+
+        let credentials = Credentials {
+            username: "alice".to_owned(),
+            password: "secret".to_owned(),
+        };
+        let destination_address =
+            TcpAddress::new("2001:db8::1".parse::<IpAddr>().unwrap().into(), 443);
+        let mut tunnel = open_tunnel(client, &destination_address, Some(&credentials))
+            .await
+            .unwrap();
+
+        // data sent after the response belongs to the tunnel.
+        let mut data = [0; 5];
+        tunnel.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+
+        assert_eq!(
+            server.await.unwrap(),
+            "CONNECT [2001:db8::1]:443 HTTP/1.1\r\nhost: [2001:db8::1]:443\r\nproxy-authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_fails_if_refused() {
+        let (client, mut server) = duplex(1024);
+
+        tokio::spawn(async move {
+            read_request_head(&mut server).await;
+            server
+                .write_all(
+                    b"HTTP/1.1 407 Proxy Authentication Required\r\ncontent-length: 0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let error = open_tunnel(client, &"example.com:443".parse().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Refused { status: 407, .. }));
+    }
+}
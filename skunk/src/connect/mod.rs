@@ -1,5 +1,8 @@
 //! Handling of TCP connections.
 
+#[cfg(feature = "http")]
+pub mod http;
+
 use std::future::Future;
 
 use tokio::{
@@ -35,4 +35,8 @@ pub enum Error {
     #[cfg(feature = "http")]
     #[error("http error")]
     Http(#[from] self::protocol::http::Error),
+
+    #[cfg(feature = "http")]
+    #[error("websocket error")]
+    WebSocket(#[from] self::protocol::websocket::Error),
 }
@@ -11,15 +11,21 @@ use bytes::{
     Bytes,
     BytesMut,
 };
-use hyper::body::Frame;
 pub use hyper::body::{
     Body,
     Incoming,
 };
+use hyper::body::{
+    Frame,
+    SizeHint,
+};
 use pin_project_lite::pin_project;
-use tokio::io::{
-    AsyncRead,
-    ReadBuf,
+use tokio::{
+    io::{
+        AsyncRead,
+        ReadBuf,
+    },
+    sync::oneshot,
 };
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -88,3 +94,150 @@ impl<R: AsyncRead> Body for Read<R> {
         })
     }
 }
+
+pin_project! {
+    /// Passes a body through, and keeps a copy of its first bytes.
+    ///
+    /// The copy is sent to the receiver returned by [`Tee::new`] when the body
+    /// ends, fails, or is dropped.
+    #[derive(Debug)]
+    pub struct Tee<B> {
+        #[pin]
+        inner: B,
+        copy: BytesMut,
+        limit: usize,
+        truncated: bool,
+        copy_tx: Option<oneshot::Sender<TeeCopy>>,
+    }
+
+    impl<B> PinnedDrop for Tee<B> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            finish(this.copy, *this.truncated, this.copy_tx);
+        }
+    }
+}
+
+/// The copy of a body made by [`Tee`].
+#[derive(Clone, Debug)]
+pub struct TeeCopy {
+    /// The first bytes of the body.
+    pub data: Bytes,
+
+    /// Whether the body was longer than what was copied.
+    pub truncated: bool,
+}
+
+impl<B> Tee<B> {
+    /// Creates a [`Tee`] that copies at most `limit` bytes of `inner`.
+    pub fn new(inner: B, limit: usize) -> (Self, oneshot::Receiver<TeeCopy>) {
+        let (copy_tx, copy_rx) = oneshot::channel();
+        let tee = Self {
+            inner,
+            copy: BytesMut::new(),
+            limit,
+            truncated: false,
+            copy_tx: Some(copy_tx),
+        };
+        (tee, copy_rx)
+    }
+}
+
+impl<B> Body for Tee<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let poll = this.inner.poll_frame(cx);
+
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let n = data.len().min(*this.limit - this.copy.len());
+                    this.copy.extend_from_slice(&data[..n]);
+                    *this.truncated |= n < data.len();
+                }
+            }
+            Poll::Ready(_) => finish(this.copy, *this.truncated, this.copy_tx),
+            Poll::Pending => {}
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn finish(copy: &mut BytesMut, truncated: bool, copy_tx: &mut Option<oneshot::Sender<TeeCopy>>) {
+    if let Some(copy_tx) = copy_tx.take() {
+        let _ = copy_tx.send(TeeCopy {
+            data: copy.split().freeze(),
+            truncated,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{
+        BodyExt,
+        StreamBody,
+    };
+
+    use super::*;
+
+    fn body(chunks: &[&'static [u8]]) -> impl Body<Data = Bytes, Error = Infallible> {
+        StreamBody::new(futures::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Frame::data(Bytes::from_static(chunk))))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn it_passes_the_body_through() {
+        let (tee, copy_rx) = Tee::new(body(&[b"hello", b" ", b"world"]), 1024);
+        let body = tee.collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello world");
+
+        let copy = copy_rx.await.unwrap();
+        assert_eq!(copy.data, "hello world");
+        assert!(!copy.truncated);
+    }
+
+    #[tokio::test]
+    async fn it_truncates_the_copy() {
+        let (tee, copy_rx) = Tee::new(body(&[b"hello", b" ", b"world"]), 8);
+        let body = tee.collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello world");
+
+        let copy = copy_rx.await.unwrap();
+        assert_eq!(copy.data, "hello wo");
+        assert!(copy.truncated);
+    }
+
+    #[tokio::test]
+    async fn it_sends_the_copy_when_dropped() {
+        let (mut tee, copy_rx) = Tee::new(body(&[b"hello", b" ", b"world"]), 1024);
+        let frame = tee.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "hello");
+        drop(tee);
+
+        let copy = copy_rx.await.unwrap();
+        assert_eq!(copy.data, "hello");
+    }
+}
@@ -1,4 +1,7 @@
 //! Implementation of HTTP using hyper.
+//!
+//! Both HTTP/1 and HTTP/2 are supported. Which one is used for a connection
+//! is determined by [`Protocol`].
 
 pub mod body;
 
@@ -14,7 +17,9 @@ use std::{
 
 use bytes::Bytes;
 use futures::{
+    stream::FuturesUnordered,
     Future,
+    StreamExt,
     TryFutureExt,
 };
 use http_body_util::BodyExt;
@@ -23,14 +28,20 @@ use hyper::{
         Body,
         Incoming,
     },
+    header::UPGRADE,
     service::service_fn,
     StatusCode,
 };
 pub use hyper::{
+    HeaderMap,
     Request,
     Response,
+    Uri,
+};
+use hyper_util::rt::{
+    TokioExecutor,
+    TokioIo,
 };
-use hyper_util::rt::TokioIo;
 use tokio::{
     io::{
         AsyncRead,
@@ -44,9 +55,12 @@ use tokio::{
 };
 
 use self::body::Empty;
-use crate::util::io::{
-    Rewind,
-    WithoutShutdown,
+use crate::{
+    protocol::sniff,
+    util::io::{
+        Rewind,
+        WithoutShutdown,
+    },
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -61,85 +75,179 @@ pub enum Error {
     ConnectionClosed,
 }
 
-pub async fn server<T, H>(io: T, request_handler: H) -> Result<(), crate::Error>
+/// HTTP protocol version spoken on a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Http1,
+    Http2,
+}
+
+impl Protocol {
+    /// Returns the protocol for an ALPN protocol ID, if it's a HTTP protocol
+    /// that we support.
+    pub fn from_alpn(alpn_protocol: &[u8]) -> Option<Self> {
+        match alpn_protocol {
+            b"http/1.0" | b"http/1.1" => Some(Self::Http1),
+            b"h2" => Some(Self::Http2),
+            _ => None,
+        }
+    }
+
+    /// Returns the ALPN protocol ID for this protocol.
+    pub fn alpn_id(&self) -> &'static [u8] {
+        match self {
+            Self::Http1 => b"http/1.1",
+            Self::Http2 => b"h2",
+        }
+    }
+
+    /// Returns the HTTP protocol for a protocol detected by sniffing, if it's a
+    /// HTTP protocol.
+    pub fn from_sniffed(protocol: sniff::Protocol) -> Option<Self> {
+        match protocol {
+            sniff::Protocol::Http1 => Some(Self::Http1),
+            sniff::Protocol::Http2 => Some(Self::Http2),
+            sniff::Protocol::Tls | sniff::Protocol::Unknown => None,
+        }
+    }
+}
+
+/// Runs a HTTP server on `io` with the given protocol. Requests are passed to
+/// `request_handler`.
+///
+/// With HTTP/2, multiple requests might be handled concurrently.
+///
+/// For HTTP/1 the underlying IO is returned when the connection is done. This
+/// is used to continue with another protocol after a `101 Switching Protocols`
+/// response.
+pub async fn server<T, H>(
+    io: T,
+    protocol: Protocol,
+    request_handler: H,
+) -> Result<Option<Rewind<T>>, crate::Error>
 where
-    T: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     H: RequestHandler,
+    H::ResponseBody: Send,
+    <H::ResponseBody as Body>::Data: Send,
     Bytes: From<<H::ResponseBody as Body>::Data>,
     <H::ResponseBody as Body>::Error: std::error::Error + Send + Sync + 'static,
 {
     let (tx_req, mut rx_req) = mpsc::channel(16);
 
-    let conn = hyper::server::conn::http1::Builder::new()
-        .serve_connection(
-            TokioIo::new(WithoutShutdown::new(io)),
-            service_fn(move |request: Request<Incoming>| {
-                let tx_req = tx_req.clone();
-                async move {
-                    let (tx_resp, rx_resp) = oneshot::channel::<Response<H::ResponseBody>>();
-
-                    // todo: can this be dropped before source_conn is dropped, and what do we
-                    // do then?
-                    tx_req
-                        .send((request, tx_resp))
-                        .await
-                        .expect("bug: all receivers for server request have been dropped");
-
-                    // receive the response from the layer future.
-                    let response = rx_resp
-                        .await
-                        .map(|response| {
-                            response.map(|body| {
-                                http_body_util::Either::Left(
-                                    body.map_frame(|frame| frame.map_data(Into::into)),
-                                )
-                            })
-                        })
-                        .unwrap_or_else(|_| {
-                            // when the inner layer fails, we won't get a response, so we'll
-                            // instead return 502
-                            tracing::debug!("the server response sender has been dropped");
-                            Response::builder()
-                                .status(StatusCode::BAD_GATEWAY)
-                                .body(http_body_util::Either::Right(Empty))
-                                .expect("constructed invalid http response")
-                        });
-
-                    Ok::<_, Infallible>(response)
-                }
-            }),
-        )
-        .without_shutdown()
-        .map_err(Error::Hyper) // why does `Error::from` not work here?
-        .map_err(crate::Error::from);
+    let service = service_fn(move |request: Request<Incoming>| {
+        let tx_req = tx_req.clone();
+        async move {
+            let (tx_resp, rx_resp) = oneshot::channel::<Response<H::ResponseBody>>();
+
+            // receive the response from the layer future. the receiver is only dropped
+            // when the handler future is done, but we answer with 502 then anyway.
+            let response = match tx_req.send((request, tx_resp)).await {
+                Ok(()) => rx_resp.await.ok(),
+                Err(_) => None,
+            };
+
+            let response = response
+                .map(|response| {
+                    response.map(|body| {
+                        http_body_util::Either::Left(
+                            body.map_frame(|frame| frame.map_data(Into::into)),
+                        )
+                    })
+                })
+                .unwrap_or_else(|| {
+                    // when the inner layer fails, we won't get a response, so we'll
+                    // instead return 502
+                    tracing::debug!("the server response sender has been dropped");
+                    Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(http_body_util::Either::Right(Empty))
+                        .expect("constructed invalid http response")
+                });
+
+            Ok::<_, Infallible>(response)
+        }
+    });
+
+    let conn = async move {
+        match protocol {
+            Protocol::Http1 => {
+                let parts = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(WithoutShutdown::new(io)), service)
+                    .without_shutdown()
+                    .await
+                    .map_err(Error::Hyper)?;
+
+                let without_shutdown = parts.io.into_inner();
+                assert!(
+                    !without_shutdown.was_shutdown(),
+                    "fixme: underlying IO was shutdown"
+                );
+                let io = without_shutdown.into_inner();
+                Ok::<_, crate::Error>(Some(Rewind::new(io, parts.read_buf)))
+            }
+            Protocol::Http2 => {
+                hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(io), service)
+                    .await
+                    .map_err(Error::Hyper)?;
+                Ok(None)
+            }
+        }
+    };
 
+    // a failed request doesn't end the handler future, since the connection might
+    // still send further requests. the first error is returned when the connection
+    // is done.
     let handler_fut = async move {
-        while let Some((request, tx_resp)) = rx_req.recv().await {
-            let response = request_handler.handle_request(request).await?;
+        let request_handler = &request_handler;
+        let mut pending = FuturesUnordered::new();
+        let mut error = None;
+
+        loop {
+            tokio::select! {
+                request_opt = rx_req.recv() => {
+                    let Some((request, tx_resp)) = request_opt
+                    else {
+                        break;
+                    };
+
+                    pending.push(async move {
+                        let response = request_handler.handle_request(request).await?;
+
+                        if tx_resp.send(response).is_err() {
+                            // with HTTP/2 the client can cancel a single request.
+                            tracing::debug!("the server response receiver has been dropped");
+                        }
+
+                        Ok::<(), crate::Error>(())
+                    });
+                }
+                Some(result) = pending.next(), if !pending.is_empty() => {
+                    handler_failed(result, &mut error);
+                }
+            }
+        }
 
-            tx_resp
-                .send(response)
-                .unwrap_or_else(|_| panic!("bug: the server response receiver has been dropped"));
+        while let Some(result) = pending.next().await {
+            handler_failed(result, &mut error);
         }
-        Ok::<(), crate::Error>(())
+
+        error.map_or(Ok(()), Err)
     };
 
     let (conn_result, handler_result) = tokio::join!(conn, handler_fut);
     handler_result?;
-    let _io = {
-        let parts = conn_result?;
-        let without_shutdown = parts.io.into_inner();
-        assert!(
-            !without_shutdown.was_shutdown(),
-            "fixme: underlying IO was shutdown"
-        );
-        let io = without_shutdown.into_inner();
-        Rewind::new(io, parts.read_buf)
-    };
-
-    // todo: perform protocol upgrade
+    conn_result
+}
 
-    Ok(())
+/// Records the error of a failed request. The client gets a 502 response for
+/// it, since the response sender was dropped.
+fn handler_failed(result: Result<(), crate::Error>, error: &mut Option<crate::Error>) {
+    if let Err(e) = result {
+        tracing::debug!(error = ?e, "request handler failed");
+        error.get_or_insert(e);
+    }
 }
 
 pub trait RequestHandler<RequestBody = Incoming> {
@@ -188,43 +296,75 @@ where
     }
 }
 
+/// Client connection future. This must be polled to drive the connection.
+///
+/// For HTTP/1 this resolves to the underlying IO stream, when the connection
+/// is done.
 #[derive(Debug)]
 pub struct Client<T, B>
 where
-    T: AsyncRead + AsyncWrite,
-    B: Body + 'static,
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    connection: Option<ClientConnection<T, B>>,
+}
+
+#[derive(Debug)]
+enum ClientConnection<T, B>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: std::error::Error + Send + Sync + 'static,
 {
-    connection: Option<hyper::client::conn::http1::Connection<TokioIo<WithoutShutdown<T>>, B>>,
+    Http1(hyper::client::conn::http1::Connection<TokioIo<WithoutShutdown<T>>, B>),
+    Http2(hyper::client::conn::http2::Connection<TokioIo<T>, B, TokioExecutor>),
 }
 
 impl<T, B> Future for Client<T, B>
 where
-    T: AsyncRead + AsyncWrite + Unpin,
-    B: Body + 'static,
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
     B::Error: std::error::Error + Send + Sync + 'static,
 {
-    type Output = Result<Rewind<T>, Error>;
+    type Output = Result<Option<Rewind<T>>, Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if let Some(connection) = &mut self.connection {
-            match connection.poll_without_shutdown(cx) {
-                Poll::Pending => Poll::Pending,
-                Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
-                Poll::Ready(Ok(())) => {
-                    let parts = self.connection.take().unwrap().into_parts();
-                    let without_shutdown = parts.io.into_inner();
-                    assert!(
-                        !without_shutdown.was_shutdown(),
-                        "fixme: underlying IO was shutdown"
-                    );
-                    let io = without_shutdown.into_inner();
-                    let io = Rewind::new(io, parts.read_buf);
-                    Poll::Ready(Ok(io))
+        match &mut self.connection {
+            Some(ClientConnection::Http1(connection)) => {
+                match connection.poll_without_shutdown(cx) {
+                    Poll::Pending => Poll::Pending,
+                    Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+                    Poll::Ready(Ok(())) => {
+                        let Some(ClientConnection::Http1(connection)) = self.connection.take()
+                        else {
+                            unreachable!();
+                        };
+                        let parts = connection.into_parts();
+                        let without_shutdown = parts.io.into_inner();
+                        assert!(
+                            !without_shutdown.was_shutdown(),
+                            "fixme: underlying IO was shutdown"
+                        );
+                        let io = without_shutdown.into_inner();
+                        let io = Rewind::new(io, parts.read_buf);
+                        Poll::Ready(Ok(Some(io)))
+                    }
                 }
             }
-        }
-        else {
-            Poll::Ready(Err(Error::ConnectionClosed))
+            Some(ClientConnection::Http2(connection)) => {
+                match Pin::new(connection).poll(cx) {
+                    Poll::Pending => Poll::Pending,
+                    Poll::Ready(result) => {
+                        self.connection = None;
+                        Poll::Ready(result.map(|()| None).map_err(Into::into))
+                    }
+                }
+            }
+            None => Poll::Ready(Err(Error::ConnectionClosed)),
         }
     }
 }
@@ -234,7 +374,16 @@ pub struct SendRequest<RequestBody>
 where
     RequestBody: Body + 'static,
 {
-    send_request: Arc<Mutex<hyper::client::conn::http1::SendRequest<RequestBody>>>,
+    inner: SendRequestInner<RequestBody>,
+}
+
+#[derive(Debug)]
+enum SendRequestInner<RequestBody>
+where
+    RequestBody: Body + 'static,
+{
+    Http1(Arc<Mutex<hyper::client::conn::http1::SendRequest<RequestBody>>>),
+    Http2(hyper::client::conn::http2::SendRequest<RequestBody>),
 }
 
 impl<RequestBody> Clone for SendRequest<RequestBody>
@@ -242,9 +391,11 @@ where
     RequestBody: Body + 'static,
 {
     fn clone(&self) -> Self {
-        Self {
-            send_request: self.send_request.clone(),
-        }
+        let inner = match &self.inner {
+            SendRequestInner::Http1(send_request) => SendRequestInner::Http1(send_request.clone()),
+            SendRequestInner::Http2(send_request) => SendRequestInner::Http2(send_request.clone()),
+        };
+        Self { inner }
     }
 }
 
@@ -254,8 +405,17 @@ where
     RequestBody::Error: std::error::Error + Send + Sync + 'static,
 {
     pub async fn send(&self, request: Request<RequestBody>) -> Result<Response<Incoming>, Error> {
-        let mut send_request = self.send_request.lock().await;
-        Ok(send_request.send_request(request).await?)
+        match &self.inner {
+            SendRequestInner::Http1(send_request) => {
+                let mut send_request = send_request.lock().await;
+                Ok(send_request.send_request(request).await?)
+            }
+            SendRequestInner::Http2(send_request) => {
+                // HTTP/2 multiplexes requests, so we don't need to wait for other requests.
+                let mut send_request = send_request.clone();
+                Ok(send_request.send_request(request).await?)
+            }
+        }
     }
 }
 
@@ -279,53 +439,181 @@ where
     }
 }
 
-pub async fn client<T, B>(io: T) -> Result<(Client<T, B>, SendRequest<B>), Error>
+/// Creates a HTTP client on `io` with the given protocol.
+pub async fn client<T, B>(
+    io: T,
+    protocol: Protocol,
+) -> Result<(Client<T, B>, SendRequest<B>), Error>
 where
-    T: AsyncRead + AsyncWrite + Unpin,
-    B: Body + 'static,
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    B: Body + Send + Unpin + 'static,
     B::Data: Send,
     B::Error: std::error::Error + Send + Sync + 'static,
 {
-    let (send_request, connection) = hyper::client::conn::http1::Builder::new()
-        .handshake(TokioIo::new(WithoutShutdown::new(io)))
-        .await?;
+    let (connection, send_request) = match protocol {
+        Protocol::Http1 => {
+            let (send_request, connection) = hyper::client::conn::http1::Builder::new()
+                .handshake(TokioIo::new(WithoutShutdown::new(io)))
+                .await?;
+            (
+                ClientConnection::Http1(connection),
+                SendRequestInner::Http1(Arc::new(Mutex::new(send_request))),
+            )
+        }
+        Protocol::Http2 => {
+            let (send_request, connection) =
+                hyper::client::conn::http2::Builder::new(TokioExecutor::new())
+                    .handshake(TokioIo::new(io))
+                    .await?;
+            (
+                ClientConnection::Http2(connection),
+                SendRequestInner::Http2(send_request),
+            )
+        }
+    };
 
     let client = Client {
         connection: Some(connection),
     };
 
     let send_request = SendRequest {
-        send_request: Arc::new(Mutex::new(send_request)),
+        inner: send_request,
     };
 
     Ok((client, send_request))
 }
 
-pub async fn proxy<I, O, F, Fut, Bq, Bs>(incoming: I, outgoing: O, f: F) -> Result<(), crate::Error>
+/// A connection that switched to another protocol, after the server
+/// responded with `101 Switching Protocols`.
+#[derive(Debug)]
+pub struct Upgraded<I, O> {
+    /// The protocol from the response's `Upgrade` header, e.g. `websocket`.
+    pub protocol: Option<String>,
+
+    /// The connection to the client.
+    pub incoming: Rewind<I>,
+
+    /// The connection to the server.
+    pub outgoing: Rewind<O>,
+}
+
+/// Proxies HTTP between `incoming` and `outgoing`. Each request is passed to
+/// `f`, together with a [`SendRequest`] to forward it with.
+///
+/// `protocol` is the protocol the client speaks, as detected by
+/// [`sniff::Protocol::detect`]. The same protocol is used for the outgoing
+/// connection, so for TLS connections both legs must have negotiated the same
+/// ALPN protocol.
+///
+/// If a response returned by `f` switches protocols (HTTP/1.1 only), the
+/// upgraded connection is returned, so that the caller can continue proxying
+/// it.
+pub async fn proxy<I, O, F, Fut, Bq, Bs>(
+    incoming: I,
+    outgoing: O,
+    protocol: Protocol,
+    f: F,
+) -> Result<Option<Upgraded<I, O>>, crate::Error>
 where
-    I: AsyncRead + AsyncWrite + Unpin,
-    O: AsyncRead + AsyncWrite + Unpin,
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    O: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     F: Fn(Request<Incoming>, SendRequest<Bq>) -> Fut,
     Fut: Future<Output = Result<Response<Bs>, crate::Error>> + Send,
-    Bq: Body + 'static,
+    Bq: Body + Send + Unpin + 'static,
     Bq::Data: Send,
     Bq::Error: std::error::Error + Send + Sync + 'static,
-    Bs: Body + 'static,
+    Bs: Body + Send + 'static,
+    Bs::Data: Send,
     Bytes: From<Bs::Data>,
     Bs::Error: std::error::Error + Send + Sync + 'static,
 {
-    let (client, send_request) = client(outgoing).await?;
+    let (client, send_request) = client(outgoing, protocol).await?;
 
-    tokio::try_join!(
+    // the `Upgrade` header of a `101 Switching Protocols` response, if any.
+    let upgrade = Arc::new(parking_lot::Mutex::new(None));
+
+    let (incoming, outgoing) = tokio::try_join!(
         server(
             incoming,
+            protocol,
             fn_handler(|request| {
-                let send_request = send_request.clone();
-                f(request, send_request)
+                let response = f(request, send_request.clone());
+                let upgrade = upgrade.clone();
+                async move {
+                    let response = response.await?;
+                    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+                        let protocol = response
+                            .headers()
+                            .get(UPGRADE)
+                            .and_then(|protocol| protocol.to_str().ok())
+                            .map(ToOwned::to_owned);
+                        *upgrade.lock() = Some(protocol);
+                    }
+                    Ok(response)
+                }
             })
         ),
         client.map_err(crate::Error::from),
     )?;
 
-    Ok(())
+    let upgrade = upgrade.lock().take();
+    match (upgrade, incoming, outgoing) {
+        (Some(protocol), Some(incoming), Some(outgoing)) => {
+            Ok(Some(Upgraded {
+                protocol,
+                incoming,
+                outgoing,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{
+        duplex,
+        AsyncReadExt,
+        AsyncWriteExt,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_keeps_serving_after_a_failed_request() {
+        let (mut client, io) = duplex(4096);
+        let server = tokio::spawn(server(
+            io,
+            Protocol::Http1,
+            fn_handler(|request: Request<Incoming>| {
+                async move {
+                    if request.uri().path() == "/fail" {
+                        Err(Error::ConnectionClosed.into())
+                    }
+                    else {
+                        Ok(Response::new(Empty))
+                    }
+                }
+            }),
+        ));
+
+        client
+            .write_all(
+                b"GET /fail HTTP/1.1\r\nhost: example.com\r\n\r\n\
+                  GET / HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        // the error is returned once the connection is done.
+        assert!(server.await.unwrap().is_err());
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        let statuses = responses
+            .lines()
+            .filter(|line| line.starts_with("HTTP/1.1"))
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, ["HTTP/1.1 502 Bad Gateway", "HTTP/1.1 200 OK"]);
+    }
 }
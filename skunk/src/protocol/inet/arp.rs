@@ -13,6 +13,9 @@ use byst::io::{
     read,
     BufReader,
     Read,
+    Write,
+    Writer,
+    WriterExt,
 };
 
 use super::MacAddress;
@@ -52,7 +55,7 @@ impl Packet {
         Self {
             hardware_type: HardwareType::ETHER,
             protocol_type,
-            hardware_address_length: 4,
+            hardware_address_length: 6,
             protocol_address_length,
             operation,
             sender_hardware_address,
@@ -145,6 +148,30 @@ impl<R: BufReader> Read<R, ()> for Packet {
     }
 }
 
+impl<W: Writer> Write<W, ()> for Packet {
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        writer.write(&self.hardware_type)?;
+        writer.write(&self.protocol_type)?;
+        writer.write(&self.hardware_address_length)?;
+        writer.write(&self.protocol_address_length)?;
+        writer.write(&self.operation)?;
+        writer.write(&self.sender_hardware_address)?;
+        write_protocol_address(writer, &self.sender_protocol_address)?;
+        writer.write(&self.target_hardware_address)?;
+        write_protocol_address(writer, &self.target_protocol_address)?;
+        Ok(())
+    }
+}
+
+fn write_protocol_address<W: Writer>(writer: &mut W, address: &IpAddr) -> Result<(), W::Error> {
+    match address {
+        IpAddr::V4(address) => writer.write(address),
+        IpAddr::V6(address) => writer.write(address),
+    }
+}
+
 pub type ProtocolType = super::ethernet::EtherType;
 
 #[derive(Debug, thiserror::Error)]
@@ -188,7 +215,7 @@ pub enum InvalidPacket<R> {
     Read(#[from] R),
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Read)]
+#[derive(Clone, Copy, PartialEq, Eq, Read, Write)]
 pub struct Operation(#[byst(network)] pub u16);
 
 network_enum! {
@@ -206,7 +233,7 @@ network_enum! {
 /// Numbers sourced from[1]
 ///
 /// [1]: https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/plain/include/uapi/linux/if_arp.h?id=e33c4963bf536900f917fb65a687724d5539bc21
-#[derive(Clone, Copy, Eq, PartialEq, Hash, Read)]
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Read, Write)]
 pub struct HardwareType(#[byst(network)] pub u16);
 
 network_enum! {
@@ -408,3 +435,49 @@ network_enum! {
     NONE => 0xFFFE;
 
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use byst::{
+        io::ReaderExt,
+        Buf,
+    };
+
+    use super::*;
+    use crate::protocol::inet::testing::to_vec;
+
+    /// Who has `10.0.69.1`? Tell `10.0.69.2`.
+    const REQUEST: [u8; 28] = [
+        0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a,
+        0x00, 0x45, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x45, 0x01,
+    ];
+
+    #[test]
+    fn it_round_trips_a_request() {
+        let packet: Packet = REQUEST.reader().read().unwrap();
+        assert_eq!(packet.operation, Operation::REQUEST);
+        assert_eq!(
+            packet.sender_hardware_address,
+            MacAddress([0x02, 0, 0, 0, 0, 0x02])
+        );
+        assert_eq!(
+            packet.target_protocol_address,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 69, 1))
+        );
+        assert_eq!(to_vec(&packet), REQUEST);
+    }
+
+    #[test]
+    fn it_creates_ethernet_packets() {
+        let packet = Packet::new(
+            Operation::REQUEST,
+            MacAddress([0x02, 0, 0, 0, 0, 0x02]),
+            Ipv4Addr::new(10, 0, 69, 2).into(),
+            MacAddress::UNSPECIFIED,
+            Ipv4Addr::new(10, 0, 69, 1).into(),
+        );
+        assert_eq!(to_vec(&packet), REQUEST);
+    }
+}
@@ -0,0 +1,235 @@
+//! Internet checksum
+//!
+//! - [RFC 1071](https://datatracker.ietf.org/doc/html/rfc1071)
+
+use std::{
+    convert::Infallible,
+    net::{
+        Ipv4Addr,
+        Ipv6Addr,
+    },
+};
+
+use byst::{
+    io::{
+        BufReader,
+        Writer,
+    },
+    Buf,
+};
+
+use super::ipv4::Protocol;
+
+/// Computes the 16-bit one's complement checksum used by IPv4, ICMP, UDP and
+/// TCP.
+///
+/// Data can be added with [`Checksum::update`], or by writing to it, since it
+/// implements [`Writer`]. Data doesn't need to be added in 16-bit aligned
+/// chunks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Checksum {
+    sum: u64,
+    odd: Option<u8>,
+}
+
+impl Checksum {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a checksum that already includes the pseudo header used by UDP
+    /// and TCP.
+    ///
+    /// `length` is the length of the UDP datagram or TCP segment, including
+    /// its header.
+    pub fn pseudo_header(addresses: AddressPair, protocol: Protocol, length: u32) -> Self {
+        let mut checksum = Self::new();
+
+        match addresses {
+            AddressPair::V4 {
+                source,
+                destination,
+            } => {
+                // https://datatracker.ietf.org/doc/html/rfc768
+                checksum.update(&source.octets());
+                checksum.update(&destination.octets());
+                checksum.update(&[0, protocol.0]);
+                checksum.update(&(length as u16).to_be_bytes());
+            }
+            AddressPair::V6 {
+                source,
+                destination,
+            } => {
+                // https://datatracker.ietf.org/doc/html/rfc8200#section-8.1
+                checksum.update(&source.octets());
+                checksum.update(&destination.octets());
+                checksum.update(&length.to_be_bytes());
+                checksum.update(&[0, 0, 0, protocol.0]);
+            }
+        }
+
+        checksum
+    }
+
+    /// Adds `data` to the checksum.
+    pub fn update(&mut self, mut data: &[u8]) {
+        if let Some(high) = self.odd {
+            let Some((&low, rest)) = data.split_first()
+            else {
+                return;
+            };
+            self.sum += u64::from(u16::from_be_bytes([high, low]));
+            self.odd = None;
+            data = rest;
+        }
+
+        let mut words = data.chunks_exact(2);
+        for word in &mut words {
+            self.sum += u64::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = words.remainder() {
+            self.odd = Some(*last);
+        }
+    }
+
+    /// Returns the checksum of all data added so far.
+    ///
+    /// An odd number of bytes is padded with a zero byte.
+    pub fn finish(&self) -> u16 {
+        let mut sum = self.sum;
+        if let Some(high) = self.odd {
+            sum += u64::from(high) << 8;
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+}
+
+/// Source and destination address of a packet, for the pseudo header.
+///
+/// Both addresses are always of the same family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressPair {
+    V4 {
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    },
+    V6 {
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+    },
+}
+
+impl From<(Ipv4Addr, Ipv4Addr)> for AddressPair {
+    fn from((source, destination): (Ipv4Addr, Ipv4Addr)) -> Self {
+        Self::V4 {
+            source,
+            destination,
+        }
+    }
+}
+
+impl From<(Ipv6Addr, Ipv6Addr)> for AddressPair {
+    fn from((source, destination): (Ipv6Addr, Ipv6Addr)) -> Self {
+        Self::V6 {
+            source,
+            destination,
+        }
+    }
+}
+
+impl Writer for Checksum {
+    type Error = Infallible;
+
+    fn write_buf<B: Buf>(&mut self, buf: B) -> Result<(), Self::Error> {
+        let mut reader = buf.reader();
+        while let Some(chunk) = reader.peek_chunk() {
+            self.update(chunk);
+            reader.advance(chunk.len()).unwrap();
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, amount: usize) -> Result<(), Self::Error> {
+        // skipped bytes are treated as zeros, which only matters for the
+        // alignment of the following data.
+        if amount > 0 {
+            let amount = if let Some(high) = self.odd.take() {
+                self.sum += u64::from(high) << 8;
+                amount - 1
+            }
+            else {
+                amount
+            };
+            if amount % 2 == 1 {
+                self.odd = Some(0);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_the_rfc1071_example() {
+        // https://datatracker.ietf.org/doc/html/rfc1071#section-3
+        let mut checksum = Checksum::new();
+        checksum.update(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]);
+        assert_eq!(checksum.finish(), !0xddf2);
+    }
+
+    #[test]
+    fn it_handles_unaligned_updates() {
+        let data = [0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x40];
+
+        let mut expected = Checksum::new();
+        expected.update(&data);
+
+        let mut checksum = Checksum::new();
+        checksum.update(&data[..1]);
+        checksum.update(&data[1..4]);
+        checksum.update(&[]);
+        checksum.update(&data[4..]);
+
+        assert_eq!(checksum.finish(), expected.finish());
+    }
+
+    #[test]
+    fn it_treats_skipped_bytes_as_zeros() {
+        let mut expected = Checksum::new();
+        expected.update(&[0x12, 0x00, 0x00, 0x00, 0x34]);
+
+        let mut checksum = Checksum::new();
+        checksum.update(&[0x12]);
+        checksum.skip(3).unwrap();
+        checksum.update(&[0x34]);
+
+        assert_eq!(checksum.finish(), expected.finish());
+    }
+
+    #[test]
+    fn it_sums_pseudo_headers() {
+        let addresses = AddressPair::from((
+            Ipv4Addr::new(192, 168, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 199),
+        ));
+        let mut checksum = Checksum::pseudo_header(addresses, Protocol::UDP, 8);
+        let mut expected = Checksum::new();
+        expected.update(&[192, 168, 0, 1, 192, 168, 0, 199, 0, 17, 0, 8]);
+        assert_eq!(checksum.finish(), expected.finish());
+
+        let addresses = AddressPair::from((Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST));
+        checksum = Checksum::pseudo_header(addresses, Protocol::TCP, 20);
+        expected = Checksum::new();
+        expected.update(&Ipv6Addr::LOCALHOST.octets());
+        expected.update(&Ipv6Addr::LOCALHOST.octets());
+        expected.update(&[0, 0, 0, 20, 0, 0, 0, 6]);
+        assert_eq!(checksum.finish(), expected.finish());
+    }
+}
@@ -12,12 +12,16 @@ use byst::{
         Reader,
         ReaderExt,
         Write,
+        Writer,
     },
     util::for_tuple,
 };
 use skunk_util::ordered_multimap::OrderedMultiMap;
 
-use crate::util::network_enum;
+use crate::{
+    protocol::inet::MacAddress,
+    util::network_enum,
+};
 
 /// A [DHCP message][1]
 ///
@@ -44,7 +48,18 @@ pub struct Message {
     pub options: (),
 }
 
-#[derive(Clone, Copy, Debug, Read, Write)]
+impl Message {
+    /// Length of the fixed part of a message, i.e. without the options.
+    pub const LENGTH: usize = 236;
+
+    /// The client's hardware address, if it is an Ethernet address.
+    pub fn client_hardware_address(&self) -> std::option::Option<MacAddress> {
+        (self.htype == HardwareType::ETHER && self.hlen == 6)
+            .then(|| MacAddress(self.chaddr[..6].try_into().unwrap()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Read, Write)]
 pub struct Opcode(pub u8);
 
 network_enum! {
@@ -158,13 +173,43 @@ pub enum InvalidMessage<R> {
 ///
 /// [1]: https://datatracker.ietf.org/doc/html/rfc1497
 /// [2]: https://datatracker.ietf.org/doc/html/rfc1533
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Options {
     inner: OrderedMultiMap<OptionCode, Option>,
 }
 
 impl Options {
     pub const MAGIC: [u8; 4] = [99, 130, 83, 99];
+
+    /// Returns the first option of type `T`.
+    pub fn get<T: options::DhcpOption>(&self) -> std::option::Option<&T> {
+        self.inner.get_first(&T::CODE).and_then(T::from_option)
+    }
+
+    pub fn contains(&self, code: OptionCode) -> bool {
+        self.inner.contains(&code)
+    }
+
+    pub fn insert(&mut self, option: impl Into<Option>) {
+        let option = option.into();
+        self.inner.insert(option.code(), option);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Option> {
+        self.inner.iter().map(|(_, option)| option)
+    }
+
+    /// Encodes the options, including the magic cookie and the `END` option.
+    ///
+    /// `PAD` and `END` options contained in `self` are skipped.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Self::MAGIC.to_vec();
+        for option in self.iter() {
+            option.write_to(&mut buf);
+        }
+        buf.push(OptionCode::END.0);
+        buf
+    }
 }
 
 impl<R: Reader> Read<R, ()> for Options {
@@ -188,9 +233,38 @@ impl<R: Reader> Read<R, ()> for Options {
     }
 }
 
+impl<W: Writer> Write<W, ()> for Options {
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        writer.write_buf(&self.to_vec()[..])
+    }
+}
+
+impl FromIterator<Option> for Options {
+    fn from_iter<T: IntoIterator<Item = Option>>(iter: T) -> Self {
+        let mut options = Self::default();
+        for option in iter {
+            options.insert(option);
+        }
+        options
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Read, Write)]
 pub struct OptionCode(pub u8);
 
+macro_rules! write_option_fields {
+    ($self:ident, $buf:ident) => {};
+    ($self:ident, $buf:ident, $first:ty) => {
+        WriteOption::write_option(&$self.0, $buf);
+    };
+    ($self:ident, $buf:ident, $first:ty, $second:ty) => {
+        WriteOption::write_option(&$self.0, $buf);
+        WriteOption::write_option(&$self.1, $buf);
+    };
+}
+
 macro_rules! make_options {
     {
         $(
@@ -206,7 +280,7 @@ macro_rules! make_options {
             )*
         }
 
-        #[derive(Clone, Debug)]
+        #[derive(Clone, Debug, PartialEq, Eq)]
         pub enum Option {
             $(
                 $name(options::$name),
@@ -226,6 +300,26 @@ macro_rules! make_options {
                     Self::Unknown { code, .. } => *code,
                 }
             }
+
+            fn write_to(&self, buf: &mut Vec<u8>) {
+                match self {
+                    Self::Pad(_) | Self::End(_) => {}
+                    $(
+                        #[allow(unreachable_patterns)]
+                        Self::$name(option) => {
+                            let start = buf.len();
+                            buf.extend([OptionCode::$code_const.0, 0]);
+                            option.write_data(buf);
+                            // todo: split options longer than 255 bytes (RFC 3396)
+                            buf[start + 1] = (buf.len() - start - 2) as u8;
+                        }
+                    )*
+                    Self::Unknown { code, .. } => {
+                        // we don't keep the data of unknown options, so we can't write them.
+                        tracing::debug!(?code, "skipping unknown DHCP option");
+                    }
+                }
+            }
         }
 
         impl<R: Reader> Read<R, ()> for Option
@@ -244,7 +338,11 @@ macro_rules! make_options {
                         let option = match code {
                             $(
                                 OptionCode::$code_const => {
-                                    limit.read().map_err(|_| InvalidMessage::InvalidOption { code, length })?
+                                    Option::$name(
+                                        limit
+                                            .read::<options::$name>()
+                                            .map_err(|_| InvalidMessage::InvalidOption { code, length })?,
+                                    )
                                 },
                             )*
                             _ => {
@@ -266,15 +364,38 @@ macro_rules! make_options {
         pub mod options {
             use std::net::Ipv4Addr;
             use byst::io::{Reader, Read, ReaderExt};
-            use super::{OptionCode, Option, OptionWrapper};
+            use super::{OptionCode, Option, OptionWrapper, WriteOption};
+
+            /// Trait implemented by all option types.
+            pub trait DhcpOption: Into<Option> {
+                const CODE: OptionCode;
+
+                fn from_option(option: &Option) -> std::option::Option<&Self>;
+            }
 
             $(
                 $(#[doc = $doc])?
-                #[derive(Clone, Debug)]
+                #[derive(Clone, Debug, PartialEq, Eq)]
                 pub struct $name $( ( $(pub $field ),* ) )?;
 
                 impl $name {
                     pub const CODE: OptionCode = OptionCode::$code_const;
+
+                    #[allow(unused_variables, clippy::ptr_arg)]
+                    pub(super) fn write_data(&self, buf: &mut Vec<u8>) {
+                        write_option_fields!(self, buf $( $(, $field)* )?);
+                    }
+                }
+
+                impl DhcpOption for $name {
+                    const CODE: OptionCode = OptionCode::$code_const;
+
+                    fn from_option(option: &Option) -> std::option::Option<&Self> {
+                        match option {
+                            Option::$name(option) => Some(option),
+                            _ => None,
+                        }
+                    }
                 }
 
                 impl From<$name> for Option {
@@ -518,3 +639,92 @@ macro_rules! impl_option_wrapper_for_tuple {
     };
 }
 for_tuple!(impl_option_wrapper_for_tuple! for 2..=2);
+
+trait WriteOption {
+    fn write_option(&self, buf: &mut Vec<u8>);
+}
+
+impl WriteOption for Ipv4Addr {
+    fn write_option(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.octets());
+    }
+}
+
+macro_rules! impl_write_option_for_int {
+    ($($ty:ty),*) => {
+        $(
+            impl WriteOption for $ty {
+                fn write_option(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_write_option_for_int!(u8, u16, u32, i32);
+
+impl WriteOption for bool {
+    fn write_option(&self, buf: &mut Vec<u8>) {
+        buf.push((*self).into());
+    }
+}
+
+impl WriteOption for OptionCode {
+    fn write_option(&self, buf: &mut Vec<u8>) {
+        buf.push(self.0);
+    }
+}
+
+impl WriteOption for String {
+    fn write_option(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl<T: WriteOption> WriteOption for Vec<T> {
+    fn write_option(&self, buf: &mut Vec<u8>) {
+        for item in self {
+            item.write_option(buf);
+        }
+    }
+}
+
+impl<A: WriteOption, B: WriteOption> WriteOption for (A, B) {
+    fn write_option(&self, buf: &mut Vec<u8>) {
+        self.0.write_option(buf);
+        self.1.write_option(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_options() {
+        let options: Options = [
+            options::DhcpMessageType::DHCPOFFER.into(),
+            options::ServerIdentifier(Ipv4Addr::new(10, 0, 69, 1)).into(),
+            options::DomainNameServer(vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)])
+                .into(),
+            options::ClientIdentifier(1, vec![0x12, 0x34]).into(),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            options.get::<options::DhcpMessageType>(),
+            Some(&options::DhcpMessageType::DHCPOFFER)
+        );
+        assert!(options.get::<options::RequestedIpAddress>().is_none());
+
+        assert_eq!(
+            options.to_vec(),
+            [
+                99, 130, 83, 99, 53, 1, 2, 54, 4, 10, 0, 69, 1, 6, 8, 1, 1, 1, 1, 8, 8, 8, 8, 61,
+                3, 1, 0x12, 0x34, 255
+            ]
+        );
+    }
+}
@@ -5,7 +5,13 @@
 
 use std::{
     convert::Infallible,
-    net::Ipv4Addr,
+    fmt::Display,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+    },
+    str::FromStr,
 };
 
 use byst::{
@@ -17,6 +23,8 @@ use byst::{
         Read,
         Reader,
         ReaderExt,
+        Write,
+        Writer,
     },
     Buf,
     Bytes,
@@ -82,6 +90,70 @@ where
     }
 }
 
+impl Message {
+    /// Decodes a message, e.g. from the payload of a UDP datagram.
+    pub fn parse(data: &[u8]) -> Result<Self, InvalidMessage> {
+        Bytes::from(data.to_vec()).reader().read::<Self>()
+    }
+
+    /// Returns the addresses from the `A` and `AAAA` records in the answer
+    /// section, and the lowest TTL of these records.
+    pub fn answer_addresses(&self) -> (Vec<IpAddr>, Option<u32>) {
+        let mut addresses = vec![];
+        let mut ttl = None::<u32>;
+        for record in &self.answers {
+            let address = match &record.rdata {
+                RecordData::A { address } => IpAddr::from(*address),
+                RecordData::Aaaa { address } => IpAddr::from(*address),
+                _ => continue,
+            };
+            addresses.push(address);
+            let record_ttl = record.ttl.max(0) as u32;
+            ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        }
+        (addresses, ttl)
+    }
+
+    /// Encodes the message.
+    ///
+    /// The section counts in the header are taken from the lengths of the
+    /// sections. Names are never compressed.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+
+        let header = Header {
+            num_questions: self.questions.len() as u16,
+            num_answers: self.answers.len() as u16,
+            num_authority: self.authority.len() as u16,
+            num_additional: self.additional.len() as u16,
+            ..self.header
+        };
+        header.write_to(&mut buf);
+
+        for question in &self.questions {
+            question.write_to(&mut buf);
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authority)
+            .chain(&self.additional)
+        {
+            record.write_to(&mut buf);
+        }
+
+        buf
+    }
+}
+
+impl<W: Writer> Write<W, ()> for Message {
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        writer.write_buf(&*self.to_vec())
+    }
+}
+
 #[derive(Clone, Copy)]
 struct PointerBase<B: Buf + Copy>(B);
 
@@ -100,6 +172,19 @@ pub struct Header {
     pub num_additional: u16,
 }
 
+impl Header {
+    pub const LENGTH: usize = 12;
+
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.transaction_id.to_be_bytes());
+        buf.extend_from_slice(&u16::from(self.flags).to_be_bytes());
+        buf.extend_from_slice(&self.num_questions.to_be_bytes());
+        buf.extend_from_slice(&self.num_answers.to_be_bytes());
+        buf.extend_from_slice(&self.num_authority.to_be_bytes());
+        buf.extend_from_slice(&self.num_additional.to_be_bytes());
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Flags {
     pub qr: Qr,
@@ -116,30 +201,52 @@ impl<R: Reader> Read<R, ()> for Flags {
     type Error = R::Error;
 
     fn read(reader: &mut R, _context: ()) -> Result<Self, Self::Error> {
-        let value = reader.read_with::<u16, _>(NetworkEndian)?;
-        let qr = if value & 0x0001 == 0 {
+        Ok(reader.read_with::<u16, _>(NetworkEndian)?.into())
+    }
+}
+
+impl From<u16> for Flags {
+    fn from(value: u16) -> Self {
+        let qr = if value & 0x8000 == 0 {
             Qr::Query
         }
         else {
             Qr::Reply
         };
-        let opcode = Opcode((value & 0x001e) as u8);
-        let aa = value & 0x0020 != 0;
-        let tc = value & 0x0040 != 0;
-        let rd = value & 0x0080 != 0;
-        let ra = value & 0x0100 != 0;
-        let z = (value & 0x0e00 >> 9) as u8;
-        let rcode = ResponseCode((value >> 12) as u8);
-        Ok(Self {
+        Self {
             qr,
-            opcode,
-            aa,
-            tc,
-            rd,
-            ra,
-            z,
-            rcode,
-        })
+            opcode: Opcode(((value >> 11) & 0x0f) as u8),
+            aa: value & 0x0400 != 0,
+            tc: value & 0x0200 != 0,
+            rd: value & 0x0100 != 0,
+            ra: value & 0x0080 != 0,
+            z: ((value >> 4) & 0x07) as u8,
+            rcode: ResponseCode((value & 0x0f) as u8),
+        }
+    }
+}
+
+impl From<Flags> for u16 {
+    fn from(value: Flags) -> Self {
+        let mut flags = (u16::from(value.opcode.0 & 0x0f) << 11)
+            | (u16::from(value.z & 0x07) << 4)
+            | u16::from(value.rcode.0 & 0x0f);
+        if value.qr == Qr::Reply {
+            flags |= 0x8000;
+        }
+        if value.aa {
+            flags |= 0x0400;
+        }
+        if value.tc {
+            flags |= 0x0200;
+        }
+        if value.rd {
+            flags |= 0x0100;
+        }
+        if value.ra {
+            flags |= 0x0080;
+        }
+        flags
     }
 }
 
@@ -199,6 +306,16 @@ impl From<ResponseCode> for u8 {
     }
 }
 
+/// Formats the name of the response code, or its value if it's unknown.
+impl Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Question {
     pub qname: Name,
@@ -218,6 +335,14 @@ impl<R: BufReader, B: Buf + Copy> Read<R, PointerBase<B>> for Question {
     }
 }
 
+impl Question {
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        self.qname.write_to(buf);
+        buf.extend_from_slice(&self.qtype.0.to_be_bytes());
+        buf.extend_from_slice(&self.qclass.0.to_be_bytes());
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ResourceRecord {
     pub name: Name,
@@ -253,11 +378,92 @@ where
     }
 }
 
-#[derive(Clone, Debug)]
+impl ResourceRecord {
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        self.name.write_to(buf);
+        buf.extend_from_slice(&self.r#type.0.to_be_bytes());
+        buf.extend_from_slice(&self.class.0.to_be_bytes());
+        buf.extend_from_slice(&self.ttl.to_be_bytes());
+
+        // the length is filled in after the data was written.
+        let rdlength_position = buf.len();
+        buf.extend_from_slice(&[0, 0]);
+        self.rdata.write_to(buf);
+        let rdlength = (buf.len() - rdlength_position - 2) as u16;
+        buf[rdlength_position..][..2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Name {
     inner: String,
 }
 
+impl Name {
+    /// The name without the trailing dot. The root name is empty.
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        if !self.inner.is_empty() {
+            for label in self.inner.split('.') {
+                buf.push(label.len() as u8);
+                buf.extend_from_slice(label.as_bytes());
+            }
+        }
+        buf.push(0);
+    }
+}
+
+impl FromStr for Name {
+    type Err = InvalidName;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_suffix('.').unwrap_or(s);
+        if s.is_empty() {
+            return Ok(Self {
+                inner: String::new(),
+            });
+        }
+
+        // each label is prefixed with its length, and the name is terminated with a
+        // zero length label.
+        let length = s.len() + 2;
+        if length > 255 {
+            return Err(InvalidName::TooLong { length, limit: 255 });
+        }
+
+        let mut last_was_all_numeric = false;
+        for label in s.split('.') {
+            if label.is_empty() {
+                return Err(InvalidName::EmptyLabel);
+            }
+            if label.len() > 63 {
+                return Err(InvalidName::LabelTooLong {
+                    length: label.len(),
+                    limit: 63,
+                });
+            }
+            is_valid_label(label.as_bytes(), &mut last_was_all_numeric)?;
+        }
+
+        if last_was_all_numeric {
+            return Err(InvalidName::NumericTld);
+        }
+
+        Ok(Self {
+            inner: s.to_owned(),
+        })
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.inner)
+    }
+}
+
 impl<R: BufReader, B: Buf + Copy> Read<R, PointerBase<B>> for Name {
     type Error = InvalidMessage;
 
@@ -411,6 +617,15 @@ network_enum! {
 
     /// text strings
     TXT => 16;
+
+    /// an IPv6 host address ([RFC 3596](https://datatracker.ietf.org/doc/html/rfc3596))
+    AAAA => 28;
+
+    /// a service location ([RFC 2782](https://datatracker.ietf.org/doc/html/rfc2782))
+    SRV => 33;
+
+    /// HTTPS service binding ([RFC 9460](https://datatracker.ietf.org/doc/html/rfc9460))
+    HTTPS => 65;
 }
 
 /// [`QTYPE` values][1]
@@ -470,6 +685,15 @@ network_enum! {
     /// text strings
     TXT => 16;
 
+    /// an IPv6 host address ([RFC 3596](https://datatracker.ietf.org/doc/html/rfc3596))
+    AAAA => 28;
+
+    /// a service location ([RFC 2782](https://datatracker.ietf.org/doc/html/rfc2782))
+    SRV => 33;
+
+    /// HTTPS service binding ([RFC 9460](https://datatracker.ietf.org/doc/html/rfc9460))
+    HTTPS => 65;
+
     /// A request for a transfer of an entire zone
     AXFR => 252;
 
@@ -490,6 +714,17 @@ impl From<RecordType> for QuestionType {
     }
 }
 
+/// Formats the name of the type, or `TYPE` followed by its value if it's
+/// unknown, like in [RFC 3597](https://datatracker.ietf.org/doc/html/rfc3597#section-5).
+impl Display for QuestionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "TYPE{}", self.0),
+        }
+    }
+}
+
 /// [`CLASS` values][1]
 ///
 /// [1]: https://datatracker.ietf.org/doc/html/rfc1035#section-3.2.4
@@ -570,6 +805,9 @@ pub enum RecordData {
     A {
         address: Ipv4Addr,
     },
+    Aaaa {
+        address: Ipv6Addr,
+    },
     Wks {
         address: Ipv4Addr,
         protocol: u8,
@@ -639,6 +877,11 @@ where
                     address: reader.read()?,
                 })
             }
+            (RecordType::AAAA, RecordClass::IN) => {
+                Ok(Self::Aaaa {
+                    address: reader.read()?,
+                })
+            }
             (RecordType::WKS, RecordClass::IN) => {
                 Ok(Self::Wks {
                     address: reader.read()?,
@@ -651,6 +894,61 @@ where
     }
 }
 
+impl RecordData {
+    /// Writes the `RDATA`. For [`RecordData::Unknown`] nothing is written,
+    /// since its data isn't kept.
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Cname { cname: name }
+            | Self::Ns { ns_dname: name }
+            | Self::Ptr { ptr_dname: name } => name.write_to(buf),
+            Self::Mx {
+                preference,
+                exchange,
+            } => {
+                buf.extend_from_slice(&preference.to_be_bytes());
+                exchange.write_to(buf);
+            }
+            Self::Null { data } | Self::Txt { txt_data: data } => extend_from_buf(buf, data),
+            Self::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.write_to(buf);
+                rname.write_to(buf);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    buf.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            Self::A { address } => buf.extend_from_slice(&address.octets()),
+            Self::Aaaa { address } => buf.extend_from_slice(&address.octets()),
+            Self::Wks {
+                address,
+                protocol,
+                bitmap,
+            } => {
+                buf.extend_from_slice(&address.octets());
+                buf.push(*protocol);
+                extend_from_buf(buf, bitmap);
+            }
+            Self::Unknown { .. } => {}
+        }
+    }
+}
+
+fn extend_from_buf(buf: &mut Vec<u8>, data: &impl Buf) {
+    let mut reader = data.reader();
+    while let Some(chunk) = reader.peek_chunk() {
+        buf.extend(chunk);
+        reader.advance(chunk.len()).unwrap();
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("Invalid DNS message")]
 pub enum InvalidMessage {
@@ -676,6 +974,10 @@ pub enum InvalidName {
     InvalidCharacter { character: u8 },
     #[error("TLD can't be all numeric")]
     NumericTld,
+    #[error("Empty label")]
+    EmptyLabel,
+    #[error("Label too long: {length} > {limit}")]
+    LabelTooLong { length: usize, limit: usize },
 }
 
 fn is_valid_label(label: impl Buf, is_all_numeric: &mut bool) -> Result<(), InvalidName> {
@@ -714,3 +1016,77 @@ fn is_valid_label(label: impl Buf, is_all_numeric: &mut bool) -> Result<(), Inva
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_formats_types_and_response_codes() {
+        assert_eq!(QuestionType::AAAA.to_string(), "AAAA");
+        assert_eq!(QuestionType(65).to_string(), "TYPE65");
+        assert_eq!(ResponseCode::NAME_ERROR.to_string(), "NAME_ERROR");
+        assert_eq!(ResponseCode(9).to_string(), "9");
+    }
+
+    #[test]
+    fn it_converts_flags() {
+        // standard query with recursion desired
+        let flags = Flags::from(0x0100);
+        assert_eq!(flags.qr, Qr::Query);
+        assert_eq!(flags.opcode, Opcode::QUERY);
+        assert!(flags.rd);
+        assert!(!flags.ra);
+        assert_eq!(flags.rcode, ResponseCode::NO_ERROR);
+
+        // NXDOMAIN response with recursion desired and available
+        let flags = Flags::from(0x8183);
+        assert_eq!(flags.qr, Qr::Reply);
+        assert!(flags.rd);
+        assert!(flags.ra);
+        assert!(!flags.aa);
+        assert_eq!(flags.rcode, ResponseCode::NAME_ERROR);
+        assert_eq!(u16::from(flags), 0x8183);
+    }
+
+    #[test]
+    fn it_encodes_a_query() {
+        let message = Message {
+            header: Header {
+                transaction_id: 0x1234,
+                flags: Flags::from(0x0100),
+                num_questions: 0,
+                num_answers: 0,
+                num_authority: 0,
+                num_additional: 0,
+            },
+            questions: [Question {
+                qname: "example.com".parse().unwrap(),
+                qtype: QuestionType::A,
+                qclass: QuestionClass::IN,
+            }]
+            .into_iter()
+            .collect(),
+            answers: vec![],
+            authority: vec![],
+            additional: vec![],
+        };
+
+        assert_eq!(
+            message.to_vec(),
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+              \x07example\x03com\x00\x00\x01\x00\x01"
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_names() {
+        assert_eq!(
+            "example.com.".parse::<Name>().unwrap().as_str(),
+            "example.com"
+        );
+        assert!("example..com".parse::<Name>().is_err());
+        assert!("-example.com".parse::<Name>().is_err());
+        assert!("example.123".parse::<Name>().is_err());
+    }
+}
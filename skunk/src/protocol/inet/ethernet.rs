@@ -188,7 +188,12 @@ where
     fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
         writer.write(&self.header)?;
         writer.write(&self.payload)?;
-        // todo: write FCS
+
+        match self.frame_check_sequence {
+            FrameCheckSequence::Absent => {}
+            FrameCheckSequence::Present(fcs) => writer.write_buf(&fcs.to_be_bytes()[..])?,
+        }
+
         Ok(())
     }
 }
@@ -265,11 +270,14 @@ impl EtherType {
     }
 }
 
+/// The frame check sequence at the end of a [`Frame`].
+///
+/// The network interface appends it when sending, so frames that we write
+/// usually leave it [`Absent`][Self::Absent].
 #[derive(Clone, Copy, Debug)]
 pub enum FrameCheckSequence {
     Absent,
     Present(u32),
-    Calculate,
 }
 
 impl From<Option<u32>> for FrameCheckSequence {
@@ -302,6 +310,21 @@ where
     }
 }
 
+impl<W: Writer, P> Write<W, ()> for AnyPayload<P>
+where
+    ipv4::Packet<P>: Write<W, (), Error = W::Error>,
+{
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        match self {
+            Self::Arp(packet) => writer.write(packet),
+            Self::Ipv4(packet) => writer.write(packet),
+            Self::Unknown => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("Payload error")]
 pub enum AnyPayloadError<R, P> {
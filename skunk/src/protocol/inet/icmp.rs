@@ -11,20 +11,42 @@ use byst::{
         Reader,
         ReaderExt,
         Write,
+        WriterExt,
     },
     Buf,
 };
 
+use super::checksum::Checksum;
 use crate::util::network_enum;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Read, Write)]
 pub struct Header {
     pub r#type: Type,
     pub code: Code,
+    #[byst(network)]
     pub checksum: u16,
     pub aux: [u8; 4],
 }
 
+impl Header {
+    /// Length of the ICMP header in bytes.
+    pub const LENGTH: usize = 8;
+
+    /// Calculates the checksum over the header and the message `data`.
+    ///
+    /// The current value of `checksum` is ignored.
+    pub fn calculate_checksum(&self, data: &[u8]) -> u16 {
+        let header = Self {
+            checksum: 0,
+            ..*self
+        };
+        let mut checksum = Checksum::new();
+        let Ok(()) = checksum.write(&header);
+        checksum.update(data);
+        checksum.finish()
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Read, Write)]
 pub struct Type(pub u8);
 
@@ -252,3 +274,32 @@ network_enum! {
     /// Redirect datagrams for the Type of Service and Host.
     TOS_AND_HOST => 3;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::inet::testing::to_vec;
+
+    /// Echo request with id 1, sequence number 1 and data `abcdefgh`.
+    const ECHO_REQUEST: [u8; 16] = [
+        0x08, 0x00, 0x66, 0x68, 0x00, 0x01, 0x00, 0x01, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67,
+        0x68,
+    ];
+
+    #[test]
+    fn it_round_trips_an_echo_request() {
+        let header: Header = ECHO_REQUEST.reader().read().unwrap();
+        assert_eq!(header.r#type, Type::ECHO_REQUEST);
+        assert_eq!(header.checksum, 0x6668);
+        assert_eq!(to_vec(&header), ECHO_REQUEST[..Header::LENGTH]);
+    }
+
+    #[test]
+    fn it_calculates_the_checksum() {
+        let header: Header = ECHO_REQUEST.reader().read().unwrap();
+        assert_eq!(
+            header.calculate_checksum(&ECHO_REQUEST[Header::LENGTH..]),
+            header.checksum
+        );
+    }
+}
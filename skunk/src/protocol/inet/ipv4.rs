@@ -20,7 +20,11 @@ use byst::{
     Bytes,
 };
 
-use super::udp;
+use super::{
+    checksum::Checksum,
+    tcp,
+    udp,
+};
 use crate::util::network_enum;
 
 #[derive(Clone, Debug)]
@@ -100,12 +104,46 @@ impl<R: Reader> Read<R, ()> for Header {
     }
 }
 
+impl Header {
+    /// Length of an IPv4 header without options.
+    pub const LENGTH: usize = 20;
+
+    /// Calculates the header checksum.
+    ///
+    /// The current value of `header_checksum` is ignored.
+    pub fn calculate_checksum(&self) -> u16 {
+        let mut buf = self.to_bytes();
+        buf[10..12].fill(0);
+        let mut checksum = Checksum::new();
+        checksum.update(&buf);
+        checksum.finish()
+    }
+
+    fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut buf = [0; Self::LENGTH];
+        buf[0] = self.version << 4 | (self.internet_header_length & 0xf);
+        buf[1] = self.differentiated_service_code_point << 2
+            | (self.explicit_congestion_notification & 3);
+        buf[2..4].copy_from_slice(&self.total_length.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        let flags_fragment_offset =
+            u16::from(self.flags.bits()) << 13 | (self.fragment_offset & 0x1fff);
+        buf[6..8].copy_from_slice(&flags_fragment_offset.to_be_bytes());
+        buf[8] = self.time_to_live;
+        buf[9] = self.protocol.0;
+        buf[10..12].copy_from_slice(&self.header_checksum.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.source_address.octets());
+        buf[16..20].copy_from_slice(&self.destination_address.octets());
+        buf
+    }
+}
+
 impl<W: Writer> Write<W, ()> for Header {
     type Error = W::Error;
 
-    fn write(&self, _writer: &mut W, _context: ()) -> Result<(), Self::Error> {
-        //writer.write(&(self.version << 4 | self.internet_header_length))?;
-        Ok(())
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        // todo: support options
+        writer.write_buf(&self.to_bytes()[..])
     }
 }
 
@@ -133,8 +171,8 @@ where
     fn read(reader: &mut R, _params: ()) -> Result<Self, Self::Error> {
         let header: Header = reader.read()?;
 
-        let payload_length =
-            usize::from(header.total_length) - usize::from(header.internet_header_length);
+        let payload_length = usize::from(header.total_length)
+            .saturating_sub(usize::from(header.internet_header_length) * 4);
         let mut limit = reader.limit(payload_length);
         let payload = limit
             .read_with(header.protocol)
@@ -184,17 +222,23 @@ pub enum InvalidHeader<R> {
 #[derive(Clone, Debug)]
 pub enum AnyPayload<P = Bytes> {
     Udp(udp::Packet<P>),
+    Tcp(tcp::Packet<P>),
     Unknown(P),
 }
 
-impl<R: Reader, P, E> Read<R, Protocol> for AnyPayload<P>
+impl<R: Reader, P> Read<R, Protocol> for AnyPayload<P>
 where
-    P: Read<R, (), Error = E>,
+    P: Read<R, (), Error = Infallible>,
+    P: for<'r> Read<Limit<&'r mut R>, (), Error = Infallible>,
 {
-    type Error = AnyPayloadError<E>;
+    type Error = AnyPayloadError<R::Error>;
 
-    fn read(_reader: &mut R, _protocol: Protocol) -> Result<Self, Self::Error> {
-        todo!()
+    fn read(reader: &mut R, protocol: Protocol) -> Result<Self, Self::Error> {
+        Ok(match protocol {
+            Protocol::UDP => Self::Udp(reader.read()?),
+            Protocol::TCP => Self::Tcp(reader.read()?),
+            _ => Self::Unknown(reader.read()?),
+        })
     }
 }
 
@@ -202,9 +246,33 @@ where
 #[error("IPv4 payload error")]
 pub enum AnyPayloadError<R> {
     Udp(#[from] udp::InvalidPacket<R>),
+    Tcp(#[from] tcp::InvalidPacket<R>),
+}
+
+impl<W: Writer, P> Write<W, ()> for AnyPayload<P>
+where
+    udp::Packet<P>: Write<W, (), Error = W::Error>,
+    tcp::Packet<P>: Write<W, (), Error = W::Error>,
+    P: Write<W, (), Error = W::Error>,
+{
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        match self {
+            Self::Udp(packet) => writer.write(packet),
+            Self::Tcp(packet) => writer.write(packet),
+            Self::Unknown(payload) => writer.write(payload),
+        }
+    }
+}
+
+impl<R> From<Infallible> for AnyPayloadError<R> {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Read)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Read, Write)]
 pub struct Protocol(pub u8);
 
 network_enum! {
@@ -219,3 +287,43 @@ network_enum! {
     /// User Datagram Protocol
     UDP => 0x11;
 }
+
+#[cfg(test)]
+mod tests {
+    use byst::Buf;
+
+    use super::*;
+    use crate::protocol::inet::testing::to_vec;
+
+    /// DNS query for `example.com` from `10.0.69.2` to `10.0.69.1`.
+    const PACKET: [u8; 57] = [
+        0x45, 0x00, 0x00, 0x39, 0xbe, 0xef, 0x40, 0x00, 0x40, 0x11, 0xdd, 0xc1, 0x0a, 0x00, 0x45,
+        0x02, 0x0a, 0x00, 0x45, 0x01, 0xcf, 0xdb, 0x00, 0x35, 0x00, 0x25, 0xaf, 0xee, 0x12, 0x34,
+        0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65, 0x78, 0x61, 0x6d,
+        0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn it_round_trips_a_header() {
+        let header: Header = PACKET.reader().read().unwrap();
+        assert_eq!(header.total_length, 57);
+        assert_eq!(header.identification, 0xbeef);
+        assert_eq!(header.flags.bits(), Flags::DONT_FRAGMENT.bits());
+        assert_eq!(header.protocol, Protocol::UDP);
+        assert_eq!(header.source_address, Ipv4Addr::new(10, 0, 69, 2));
+        assert_eq!(header.destination_address, Ipv4Addr::new(10, 0, 69, 1));
+        assert_eq!(to_vec(&header), PACKET[..Header::LENGTH]);
+    }
+
+    #[test]
+    fn it_calculates_the_header_checksum() {
+        let mut header: Header = PACKET.reader().read().unwrap();
+        assert_eq!(header.calculate_checksum(), 0xddc1);
+
+        header.time_to_live -= 1;
+        header.header_checksum = header.calculate_checksum();
+        let mut checksum = Checksum::new();
+        checksum.update(&to_vec(&header));
+        assert_eq!(checksum.finish(), 0);
+    }
+}
@@ -11,12 +11,16 @@ use std::{
 use byst::{
     endianness::NetworkEndian,
     io::{
+        BufReader,
         Limit,
         Read,
         Reader,
         ReaderExt,
         Write,
+        Writer,
+        WriterExt,
     },
+    Buf,
     Bytes,
 };
 use smallvec::SmallVec;
@@ -69,6 +73,61 @@ where
     }
 }
 
+impl<W: Writer, P> Write<W, ()> for Packet<P>
+where
+    P: Write<W, (), Error = W::Error>,
+    P: Write<Length, (), Error = Infallible>,
+{
+    type Error = WriteError<W::Error>;
+
+    /// Writes the packet, with the `payload_length` in the header computed from
+    /// the extension headers and the payload.
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        let mut length = Length::default();
+        length.write(&self.extension_headers).map_err(|error| {
+            match error {
+                WriteError::Write(error) => match error {},
+                WriteError::Unsupported(header_type) => WriteError::Unsupported(header_type),
+                WriteError::PayloadTooLarge { length } => WriteError::PayloadTooLarge { length },
+            }
+        })?;
+        if let Some(payload) = &self.payload {
+            let Ok(()) = length.write(payload);
+        }
+        let payload_length = u16::try_from(length.0)
+            .map_err(|_| WriteError::PayloadTooLarge { length: length.0 })?;
+
+        let header = Header {
+            payload_length,
+            ..self.header
+        };
+        writer.write(&header)?;
+        writer.write(&self.extension_headers)?;
+        if let Some(payload) = &self.payload {
+            writer.write(payload)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writer that only counts the written bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Length(pub usize);
+
+impl Writer for Length {
+    type Error = Infallible;
+
+    fn write_buf<B: Buf>(&mut self, buf: B) -> Result<(), Self::Error> {
+        self.0 += buf.reader().remaining();
+        Ok(())
+    }
+
+    fn skip(&mut self, amount: usize) -> Result<(), Self::Error> {
+        self.0 += amount;
+        Ok(())
+    }
+}
+
 /// [IPv6 header](https://datatracker.ietf.org/doc/html/rfc8200#section-3)
 ///
 /// ```plain
@@ -143,13 +202,13 @@ impl<R: Reader> Read<R, ()> for Header {
     fn read(reader: &mut R, _context: ()) -> Result<Self, Self::Error> {
         let value: u32 = reader.read_with(NetworkEndian)?;
 
-        let version = (value & 0xf) as u8;
+        let version = (value >> 28) as u8;
         if version != 6 {
             return Err(InvalidHeader::InvalidVersion { value: version });
         }
 
-        let traffic_class = TrafficClass::from((value >> 4) as u8);
-        let flow_label = FlowLabel(value >> 12);
+        let traffic_class = TrafficClass::from((value >> 20) as u8);
+        let flow_label = FlowLabel(value & 0xfffff);
 
         let payload_length = reader.read_with(NetworkEndian)?;
         let next_header = reader.read()?;
@@ -170,6 +229,23 @@ impl<R: Reader> Read<R, ()> for Header {
     }
 }
 
+impl<W: Writer> Write<W, ()> for Header {
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        let value = u32::from(self.version) << 28
+            | u32::from(u8::from(self.traffic_class)) << 20
+            | (u32::from(self.flow_label) & 0xfffff);
+        writer.write_buf(&value.to_be_bytes()[..])?;
+        writer.write_buf(&self.payload_length.to_be_bytes()[..])?;
+        writer.write(&self.next_header)?;
+        writer.write(&self.hop_limit)?;
+        writer.write(&self.source_address)?;
+        writer.write(&self.destination_address)?;
+        Ok(())
+    }
+}
+
 // todo: also use this for ipv4
 #[derive(Clone, Copy, Debug)]
 pub struct TrafficClass {
@@ -186,6 +262,12 @@ impl From<u8> for TrafficClass {
     }
 }
 
+impl From<TrafficClass> for u8 {
+    fn from(value: TrafficClass) -> Self {
+        (value.ds << 2) | (value.ecn & 3)
+    }
+}
+
 /// 20-bit flow label.
 ///
 /// See [Section 6][1], [RFC][2]
@@ -313,7 +395,7 @@ impl<R: Reader> Read<R, NextHeader> for ExtensionHeaders {
     type Error = InvalidExtensionHeader<R::Error>;
 
     fn read(reader: &mut R, mut next_header: NextHeader) -> Result<Self, Self::Error> {
-        let mut is_first = false;
+        let mut is_first = true;
         let mut num_destination_options = 0;
         let mut previous_was_destination_options = false;
         let mut previous_was_fragment = false;
@@ -385,6 +467,7 @@ impl<R: Reader> Read<R, NextHeader> for ExtensionHeaders {
             else {
                 // end of extension headers
                 protocol = Some(next_header.into());
+                break;
             }
         }
 
@@ -395,6 +478,17 @@ impl<R: Reader> Read<R, NextHeader> for ExtensionHeaders {
     }
 }
 
+impl<W: Writer> Write<W, ()> for ExtensionHeaders {
+    type Error = WriteError<W::Error>;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        for extension_header in &self.extension_headers {
+            writer.write(extension_header)?;
+        }
+        Ok(())
+    }
+}
+
 /// [IPv6 Extension Headers][1]
 ///
 /// [1]: https://datatracker.ietf.org/doc/html/rfc8200#section-4
@@ -443,6 +537,22 @@ impl ExtensionHeader {
     }
 }
 
+impl<W: Writer> Write<W, ()> for ExtensionHeader {
+    type Error = WriteError<W::Error>;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        match self {
+            Self::HopByHopOptions(inner) => writer.write(inner)?,
+            Self::Routing(inner) => writer.write(inner)?,
+            Self::Fragment(inner) => writer.write(inner)?,
+            Self::DestinationOptions(inner) => writer.write(inner)?,
+            // we don't keep the contents of these headers, so we can't write them.
+            _ => return Err(WriteError::Unsupported(self.header_type())),
+        }
+        Ok(())
+    }
+}
+
 impl<R: Reader> Read<R, ExtensionHeaderType> for ExtensionHeader {
     type Error = InvalidExtensionHeader<R::Error>;
 
@@ -486,6 +596,8 @@ pub mod extension_headers {
             Reader,
             ReaderExt,
             Write,
+            Writer,
+            WriterExt,
         },
     };
     use smallvec::SmallVec;
@@ -514,6 +626,14 @@ pub mod extension_headers {
         }
     }
 
+    impl<W: Writer> Write<W, ()> for HopByHop {
+        type Error = W::Error;
+
+        fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+            write_options(writer, self.next_header, &self.options)
+        }
+    }
+
     #[derive(Clone, Debug)]
     pub struct DestinationOptions {
         pub next_header: NextHeader,
@@ -532,6 +652,14 @@ pub mod extension_headers {
         }
     }
 
+    impl<W: Writer> Write<W, ()> for DestinationOptions {
+        type Error = W::Error;
+
+        fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+            write_options(writer, self.next_header, &self.options)
+        }
+    }
+
     /// Routing extension header
     ///
     /// [Routing types](https://www.iana.org/assignments/ipv6-parameters/ipv6-parameters.xhtml#ipv6-parameters-3)
@@ -555,17 +683,16 @@ pub mod extension_headers {
             let routing_type: RoutingType = reader.read()?;
             let segments_left: u8 = reader.read()?;
 
-            let data_length = usize::from(header_extension_length) * 8 + 4;
-            let mut limit = reader.limit(data_length);
-
-            let routing_data = if segments_left == 0 {
-                RoutingData::Unrecognized { routing_type }
-            }
-            else {
+            if segments_left != 0 {
                 return Err(InvalidExtensionHeader::DiscardPacket);
-            };
+            }
 
-            limit.skip_remaining()?;
+            let data_length = usize::from(header_extension_length) * 8 + 4;
+            let mut data = Vec::with_capacity(data_length);
+            for _ in 0..data_length {
+                data.push(reader.read::<u8>()?);
+            }
+            let routing_data = RoutingData::Unrecognized { routing_type, data };
 
             Ok(Self {
                 next_header,
@@ -575,6 +702,29 @@ pub mod extension_headers {
         }
     }
 
+    impl<W: Writer> Write<W, ()> for Routing {
+        type Error = W::Error;
+
+        fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+            let (routing_type, data) = match &self.routing_data {
+                RoutingData::Unrecognized { routing_type, data } => (*routing_type, data),
+            };
+
+            // the data is padded with zeros to a multiple of 8 octets, including the first
+            // 4 octets of the header.
+            let header_extension_length = data.len().saturating_sub(4).div_ceil(8);
+            let padding = header_extension_length * 8 + 4 - data.len();
+
+            writer.write(&self.next_header)?;
+            writer.write(&(header_extension_length as u8))?;
+            writer.write(&routing_type)?;
+            writer.write(&self.segments_left)?;
+            writer.write_buf(&data[..])?;
+            writer.write_buf(&[0; 8][..padding])?;
+            Ok(())
+        }
+    }
+
     #[derive(Clone, Copy, PartialEq, Eq, Hash, Read, Write)]
     pub struct RoutingType(pub u8);
 
@@ -584,7 +734,12 @@ pub mod extension_headers {
 
     #[derive(Clone, Debug)]
     pub enum RoutingData {
-        Unrecognized { routing_type: RoutingType },
+        Unrecognized {
+            routing_type: RoutingType,
+
+            /// The type-specific data, which is kept as-is.
+            data: Vec<u8>,
+        },
     }
 
     #[derive(Clone, Debug)]
@@ -592,7 +747,7 @@ pub mod extension_headers {
         pub next_header: NextHeader,
         pub fragment_offset: u16,
         pub more: bool,
-        pub identification: u16,
+        pub identification: u32,
     }
 
     impl<R: Reader> Read<R, ()> for Fragment {
@@ -603,8 +758,8 @@ pub mod extension_headers {
             reader.skip(1)?;
 
             let value: u16 = reader.read_with(NetworkEndian)?;
-            let fragment_offset = value & 0x1fff;
-            let more = value & 0x8000 != 0;
+            let fragment_offset = value >> 3;
+            let more = value & 1 != 0;
 
             let identification = reader.read_with(NetworkEndian)?;
 
@@ -617,6 +772,20 @@ pub mod extension_headers {
         }
     }
 
+    impl<W: Writer> Write<W, ()> for Fragment {
+        type Error = W::Error;
+
+        fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+            let value = (self.fragment_offset << 3) | u16::from(self.more);
+
+            writer.write(&self.next_header)?;
+            writer.write(&0u8)?;
+            writer.write_buf(&value.to_be_bytes()[..])?;
+            writer.write_buf(&self.identification.to_be_bytes()[..])?;
+            Ok(())
+        }
+    }
+
     #[derive(Clone, Debug)]
     pub struct AuthenticationHeader {
         pub next_header: NextHeader,
@@ -665,6 +834,21 @@ pub mod extension_headers {
         Ok((next_header, options))
     }
 
+    fn write_options<W: Writer>(
+        writer: &mut W,
+        next_header: NextHeader,
+        _options: &Options,
+    ) -> Result<(), W::Error> {
+        // we don't parse any options besides padding, so we only need to write the
+        // padding for the minimum length of 8 octets.
+        writer.write(&next_header)?;
+        writer.write(&0u8)?;
+        writer.write(&OptionType::PADN)?;
+        writer.write(&4u8)?;
+        writer.write_buf(&[0; 4][..])?;
+        Ok(())
+    }
+
     /// Hop-by-Hop and destination options
     ///
     /// [RFC 8200 Section 4.2](https://datatracker.ietf.org/doc/html/rfc8200#section-4.2)
@@ -843,3 +1027,112 @@ pub enum InvalidExtensionHeader<R> {
     #[error("Discarding packet")]
     DiscardPacket,
 }
+
+#[derive(Debug, thiserror::Error)]
+#[error("Can't write IPv6 packet")]
+pub enum WriteError<W> {
+    Write(#[from] W),
+
+    #[error("Can't write extension header: {0:?}")]
+    Unsupported(ExtensionHeaderType),
+
+    #[error("Payload too large: {length} bytes")]
+    PayloadTooLarge {
+        length: usize,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use byst::Buf;
+
+    use super::*;
+    use crate::protocol::inet::{
+        testing::{
+            to_vec,
+            VecWriter,
+        },
+        udp::RawPayload,
+    };
+
+    /// Header of a UDP datagram from `fe80::1` to `ff02::1`, with traffic class
+    /// `0xb8` and flow label `0x12345`.
+    const HEADER: [u8; 40] = [
+        0x6b, 0x81, 0x23, 0x45, 0x00, 0x08, 0x11, 0x40, 0xfe, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xff, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn it_round_trips_a_header() {
+        let header: Header = HEADER.reader().read().unwrap();
+        assert_eq!(header.version, 6);
+        assert_eq!(u8::from(header.traffic_class), 0xb8);
+        assert_eq!(header.flow_label, FlowLabel::new(0x12345).unwrap());
+        assert_eq!(header.payload_length, 8);
+        assert_eq!(Protocol::from(header.next_header), Protocol::UDP);
+        assert_eq!(header.hop_limit, 64);
+        assert_eq!(
+            header.source_address,
+            "fe80::1".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            header.destination_address,
+            "ff02::1".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(to_vec(&header), HEADER);
+    }
+
+    /// Routing header with 8 octets of type-specific data, followed by UDP.
+    const ROUTING_HEADER: [u8; 16] = [
+        0x11, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00,
+        0x01,
+    ];
+
+    fn routing_header() -> ExtensionHeaders {
+        ROUTING_HEADER
+            .reader()
+            .read_with(NextHeader::from(ExtensionHeaderType::ROUTING))
+            .unwrap()
+    }
+
+    #[test]
+    fn it_round_trips_a_routing_header() {
+        let extension_headers = routing_header();
+        assert_eq!(extension_headers.protocol(), Some(Protocol::UDP));
+        assert_eq!(to_vec(&extension_headers), ROUTING_HEADER);
+    }
+
+    #[test]
+    fn it_writes_the_payload_length() {
+        let header: Header = HEADER.reader().read().unwrap();
+        let packet = Packet {
+            header: Header {
+                payload_length: 0,
+                next_header: ExtensionHeaderType::ROUTING.into(),
+                ..header
+            },
+            extension_headers: routing_header(),
+            payload: Some(RawPayload(&[0x12, 0x34])),
+        };
+        let data = to_vec(&packet);
+        assert_eq!(data[4..6], 18u16.to_be_bytes());
+        assert_eq!(data[40..56], ROUTING_HEADER);
+        assert_eq!(data[56..], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn it_refuses_to_write_unsupported_extension_headers() {
+        let extension_header =
+            ExtensionHeader::AuthenticationHeader(extension_headers::AuthenticationHeader {
+                next_header: NextHeader(Protocol::UDP.0),
+            });
+        let result = extension_header.write(&mut VecWriter::default(), ());
+        assert!(matches!(
+            result,
+            Err(WriteError::Unsupported(
+                ExtensionHeaderType::AUTHENTICATION_HEADER
+            ))
+        ));
+    }
+}
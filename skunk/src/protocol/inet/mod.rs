@@ -1,4 +1,5 @@
 pub mod arp;
+pub mod checksum;
 pub mod dhcp;
 pub mod dns;
 pub mod ethernet;
@@ -30,6 +31,52 @@ pub use skunk_macros::{
 
 pub use self::mac_address::MacAddress;
 
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::convert::Infallible;
+
+    use byst::{
+        io::{
+            BufReader,
+            Write,
+            Writer,
+        },
+        Buf,
+    };
+
+    /// Writer that collects the written bytes, for round-trip tests.
+    #[derive(Debug, Default)]
+    pub struct VecWriter(pub Vec<u8>);
+
+    impl Writer for VecWriter {
+        type Error = Infallible;
+
+        fn write_buf<B: Buf>(&mut self, buf: B) -> Result<(), Self::Error> {
+            let mut reader = buf.reader();
+            while let Some(chunk) = reader.peek_chunk() {
+                self.0.extend_from_slice(chunk);
+                reader.advance(chunk.len()).unwrap();
+            }
+            Ok(())
+        }
+
+        fn skip(&mut self, amount: usize) -> Result<(), Self::Error> {
+            self.0.resize(self.0.len() + amount, 0);
+            Ok(())
+        }
+    }
+
+    pub fn to_vec<T>(value: &T) -> Vec<u8>
+    where
+        T: Write<VecWriter, ()>,
+        T::Error: std::fmt::Debug,
+    {
+        let mut writer = VecWriter::default();
+        value.write(&mut writer, ()).unwrap();
+        writer.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::{
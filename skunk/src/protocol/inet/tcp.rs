@@ -1,16 +1,38 @@
+//! TCP protocol implementation.
+//!
+//! # References
+//! - [Transmission Control Protocol (TCP)](https://datatracker.ietf.org/doc/html/rfc9293)
+//! - [TCP Extensions for High Performance](https://datatracker.ietf.org/doc/html/rfc7323)
+
+use std::convert::Infallible;
+
 use bitflags::bitflags;
 use byst::{
     endianness::NetworkEndian,
     io::{
-        BufReader,
         Read,
         Reader,
         ReaderExt,
+        Write,
+        Writer,
+        WriterExt,
     },
+    Bytes,
 };
+use smallvec::SmallVec;
 
+use super::{
+    checksum::{
+        AddressPair,
+        Checksum,
+    },
+    ipv4::Protocol,
+};
 use crate::util::network_enum;
 
+/// Maximum length of the options in a TCP header.
+pub const MAX_OPTIONS_LENGTH: usize = 40;
+
 #[derive(Clone, Debug)]
 pub struct Header {
     pub source_port: u16,
@@ -22,7 +44,33 @@ pub struct Header {
     pub window_size: u16,
     pub checksum: u16,
     pub urgent_pointer: u16,
-    //pub options: O,
+    pub options: Options,
+}
+
+impl Header {
+    /// Creates a header with the given ports and flags.
+    ///
+    /// The `data_offset` is computed from the options, when the header is
+    /// written.
+    pub fn new(source_port: u16, destination_port: u16, flags: Flags) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            sequence_number: 0,
+            acknowledgment_number: 0,
+            data_offset: 5,
+            flags,
+            window_size: 0,
+            checksum: 0,
+            urgent_pointer: 0,
+            options: Options::default(),
+        }
+    }
+
+    /// Length of the header in bytes, including options and padding.
+    pub fn length(&self) -> usize {
+        20 + self.options.padded_length()
+    }
 }
 
 impl<R: Reader> Read<R, ()> for Header {
@@ -44,17 +92,12 @@ impl<R: Reader> Read<R, ()> for Header {
         }
 
         // read options
-        /*let options = {
-            let reader = reader.limit((data_offset - 5) * 4);
-            let options = vec![];
-            while let Ok(kind) = reader.read::<OptionKind>().map_err(|End| ()) {
-                let length = match kind {
-                    OptionKind::End => {
-                        options.push(Option::End);
-                    }
-                }
-            }
-        };*/
+        let options_length = usize::from(data_offset - 5) * 4;
+        let mut buf = [0; MAX_OPTIONS_LENGTH];
+        for byte in &mut buf[..options_length] {
+            *byte = reader.read()?;
+        }
+        let options = Options::parse(&buf[..options_length]).map_err(InvalidHeader::Option)?;
 
         Ok(Self {
             source_port,
@@ -66,13 +109,35 @@ impl<R: Reader> Read<R, ()> for Header {
             window_size,
             checksum,
             urgent_pointer,
-            //options,
+            options,
         })
     }
 }
 
+impl<W: Writer> Write<W, ()> for Header {
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        let length = self.length();
+        let mut buf = SmallVec::<[u8; 60]>::with_capacity(length);
+
+        buf.extend_from_slice(&self.source_port.to_be_bytes());
+        buf.extend_from_slice(&self.destination_port.to_be_bytes());
+        buf.extend_from_slice(&self.sequence_number.to_be_bytes());
+        buf.extend_from_slice(&self.acknowledgment_number.to_be_bytes());
+        buf.push(((length / 4) as u8) << 4);
+        buf.push(self.flags.bits());
+        buf.extend_from_slice(&self.window_size.to_be_bytes());
+        buf.extend_from_slice(&self.checksum.to_be_bytes());
+        buf.extend_from_slice(&self.urgent_pointer.to_be_bytes());
+        self.options.write_to(&mut buf);
+
+        writer.write_buf(&buf[..])
+    }
+}
+
 bitflags! {
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct Flags: u8 {
         const CWR = 0b10000000;
         const ECE = 0b01000000;
@@ -85,47 +150,346 @@ bitflags! {
     }
 }
 
+/// A TCP segment.
+#[derive(Clone, Debug)]
+pub struct Packet<P = Bytes> {
+    pub header: Header,
+    pub payload: P,
+}
+
+impl<P: AsRef<[u8]>> Packet<P> {
+    /// Calculates the checksum over the pseudo header, the TCP header and the
+    /// payload.
+    ///
+    /// The current value of `header.checksum` is ignored.
+    pub fn calculate_checksum(&self, addresses: AddressPair) -> u16 {
+        let payload = self.payload.as_ref();
+        let mut checksum = Checksum::pseudo_header(
+            addresses,
+            Protocol::TCP,
+            (self.header.length() + payload.len()) as u32,
+        );
+        let header = Header {
+            checksum: 0,
+            ..self.header.clone()
+        };
+        let Ok(()) = checksum.write(&header);
+        checksum.update(payload);
+        checksum.finish()
+    }
+}
+
+impl<R: Reader, P> Read<R, ()> for Packet<P>
+where
+    P: Read<R, (), Error = Infallible>,
+{
+    type Error = InvalidPacket<R::Error>;
+
+    fn read(reader: &mut R, _context: ()) -> Result<Self, Self::Error> {
+        let header = reader.read()?;
+
+        // the payload is the rest of the IP payload.
+        let payload = match reader.read() {
+            Ok(payload) => payload,
+            Err(e) => match e {},
+        };
+
+        Ok(Self { header, payload })
+    }
+}
+
+impl<W: Writer, P: AsRef<[u8]>> Write<W, ()> for Packet<P> {
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        writer.write(&self.header)?;
+        writer.write_buf(self.payload.as_ref())?;
+        Ok(())
+    }
+}
+
+/// TCP options.
 #[derive(Clone, Debug, Default)]
-pub struct ReceivedOptions<B> {
-    buf: B,
+pub struct Options {
+    inner: SmallVec<[Option; 4]>,
 }
 
-impl<R: BufReader> Read<R, ()> for ReceivedOptions<R::View> {
-    type Error = InvalidOption;
+impl Options {
+    /// Parses the options part of a TCP header.
+    pub fn parse(mut buf: &[u8]) -> Result<Self, InvalidOption> {
+        let mut inner = SmallVec::new();
+
+        while let Some((&kind, rest)) = buf.split_first() {
+            let kind = OptionKind(kind);
+
+            match kind {
+                OptionKind::END => {
+                    // the rest is padding
+                    break;
+                }
+                OptionKind::NOP => {
+                    inner.push(Option::Nop);
+                    buf = rest;
+                }
+                _ => {
+                    let (&length, rest) = rest
+                        .split_first()
+                        .ok_or(InvalidOption::Truncated { kind })?;
+                    let data_length = usize::from(length)
+                        .checked_sub(2)
+                        .ok_or(InvalidOption::InvalidLength { kind, length })?;
+                    if data_length > rest.len() {
+                        return Err(InvalidOption::Truncated { kind });
+                    }
+                    let (data, rest) = rest.split_at(data_length);
+                    inner.push(Option::parse(kind, data)?);
+                    buf = rest;
+                }
+            }
+        }
+
+        Ok(Self { inner })
+    }
+
+    /// Length of the options in bytes, without padding.
+    pub fn length(&self) -> usize {
+        self.inner.iter().map(Option::length).sum()
+    }
+
+    /// Length of the options in bytes, padded to a multiple of 4.
+    pub fn padded_length(&self) -> usize {
+        (self.length() + 3) & !3
+    }
+
+    /// Writes the options to `buf`, padded with `END` options.
+    pub fn write_to(&self, buf: &mut impl Extend<u8>) {
+        for option in &self.inner {
+            option.write_to(buf);
+        }
+        buf.extend(std::iter::repeat_n(0, self.padded_length() - self.length()));
+    }
+
+    pub fn push(&mut self, option: Option) {
+        self.inner.push(option);
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Option> {
+        self.inner.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn maximum_segment_size(&self) -> std::option::Option<u16> {
+        self.iter().find_map(|option| {
+            match option {
+                Option::MaximumSegmentSize(mss) => Some(*mss),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn window_scale(&self) -> std::option::Option<u8> {
+        self.iter().find_map(|option| {
+            match option {
+                Option::WindowScale(shift) => Some(*shift),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn sack_permitted(&self) -> bool {
+        self.iter()
+            .any(|option| matches!(option, Option::SackPermitted))
+    }
 
-    fn read(_reader: &mut R, _context: ()) -> Result<Self, Self::Error> {
-        //Ok(Self {
-        //    buf: reader.read()?,
-        //})
-        todo!();
+    pub fn timestamps(&self) -> std::option::Option<(u32, u32)> {
+        self.iter().find_map(|option| {
+            match option {
+                Option::Timestamps { value, echo_reply } => Some((*value, *echo_reply)),
+                _ => None,
+            }
+        })
     }
 }
 
-pub enum Option<D> {
-    End,
+impl FromIterator<Option> for Options {
+    fn from_iter<T: IntoIterator<Item = Option>>(iter: T) -> Self {
+        Self {
+            inner: iter.into_iter().collect(),
+        }
+    }
+}
+
+/// A TCP option.
+///
+/// `END` is not represented, since it only marks the start of the padding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Option {
     Nop,
-    Other { kind: OptionKind, data: D },
+    MaximumSegmentSize(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Sack(SmallVec<[(u32, u32); 4]>),
+    Timestamps { value: u32, echo_reply: u32 },
+    Other { kind: OptionKind, data: Vec<u8> },
+}
+
+impl Option {
+    fn parse(kind: OptionKind, data: &[u8]) -> Result<Self, InvalidOption> {
+        let invalid_length = || {
+            InvalidOption::InvalidLength {
+                kind,
+                length: data.len() as u8 + 2,
+            }
+        };
+
+        let option = match kind {
+            OptionKind::MAXIMUM_SEGMENT_SIZE => {
+                let data: [u8; 2] = data.try_into().map_err(|_| invalid_length())?;
+                Self::MaximumSegmentSize(u16::from_be_bytes(data))
+            }
+            OptionKind::WINDOW_SCALE => {
+                let [shift] = data
+                else {
+                    return Err(invalid_length());
+                };
+                Self::WindowScale(*shift)
+            }
+            OptionKind::SACK_PERMITTED => {
+                if !data.is_empty() {
+                    return Err(invalid_length());
+                }
+                Self::SackPermitted
+            }
+            OptionKind::SACK => {
+                let blocks = data.chunks_exact(8);
+                if !blocks.remainder().is_empty() {
+                    return Err(invalid_length());
+                }
+                Self::Sack(
+                    blocks
+                        .map(|block| {
+                            (
+                                u32::from_be_bytes(block[..4].try_into().unwrap()),
+                                u32::from_be_bytes(block[4..].try_into().unwrap()),
+                            )
+                        })
+                        .collect(),
+                )
+            }
+            OptionKind::TIMESTAMPS => {
+                if data.len() != 8 {
+                    return Err(invalid_length());
+                }
+                Self::Timestamps {
+                    value: u32::from_be_bytes(data[..4].try_into().unwrap()),
+                    echo_reply: u32::from_be_bytes(data[4..].try_into().unwrap()),
+                }
+            }
+            _ => {
+                Self::Other {
+                    kind,
+                    data: data.to_owned(),
+                }
+            }
+        };
+
+        Ok(option)
+    }
+
+    pub fn kind(&self) -> OptionKind {
+        match self {
+            Self::Nop => OptionKind::NOP,
+            Self::MaximumSegmentSize(_) => OptionKind::MAXIMUM_SEGMENT_SIZE,
+            Self::WindowScale(_) => OptionKind::WINDOW_SCALE,
+            Self::SackPermitted => OptionKind::SACK_PERMITTED,
+            Self::Sack(_) => OptionKind::SACK,
+            Self::Timestamps { .. } => OptionKind::TIMESTAMPS,
+            Self::Other { kind, .. } => *kind,
+        }
+    }
+
+    /// Length of the option in bytes, including kind and length.
+    pub fn length(&self) -> usize {
+        match self {
+            Self::Nop => 1,
+            Self::MaximumSegmentSize(_) => 4,
+            Self::WindowScale(_) => 3,
+            Self::SackPermitted => 2,
+            Self::Sack(blocks) => 2 + 8 * blocks.len(),
+            Self::Timestamps { .. } => 10,
+            Self::Other { data, .. } => 2 + data.len(),
+        }
+    }
+
+    fn write_to(&self, buf: &mut impl Extend<u8>) {
+        buf.extend([self.kind().0]);
+        if let Self::Nop = self {
+            return;
+        }
+        buf.extend([self.length() as u8]);
+
+        match self {
+            Self::Nop | Self::SackPermitted => {}
+            Self::MaximumSegmentSize(mss) => buf.extend(mss.to_be_bytes()),
+            Self::WindowScale(shift) => buf.extend([*shift]),
+            Self::Sack(blocks) => {
+                for (left, right) in blocks {
+                    buf.extend(left.to_be_bytes());
+                    buf.extend(right.to_be_bytes());
+                }
+            }
+            Self::Timestamps { value, echo_reply } => {
+                buf.extend(value.to_be_bytes());
+                buf.extend(echo_reply.to_be_bytes());
+            }
+            Self::Other { data, .. } => buf.extend(data.iter().copied()),
+        }
+    }
 }
 
 /// See[1]
 ///
 /// [1]: https://www.iana.org/assignments/tcp-parameters/tcp-parameters.xhtml#tcp-parameters-1
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Read)]
-pub struct OptionKind(u8);
+#[derive(Clone, Copy, Default, PartialEq, Eq, Read)]
+pub struct OptionKind(pub u8);
 
 network_enum! {
-    for OptionKind;
+    for OptionKind: Debug;
 
     /// End of Options list
     END => 0x00;
 
     /// No-Operation
     NOP => 0x01;
+
+    /// Maximum Segment Size
+    MAXIMUM_SEGMENT_SIZE => 0x02;
+
+    /// Window Scale
+    WINDOW_SCALE => 0x03;
+
+    /// SACK Permitted
+    SACK_PERMITTED => 0x04;
+
+    /// SACK
+    SACK => 0x05;
+
+    /// Timestamps
+    TIMESTAMPS => 0x08;
 }
 
 #[derive(Debug, thiserror::Error)]
 #[error("Invalid TCP option")]
-pub enum InvalidOption {}
+pub enum InvalidOption {
+    #[error("Truncated TCP option: {kind:?}")]
+    Truncated { kind: OptionKind },
+
+    #[error("Invalid length for TCP option {kind:?}: {length}")]
+    InvalidLength { kind: OptionKind, length: u8 },
+}
 
 #[derive(Debug, thiserror::Error)]
 #[error("Invalid TCP header")]
@@ -136,4 +500,111 @@ pub enum InvalidHeader<R> {
     InvalidDataOffset {
         data_offset: u8,
     },
+
+    Option(#[source] InvalidOption),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid TCP packet")]
+pub enum InvalidPacket<R> {
+    Header(#[from] InvalidHeader<R>),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use byst::Buf;
+
+    use super::*;
+    use crate::protocol::inet::testing::to_vec;
+
+    /// SYN from `10.0.69.2:51234` to `93.184.216.34:443`.
+    const SYN: [u8; 40] = [
+        0xc8, 0x22, 0x01, 0xbb, 0x12, 0x34, 0x56, 0x78, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x02, 0xfa,
+        0xf0, 0x95, 0x7e, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x00,
+        0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+    ];
+
+    #[test]
+    fn it_round_trips_a_syn() {
+        let header: Header = SYN.reader().read().unwrap();
+        assert_eq!(header.source_port, 51234);
+        assert_eq!(header.destination_port, 443);
+        assert_eq!(header.sequence_number, 0x12345678);
+        assert_eq!(header.flags, Flags::SYN);
+        assert_eq!(header.window_size, 64240);
+        assert_eq!(header.options.maximum_segment_size(), Some(1460));
+        assert_eq!(header.length(), SYN.len());
+        assert_eq!(to_vec(&header), SYN);
+    }
+
+    #[test]
+    fn it_calculates_the_checksum() {
+        let packet = Packet {
+            header: SYN.reader().read::<Header>().unwrap(),
+            payload: b"",
+        };
+        let addresses =
+            AddressPair::from((Ipv4Addr::new(10, 0, 69, 2), Ipv4Addr::new(93, 184, 216, 34)));
+        let checksum = packet.calculate_checksum(addresses);
+        assert_eq!(checksum, 0x957e);
+
+        // the payload is included in the checksum.
+        let packet = Packet {
+            header: packet.header,
+            payload: b"hello",
+        };
+        assert_ne!(packet.calculate_checksum(addresses), checksum);
+    }
+
+    #[test]
+    fn it_parses_syn_options() {
+        // MSS 1460, SACK permitted, timestamps, NOP, window scale 7
+        let buf = [
+            0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00,
+            0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ];
+        let options = Options::parse(&buf).unwrap();
+
+        assert_eq!(options.maximum_segment_size(), Some(1460));
+        assert_eq!(options.window_scale(), Some(7));
+        assert!(options.sack_permitted());
+        assert_eq!(options.timestamps(), Some((42, 0)));
+        assert_eq!(options.length(), buf.len());
+
+        let mut written = vec![];
+        options.write_to(&mut written);
+        assert_eq!(written, buf);
+    }
+
+    #[test]
+    fn it_stops_at_end_of_options() {
+        let options = Options::parse(&[0x01, 0x00, 0x02, 0x04]).unwrap();
+        assert_eq!(options.iter().collect::<Vec<_>>(), [&Option::Nop]);
+    }
+
+    #[test]
+    fn it_rejects_truncated_options() {
+        assert!(matches!(
+            Options::parse(&[0x02, 0x04, 0x05]),
+            Err(InvalidOption::Truncated { .. })
+        ));
+        assert!(matches!(
+            Options::parse(&[0x02, 0x01]),
+            Err(InvalidOption::InvalidLength { .. })
+        ));
+    }
+
+    #[test]
+    fn it_pads_written_options() {
+        let options: Options = [Option::MaximumSegmentSize(1460), Option::WindowScale(0)]
+            .into_iter()
+            .collect();
+        assert_eq!(options.length(), 7);
+
+        let mut written = vec![];
+        options.write_to(&mut written);
+        assert_eq!(written, [0x02, 0x04, 0x05, 0xb4, 0x03, 0x03, 0x00, 0x00]);
+    }
 }
@@ -6,11 +6,22 @@ use byst::{
         Read,
         Reader,
         ReaderExt,
+        Write,
+        Writer,
+        WriterExt,
     },
     Bytes,
 };
 
-#[derive(Clone, Copy, Debug, Read)]
+use super::{
+    checksum::{
+        AddressPair,
+        Checksum,
+    },
+    ipv4::Protocol,
+};
+
+#[derive(Clone, Copy, Debug, Read, Write)]
 pub struct Header {
     #[byst(network)]
     pub source_port: u16,
@@ -31,6 +42,38 @@ pub struct Packet<P = Bytes> {
     pub payload: P,
 }
 
+impl Header {
+    /// Length of the UDP header in bytes.
+    pub const LENGTH: usize = 8;
+}
+
+impl<P> Packet<P>
+where
+    P: Write<Checksum, (), Error = Infallible>,
+{
+    /// Calculates the checksum over the pseudo header, the UDP header and the
+    /// payload.
+    ///
+    /// The current value of `header.checksum` is ignored.
+    pub fn calculate_checksum(&self, addresses: AddressPair) -> u16 {
+        let mut checksum =
+            Checksum::pseudo_header(addresses, Protocol::UDP, self.header.length.into());
+        let header = Header {
+            checksum: 0,
+            ..self.header
+        };
+        let Ok(()) = checksum.write(&header);
+        let Ok(()) = checksum.write(&self.payload);
+
+        // a checksum of 0 means that no checksum was computed, so it's transmitted as
+        // all ones.
+        match checksum.finish() {
+            0 => 0xffff,
+            value => value,
+        }
+    }
+}
+
 impl<R: Reader, P, E> Read<R, ()> for Packet<P>
 where
     P: for<'r> Read<Limit<&'r mut R>, (), Error = E>,
@@ -40,8 +83,9 @@ where
     fn read(reader: &mut R, _params: ()) -> Result<Self, Self::Error> {
         let header: Header = reader.read()?;
 
+        // the length field includes the header.
         let payload = reader
-            .limit(header.length.into())
+            .limit(usize::from(header.length).saturating_sub(Header::LENGTH))
             .read()
             .map_err(InvalidPacket::Payload)?;
 
@@ -49,9 +93,74 @@ where
     }
 }
 
+impl<W: Writer, P> Write<W, ()> for Packet<P>
+where
+    P: Write<W, (), Error = W::Error>,
+{
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        writer.write(&self.header)?;
+        writer.write(&self.payload)?;
+        Ok(())
+    }
+}
+
+/// A payload that is already encoded.
+#[derive(Clone, Copy, Debug)]
+pub struct RawPayload<'a>(pub &'a [u8]);
+
+impl<'a, W: Writer> Write<W, ()> for RawPayload<'a> {
+    type Error = W::Error;
+
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        writer.write_buf(self.0)
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("Invalid UDP packet")]
 pub enum InvalidPacket<R, P = Infallible> {
     Read(#[from] R),
     Payload(#[source] P),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use byst::Buf;
+
+    use super::*;
+    use crate::protocol::inet::testing::to_vec;
+
+    /// DNS query for `example.com` from `10.0.69.2` to `10.0.69.1`.
+    const DATAGRAM: [u8; 37] = [
+        0xcf, 0xdb, 0x00, 0x35, 0x00, 0x25, 0xaf, 0xee, 0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63,
+        0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn it_round_trips_a_header() {
+        let header: Header = DATAGRAM.reader().read().unwrap();
+        assert_eq!(header.source_port, 53211);
+        assert_eq!(header.destination_port, 53);
+        assert_eq!(header.length, 37);
+        assert_eq!(header.checksum, 0xafee);
+        assert_eq!(to_vec(&header), DATAGRAM[..8]);
+    }
+
+    #[test]
+    fn it_calculates_the_checksum() {
+        let packet = Packet {
+            header: DATAGRAM.reader().read::<Header>().unwrap(),
+            payload: RawPayload(&DATAGRAM[8..]),
+        };
+        let checksum = packet.calculate_checksum(AddressPair::from((
+            Ipv4Addr::new(10, 0, 69, 2),
+            Ipv4Addr::new(10, 0, 69, 1),
+        )));
+        assert_eq!(checksum, packet.header.checksum);
+    }
+}
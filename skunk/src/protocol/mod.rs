@@ -2,8 +2,11 @@
 
 #[cfg(feature = "http")]
 pub mod http;
+pub mod sniff;
 #[cfg(feature = "tls")]
 pub mod tls;
+#[cfg(feature = "http")]
+pub mod websocket;
 
 // todo: feature flag
 pub mod inet;
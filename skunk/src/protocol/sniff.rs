@@ -0,0 +1,228 @@
+//! Protocol detection.
+//!
+//! This peeks at the first bytes a client sends on a connection to find out
+//! which protocol it speaks, independent of the port it connects to.
+
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+};
+
+use crate::util::io::Rewind;
+
+/// How long to wait for the client to send enough data to detect the protocol.
+///
+/// Some protocols (e.g. SMTP) expect the server to speak first, so the client
+/// won't send anything until we give up.
+pub const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The HTTP/2 connection preface a client sends before anything else.
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// HTTP/1.x request methods, followed by the space that separates it from the
+/// request target.
+const HTTP1_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
+/// Protocol spoken by a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// The client started a TLS handshake.
+    Tls,
+
+    /// The client sent a HTTP/1.x request line.
+    Http1,
+
+    /// The client sent the HTTP/2 connection preface.
+    Http2,
+
+    /// Anything else, including connections on which the client didn't send
+    /// anything.
+    Unknown,
+}
+
+impl Protocol {
+    /// Detects the protocol a client speaks from the first bytes it sends.
+    ///
+    /// The bytes read are put back, so the returned stream can be passed on
+    /// to whatever handles the protocol.
+    pub async fn detect<T>(mut io: T) -> Result<(Self, Rewind<T>), std::io::Error>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(HTTP2_PREFACE.len());
+
+        let result = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Some(protocol) = Self::classify(&buf) {
+                    break Ok::<_, std::io::Error>(protocol);
+                }
+                if io.read_buf(&mut buf).await? == 0 {
+                    break Ok(Self::Unknown);
+                }
+            }
+        })
+        .await;
+
+        let protocol = match result {
+            Ok(result) => result?,
+            Err(_) => Self::Unknown,
+        };
+
+        Ok((protocol, Rewind::new(io, buf.freeze())))
+    }
+
+    /// Classifies the start of a stream.
+    ///
+    /// Returns `None` if more data is needed.
+    pub fn classify(buf: &[u8]) -> Option<Self> {
+        if buf.is_empty() {
+            return None;
+        }
+
+        let mut incomplete = false;
+
+        for (protocol, is_match) in [
+            (Self::Tls, is_tls_client_hello(buf)),
+            (Self::Http2, matches_prefix(buf, HTTP2_PREFACE)),
+            (
+                Self::Http1,
+                HTTP1_METHODS
+                    .iter()
+                    .map(|method| matches_prefix(buf, method))
+                    .find(|is_match| *is_match != Some(false))
+                    .unwrap_or(Some(false)),
+            ),
+        ] {
+            match is_match {
+                Some(true) => return Some(protocol),
+                Some(false) => {}
+                None => incomplete = true,
+            }
+        }
+
+        (!incomplete).then_some(Self::Unknown)
+    }
+}
+
+/// Checks if `buf` starts with a TLS record containing a `ClientHello`.
+///
+/// Returns `None` if `buf` is too short to tell.
+fn is_tls_client_hello(buf: &[u8]) -> Option<bool> {
+    // content type: handshake
+    // legacy record version: 3.x, with 3.0 for SSLv3 up to 3.4 for TLS 1.3
+    // length: any
+    // handshake type: client hello
+    let checks: [fn(u8) -> bool; 6] = [
+        |b| b == 0x16,
+        |b| b == 0x03,
+        |b| b <= 0x04,
+        |_| true,
+        |_| true,
+        |b| b == 0x01,
+    ];
+
+    for (check, byte) in checks.iter().zip(buf) {
+        if !check(*byte) {
+            return Some(false);
+        }
+    }
+
+    (buf.len() >= checks.len()).then_some(true)
+}
+
+/// Checks if `buf` starts with `prefix`.
+///
+/// Returns `None` if `buf` is a prefix of `prefix`, and thus too short to
+/// tell.
+fn matches_prefix(buf: &[u8], prefix: &[u8]) -> Option<bool> {
+    if buf.len() >= prefix.len() {
+        Some(buf.starts_with(prefix))
+    }
+    else if prefix.starts_with(buf) {
+        None
+    }
+    else {
+        Some(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{
+        duplex,
+        AsyncWriteExt,
+    };
+
+    use super::*;
+
+    #[test]
+    fn it_detects_tls() {
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03];
+        assert_eq!(Protocol::classify(&client_hello), Some(Protocol::Tls));
+        assert_eq!(Protocol::classify(&client_hello[..3]), None);
+
+        // an alert record
+        assert_eq!(
+            Protocol::classify(&[0x15, 0x03, 0x03, 0x00, 0x02]),
+            Some(Protocol::Unknown)
+        );
+    }
+
+    #[test]
+    fn it_detects_http1() {
+        assert_eq!(
+            Protocol::classify(b"GET / HTTP/1.1\r\n"),
+            Some(Protocol::Http1)
+        );
+        assert_eq!(
+            Protocol::classify(b"OPTIONS * HTTP/1.1\r\n"),
+            Some(Protocol::Http1)
+        );
+        assert_eq!(Protocol::classify(b"PO"), None);
+        assert_eq!(Protocol::classify(b"GETS"), Some(Protocol::Unknown));
+    }
+
+    #[test]
+    fn it_detects_http2() {
+        assert_eq!(Protocol::classify(HTTP2_PREFACE), Some(Protocol::Http2));
+
+        // "PRI" could still be either HTTP/2 or something else.
+        assert_eq!(Protocol::classify(b"PRI * HTTP/2.0\r\n"), None);
+    }
+
+    #[test]
+    fn it_detects_unknown_protocols() {
+        assert_eq!(Protocol::classify(b""), None);
+        assert_eq!(
+            Protocol::classify(b"SSH-2.0-OpenSSH_9.6\r\n"),
+            Some(Protocol::Unknown)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_puts_back_what_it_read() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"PRI * HTTP/2.0\r\n").await.unwrap();
+        client.write_all(b"\r\nSM\r\n\r\n\0\0\0\x04").await.unwrap();
+        drop(client);
+
+        let (protocol, mut io) = Protocol::detect(server).await.unwrap();
+        assert_eq!(protocol, Protocol::Http2);
+        let mut data = vec![];
+        io.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04");
+    }
+}
@@ -0,0 +1,510 @@
+//! Cache for the certificates we forge for intercepted connections.
+//!
+//! Certificates are cached by the host the client connected to and the
+//! fingerprint of the certificate the server presented, so a changed server
+//! certificate results in a new forged certificate. The cache is bounded and
+//! evicts the least recently used certificate.
+//!
+//! The cache can be persisted to a directory, together with the keys of the
+//! forged certificates. This way clients see the same certificate across
+//! restarts, which matters for clients that pin certificates on first use.
+//! Changes are saved in the background, shortly after they happen.
+
+use std::{
+    fmt::Display,
+    io::Write,
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+use rcgen::KeyPair;
+use rustls::pki_types::CertificateDer;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use tempfile::NamedTempFile;
+use tokio::sync::{
+    mpsc,
+    OnceCell,
+};
+
+use super::{
+    imitation::KeyAlgorithm,
+    Ca,
+    Error,
+};
+use crate::util::hex;
+
+/// Default number of certificates that are cached.
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+const KEY_FILE: &str = "key.pem";
+const RSA_KEY_FILE: &str = "rsa-key.pem";
+const CERTS_FILE: &str = "certs.yml";
+
+/// How long to wait after a change before saving the certificates, so that a
+/// burst of new certificates results in a single write.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+type Certs = IndexMap<CacheKey, CertificateDer<'static>>;
+
+/// SHA-256 fingerprint of a certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl Fingerprint {
+    pub fn of(cert: &CertificateDer<'_>) -> Self {
+        Self(Sha256::digest(cert).into())
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+/// Failed to parse [`Fingerprint`].
+#[derive(Debug, thiserror::Error)]
+#[error("invalid fingerprint: {0}")]
+pub struct FingerprintParseError(String);
+
+impl FromStr for Fingerprint {
+    type Err = FingerprintParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(Self)
+            .ok_or_else(|| FingerprintParseError(s.to_owned()))
+    }
+}
+
+impl Serialize for Fingerprint {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Fingerprint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Key of a cached certificate.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    /// The server name the client sent, or the address it connected to.
+    pub host: String,
+
+    /// Fingerprint of the certificate the server presented.
+    pub upstream_fingerprint: Fingerprint,
+
+    /// The algorithm of the key of the forged certificate.
+    #[serde(default)]
+    pub key_algorithm: KeyAlgorithm,
+}
+
+/// Bounded cache of forged certificates.
+#[derive(Debug)]
+pub struct CertCache {
+    ecdsa_key: Arc<KeyPair>,
+    rsa_key: OnceCell<Arc<KeyPair>>,
+    capacity: usize,
+    persist: Option<Persist>,
+    certs: Arc<Mutex<Certs>>,
+}
+
+#[derive(Debug)]
+struct Persist {
+    path: PathBuf,
+
+    /// Tells the save task that the certificates changed.
+    changed: mpsc::Sender<()>,
+}
+
+/// Contents of the certificates file.
+#[derive(Debug, Serialize, Deserialize)]
+struct CertsFile {
+    /// Fingerprint of the CA that signed the certificates. If the CA changes,
+    /// the certificates are discarded.
+    ca: Fingerprint,
+    certs: Vec<CertsFileEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CertsFileEntry {
+    #[serde(flatten)]
+    key: CacheKey,
+    /// DER encoded certificate, hex-encoded.
+    cert: String,
+}
+
+impl CertCache {
+    /// Creates an empty cache with new random keys, that is not persisted.
+    pub async fn new(capacity: usize) -> Result<Self, Error> {
+        let ecdsa_key = load_or_generate_key(None, KeyAlgorithm::Ecdsa).await?;
+        Ok(Self {
+            ecdsa_key,
+            rsa_key: OnceCell::new(),
+            capacity,
+            persist: None,
+            certs: Default::default(),
+        })
+    }
+
+    /// Opens a cache that is persisted in the directory `path`.
+    ///
+    /// If the directory doesn't contain keys, new ones are generated and
+    /// saved. Certificates that were signed by a different CA than `ca` are
+    /// discarded.
+    pub async fn open(path: impl AsRef<Path>, capacity: usize, ca: &Ca) -> Result<Self, Error> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+
+        let ecdsa_key = load_or_generate_key(Some(path), KeyAlgorithm::Ecdsa).await?;
+
+        let ca_fingerprint = Fingerprint::of(ca.root_cert());
+        let certs_file = path.join(CERTS_FILE);
+        let mut certs = IndexMap::new();
+        if certs_file.exists() {
+            match serde_yml::from_str::<CertsFile>(&std::fs::read_to_string(&certs_file)?) {
+                Ok(file) if file.ca == ca_fingerprint => {
+                    for entry in file.certs {
+                        if let Some(cert) = hex::decode(&entry.cert) {
+                            certs.insert(entry.key, CertificateDer::from(cert));
+                        }
+                    }
+                }
+                Ok(_) => tracing::info!("CA changed. Discarding cached certificates."),
+                Err(error) => tracing::warn!(?error, "Failed to read cached certificates"),
+            }
+        }
+
+        let certs = Arc::new(Mutex::new(certs));
+        let (changed_tx, changed_rx) = mpsc::channel(1);
+        tokio::spawn(save_certs(
+            certs.clone(),
+            certs_file,
+            ca_fingerprint,
+            changed_rx,
+        ));
+
+        let cache = Self {
+            ecdsa_key,
+            rsa_key: OnceCell::new(),
+            capacity,
+            persist: Some(Persist {
+                path: path.to_owned(),
+                changed: changed_tx,
+            }),
+            certs,
+        };
+        cache.evict(&mut cache.certs.lock());
+
+        Ok(cache)
+    }
+
+    /// The key of all certificates in this cache with the given algorithm.
+    ///
+    /// The RSA key is only generated when it's first needed, since this is
+    /// slow.
+    pub async fn server_key(&self, algorithm: KeyAlgorithm) -> Result<Arc<KeyPair>, Error> {
+        match algorithm {
+            KeyAlgorithm::Ecdsa => Ok(self.ecdsa_key.clone()),
+            KeyAlgorithm::Rsa => {
+                let path = self.persist.as_ref().map(|persist| persist.path.as_path());
+                self.rsa_key
+                    .get_or_try_init(|| load_or_generate_key(path, algorithm))
+                    .await
+                    .cloned()
+            }
+        }
+    }
+
+    /// Returns the cached certificate and marks it as recently used.
+    pub fn get(&self, key: &CacheKey) -> Option<CertificateDer<'static>> {
+        let mut certs = self.certs.lock();
+        let index = certs.get_index_of(key)?;
+        let last = certs.len() - 1;
+        certs.move_index(index, last);
+        Some(certs[last].clone())
+    }
+
+    /// Adds a certificate, evicting the least recently used certificate if the
+    /// cache is full.
+    pub fn insert(&self, key: CacheKey, cert: CertificateDer<'static>) {
+        let mut certs = self.certs.lock();
+        certs.shift_remove(&key);
+        certs.insert(key, cert);
+        self.evict(&mut certs);
+        drop(certs);
+        self.save();
+    }
+
+    /// Returns the keys of all cached certificates, from least to most recently
+    /// used.
+    pub fn keys(&self) -> Vec<CacheKey> {
+        self.certs.lock().keys().cloned().collect()
+    }
+
+    /// Removes all certificates for `host`, or all certificates if `host` is
+    /// `None`. Returns the number of removed certificates.
+    pub fn purge(&self, host: Option<&str>) -> usize {
+        let mut certs = self.certs.lock();
+        let before = certs.len();
+        certs.retain(|key, _| host.is_some_and(|host| key.host != host));
+        let purged = before - certs.len();
+        drop(certs);
+        if purged > 0 {
+            self.save();
+        }
+        purged
+    }
+
+    fn evict(&self, certs: &mut Certs) {
+        let excess = certs.len().saturating_sub(self.capacity);
+        if excess > 0 {
+            certs.drain(..excess);
+        }
+    }
+
+    /// Schedules saving the certificates, if the cache is persisted.
+    fn save(&self) {
+        if let Some(persist) = &self.persist {
+            // if the channel is full, a save is already pending.
+            let _ = persist.changed.try_send(());
+        }
+    }
+}
+
+/// Saves the certificates to `certs_file` after they changed, until the cache
+/// is dropped.
+async fn save_certs(
+    certs: Arc<Mutex<Certs>>,
+    certs_file: PathBuf,
+    ca_fingerprint: Fingerprint,
+    mut changed: mpsc::Receiver<()>,
+) {
+    while changed.recv().await.is_some() {
+        tokio::time::sleep(SAVE_DELAY).await;
+
+        let certs = certs
+            .lock()
+            .iter()
+            .map(|(key, cert)| (key.clone(), cert.clone()))
+            .collect::<Vec<_>>();
+        let certs_file = certs_file.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let file = CertsFile {
+                ca: ca_fingerprint,
+                certs: certs
+                    .into_iter()
+                    .map(|(key, cert)| {
+                        CertsFileEntry {
+                            key,
+                            cert: hex::encode(cert.as_ref()),
+                        }
+                    })
+                    .collect(),
+            };
+
+            let yaml = match serde_yml::to_string(&file) {
+                Ok(yaml) => yaml,
+                Err(error) => {
+                    tracing::error!(?error, "Failed to serialize cached certificates");
+                    return;
+                }
+            };
+            if let Err(error) = write_atomically(&certs_file, yaml.as_bytes()) {
+                tracing::error!(?error, "Failed to save cached certificates");
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
+
+/// Writes `data` to a temporary file next to `path`, and then renames it to
+/// `path`. This way a crash never leaves a partially written file behind.
+pub(super) fn write_atomically(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = NamedTempFile::new_in(path.parent().unwrap_or(Path::new(".")))?;
+    file.write_all(data)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+/// Writes a private key to `path`, readable only by the owner.
+fn write_key(path: &Path, pem: &str) -> Result<(), std::io::Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(pem.as_bytes())
+}
+
+/// Loads the key with `algorithm` from the directory `path`, or generates a
+/// new one and saves it there.
+async fn load_or_generate_key(
+    path: Option<&Path>,
+    algorithm: KeyAlgorithm,
+) -> Result<Arc<KeyPair>, Error> {
+    let key_file = path.map(|path| {
+        path.join(match algorithm {
+            KeyAlgorithm::Ecdsa => KEY_FILE,
+            KeyAlgorithm::Rsa => RSA_KEY_FILE,
+        })
+    });
+
+    if let Some(key_file) = &key_file {
+        if key_file.exists() {
+            return Ok(Arc::new(KeyPair::from_pem(&std::fs::read_to_string(
+                key_file,
+            )?)?));
+        }
+    }
+
+    let key = tokio::task::spawn_blocking(move || {
+        Ok::<_, Error>(Arc::new(KeyPair::generate_for(
+            algorithm.signature_algorithm(),
+        )?))
+    })
+    .await
+    .unwrap()?;
+
+    if let Some(key_file) = &key_file {
+        write_key(key_file, &key.serialize_pem())?;
+    }
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(host: &str, fingerprint: u8) -> CacheKey {
+        CacheKey {
+            host: host.to_owned(),
+            upstream_fingerprint: Fingerprint([fingerprint; 32]),
+            key_algorithm: KeyAlgorithm::Ecdsa,
+        }
+    }
+
+    fn cert(byte: u8) -> CertificateDer<'static> {
+        CertificateDer::from(vec![byte])
+    }
+
+    fn cache(capacity: usize) -> CertCache {
+        CertCache {
+            ecdsa_key: Arc::new(KeyPair::generate().unwrap()),
+            rsa_key: OnceCell::new(),
+            capacity,
+            persist: None,
+            certs: Default::default(),
+        }
+    }
+
+    #[test]
+    fn it_evicts_the_least_recently_used_certificate() {
+        let cache = cache(2);
+        cache.insert(key("a", 1), cert(1));
+        cache.insert(key("b", 1), cert(2));
+        assert_eq!(cache.get(&key("a", 1)), Some(cert(1)));
+
+        cache.insert(key("c", 1), cert(3));
+        assert_eq!(cache.get(&key("b", 1)), None);
+        assert_eq!(cache.keys(), vec![key("a", 1), key("c", 1)]);
+    }
+
+    #[test]
+    fn it_keys_by_upstream_fingerprint() {
+        let cache = cache(10);
+        cache.insert(key("a", 1), cert(1));
+        assert_eq!(cache.get(&key("a", 2)), None);
+    }
+
+    #[test]
+    fn it_purges_by_host() {
+        let cache = cache(10);
+        cache.insert(key("a", 1), cert(1));
+        cache.insert(key("a", 2), cert(2));
+        cache.insert(key("b", 1), cert(3));
+
+        assert_eq!(cache.purge(Some("a")), 2);
+        assert_eq!(cache.keys(), vec![key("b", 1)]);
+        assert_eq!(cache.purge(None), 1);
+        assert!(cache.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_persists_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Ca::generate().await.unwrap();
+
+        let cache = CertCache::open(dir.path(), 10, &ca).await.unwrap();
+        cache.insert(key("a", 1), cert(1));
+        cache.insert(key("b", 1), cert(2));
+
+        // wait for the save task.
+        let certs_file = dir.path().join(CERTS_FILE);
+        for _ in 0..50 {
+            if certs_file.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        drop(cache);
+
+        let cache = CertCache::open(dir.path(), 10, &ca).await.unwrap();
+        assert_eq!(cache.keys(), vec![key("a", 1), key("b", 1)]);
+        assert_eq!(cache.get(&key("b", 1)), Some(cert(2)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_saves_keys_readable_only_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let ca = Ca::generate().await.unwrap();
+        CertCache::open(dir.path(), 10, &ca).await.unwrap();
+
+        let metadata = std::fs::metadata(dir.path().join(KEY_FILE)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn fingerprints_round_trip() {
+        let fingerprint = Fingerprint::of(&cert(42));
+        assert_eq!(
+            fingerprint.to_string().parse::<Fingerprint>().unwrap(),
+            fingerprint
+        );
+        assert!("abc".parse::<Fingerprint>().is_err());
+    }
+}
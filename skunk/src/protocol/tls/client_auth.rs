@@ -0,0 +1,177 @@
+//! Client certificates.
+//!
+//! On the upstream leg we can present a [`ClientCert`] to servers that require
+//! mutual TLS. On the downstream leg we can ask clients for their certificate
+//! with [`RequestClientCert`], so we can see which identity they tried to use.
+
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use rustls::{
+    client::danger::HandshakeSignatureValid,
+    crypto::WebPkiSupportedAlgorithms,
+    pki_types::{
+        CertificateDer,
+        PrivateKeyDer,
+        UnixTime,
+    },
+    server::danger::{
+        ClientCertVerified,
+        ClientCertVerifier,
+    },
+    DigitallySignedStruct,
+    DistinguishedName,
+    SignatureScheme,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use super::{
+    pkcs12::Pkcs12,
+    Error,
+};
+
+/// Client certificate and key files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ClientCert {
+    /// PEM encoded certificate chain and private key.
+    Pem { cert: PathBuf, key: PathBuf },
+
+    /// PKCS#12 file containing both certificate chain and private key.
+    Pkcs12 {
+        pkcs12: PathBuf,
+        #[serde(default)]
+        password: String,
+    },
+}
+
+impl ClientCert {
+    /// Loads the certificate chain and private key.
+    pub fn load(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+        match self {
+            Self::Pem { cert, key } => {
+                let certs = rustls_pemfile::certs(&mut std::fs::read(cert)?.as_slice())
+                    .collect::<Result<Vec<_>, _>>()?;
+                if certs.is_empty() {
+                    return Err(Error::NoCertificate { path: cert.clone() });
+                }
+                let key = rustls_pemfile::private_key(&mut std::fs::read(key)?.as_slice())?
+                    .ok_or_else(|| Error::NoPrivateKey { path: key.clone() })?;
+                Ok((certs, key))
+            }
+            Self::Pkcs12 { pkcs12, password } => {
+                let Pkcs12 { certs, key } = Pkcs12::parse(&std::fs::read(pkcs12)?, password)?;
+                Ok((certs, key))
+            }
+        }
+    }
+
+    /// Resolves relative paths against `base`.
+    pub fn relative_to(&mut self, base: impl AsRef<Path>) {
+        let base = base.as_ref();
+        match self {
+            Self::Pem { cert, key } => {
+                *cert = base.join(&*cert);
+                *key = base.join(&*key);
+            }
+            Self::Pkcs12 { pkcs12, .. } => *pkcs12 = base.join(&*pkcs12),
+        }
+    }
+}
+
+/// Client certificate verifier that asks clients for a certificate, but
+/// accepts any certificate, or none.
+///
+/// The certificate the client presented is then available from the
+/// connection. Note that some clients (e.g. browsers) will ask the user to
+/// pick a certificate.
+#[derive(Debug)]
+pub struct RequestClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl RequestClientCert {
+    pub fn new() -> Self {
+        Self {
+            algorithms: rustls::crypto::aws_lc_rs::default_provider()
+                .signature_verification_algorithms,
+        }
+    }
+}
+
+impl Default for RequestClientCert {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientCertVerifier for RequestClientCert {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_both_formats() {
+        let certs: Vec<ClientCert> = serde_yml::from_str(
+            r#"
+            - cert: client.pem
+              key: client.key
+            - pkcs12: client.p12
+              password: hunter2
+            "#,
+        )
+        .unwrap();
+
+        assert!(matches!(&certs[0], ClientCert::Pem { .. }));
+        assert!(matches!(&certs[1], ClientCert::Pkcs12 { password, .. } if password == "hunter2"));
+    }
+}
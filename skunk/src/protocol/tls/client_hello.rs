@@ -0,0 +1,469 @@
+//! Parsing of the TLS `ClientHello` message and client fingerprinting.
+//!
+//! rustls only gives us a few fields of the `ClientHello`, and not in the
+//! order the client sent them. For fingerprinting we need the raw message, so
+//! we parse it ourselves.
+//!
+//! - [RFC 8446, section 4.1.2](https://datatracker.ietf.org/doc/html/rfc8446#section-4.1.2)
+//! - [JA3](https://github.com/salesforce/ja3)
+//! - [JA4](https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md)
+
+use md5::{
+    Digest,
+    Md5,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use sha2::Sha256;
+
+use crate::util::hex;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// Error while parsing a `ClientHello`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("not a TLS handshake record: content type {0}")]
+    NotHandshake(u8),
+
+    #[error("not a client hello: handshake type {0}")]
+    NotClientHello(u8),
+
+    #[error("client hello is truncated")]
+    Truncated,
+}
+
+/// The fields of a `ClientHello` that are interesting for identifying the
+/// client.
+///
+/// All lists are in the order the client sent them, and include GREASE values.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// The `legacy_version` field. The actual versions are in
+    /// [`supported_versions`][Self::supported_versions].
+    pub version: u16,
+
+    pub cipher_suites: Vec<u16>,
+
+    /// Extension types.
+    pub extensions: Vec<u16>,
+
+    pub server_name: Option<String>,
+
+    pub alpn_protocols: Vec<Vec<u8>>,
+
+    pub supported_versions: Vec<u16>,
+
+    pub supported_groups: Vec<u16>,
+
+    pub ec_point_formats: Vec<u8>,
+
+    pub signature_algorithms: Vec<u16>,
+}
+
+impl ClientHello {
+    /// Parses the `ClientHello` from the first bytes a client sent.
+    ///
+    /// `data` must start with a TLS record. The handshake message may be
+    /// fragmented over multiple records. Any data after the `ClientHello` is
+    /// ignored.
+    pub fn parse(mut data: &[u8]) -> Result<Self, Error> {
+        // reassemble the handshake message from the records.
+        let mut message = vec![];
+        loop {
+            let mut record = Cursor(data);
+            let content_type = record.u8()?;
+            if content_type != CONTENT_TYPE_HANDSHAKE {
+                return Err(Error::NotHandshake(content_type));
+            }
+            let _legacy_record_version = record.u16()?;
+            let fragment = record.vec16()?;
+            message.extend_from_slice(fragment);
+            data = record.0;
+
+            if message.len() >= 4 {
+                let length = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+                if message.len() >= 4 + length {
+                    message.truncate(4 + length);
+                    break;
+                }
+            }
+        }
+
+        let mut message = Cursor(&message);
+        let handshake_type = message.u8()?;
+        if handshake_type != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return Err(Error::NotClientHello(handshake_type));
+        }
+        let length = message.u24()?;
+        let mut body = Cursor(message.take(length as usize)?);
+
+        let mut client_hello = ClientHello {
+            version: body.u16()?,
+            ..Default::default()
+        };
+        let _random = body.take(32)?;
+        let _legacy_session_id = body.vec8()?;
+        client_hello.cipher_suites = Cursor(body.vec16()?).u16s()?;
+        let _legacy_compression_methods = body.vec8()?;
+
+        // extensions are optional in TLS 1.2
+        if body.0.is_empty() {
+            return Ok(client_hello);
+        }
+
+        let mut extensions = Cursor(body.vec16()?);
+        while !extensions.0.is_empty() {
+            let extension_type = extensions.u16()?;
+            let mut extension = Cursor(extensions.vec16()?);
+            client_hello.extensions.push(extension_type);
+
+            match extension_type {
+                EXTENSION_SERVER_NAME => {
+                    let mut names = Cursor(extension.vec16()?);
+                    while !names.0.is_empty() {
+                        let name_type = names.u8()?;
+                        let name = names.vec16()?;
+                        // 0 is `host_name`, which is the only type defined.
+                        if name_type == 0 {
+                            client_hello.server_name =
+                                Some(String::from_utf8_lossy(name).into_owned());
+                        }
+                    }
+                }
+                EXTENSION_SUPPORTED_GROUPS => {
+                    client_hello.supported_groups = Cursor(extension.vec16()?).u16s()?;
+                }
+                EXTENSION_EC_POINT_FORMATS => {
+                    client_hello.ec_point_formats = extension.vec8()?.to_owned();
+                }
+                EXTENSION_SIGNATURE_ALGORITHMS => {
+                    client_hello.signature_algorithms = Cursor(extension.vec16()?).u16s()?;
+                }
+                EXTENSION_ALPN => {
+                    let mut protocols = Cursor(extension.vec16()?);
+                    while !protocols.0.is_empty() {
+                        client_hello
+                            .alpn_protocols
+                            .push(protocols.vec8()?.to_owned());
+                    }
+                }
+                EXTENSION_SUPPORTED_VERSIONS => {
+                    client_hello.supported_versions = Cursor(extension.vec8()?).u16s()?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(client_hello)
+    }
+
+    /// Returns the JA3 string, e.g.
+    /// `771,4865-4866-4867,0-23-65281-10-11-35-16-5-13,29-23-24,0`.
+    pub fn ja3_string(&self) -> String {
+        fn join<T: Copy + Into<u16>>(values: &[T]) -> String {
+            values
+                .iter()
+                .map(|value| (*value).into())
+                .filter(|value| !is_grease(*value))
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        }
+
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(&self.cipher_suites),
+            join(&self.extensions),
+            join(&self.supported_groups),
+            join(&self.ec_point_formats),
+        )
+    }
+
+    /// Returns the JA3 fingerprint, which is the MD5 hash of the
+    /// [JA3 string][Self::ja3_string].
+    pub fn ja3(&self) -> String {
+        hex::encode(&Md5::digest(self.ja3_string()))
+    }
+
+    /// Returns the JA4 fingerprint, e.g.
+    /// `t13d1516h2_8daaf6152771_e5627efa2ab1`.
+    ///
+    /// This assumes the client hello was sent over TCP.
+    pub fn ja4(&self) -> String {
+        let version = self
+            .supported_versions
+            .iter()
+            .copied()
+            .filter(|version| !is_grease(*version))
+            .max()
+            .unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            0x0002 => "s2",
+            0xfeff => "d1",
+            0xfefd => "d2",
+            0xfefc => "d3",
+            _ => "00",
+        };
+
+        let sni = if self.extensions.contains(&EXTENSION_SERVER_NAME) {
+            'd'
+        }
+        else {
+            'i'
+        };
+
+        let mut cipher_suites = without_grease(&self.cipher_suites);
+        let mut extensions = without_grease(&self.extensions);
+
+        let alpn = match self.alpn_protocols.first() {
+            Some(protocol) if !protocol.is_empty() => {
+                let first = protocol[0];
+                let last = protocol[protocol.len() - 1];
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                }
+                else {
+                    let hex = hex::encode(&[first, last]);
+                    format!("{}{}", &hex[..1], &hex[3..])
+                }
+            }
+            _ => "00".to_owned(),
+        };
+
+        let mut ja4 = format!(
+            "t{version}{sni}{:02}{:02}{alpn}_",
+            cipher_suites.len().min(99),
+            extensions.len().min(99),
+        );
+
+        cipher_suites.sort_unstable();
+        if cipher_suites.is_empty() {
+            ja4.push_str("000000000000");
+        }
+        else {
+            ja4.push_str(&truncated_sha256(&join_hex(&cipher_suites)));
+        }
+        ja4.push('_');
+
+        // the server name and ALPN are already part of the first section.
+        extensions.retain(|extension| ![EXTENSION_SERVER_NAME, EXTENSION_ALPN].contains(extension));
+        extensions.sort_unstable();
+        if extensions.is_empty() {
+            ja4.push_str("000000000000");
+        }
+        else {
+            let mut input = join_hex(&extensions);
+            let signature_algorithms = without_grease(&self.signature_algorithms);
+            if !signature_algorithms.is_empty() {
+                input.push('_');
+                input.push_str(&join_hex(&signature_algorithms));
+            }
+            ja4.push_str(&truncated_sha256(&input));
+        }
+
+        ja4
+    }
+}
+
+/// Checks if `value` is a [GREASE](https://datatracker.ietf.org/doc/html/rfc8701) value.
+/// These are sent by clients to make sure servers tolerate unknown values, and
+/// are picked randomly, so they're ignored for fingerprints.
+fn is_grease(value: u16) -> bool {
+    let [high, low] = value.to_be_bytes();
+    high == low && low & 0x0f == 0x0a
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values
+        .iter()
+        .copied()
+        .filter(|value| !is_grease(*value))
+        .collect()
+}
+
+fn join_hex(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{value:04x}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn truncated_sha256(input: &str) -> String {
+    let mut hash = hex::encode(&Sha256::digest(input));
+    hash.truncate(12);
+    hash
+}
+
+/// Reads big-endian integers and length-prefixed vectors.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(Error::Truncated);
+        }
+        let (data, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let data = self.take(2)?;
+        Ok(u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn u24(&mut self) -> Result<u32, Error> {
+        let data = self.take(3)?;
+        Ok(u32::from_be_bytes([0, data[0], data[1], data[2]]))
+    }
+
+    fn vec8(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u8()?;
+        self.take(length.into())
+    }
+
+    fn vec16(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u16()?;
+        self.take(length.into())
+    }
+
+    /// Reads the remaining data as a list of `u16`s.
+    fn u16s(&mut self) -> Result<Vec<u16>, Error> {
+        let mut values = Vec::with_capacity(self.0.len() / 2);
+        while !self.0.is_empty() {
+            values.push(self.u16()?);
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `ClientHello` split over two records, with a GREASE cipher suite and
+    /// extension.
+    const CLIENT_HELLO: &[u8] = &[
+        // record: handshake, TLS 1.0, length 51
+        0x16, 0x03, 0x01, 0x00, 0x33, //
+        // handshake: client hello, length 126
+        0x01, 0x00, 0x00, 0x7e, //
+        // legacy version: TLS 1.2
+        0x03, 0x03, //
+        // random
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f, //
+        // session id: empty
+        0x00, //
+        // cipher suites: GREASE, TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384,
+        // TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+        0x00, 0x08, 0x1a, 0x1a, 0x13, 0x01, 0x13, 0x02, 0xc0, 0x2f, //
+        // compression methods: null
+        0x01, 0x00, //
+        // record: handshake, TLS 1.2, length 79
+        0x16, 0x03, 0x03, 0x00, 0x4f, //
+        // extensions, length 77
+        0x00, 0x4d, //
+        // GREASE, empty
+        0x2a, 0x2a, 0x00, 0x00, //
+        // server name: example.com
+        0x00, 0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, b'e', b'x', b'a', b'm', b'p', b'l',
+        b'e', b'.', b'c', b'o', b'm', //
+        // supported groups: x25519, secp256r1
+        0x00, 0x0a, 0x00, 0x06, 0x00, 0x04, 0x00, 0x1d, 0x00, 0x17, //
+        // ec point formats: uncompressed
+        0x00, 0x0b, 0x00, 0x02, 0x01, 0x00, //
+        // signature algorithms: ecdsa_secp256r1_sha256, rsa_pss_rsae_sha256
+        0x00, 0x0d, 0x00, 0x06, 0x00, 0x04, 0x04, 0x03, 0x08, 0x04, //
+        // alpn: h2, http/1.1
+        0x00, 0x10, 0x00, 0x0e, 0x00, 0x0c, 0x02, b'h', b'2', 0x08, b'h', b't', b't', b'p', b'/',
+        b'1', b'.', b'1', //
+        // supported versions: TLS 1.3, TLS 1.2
+        0x00, 0x2b, 0x00, 0x05, 0x04, 0x03, 0x04, 0x03, 0x03,
+    ];
+
+    const JA3: &str = "c9e264cb3675678ee364e81f3b6da7ad";
+    const JA4: &str = "t13d0306h2_40b44b994229_fb71836bce29";
+
+    #[test]
+    fn it_parses_a_fragmented_client_hello() {
+        let client_hello = ClientHello::parse(CLIENT_HELLO).unwrap();
+        assert_eq!(
+            client_hello,
+            ClientHello {
+                version: 0x0303,
+                cipher_suites: vec![0x1a1a, 0x1301, 0x1302, 0xc02f],
+                extensions: vec![0x2a2a, 0, 10, 11, 13, 16, 43],
+                server_name: Some("example.com".to_owned()),
+                alpn_protocols: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                supported_versions: vec![0x0304, 0x0303],
+                supported_groups: vec![0x001d, 0x0017],
+                ec_point_formats: vec![0],
+                signature_algorithms: vec![0x0403, 0x0804],
+            }
+        );
+    }
+
+    #[test]
+    fn it_rejects_truncated_and_other_messages() {
+        assert!(matches!(
+            ClientHello::parse(&CLIENT_HELLO[..60]),
+            Err(Error::Truncated)
+        ));
+        assert!(matches!(
+            ClientHello::parse(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]),
+            Err(Error::NotHandshake(0x15))
+        ));
+        assert!(matches!(
+            ClientHello::parse(&[0x16, 0x03, 0x03, 0x00, 0x04, 0x02, 0x00, 0x00, 0x00]),
+            Err(Error::NotClientHello(2))
+        ));
+    }
+
+    #[test]
+    fn it_computes_ja3() {
+        let client_hello = ClientHello::parse(CLIENT_HELLO).unwrap();
+        assert_eq!(
+            client_hello.ja3_string(),
+            "771,4865-4866-49199,0-10-11-13-16-43,29-23,0"
+        );
+        assert_eq!(client_hello.ja3(), JA3);
+    }
+
+    #[test]
+    fn it_computes_ja4() {
+        let client_hello = ClientHello::parse(CLIENT_HELLO).unwrap();
+        assert_eq!(client_hello.ja4(), JA4);
+    }
+
+    #[test]
+    fn it_detects_grease_values() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+}
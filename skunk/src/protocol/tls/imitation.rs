@@ -0,0 +1,503 @@
+//! Policies for imitating the certificates presented by servers.
+//!
+//! When decrypting a connection we present a certificate to the client that
+//! imitates the certificate of the server, but is signed by our CA. What is
+//! copied from the server's certificate is controlled by an
+//! [`ImitationPolicy`], which can be chosen per host with
+//! [`ImitationPolicies`].
+//!
+//! Note that forged certificates are cached, so changing the policy only
+//! affects certificates that are forged afterwards.
+
+use std::{
+    fmt::Display,
+    net::IpAddr,
+    str::FromStr,
+};
+
+use rcgen::{
+    CertificateParams,
+    CustomExtension,
+    DistinguishedName,
+    DnType,
+    SanType,
+};
+use rustls::pki_types::CertificateDer;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use time::{
+    Duration,
+    OffsetDateTime,
+};
+use x509_parser::public_key::PublicKey;
+
+use super::{
+    name_constraints,
+    Ca,
+    Error,
+};
+use crate::rule::regex::Regex;
+
+/// The algorithm of the key of a forged certificate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    /// ECDSA with curve P-256.
+    #[default]
+    Ecdsa,
+
+    /// 2048 bit RSA.
+    Rsa,
+}
+
+impl KeyAlgorithm {
+    /// Returns the algorithm that is closest to the key of `cert`.
+    pub fn of(cert: &CertificateDer<'_>) -> Result<Self, Error> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert)
+            .map_err(|_| Error::Rcgen(rcgen::Error::CouldNotParseCertificate))?;
+        match cert.public_key().parsed() {
+            Ok(PublicKey::RSA(_)) => Ok(Self::Rsa),
+            _ => Ok(Self::Ecdsa),
+        }
+    }
+
+    pub(super) fn signature_algorithm(&self) -> &'static rcgen::SignatureAlgorithm {
+        match self {
+            Self::Ecdsa => &rcgen::PKCS_ECDSA_P256_SHA256,
+            Self::Rsa => &rcgen::PKCS_RSA_SHA256,
+        }
+    }
+}
+
+/// Which key type forged certificates use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyType {
+    /// Use RSA if the server's certificate uses RSA, and ECDSA otherwise.
+    #[default]
+    Match,
+
+    /// Always use ECDSA.
+    Ecdsa,
+
+    /// Always use RSA.
+    Rsa,
+}
+
+/// Extensions of the server's certificate that can be dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Extension {
+    BasicConstraints,
+    KeyUsage,
+    ExtendedKeyUsage,
+    NameConstraints,
+}
+
+/// An extension that is added to forged certificates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddExtension {
+    /// Object identifier of the extension, e.g. `1.3.6.1.4.1.11129.2.4.2`.
+    pub oid: Oid,
+
+    /// DER encoded value of the extension, hex-encoded.
+    #[serde(with = "hex_string")]
+    pub value: Vec<u8>,
+
+    #[serde(default)]
+    pub critical: bool,
+}
+
+/// Object identifier, e.g. `2.5.29.37`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Oid(pub Vec<u64>);
+
+/// Failed to parse [`Oid`].
+#[derive(Debug, thiserror::Error)]
+#[error("invalid object identifier: {0}")]
+pub struct OidParseError(String);
+
+impl FromStr for Oid {
+    type Err = OidParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split('.')
+            .map(|arc| arc.parse().ok())
+            .collect::<Option<Vec<u64>>>()
+            .filter(|arcs| arcs.len() >= 2)
+            .map(Self)
+            .ok_or_else(|| OidParseError(s.to_owned()))
+    }
+}
+
+impl TryFrom<String> for Oid {
+    type Error = OidParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Oid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for arc in &self.0 {
+            if !first {
+                f.write_str(".")?;
+            }
+            write!(f, "{arc}")?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+impl From<Oid> for String {
+    fn from(value: Oid) -> Self {
+        value.to_string()
+    }
+}
+
+/// How the certificate presented by a server is imitated.
+///
+/// The defaults copy everything we can copy, but make sure that the forged
+/// certificate isn't valid longer than our CA.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImitationPolicy {
+    /// Copy the subject alternative names. Otherwise the certificate is only
+    /// valid for the host the client connected to.
+    pub copy_subject_alt_names: bool,
+
+    /// Copy the subject. Otherwise the subject only contains the host as
+    /// common name.
+    pub copy_subject: bool,
+
+    /// Copy the validity dates. Otherwise the certificate is valid from a day
+    /// ago for [`validity_days`](Self::validity_days).
+    pub copy_validity: bool,
+
+    /// How long the certificate is valid, if the validity isn't copied.
+    pub validity_days: u32,
+
+    /// Restrict the validity to the validity of our CA.
+    pub clamp_validity: bool,
+
+    /// The key type of the certificate.
+    pub key_type: KeyType,
+
+    /// Extensions that are not copied from the server's certificate.
+    pub drop_extensions: Vec<Extension>,
+
+    /// Extensions that are added to the certificate.
+    pub add_extensions: Vec<AddExtension>,
+}
+
+impl Default for ImitationPolicy {
+    fn default() -> Self {
+        Self {
+            copy_subject_alt_names: true,
+            copy_subject: true,
+            copy_validity: true,
+            validity_days: 365,
+            clamp_validity: true,
+            key_type: KeyType::default(),
+            drop_extensions: vec![],
+            add_extensions: vec![],
+        }
+    }
+}
+
+/// Parameters for a forged certificate.
+pub struct Imitation {
+    pub cert_params: CertificateParams,
+    pub key_algorithm: KeyAlgorithm,
+}
+
+impl ImitationPolicy {
+    /// Creates the parameters for a certificate for `host` that imitates the
+    /// server's certificate `upstream` and will be signed by `ca`.
+    ///
+    /// Subject alternative names that the CA's name constraints don't allow
+    /// are dropped. Fails with [`Error::NameConstraint`] if they don't allow
+    /// `host`.
+    pub fn imitate(
+        &self,
+        upstream: &CertificateDer<'_>,
+        host: &str,
+        ca: &Ca,
+    ) -> Result<Imitation, Error> {
+        // although the name suggest that this method parses *ca* certs, it seems to
+        // just extract some of the certificate information.
+        let upstream_params = CertificateParams::from_ca_cert_der(upstream)?;
+
+        // we start with the default parameters, so that we don't copy the serial
+        // number or subject key identifier, which wouldn't match our certificate.
+        let mut cert_params = CertificateParams::default();
+
+        if !self.drops(Extension::BasicConstraints) {
+            cert_params.is_ca = upstream_params.is_ca;
+        }
+        if !self.drops(Extension::KeyUsage) {
+            cert_params.key_usages = upstream_params.key_usages;
+        }
+        if !self.drops(Extension::ExtendedKeyUsage) {
+            cert_params.extended_key_usages = upstream_params.extended_key_usages;
+        }
+        if !self.drops(Extension::NameConstraints) {
+            cert_params.name_constraints = upstream_params.name_constraints;
+        }
+
+        cert_params.subject_alt_names = if self.copy_subject_alt_names {
+            upstream_params.subject_alt_names
+        }
+        else {
+            vec![host_san(host)?]
+        };
+        if let Some(constraints) = ca.name_constraints() {
+            if !name_constraints::permits(constraints, &host_san(host)?) {
+                return Err(Error::NameConstraint {
+                    name: host.to_owned(),
+                });
+            }
+            cert_params
+                .subject_alt_names
+                .retain(|name| name_constraints::permits(constraints, name));
+        }
+
+        cert_params.distinguished_name = if self.copy_subject {
+            upstream_params.distinguished_name
+        }
+        else {
+            let mut distinguished_name = DistinguishedName::new();
+            distinguished_name.push(DnType::CommonName, host);
+            distinguished_name
+        };
+
+        if self.copy_validity {
+            cert_params.not_before = upstream_params.not_before;
+            cert_params.not_after = upstream_params.not_after;
+        }
+        else {
+            let now = OffsetDateTime::now_utc();
+            cert_params.not_before = now - Duration::DAY;
+            cert_params.not_after = now + Duration::days(self.validity_days.into());
+        }
+        if self.clamp_validity {
+            let ca_params = ca.params();
+            cert_params.not_before = cert_params.not_before.max(ca_params.not_before);
+            cert_params.not_after = cert_params.not_after.min(ca_params.not_after);
+        }
+
+        cert_params.custom_extensions = self
+            .add_extensions
+            .iter()
+            .map(|extension| {
+                let mut custom =
+                    CustomExtension::from_oid_content(&extension.oid.0, extension.value.clone());
+                custom.set_criticality(extension.critical);
+                custom
+            })
+            .collect();
+
+        let key_algorithm = match self.key_type {
+            KeyType::Match => KeyAlgorithm::of(upstream)?,
+            KeyType::Ecdsa => KeyAlgorithm::Ecdsa,
+            KeyType::Rsa => KeyAlgorithm::Rsa,
+        };
+
+        Ok(Imitation {
+            cert_params,
+            key_algorithm,
+        })
+    }
+
+    fn drops(&self, extension: Extension) -> bool {
+        self.drop_extensions.contains(&extension)
+    }
+}
+
+fn host_san(host: &str) -> Result<SanType, Error> {
+    match IpAddr::from_str(host) {
+        Ok(ip_address) => Ok(SanType::IpAddress(ip_address)),
+        Err(_) => Ok(SanType::DnsName(host.to_owned().try_into()?)),
+    }
+}
+
+/// Imitation policies per host.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ImitationPolicies {
+    /// The policy for hosts that don't match any of [`hosts`](Self::hosts).
+    #[serde(flatten)]
+    pub default: ImitationPolicy,
+
+    /// Policies for specific hosts. The first matching policy is used.
+    #[serde(default)]
+    pub hosts: Vec<HostImitationPolicy>,
+}
+
+impl ImitationPolicies {
+    /// Returns the policy for `host`.
+    pub fn for_host(&self, host: &str) -> &ImitationPolicy {
+        self.hosts
+            .iter()
+            .find(|policy| policy.host.is_match(host))
+            .map_or(&self.default, |policy| &policy.policy)
+    }
+}
+
+/// Imitation policy for hosts matching a regex.
+///
+/// Options that aren't set use the defaults of [`ImitationPolicy`], not the
+/// options of [`ImitationPolicies::default`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostImitationPolicy {
+    pub host: Regex,
+
+    #[serde(flatten)]
+    pub policy: ImitationPolicy,
+}
+
+mod hex_string {
+    use serde::{
+        Deserialize,
+        Deserializer,
+        Serializer,
+    };
+
+    use crate::util::hex;
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        hex::decode(&s).ok_or_else(|| serde::de::Error::custom(format!("invalid hex: {s}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{
+        ExtendedKeyUsagePurpose,
+        KeyPair,
+    };
+
+    use super::*;
+
+    async fn upstream() -> (Ca, CertificateDer<'static>) {
+        let ca = Ca::generate().await.unwrap();
+        let mut params = CertificateParams::new(vec!["example.com".to_owned()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "example.com");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.not_before = rcgen::date_time_ymd(2020, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2030, 1, 1);
+        let key = KeyPair::generate_for(&rcgen::PKCS_RSA_SHA256).unwrap();
+        let cert = params.self_signed(&key).unwrap().der().to_owned();
+        (ca, cert)
+    }
+
+    #[tokio::test]
+    async fn it_copies_the_upstream_certificate() {
+        let (ca, upstream) = upstream().await;
+        let imitation = ImitationPolicy::default()
+            .imitate(&upstream, "example.com", &ca)
+            .unwrap();
+
+        assert_eq!(imitation.key_algorithm, KeyAlgorithm::Rsa);
+        let params = imitation.cert_params;
+        assert_eq!(
+            params.subject_alt_names,
+            vec![SanType::DnsName("example.com".try_into().unwrap())]
+        );
+        assert_eq!(
+            params.extended_key_usages,
+            vec![ExtendedKeyUsagePurpose::ServerAuth]
+        );
+        assert_eq!(params.not_before, rcgen::date_time_ymd(2020, 1, 1));
+        assert_eq!(params.not_after, rcgen::date_time_ymd(2030, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn it_applies_the_policy() {
+        let (ca, upstream) = upstream().await;
+        let policy = ImitationPolicy {
+            copy_subject_alt_names: false,
+            copy_validity: false,
+            key_type: KeyType::Ecdsa,
+            drop_extensions: vec![Extension::ExtendedKeyUsage],
+            add_extensions: vec![AddExtension {
+                oid: "1.2.3.4".parse().unwrap(),
+                value: vec![0x05, 0x00],
+                critical: false,
+            }],
+            ..Default::default()
+        };
+        let imitation = policy.imitate(&upstream, "10.0.0.1", &ca).unwrap();
+
+        assert_eq!(imitation.key_algorithm, KeyAlgorithm::Ecdsa);
+        let params = imitation.cert_params;
+        assert_eq!(
+            params.subject_alt_names,
+            vec![SanType::IpAddress("10.0.0.1".parse().unwrap())]
+        );
+        assert!(params.extended_key_usages.is_empty());
+        assert_eq!(params.custom_extensions.len(), 1);
+        assert!(params.not_after - params.not_before > Duration::days(365));
+        assert!(params.not_after - params.not_before < Duration::days(367));
+    }
+
+    #[test]
+    fn it_picks_the_policy_by_host() {
+        let policies: ImitationPolicies = serde_yml::from_str(
+            r#"
+            key_type: ecdsa
+            hosts:
+              - host: "^rsa\\."
+                key_type: rsa
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(policies.for_host("example.com").key_type, KeyType::Ecdsa);
+        assert_eq!(policies.for_host("rsa.example.com").key_type, KeyType::Rsa);
+        assert!(policies.for_host("rsa.example.com").copy_subject);
+    }
+
+    #[tokio::test]
+    async fn it_drops_names_outside_the_name_constraints() {
+        let (_, upstream) = upstream().await;
+        let mut params =
+            CertificateParams::new(vec!["example.com".to_owned(), "example.org".to_owned()])
+                .unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "example.com");
+        let key = KeyPair::generate().unwrap();
+        let upstream_with_names = params.self_signed(&key).unwrap().der().to_owned();
+        let ca = Ca::generate_with_name_constraints(
+            name_constraints::from_subtrees(&["example.com".to_owned()], &[]).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let imitation = ImitationPolicy::default()
+            .imitate(&upstream_with_names, "example.com", &ca)
+            .unwrap();
+        assert_eq!(
+            imitation.cert_params.subject_alt_names,
+            vec![SanType::DnsName("example.com".try_into().unwrap())]
+        );
+
+        assert!(matches!(
+            ImitationPolicy::default().imitate(&upstream, "example.org", &ca),
+            Err(Error::NameConstraint { name }) if name == "example.org"
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+//! Logging of TLS secrets.
+//!
+//! The secrets are written in the [NSS key log format][1], which is understood
+//! by e.g. Wireshark. This way traffic that was captured elsewhere (e.g. with
+//! tcpdump) can be decrypted.
+//!
+//! [1]: https://nss-crypto.org/reference/security/nss/legacy/key_log_format/index.html
+
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::Write as _,
+    path::Path,
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use super::Error;
+use crate::util::hex;
+
+/// File that secrets of all connections are appended to.
+#[derive(Debug)]
+pub struct KeyLogFile {
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    /// Opens the file for appending, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write(&self, line: &str) {
+        if let Err(error) = self.file.lock().write_all(line.as_bytes()) {
+            tracing::error!(?error, "Failed to write to key log file");
+        }
+    }
+}
+
+/// Key log for a single intercepted connection.
+///
+/// When decrypting a connection this is shared by both the connection to the
+/// client and the connection to the server, so it contains the secrets for
+/// both.
+#[derive(Debug, Default)]
+pub struct KeyLog {
+    file: Option<Arc<KeyLogFile>>,
+    lines: Mutex<String>,
+}
+
+impl KeyLog {
+    /// Creates a key log that also appends all secrets to `file`.
+    pub fn new(file: Option<Arc<KeyLogFile>>) -> Self {
+        Self {
+            file,
+            lines: Mutex::new(String::new()),
+        }
+    }
+
+    /// Returns the lines logged so far.
+    pub fn lines(&self) -> String {
+        self.lines.lock().clone()
+    }
+}
+
+impl rustls::KeyLog for KeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!(
+            "{label} {} {}\n",
+            hex::encode(client_random),
+            hex::encode(secret)
+        );
+
+        if let Some(file) = &self.file {
+            file.write(&line);
+        }
+        self.lines.lock().push_str(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::KeyLog as _;
+
+    use super::*;
+
+    #[test]
+    fn it_logs_in_nss_format() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let file = Arc::new(KeyLogFile::open(&path).unwrap());
+
+        let key_log = KeyLog::new(Some(file.clone()));
+        key_log.log("CLIENT_RANDOM", &[0x01, 0xab], &[0xff, 0x00, 0x10]);
+        let other = KeyLog::new(Some(file));
+        other.log("EXPORTER_SECRET", &[0x02], &[0x03]);
+
+        assert_eq!(key_log.lines(), "CLIENT_RANDOM 01ab ff0010\n");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "CLIENT_RANDOM 01ab ff0010\nEXPORTER_SECRET 02 03\n"
+        );
+    }
+}
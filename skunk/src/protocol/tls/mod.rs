@@ -0,0 +1,1316 @@
+//! TLS connections.
+//!
+//! This is mainly used to decrypt TLS (e.g. HTTPS) traffic. Note, that in order
+//! for a client to accept the modified certificates, the skunk root certificate
+//! needs to be installed.
+
+pub mod cert_cache;
+pub mod client_auth;
+pub mod client_hello;
+pub mod imitation;
+pub mod key_log;
+pub mod name_constraints;
+pub mod pinned;
+pub mod pkcs12;
+pub mod upstream;
+
+use std::{
+    fmt::{
+        Debug,
+        Display,
+    },
+    net::IpAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::Poll,
+};
+
+use pin_project_lite::pin_project;
+use rcgen::{
+    BasicConstraints,
+    Certificate,
+    CertificateParams,
+    DistinguishedName,
+    DnType,
+    IsCa,
+    KeyPair,
+    KeyUsagePurpose,
+    NameConstraints,
+    SanType,
+};
+use rustls::{
+    client::VerifierBuilderError,
+    pki_types::{
+        CertificateDer,
+        PrivateKeyDer,
+        ServerName,
+    },
+    server::Acceptor,
+    ClientConfig,
+    RootCertStore,
+    ServerConfig,
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+    AsyncWriteExt,
+    ReadBuf,
+};
+use tokio_rustls::{
+    LazyConfigAcceptor,
+    StartHandshake,
+    TlsConnector,
+};
+
+use self::{
+    cert_cache::{
+        CacheKey,
+        CertCache,
+        Fingerprint,
+        DEFAULT_CAPACITY,
+    },
+    client_auth::RequestClientCert,
+    client_hello::ClientHello,
+    imitation::{
+        Imitation,
+        ImitationPolicies,
+    },
+    key_log::{
+        KeyLog,
+        KeyLogFile,
+    },
+    pinned::PinnedHosts,
+    upstream::{
+        UpstreamPolicies,
+        Verification,
+        Verifier,
+        Verifiers,
+    },
+};
+use crate::{
+    address::{
+        HostAddress,
+        TcpAddress,
+    },
+    util::Lazy,
+};
+
+/// TLS error type
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+
+    #[error("rcgen error")]
+    Rcgen(#[from] rcgen::Error),
+
+    #[error("rustls error")]
+    Rustls(#[from] rustls::Error),
+
+    #[error("missing certificate: {path}")]
+    NoCertificate { path: PathBuf },
+
+    #[error("missing private key: {path}")]
+    NoPrivateKey { path: PathBuf },
+
+    #[error("failed to read PKCS#12 file")]
+    Pkcs12(#[from] pkcs12::Error),
+
+    #[error("invalid server name: {hostname}")]
+    InvalidServerName { hostname: String },
+
+    #[error("the target server didn't send a server certificate chain")]
+    NoTargetCertificate,
+
+    #[error("error while loading native certificates")]
+    NativeCertsError(#[from] NativeCertsError),
+
+    #[error("failed to build certificate verifier")]
+    VerifierBuilder(#[from] VerifierBuilderError),
+
+    #[error("upstream verification mode `ca-bundle` requires a `ca_bundle`")]
+    MissingCaBundle,
+
+    #[error("name constraints of the CA don't allow {name}")]
+    NameConstraint { name: String },
+
+    #[error("client rejected our certificate for {host}")]
+    CertificateRejected {
+        host: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// A certificate authority
+#[derive(Clone)]
+pub struct Ca {
+    key_pair: Arc<KeyPair>,
+    cert: Arc<CertificateDer<'static>>,
+    cert_for_signing: Arc<Certificate>,
+}
+
+impl Ca {
+    /// Create a CA by reading key and certificate from a file.
+    ///
+    /// Both files can be PEM or DER encoded.
+    pub fn open(key_file: impl AsRef<Path>, cert_file: impl AsRef<Path>) -> Result<Self, Error> {
+        let key_file = key_file.as_ref();
+        let key = std::fs::read(key_file)?;
+        let key = match rustls_pemfile::private_key(&mut key.as_slice())? {
+            Some(key) => key,
+            None => {
+                PrivateKeyDer::try_from(key).map_err(|_| {
+                    Error::NoPrivateKey {
+                        path: key_file.to_owned(),
+                    }
+                })?
+            }
+        };
+
+        let cert_file = cert_file.as_ref();
+        let cert = std::fs::read(cert_file)?;
+        let cert = if cert.starts_with(b"-----") {
+            rustls_pemfile::certs(&mut cert.as_slice())
+                .next()
+                .ok_or_else(|| {
+                    Error::NoCertificate {
+                        path: cert_file.to_owned(),
+                    }
+                })??
+        }
+        else {
+            CertificateDer::from(cert)
+        };
+
+        Self::from_der(&key, cert)
+    }
+
+    /// Create a CA by reading key and certificate from a PKCS#12 file.
+    ///
+    /// If the file contains a chain, the first certificate is used.
+    pub fn open_pkcs12(path: impl AsRef<Path>, password: &str) -> Result<Self, Error> {
+        let pkcs12::Pkcs12 { certs, key } = pkcs12::Pkcs12::parse(&std::fs::read(path)?, password)?;
+        let cert = certs
+            .into_iter()
+            .next()
+            .ok_or(pkcs12::Error::NoCertificate)?;
+        Self::from_der(&key, cert)
+    }
+
+    /// Create a CA from its key and certificate.
+    pub fn from_der(key: &PrivateKeyDer<'_>, cert: CertificateDer<'static>) -> Result<Self, Error> {
+        let key_pair = Arc::new(KeyPair::try_from(key)?);
+
+        // we need to create a `Certificate` from the `CertificateDer`. This is not
+        // possible. But only certain parameters from the `Certificate` are used
+        // for signing, so it doesn't matter that we just sign a new one with the right
+        // parameters. see https://github.com/rustls/rcgen/issues/268
+        let cert_params = CertificateParams::from_ca_cert_der(&cert)?;
+        let cert_for_signing = Arc::new(cert_params.self_signed(&key_pair)?);
+
+        Ok(Self {
+            key_pair,
+            cert: Arc::new(cert),
+            cert_for_signing,
+        })
+    }
+
+    /// Generate a new CA with a random key.
+    pub async fn generate() -> Result<Self, Error> {
+        Self::generate_with_name_constraints(None).await
+    }
+
+    /// Generate a new CA with a random key, that can only sign certificates
+    /// for names allowed by `name_constraints`.
+    ///
+    /// Clients that check name constraints won't accept certificates for other
+    /// names, even if we signed them. So this limits the damage if the CA's key
+    /// is ever leaked.
+    pub async fn generate_with_name_constraints(
+        name_constraints: Option<NameConstraints>,
+    ) -> Result<Self, Error> {
+        let mut cert_params = CertificateParams::default();
+        cert_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        cert_params.distinguished_name = DistinguishedName::new();
+        cert_params
+            .distinguished_name
+            .push(DnType::CommonName, "skunk root ca");
+        cert_params
+            .distinguished_name
+            .push(DnType::OrganizationName, "gocksec");
+        cert_params.key_usages.push(KeyUsagePurpose::KeyCertSign);
+        cert_params
+            .key_usages
+            .push(KeyUsagePurpose::DigitalSignature);
+        cert_params.name_constraints = name_constraints;
+
+        let (key_pair, cert_for_signing) = tokio::task::spawn_blocking(move || {
+            let key_pair = Arc::new(KeyPair::generate()?);
+            let cert_for_signing = Arc::new(cert_params.self_signed(&key_pair)?);
+            Ok::<_, Error>((key_pair, cert_for_signing))
+        })
+        .await
+        .unwrap()?;
+
+        Ok(Self {
+            key_pair,
+            cert: Arc::new(cert_for_signing.der().to_owned()),
+            cert_for_signing,
+        })
+    }
+
+    /// Save this CA's key and certificate to PEM files.
+    pub fn save(
+        &self,
+        key_file: impl AsRef<Path>,
+        cert_file: impl AsRef<Path>,
+    ) -> Result<(), Error> {
+        std::fs::write(key_file, self.key_pair.serialize_pem())?;
+        std::fs::write(cert_file, self.root_cert_pem())?;
+        Ok(())
+    }
+
+    /// Create a certificate signed by this CA.
+    ///
+    /// Fails with [`Error::NameConstraint`] if the CA's name constraints don't
+    /// allow one of the certificate's subject alternative names. When
+    /// imitating certificates, those names are already dropped by
+    /// [`ImitationPolicy::imitate`](imitation::ImitationPolicy::imitate).
+    pub async fn sign(
+        &self,
+        server_key: Arc<KeyPair>,
+        mut cert_params: CertificateParams,
+    ) -> Result<CertificateDer<'static>, Error> {
+        if let Some(name_constraints) = self.name_constraints() {
+            if let Some(name) = cert_params
+                .subject_alt_names
+                .iter()
+                .find(|name| !name_constraints::permits(name_constraints, name))
+            {
+                let name = match name {
+                    SanType::DnsName(name) => name.as_str().to_owned(),
+                    SanType::IpAddress(address) => address.to_string(),
+                    _ => format!("{name:?}"),
+                };
+                return Err(Error::NameConstraint { name });
+            }
+        }
+
+        // since we generate new certificates during each session, but with the same
+        // issuer, we need to generate a random serial number.
+        cert_params.serial_number = None;
+
+        let ca_key = self.key_pair.clone();
+        let ca_cert = self.cert_for_signing.clone();
+
+        let server_cert = tokio::task::spawn_blocking(move || {
+            cert_params.signed_by(&server_key, &ca_cert, &ca_key)
+        })
+        .await
+        .unwrap()?;
+
+        Ok(server_cert.into())
+    }
+
+    /// Return the CA's root certificate.
+    pub fn root_cert(&self) -> &Arc<CertificateDer<'static>> {
+        &self.cert
+    }
+
+    /// Return the CA's root certificate PEM encoded.
+    pub fn root_cert_pem(&self) -> String {
+        pem::encode(&pem::Pem::new("CERTIFICATE", self.cert.to_vec()))
+    }
+
+    /// Return the CA's root certificate, and optionally its key, as a PKCS#12
+    /// file protected by `password`.
+    pub fn to_pkcs12(&self, password: &str, include_key: bool) -> Result<Vec<u8>, Error> {
+        let key = include_key.then(|| PrivateKeyDer::Pkcs8(self.key_pair.serialize_der().into()));
+        Ok(pkcs12::encode(
+            std::slice::from_ref(&*self.cert),
+            key.as_ref(),
+            password,
+        )?)
+    }
+
+    /// Return the parameters of the CA's certificate, e.g. its validity.
+    pub fn params(&self) -> &CertificateParams {
+        self.cert_for_signing.params()
+    }
+
+    /// Return the names this CA is allowed to sign certificates for, or `None`
+    /// if it isn't constrained.
+    pub fn name_constraints(&self) -> Option<&NameConstraints> {
+        self.params().name_constraints.as_ref()
+    }
+}
+
+impl Debug for Ca {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ca")
+            .field("key_pair", &self.key_pair)
+            .field("cert", &self.cert)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ServerContext {
+    cert_cache: Arc<CertCache>,
+    ca: Ca,
+    request_client_cert: bool,
+}
+
+/// General TLS context that can be used to create server and client
+/// connections.
+///
+/// You're probably interested in the [`Context::decrypt`] method.
+#[derive(Clone, Debug)]
+pub struct Context {
+    verifiers: Arc<Verifiers>,
+    server_context: ServerContext,
+    key_log_file: Option<Arc<KeyLogFile>>,
+    imitation_policies: Arc<ImitationPolicies>,
+    pinned_hosts: Arc<PinnedHosts>,
+}
+
+impl Context {
+    /// Create context from [`Ca`].
+    ///
+    /// Forged certificates are cached in memory, see
+    /// [`Context::with_cert_cache`] for a persistent cache.
+    pub async fn new(ca: Ca) -> Result<Self, Error> {
+        Self::with_cert_cache(ca, CertCache::new(DEFAULT_CAPACITY).await?)
+    }
+
+    /// Create context from [`Ca`], caching forged certificates in
+    /// `cert_cache`.
+    pub fn with_cert_cache(ca: Ca, cert_cache: CertCache) -> Result<Self, Error> {
+        Ok(Self {
+            verifiers: Arc::new(Verifiers::new(&UpstreamPolicies::default())?),
+            server_context: ServerContext {
+                cert_cache: Arc::new(cert_cache),
+                ca,
+                request_client_cert: false,
+            },
+            key_log_file: None,
+            imitation_policies: Default::default(),
+            pinned_hosts: Default::default(),
+        })
+    }
+
+    /// The cache of forged certificates.
+    pub fn cert_cache(&self) -> &Arc<CertCache> {
+        &self.server_context.cert_cache
+    }
+
+    /// Record hosts whose clients pin certificates in `pinned_hosts`.
+    ///
+    /// By default they're only kept in memory.
+    pub fn with_pinned_hosts(mut self, pinned_hosts: PinnedHosts) -> Self {
+        self.pinned_hosts = Arc::new(pinned_hosts);
+        self
+    }
+
+    /// Hosts whose connections are passed through, because their clients pin
+    /// certificates.
+    pub fn pinned_hosts(&self) -> &Arc<PinnedHosts> {
+        &self.pinned_hosts
+    }
+
+    /// Log the secrets of all TLS connections to `key_log_file`.
+    ///
+    /// The secrets of each connection are then also available from
+    /// [`Incoming::key_log`] and [`Outgoing::key_log`].
+    pub fn with_key_log_file(mut self, key_log_file: KeyLogFile) -> Self {
+        self.key_log_file = Some(Arc::new(key_log_file));
+        self
+    }
+
+    /// Use `imitation_policies` to decide how server certificates are imitated.
+    pub fn with_imitation_policies(mut self, imitation_policies: ImitationPolicies) -> Self {
+        self.imitation_policies = Arc::new(imitation_policies);
+        self
+    }
+
+    /// Ask clients for a certificate when decrypting their connections. Any
+    /// certificate is accepted, and clients may choose not to send one.
+    ///
+    /// The certificate chain the client presented is available from
+    /// [`Incoming::peer_certificates`].
+    pub fn with_request_client_cert(mut self, request_client_cert: bool) -> Self {
+        self.server_context.request_client_cert = request_client_cert;
+        self
+    }
+
+    /// Use `upstream_policies` to decide how the certificates of servers are
+    /// verified.
+    pub fn with_upstream_policies(
+        mut self,
+        upstream_policies: &UpstreamPolicies,
+    ) -> Result<Self, Error> {
+        self.verifiers = Arc::new(Verifiers::new(upstream_policies)?);
+        Ok(self)
+    }
+
+    fn new_key_log(&self) -> Option<Arc<KeyLog>> {
+        self.key_log_file
+            .as_ref()
+            .map(|file| Arc::new(KeyLog::new(Some(file.clone()))))
+    }
+
+    /// Start accepting a TLS server connection.
+    pub async fn start_accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> Result<Accept<S>, Error> {
+        // rustls doesn't give us the raw `CLIENT_HELLO`, so we record what it reads
+        // and parse it ourselves.
+        let StartHandshake { accepted, io, .. } =
+            LazyConfigAcceptor::new(Acceptor::default(), Record::new(stream)).await?;
+        let (stream, recorded) = io.into_parts();
+
+        let client_hello = ClientHello::parse(&recorded)
+            .map_err(|error| tracing::debug!(?error, "Failed to parse client hello"))
+            .ok();
+
+        Ok(Accept {
+            start_handshake: StartHandshake::from_parts(accepted, stream),
+            client_hello,
+            recorded,
+            server_context: self.server_context.clone(),
+            key_log: self.new_key_log(),
+        })
+    }
+
+    /// Create a TLS client connection.
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        domain: ServerName<'static>,
+    ) -> Result<Outgoing<S>, Error> {
+        self.connect_with_alpn(stream, domain, vec![]).await
+    }
+
+    /// Create a TLS client connection that offers the given ALPN protocols.
+    ///
+    /// The server's certificate is verified according to the
+    /// [`UpstreamPolicy`](upstream::UpstreamPolicy) for `domain`.
+    pub async fn connect_with_alpn<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        domain: ServerName<'static>,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> Result<Outgoing<S>, Error> {
+        connect(
+            self.verifiers.for_host(&domain.to_str()),
+            false,
+            stream,
+            domain,
+            alpn_protocols,
+            self.new_key_log(),
+        )
+        .await
+    }
+
+    /// Decrypt the incoming connection by presenting our own certificate.
+    ///
+    /// This first establishes the outgoing connection to get the certificate
+    /// from the actual server. This certificate is then imitated according to
+    /// the [`ImitationPolicy`](imitation::ImitationPolicy) for the host and
+    /// signed by our CA. The imitated certificate is presented to the client.
+    ///
+    /// The ALPN protocols offered by the client are offered to the server, and
+    /// the protocol the server picked is then picked for the client as well.
+    /// This way both connections speak the same protocol.
+    ///
+    /// The server's certificate is verified according to the
+    /// [`UpstreamPolicy`](upstream::UpstreamPolicy) for the host. The outcome
+    /// is available from [`Outgoing::verification`].
+    ///
+    /// If the client didn't send a server name, the `destination` address from
+    /// the proxy layer is used instead. If that is an IP address, the
+    /// certificate presented by the server is not checked against it, since
+    /// most server certificates don't contain IP addresses. The IP address is
+    /// then added to the subject alternative names of our certificate, so that
+    /// the client accepts it.
+    ///
+    /// If the client rejects our certificate, it probably pins certificates.
+    /// The host is then added to the [`PinnedHosts`] and
+    /// [`Error::CertificateRejected`] is returned. A client that just closes
+    /// the connection has to do so a few times in a row before its host is
+    /// added. Later connections to that
+    /// host are not decrypted, but [`Decrypted::Pinned`] is returned, so they
+    /// can be passed through.
+    pub async fn decrypt<I, O>(
+        &self,
+        incoming: I,
+        mut outgoing: O,
+        destination: &TcpAddress,
+    ) -> Result<Decrypted<I, O>, Error>
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+        O: AsyncRead + AsyncWrite + Unpin,
+    {
+        // start the tls handshake with the source
+        let source_accept = self.start_accept(incoming).await?;
+
+        // get the server_name provided by the TLS client at the source, or fall back
+        // to the destination address we got from the proxy layer.
+        let (source_server_name, ip_address) = match source_accept.server_name() {
+            Some(server_name) => (server_name, None),
+            None => {
+                match &destination.host {
+                    HostAddress::DnsName(name) => (name.clone(), None),
+                    HostAddress::IpAddress(ip_address) => {
+                        (ip_address.to_string(), Some(*ip_address))
+                    }
+                }
+            }
+        };
+
+        // the client rejected our certificate before, so we forward what it sent so
+        // far, and let the caller pass the connection through.
+        if self.pinned_hosts.contains(&source_server_name) {
+            let Accept {
+                start_handshake,
+                client_hello,
+                recorded,
+                ..
+            } = source_accept;
+            outgoing.write_all(&recorded).await?;
+            return Ok(Decrypted::Pinned(Box::new(Pinned {
+                incoming: start_handshake.io,
+                outgoing,
+                host: source_server_name,
+                client_hello,
+            })));
+        }
+
+        let domain = match IpAddr::from_str(&source_server_name) {
+            Ok(ip_address) => ServerName::IpAddress(ip_address.into()),
+            Err(_) => {
+                ServerName::DnsName(source_server_name.to_owned().try_into().map_err(|_| {
+                    Error::InvalidServerName {
+                        hostname: source_server_name.clone(),
+                    }
+                })?)
+            }
+        };
+
+        // connect to the target, offering the same protocols as the source. if the
+        // domain is an IP address, rustls won't send a server name.
+        let target = connect(
+            self.verifiers.for_host(&source_server_name),
+            ip_address.is_some(),
+            outgoing,
+            domain,
+            source_accept.alpn_protocols(),
+            source_accept.key_log.clone(),
+        )
+        .await?;
+        let alpn_protocol = target.get_tls_connection().alpn_protocol();
+
+        // imitate the server certificate we got from the target.
+        let target_cert = target
+            .inner
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .ok_or(Error::NoTargetCertificate)?;
+        let Imitation {
+            mut cert_params,
+            key_algorithm,
+        } = self
+            .imitation_policies
+            .for_host(&source_server_name)
+            .imitate(target_cert, &source_server_name, &self.server_context.ca)?;
+
+        // the client connected by IP address, so it will check our certificate against
+        // that.
+        if let Some(ip_address) = ip_address {
+            let san = SanType::IpAddress(ip_address);
+            if !cert_params.subject_alt_names.contains(&san) {
+                cert_params.subject_alt_names.push(san);
+            }
+        }
+
+        // finish the TLS handshake with the source by signing the imitated
+        // certificate with our CA.
+        let cache_key = CacheKey {
+            host: source_server_name.clone(),
+            upstream_fingerprint: Fingerprint::of(target_cert),
+            key_algorithm,
+        };
+        let source = match source_accept
+            .finish(cache_key, cert_params, alpn_protocol)
+            .await
+        {
+            Ok(source) => {
+                self.pinned_hosts.record_accepted(&source_server_name);
+                source
+            }
+            Err(Error::Io(error)) => {
+                let Some(rejection) = pinned::certificate_rejection(&error)
+                else {
+                    return Err(error.into());
+                };
+                if !self
+                    .pinned_hosts
+                    .record_rejection(&source_server_name, &rejection)
+                {
+                    tracing::debug!(host = %source_server_name, %rejection, "Client aborted the handshake.");
+                    return Err(error.into());
+                }
+                tracing::info!(host = %source_server_name, %rejection, "Client rejected our certificate. Passing its connections through from now on.");
+                return Err(Error::CertificateRejected {
+                    host: source_server_name,
+                    source: error,
+                });
+            }
+            Err(error) => return Err(error),
+        };
+
+        Ok(Decrypted::Decrypted(source, target))
+    }
+
+    /// Maybe decrypts TLS traffic. This is a convenience function that returns
+    /// a single type regardless of whether encryption is used or not.
+    pub async fn maybe_decrypt<I, O>(
+        &self,
+        incoming: I,
+        outgoing: O,
+        destination: &TcpAddress,
+        decrypt: bool,
+    ) -> Result<Decrypted<I, O, maybe::Incoming<I>, maybe::Outgoing<O>>, Error>
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+        O: AsyncRead + AsyncWrite + Unpin,
+    {
+        let decrypted = if decrypt {
+            match self.decrypt(incoming, outgoing, destination).await? {
+                Decrypted::Decrypted(incoming, outgoing) => {
+                    Decrypted::Decrypted(
+                        maybe::Incoming::Encrypted(incoming),
+                        maybe::Outgoing::Encrypted(outgoing),
+                    )
+                }
+                Decrypted::Pinned(pinned) => Decrypted::Pinned(pinned),
+            }
+        }
+        else {
+            Decrypted::Decrypted(
+                maybe::Incoming::Unencrypted(incoming),
+                maybe::Outgoing::Unencrypted(outgoing),
+            )
+        };
+        Ok(decrypted)
+    }
+}
+
+/// Result of [`Context::decrypt`].
+#[derive(Debug)]
+pub enum Decrypted<I, O, DI = Incoming<I>, DO = Outgoing<O>> {
+    /// The connection was decrypted.
+    Decrypted(DI, DO),
+
+    /// The connection wasn't decrypted, because the client pins certificates.
+    Pinned(Box<Pinned<I, O>>),
+}
+
+/// Connection to a host whose clients pin certificates.
+///
+/// What the client sent so far was already forwarded to `outgoing`, so the
+/// connection can just be passed through.
+#[derive(Debug)]
+pub struct Pinned<I, O> {
+    pub incoming: I,
+    pub outgoing: O,
+
+    /// The server name the client sent, or the address it connected to.
+    pub host: String,
+
+    /// The `CLIENT_HELLO` message sent by the client, or `None` if we failed to
+    /// parse it.
+    pub client_hello: Option<ClientHello>,
+}
+
+/// Create a TLS client connection verified by `verifier`, offering the given
+/// ALPN protocols, and logging secrets to `key_log`.
+///
+/// If `ignore_server_name` is set, the server's certificate is not checked
+/// against the server name.
+async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    verifier: &Verifier,
+    ignore_server_name: bool,
+    stream: S,
+    domain: ServerName<'static>,
+    alpn_protocols: Vec<Vec<u8>>,
+    key_log: Option<Arc<KeyLog>>,
+) -> Result<Outgoing<S>, Error> {
+    let client_config = verifier.client_config(ignore_server_name);
+    let client_config = if alpn_protocols.is_empty() && key_log.is_none() {
+        client_config.clone()
+    }
+    else {
+        let mut client_config = ClientConfig::clone(client_config);
+        client_config.alpn_protocols = alpn_protocols;
+        if let Some(key_log) = &key_log {
+            client_config.key_log = key_log.clone();
+        }
+        Arc::new(client_config)
+    };
+
+    let stream = TlsConnector::from(client_config)
+        .connect(domain.clone(), stream)
+        .await?;
+
+    let verification = verifier.verification(
+        stream.get_ref().1.peer_certificates().unwrap_or_default(),
+        &domain,
+        ignore_server_name,
+    );
+
+    Ok(Outgoing {
+        inner: Box::new(stream),
+        key_log,
+        verification,
+    })
+}
+
+impl From<Context> for Arc<ClientConfig> {
+    fn from(value: Context) -> Self {
+        value.verifiers.default().client_config(false).clone()
+    }
+}
+
+/// Process of accepting a TLS server connection
+pub struct Accept<S> {
+    start_handshake: StartHandshake<S>,
+    client_hello: Option<ClientHello>,
+    recorded: Vec<u8>,
+    server_context: ServerContext,
+    key_log: Option<Arc<KeyLog>>,
+}
+
+impl<S> Accept<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Finish the TLS handshake.
+    ///
+    /// The `cert_params` argument will be used to create a certificate signed
+    /// by the skunk CA that is presented to the client. The `cache_key`
+    /// is used to cache certificates, and determines the algorithm of the
+    /// certificate's key. If `alpn_protocol` is set, it is
+    /// selected, if the client offered it.
+    pub async fn finish(
+        self,
+        cache_key: CacheKey,
+        cert_params: CertificateParams,
+        alpn_protocol: Option<&[u8]>,
+    ) -> Result<Incoming<S>, Error> {
+        let cert_cache = &self.server_context.cert_cache;
+        let server_key = cert_cache.server_key(cache_key.key_algorithm).await?;
+        let server_cert = if let Some(cert) = cert_cache.get(&cache_key) {
+            cert
+        }
+        else {
+            let cert = self
+                .server_context
+                .ca
+                .sign(server_key.clone(), cert_params)
+                .await?;
+            cert_cache.insert(cache_key, cert.clone());
+            cert
+        };
+
+        let cert_chain = vec![
+            server_cert,
+            //CertificateDer::clone(self.server_context.ca.root_cert()),
+        ];
+        let server_key = PrivateKeyDer::try_from(server_key.serialize_der()).unwrap();
+
+        let server_config = ServerConfig::builder();
+        let server_config = if self.server_context.request_client_cert {
+            server_config.with_client_cert_verifier(Arc::new(RequestClientCert::new()))
+        }
+        else {
+            server_config.with_no_client_auth()
+        };
+        let mut server_config = server_config
+            .with_single_cert(cert_chain, server_key)
+            .unwrap();
+        server_config.alpn_protocols = alpn_protocol
+            .map(|p| vec![p.to_owned()])
+            .unwrap_or_default();
+        if let Some(key_log) = &self.key_log {
+            server_config.key_log = key_log.clone();
+        }
+
+        let stream = self
+            .start_handshake
+            .into_stream(Arc::new(server_config))
+            .await?;
+
+        Ok(Incoming {
+            inner: Box::new(stream),
+            client_hello: self.client_hello.map(Box::new),
+            key_log: self.key_log,
+        })
+    }
+
+    /// The `CLIENT_HELLO` message sent by the client, or `None` if we failed to
+    /// parse it.
+    pub fn client_hello(&self) -> Option<&ClientHello> {
+        self.client_hello.as_ref()
+    }
+
+    /// The server name that was sent by the client in the `CLIENT_HELLO`
+    /// message.
+    pub fn server_name(&self) -> Option<String> {
+        let client_hello = self.start_handshake.client_hello();
+        client_hello.server_name().map(ToOwned::to_owned)
+    }
+
+    /// The ALPN protocols that were offered by the client in the
+    /// `CLIENT_HELLO` message.
+    pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        let client_hello = self.start_handshake.client_hello();
+        client_hello
+            .alpn()
+            .map(|protocols| protocols.map(ToOwned::to_owned).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// An outgoing (client) connection that is TLS encrypted.
+#[derive(Debug)]
+pub struct Outgoing<Inner> {
+    // this is at least 1065 bytes large, so we box it.
+    inner: Box<tokio_rustls::client::TlsStream<Inner>>,
+    key_log: Option<Arc<KeyLog>>,
+    verification: Verification,
+}
+
+impl<Inner> Outgoing<Inner> {
+    pub fn get_tls_connection(&self) -> &rustls::ClientConnection {
+        self.inner.get_ref().1
+    }
+
+    /// The certificate chain the server presented.
+    pub fn peer_certificates(&self) -> &[CertificateDer<'static>] {
+        self.get_tls_connection()
+            .peer_certificates()
+            .unwrap_or_default()
+    }
+
+    /// How the server's certificate was verified.
+    pub fn verification(&self) -> &Verification {
+        &self.verification
+    }
+
+    /// The secrets of this connection, if key logging is enabled.
+    pub fn key_log(&self) -> Option<&KeyLog> {
+        self.key_log.as_deref()
+    }
+}
+
+impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncRead for Outgoing<Inner> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Outgoing<Inner> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// An incoming (server) connection that is TLS encrypted.
+#[derive(Debug)]
+pub struct Incoming<Inner> {
+    // this is at least 1145 bytes large, so we box it.
+    inner: Box<tokio_rustls::server::TlsStream<Inner>>,
+    client_hello: Option<Box<ClientHello>>,
+    key_log: Option<Arc<KeyLog>>,
+}
+
+impl<Inner> Incoming<Inner> {
+    pub fn get_tls_connection(&self) -> &rustls::ServerConnection {
+        self.inner.get_ref().1
+    }
+
+    /// The `CLIENT_HELLO` message sent by the client, or `None` if we failed to
+    /// parse it.
+    pub fn client_hello(&self) -> Option<&ClientHello> {
+        self.client_hello.as_deref()
+    }
+
+    /// The certificate chain the client presented, if it was asked for one.
+    pub fn peer_certificates(&self) -> &[CertificateDer<'static>] {
+        self.get_tls_connection()
+            .peer_certificates()
+            .unwrap_or_default()
+    }
+
+    /// The secrets of this connection, if key logging is enabled.
+    ///
+    /// If this connection was created by [`Context::decrypt`], this also
+    /// contains the secrets of the connection to the server.
+    pub fn key_log(&self) -> Option<&KeyLog> {
+        self.key_log.as_deref()
+    }
+}
+
+impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncRead for Incoming<Inner> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Incoming<Inner> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pin_project! {
+    /// Stream wrapper that records all data read from it.
+    #[derive(Debug)]
+    struct Record<S> {
+        #[pin]
+        inner: S,
+        recorded: Vec<u8>,
+    }
+}
+
+impl<S> Record<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            recorded: vec![],
+        }
+    }
+
+    fn into_parts(self) -> (S, Vec<u8>) {
+        (self.inner, self.recorded)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Record<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        this.recorded.extend_from_slice(&buf.filled()[filled..]);
+        result
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Record<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+pub mod maybe {
+    //! Stream types that represent connections that are either encrypted or
+    //! unencrypted.
+
+    use std::{
+        ops::DerefMut,
+        pin::Pin,
+        task::Poll,
+    };
+
+    use tokio::io::{
+        AsyncRead,
+        AsyncWrite,
+        ReadBuf,
+    };
+
+    use super::{
+        client_hello::ClientHello,
+        key_log::KeyLog,
+        upstream::Verification,
+    };
+
+    /// An outgoing (client) connection that might be TLS encrypted.
+    #[derive(Debug)]
+    pub enum Outgoing<Inner> {
+        Encrypted(super::Outgoing<Inner>),
+        Unencrypted(Inner),
+    }
+
+    impl<Inner> Outgoing<Inner> {
+        pub fn get_tls_connection(&self) -> Option<&rustls::ClientConnection> {
+            match self {
+                Outgoing::Encrypted(inner) => Some(inner.get_tls_connection()),
+                Outgoing::Unencrypted(_) => None,
+            }
+        }
+
+        pub fn verification(&self) -> Option<&Verification> {
+            match self {
+                Outgoing::Encrypted(inner) => Some(inner.verification()),
+                Outgoing::Unencrypted(_) => None,
+            }
+        }
+    }
+
+    impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncRead for Outgoing<Inner> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            match self.deref_mut() {
+                Outgoing::Encrypted(inner) => Pin::new(inner).poll_read(cx, buf),
+                Outgoing::Unencrypted(inner) => Pin::new(inner).poll_read(cx, buf),
+            }
+        }
+    }
+
+    impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Outgoing<Inner> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, std::io::Error>> {
+            match self.deref_mut() {
+                Outgoing::Encrypted(inner) => Pin::new(inner).poll_write(cx, buf),
+                Outgoing::Unencrypted(inner) => Pin::new(inner).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            match self.deref_mut() {
+                Outgoing::Encrypted(inner) => Pin::new(inner).poll_flush(cx),
+                Outgoing::Unencrypted(inner) => Pin::new(inner).poll_flush(cx),
+            }
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            match self.deref_mut() {
+                Outgoing::Encrypted(inner) => Pin::new(inner).poll_shutdown(cx),
+                Outgoing::Unencrypted(inner) => Pin::new(inner).poll_shutdown(cx),
+            }
+        }
+    }
+
+    /// An incoming (server) connection that might be TLS encrypted.
+    #[derive(Debug)]
+    pub enum Incoming<Inner> {
+        Encrypted(super::Incoming<Inner>),
+        Unencrypted(Inner),
+    }
+
+    impl<Inner> Incoming<Inner> {
+        pub fn get_tls_connection(&self) -> Option<&rustls::ServerConnection> {
+            match self {
+                Incoming::Encrypted(inner) => Some(inner.get_tls_connection()),
+                Incoming::Unencrypted(_) => None,
+            }
+        }
+
+        pub fn client_hello(&self) -> Option<&ClientHello> {
+            match self {
+                Incoming::Encrypted(inner) => inner.client_hello(),
+                Incoming::Unencrypted(_) => None,
+            }
+        }
+
+        pub fn key_log(&self) -> Option<&KeyLog> {
+            match self {
+                Incoming::Encrypted(inner) => inner.key_log(),
+                Incoming::Unencrypted(_) => None,
+            }
+        }
+    }
+
+    impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncRead for Incoming<Inner> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            match self.deref_mut() {
+                Incoming::Encrypted(inner) => Pin::new(inner).poll_read(cx, buf),
+                Incoming::Unencrypted(inner) => Pin::new(inner).poll_read(cx, buf),
+            }
+        }
+    }
+
+    impl<Inner: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Incoming<Inner> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, std::io::Error>> {
+            match self.deref_mut() {
+                Incoming::Encrypted(inner) => Pin::new(inner).poll_write(cx, buf),
+                Incoming::Unencrypted(inner) => Pin::new(inner).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            match self.deref_mut() {
+                Incoming::Encrypted(inner) => Pin::new(inner).poll_flush(cx),
+                Incoming::Unencrypted(inner) => Pin::new(inner).poll_flush(cx),
+            }
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            match self.deref_mut() {
+                Incoming::Encrypted(inner) => Pin::new(inner).poll_shutdown(cx),
+                Incoming::Unencrypted(inner) => Pin::new(inner).poll_shutdown(cx),
+            }
+        }
+    }
+}
+
+/// Returns the default TLS client config. This uses the natively installed root
+/// certificates from [`native_certificates`].
+pub fn default_client_config() -> Result<Arc<ClientConfig>, Error> {
+    static CONFIG: Lazy<ClientConfig> = Lazy::new();
+    CONFIG.get_or_try_init(|| {
+        Ok(ClientConfig::builder()
+            .with_root_certificates(native_certificates()?)
+            .with_no_client_auth())
+    })
+}
+
+/// Loads root certificates from the system that can be used as trust anchors.
+/// This only loads the certificates on the first call and will cache the
+/// result.
+pub fn native_certificates() -> Result<Arc<RootCertStore>, Error> {
+    static CERTS: Lazy<RootCertStore> = Lazy::new();
+    CERTS.get_or_try_init(|| {
+        let result = rustls_native_certs::load_native_certs();
+        if !result.errors.is_empty() {
+            Err(NativeCertsError {
+                errors: result.errors,
+            }
+            .into())
+        }
+        else {
+            let mut certs = RootCertStore::empty();
+            for cert in result.certs {
+                certs.add(cert)?;
+            }
+            Ok(certs)
+        }
+    })
+}
+
+#[derive(Debug)]
+pub struct NativeCertsError {
+    errors: Vec<rustls_native_certs::Error>,
+}
+
+impl Display for NativeCertsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.errors.len() == 1 {
+            write!(f, "{}", self.errors[0])?;
+        }
+        else {
+            for (i, e) in self.errors.iter().enumerate() {
+                writeln!(f, "{i}. {e}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NativeCertsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.errors[0])
+    }
+}
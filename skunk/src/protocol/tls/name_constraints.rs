@@ -0,0 +1,209 @@
+//! Checking names against the name constraints of a CA ([RFC 5280][1]).
+//!
+//! Only DNS names and IP addresses are checked. Other names are not
+//! constrained.
+//!
+//! [1]: https://datatracker.ietf.org/doc/html/rfc5280#section-4.2.1.10
+
+use std::net::IpAddr;
+
+use rcgen::SanType;
+pub use rcgen::{
+    CidrSubnet,
+    GeneralSubtree,
+    NameConstraints,
+};
+
+/// Invalid subtree passed to [`parse_subtree`].
+#[derive(Debug, thiserror::Error)]
+#[error("invalid name constraint: {0}")]
+pub struct InvalidSubtree(String);
+
+/// Parses a subtree from an IP subnet in CIDR notation (e.g. `10.0.0.0/8`), or
+/// a DNS name.
+pub fn parse_subtree(subtree: &str) -> Result<GeneralSubtree, InvalidSubtree> {
+    if let Some((address, prefix)) = subtree.split_once('/') {
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|_| InvalidSubtree(subtree.to_owned()))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| InvalidSubtree(subtree.to_owned()))?;
+        Ok(GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(
+            address, prefix,
+        )))
+    }
+    else if !subtree.is_empty() && subtree.is_ascii() && !subtree.contains(['*', ' ']) {
+        Ok(GeneralSubtree::DnsName(subtree.to_owned()))
+    }
+    else {
+        Err(InvalidSubtree(subtree.to_owned()))
+    }
+}
+
+/// Creates name constraints from `permitted` and `excluded` subtrees, that
+/// are parsed with [`parse_subtree`]. Returns `None` if both are empty.
+pub fn from_subtrees(
+    permitted: &[String],
+    excluded: &[String],
+) -> Result<Option<NameConstraints>, InvalidSubtree> {
+    if permitted.is_empty() && excluded.is_empty() {
+        return Ok(None);
+    }
+    let parse = |subtrees: &[String]| {
+        subtrees
+            .iter()
+            .map(|subtree| parse_subtree(subtree))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(Some(NameConstraints {
+        permitted_subtrees: parse(permitted)?,
+        excluded_subtrees: parse(excluded)?,
+    }))
+}
+
+/// Checks whether `name` is allowed by `constraints`.
+///
+/// A name is allowed if it's not in an excluded subtree, and it's in a
+/// permitted subtree, if there are any permitted subtrees for its type.
+pub fn permits(constraints: &NameConstraints, name: &SanType) -> bool {
+    let excluded = constraints
+        .excluded_subtrees
+        .iter()
+        .any(|subtree| matches(subtree, name) == Some(true));
+    if excluded {
+        return false;
+    }
+
+    let mut permitted = constraints
+        .permitted_subtrees
+        .iter()
+        .filter_map(|subtree| matches(subtree, name))
+        .peekable();
+    permitted.peek().is_none() || permitted.any(|matches| matches)
+}
+
+/// Checks whether `name` is in `subtree`, or returns `None` if the subtree is
+/// for another type of name.
+fn matches(subtree: &GeneralSubtree, name: &SanType) -> Option<bool> {
+    match (subtree, name) {
+        (GeneralSubtree::DnsName(subtree), SanType::DnsName(name)) => {
+            Some(dns_name_matches(subtree, name.as_str()))
+        }
+        (GeneralSubtree::IpAddress(subnet), SanType::IpAddress(address)) => {
+            Some(ip_address_matches(subnet, address))
+        }
+        _ => None,
+    }
+}
+
+/// A DNS name is in the subtree, if it's the subtree's name, or a subdomain of
+/// it. If the subtree starts with a `.`, only subdomains are in it.
+fn dns_name_matches(subtree: &str, name: &str) -> bool {
+    let subtree = subtree.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    if subtree.is_empty() {
+        true
+    }
+    else if subtree.starts_with('.') {
+        name.ends_with(&subtree)
+    }
+    else {
+        name == subtree || name.ends_with(&format!(".{subtree}"))
+    }
+}
+
+/// Checks whether `address` is in `subnet`. Addresses are never in subnets of
+/// the other IP version.
+fn ip_address_matches(subnet: &CidrSubnet, address: &IpAddr) -> bool {
+    fn masked_eq(subnet: &[u8], mask: &[u8], address: &[u8]) -> bool {
+        subnet
+            .iter()
+            .zip(mask)
+            .zip(address)
+            .all(|((subnet, mask), address)| subnet & mask == address & mask)
+    }
+
+    match (subnet, address) {
+        (CidrSubnet::V4(subnet, mask), IpAddr::V4(address)) => {
+            masked_eq(subnet, mask, &address.octets())
+        }
+        (CidrSubnet::V6(subnet, mask), IpAddr::V6(address)) => {
+            masked_eq(subnet, mask, &address.octets())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dns_name(name: &str) -> SanType {
+        SanType::DnsName(name.try_into().unwrap())
+    }
+
+    #[test]
+    fn it_checks_dns_names() {
+        let constraints = NameConstraints {
+            permitted_subtrees: vec![
+                GeneralSubtree::DnsName("example.com".to_owned()),
+                GeneralSubtree::DnsName(".example.org".to_owned()),
+            ],
+            excluded_subtrees: vec![GeneralSubtree::DnsName("secret.example.com".to_owned())],
+        };
+
+        assert!(permits(&constraints, &dns_name("example.com")));
+        assert!(permits(&constraints, &dns_name("www.Example.com")));
+        assert!(permits(&constraints, &dns_name("*.example.com")));
+        assert!(permits(&constraints, &dns_name("www.example.org")));
+        assert!(!permits(&constraints, &dns_name("example.org")));
+        assert!(!permits(&constraints, &dns_name("notexample.com")));
+        assert!(!permits(&constraints, &dns_name("api.secret.example.com")));
+        // there are no constraints for IP addresses.
+        assert!(permits(
+            &constraints,
+            &SanType::IpAddress("127.0.0.1".parse().unwrap())
+        ));
+    }
+
+    #[test]
+    fn it_parses_subtrees() {
+        assert!(matches!(
+            parse_subtree("example.com").unwrap(),
+            GeneralSubtree::DnsName(name) if name == "example.com"
+        ));
+        assert_eq!(
+            parse_subtree("192.0.2.0/24").unwrap(),
+            GeneralSubtree::IpAddress(CidrSubnet::V4([192, 0, 2, 0], [255, 255, 255, 0]))
+        );
+        assert!(parse_subtree("192.0.2.0/33").is_err());
+        assert!(parse_subtree("*.example.com").is_err());
+    }
+
+    #[test]
+    fn it_checks_ip_addresses() {
+        let constraints = NameConstraints {
+            permitted_subtrees: vec![GeneralSubtree::IpAddress("192.0.2.0/24".parse().unwrap())],
+            excluded_subtrees: vec![],
+        };
+
+        assert!(permits(
+            &constraints,
+            &SanType::IpAddress("192.0.2.42".parse().unwrap())
+        ));
+        assert!(!permits(
+            &constraints,
+            &SanType::IpAddress("198.51.100.1".parse().unwrap())
+        ));
+        assert!(!permits(
+            &constraints,
+            &SanType::IpAddress("::1".parse().unwrap())
+        ));
+        assert!(permits(&constraints, &dns_name("example.com")));
+    }
+}
@@ -0,0 +1,425 @@
+//! Hosts whose clients pin certificates.
+//!
+//! Clients that pin certificates abort the handshake when they see our forged
+//! certificate. When that happens, the host is recorded here, and later
+//! connections to it are passed through without decrypting them.
+//!
+//! A client that sends a certificate alert rejected our certificate for sure.
+//! A client that just closes the connection might have done so for other
+//! reasons, so its host is only recorded after it aborted several handshakes
+//! in a row. Entries can be made to expire, so that hosts are decrypted again
+//! once the client stopped pinning. The table can be persisted to a file, and
+//! changes are saved in the background, shortly after they happen.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+use rustls::AlertDescription;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use super::{
+    cert_cache::write_atomically,
+    Error,
+};
+
+/// Number of handshakes a client has to abort in a row by closing the
+/// connection, before its host is pinned.
+const MAX_ABORTED_HANDSHAKES: usize = 3;
+
+/// Aborted handshakes only count as in a row, if they happen within this time.
+const ABORTED_HANDSHAKES_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait after a change before saving the table, so that a burst of
+/// changes results in a single write.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+type Hosts = IndexMap<String, PinnedHost>;
+
+/// A host whose clients rejected our certificate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PinnedHost {
+    /// The server name the client sent, or the address it connected to.
+    pub host: String,
+
+    /// When the client rejected our certificate.
+    pub detected_at: SystemTime,
+
+    /// How the client rejected our certificate, e.g. the alert it sent.
+    pub reason: String,
+}
+
+/// Table of hosts whose connections are passed through, because their clients
+/// pin certificates.
+#[derive(Debug)]
+pub struct PinnedHosts {
+    persist: Option<Persist>,
+    ttl: Option<Duration>,
+    hosts: Arc<Mutex<Hosts>>,
+    aborted_handshakes: Mutex<HashMap<String, AbortedHandshakes>>,
+}
+
+#[derive(Debug)]
+struct Persist {
+    /// Tells the save task that the table changed.
+    changed: mpsc::Sender<()>,
+
+    save_task: JoinHandle<()>,
+}
+
+/// Handshakes a client aborted in a row by closing the connection.
+#[derive(Debug)]
+struct AbortedHandshakes {
+    count: usize,
+    since: Instant,
+}
+
+impl Default for PinnedHosts {
+    fn default() -> Self {
+        Self {
+            persist: None,
+            ttl: None,
+            hosts: Default::default(),
+            aborted_handshakes: Default::default(),
+        }
+    }
+}
+
+impl PinnedHosts {
+    /// Creates an empty table that is not persisted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a table that is persisted in the file at `path`.
+    ///
+    /// This must be called from within a tokio runtime, since it spawns the
+    /// task that saves the table.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        let mut hosts = IndexMap::new();
+        if path.exists() {
+            match serde_yml::from_str::<Vec<PinnedHost>>(&std::fs::read_to_string(path)?) {
+                Ok(entries) => {
+                    for entry in entries {
+                        hosts.insert(entry.host.clone(), entry);
+                    }
+                }
+                Err(error) => tracing::warn!(?error, "Failed to read pinned hosts"),
+            }
+        }
+
+        let hosts = Arc::new(Mutex::new(hosts));
+        let (changed_tx, changed_rx) = mpsc::channel(1);
+        let save_task = tokio::spawn(save_hosts(hosts.clone(), path.to_owned(), changed_rx));
+
+        Ok(Self {
+            persist: Some(Persist {
+                changed: changed_tx,
+                save_task,
+            }),
+            hosts,
+            ..Default::default()
+        })
+    }
+
+    /// Makes hosts expire after `ttl`. By default hosts stay pinned until they
+    /// are removed.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Waits until pending changes are saved.
+    pub async fn close(self) {
+        if let Some(persist) = self.persist {
+            drop(persist.changed);
+            let _ = persist.save_task.await;
+        }
+    }
+
+    /// Returns whether connections to `host` are passed through.
+    pub fn contains(&self, host: &str) -> bool {
+        let mut hosts = self.hosts.lock();
+        match hosts.get(host) {
+            Some(pinned) if self.is_expired(pinned) => {
+                tracing::debug!(host, "Pinned host expired");
+                hosts.shift_remove(host);
+                drop(hosts);
+                self.save();
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Records that a client rejected our certificate for `host`.
+    pub fn insert(&self, host: String, reason: String) {
+        let mut hosts = self.hosts.lock();
+        hosts.insert(
+            host.clone(),
+            PinnedHost {
+                host,
+                detected_at: SystemTime::now(),
+                reason,
+            },
+        );
+        drop(hosts);
+        self.save();
+    }
+
+    /// Records that a client rejected our certificate for `host`. Returns
+    /// whether the host is pinned now.
+    pub(super) fn record_rejection(&self, host: &str, rejection: &Rejection) -> bool {
+        if let Rejection::Closed = rejection {
+            let now = Instant::now();
+            let mut aborted_handshakes = self.aborted_handshakes.lock();
+            aborted_handshakes
+                .retain(|_, aborted| now.duration_since(aborted.since) < ABORTED_HANDSHAKES_WINDOW);
+            let aborted = aborted_handshakes
+                .entry(host.to_owned())
+                .or_insert(AbortedHandshakes {
+                    count: 0,
+                    since: now,
+                });
+            aborted.count += 1;
+            if aborted.count < MAX_ABORTED_HANDSHAKES {
+                return false;
+            }
+            aborted_handshakes.remove(host);
+        }
+
+        self.insert(host.to_owned(), rejection.to_string());
+        true
+    }
+
+    /// Records that a client accepted our certificate for `host`.
+    pub(super) fn record_accepted(&self, host: &str) {
+        self.aborted_handshakes.lock().remove(host);
+    }
+
+    /// Returns all pinned hosts that haven't expired, in the order they were
+    /// detected.
+    pub fn hosts(&self) -> Vec<PinnedHost> {
+        self.hosts
+            .lock()
+            .values()
+            .filter(|pinned| !self.is_expired(pinned))
+            .cloned()
+            .collect()
+    }
+
+    /// Removes `host`, or all hosts if `host` is `None`, so that their
+    /// connections are decrypted again. Returns the number of removed hosts.
+    pub fn remove(&self, host: Option<&str>) -> usize {
+        let mut hosts = self.hosts.lock();
+        let before = hosts.len();
+        hosts.retain(|pinned, _| host.is_some_and(|host| pinned != host));
+        let removed = before - hosts.len();
+        drop(hosts);
+        if removed > 0 {
+            self.save();
+        }
+        removed
+    }
+
+    fn is_expired(&self, pinned: &PinnedHost) -> bool {
+        self.ttl.is_some_and(|ttl| {
+            pinned
+                .detected_at
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= ttl)
+        })
+    }
+
+    /// Schedules saving the table, if it's persisted.
+    fn save(&self) {
+        if let Some(persist) = &self.persist {
+            // if the channel is full, a save is already pending.
+            let _ = persist.changed.try_send(());
+        }
+    }
+}
+
+/// Saves the table to `path` after it changed, until the table is dropped.
+async fn save_hosts(hosts: Arc<Mutex<Hosts>>, path: PathBuf, mut changed: mpsc::Receiver<()>) {
+    while changed.recv().await.is_some() {
+        tokio::time::sleep(SAVE_DELAY).await;
+
+        let hosts = hosts.lock().values().cloned().collect::<Vec<_>>();
+        let path = path.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let yaml = match serde_yml::to_string(&hosts) {
+                Ok(yaml) => yaml,
+                Err(error) => {
+                    tracing::error!(?error, "Failed to serialize pinned hosts");
+                    return;
+                }
+            };
+            if let Err(error) = write_atomically(&path, yaml.as_bytes()) {
+                tracing::error!(?error, "Failed to save pinned hosts");
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
+
+/// How a client rejected our certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum Rejection {
+    /// The client sent an alert about the certificate.
+    Alert(AlertDescription),
+
+    /// The client closed the connection.
+    Closed,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Alert(alert) => write!(f, "alert: {alert:?}"),
+            Self::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+/// Checks whether the error from a server handshake means that the client
+/// rejected our certificate, and if so, returns how.
+///
+/// Clients either send an alert about the certificate, or just close the
+/// connection. Since the server sends its certificate in its first flight,
+/// this is right after the client saw it.
+pub(super) fn certificate_rejection(error: &std::io::Error) -> Option<Rejection> {
+    use std::io::ErrorKind;
+
+    if let Some(rustls::Error::AlertReceived(alert)) = error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<rustls::Error>())
+    {
+        return matches!(
+            alert,
+            AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCA
+        )
+        .then_some(Rejection::Alert(*alert));
+    }
+
+    match error.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+            Some(Rejection::Closed)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_persists_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pinned.yml");
+
+        let pinned = PinnedHosts::open(&path).unwrap();
+        pinned.insert("a.example.com".to_owned(), "alert: UnknownCA".to_owned());
+        pinned.insert("b.example.com".to_owned(), "connection closed".to_owned());
+        assert_eq!(pinned.remove(Some("b.example.com")), 1);
+        pinned.close().await;
+
+        let pinned = PinnedHosts::open(&path).unwrap();
+        assert!(pinned.contains("a.example.com"));
+        assert!(!pinned.contains("b.example.com"));
+        assert_eq!(pinned.remove(None), 1);
+        assert!(pinned.hosts().is_empty());
+    }
+
+    #[test]
+    fn it_detects_certificate_rejections() {
+        let alert = |alert| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                rustls::Error::AlertReceived(alert),
+            )
+        };
+
+        assert_eq!(
+            certificate_rejection(&alert(AlertDescription::UnknownCA)),
+            Some(Rejection::Alert(AlertDescription::UnknownCA))
+        );
+        assert!(certificate_rejection(&alert(AlertDescription::HandshakeFailure)).is_none());
+        assert_eq!(
+            certificate_rejection(&std::io::ErrorKind::UnexpectedEof.into()),
+            Some(Rejection::Closed)
+        );
+    }
+
+    #[test]
+    fn it_pins_after_certificate_alerts() {
+        let pinned = PinnedHosts::new();
+        assert!(pinned.record_rejection(
+            "example.com",
+            &Rejection::Alert(AlertDescription::UnknownCA)
+        ));
+        assert!(pinned.contains("example.com"));
+    }
+
+    #[test]
+    fn it_pins_after_several_closed_connections() {
+        let pinned = PinnedHosts::new();
+        assert!(!pinned.record_rejection("example.com", &Rejection::Closed));
+        assert!(!pinned.record_rejection("example.com", &Rejection::Closed));
+        assert!(!pinned.contains("example.com"));
+
+        // an accepted certificate starts counting again.
+        pinned.record_accepted("example.com");
+        assert!(!pinned.record_rejection("example.com", &Rejection::Closed));
+        assert!(!pinned.record_rejection("example.com", &Rejection::Closed));
+        assert!(pinned.record_rejection("example.com", &Rejection::Closed));
+        assert!(pinned.contains("example.com"));
+    }
+
+    #[test]
+    fn it_keeps_hosts_without_ttl() {
+        let pinned = PinnedHosts::new();
+        pinned.insert("example.com".to_owned(), "alert: UnknownCA".to_owned());
+        assert_eq!(pinned.hosts().len(), 1);
+        assert!(pinned.contains("example.com"));
+    }
+
+    #[test]
+    fn it_expires_hosts() {
+        let pinned = PinnedHosts::new().with_ttl(Duration::ZERO);
+        pinned.insert("example.com".to_owned(), "alert: UnknownCA".to_owned());
+        assert!(pinned.hosts().is_empty());
+        assert!(!pinned.contains("example.com"));
+    }
+}
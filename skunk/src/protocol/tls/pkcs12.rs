@@ -0,0 +1,183 @@
+//! Reading and writing PKCS#12 files ([RFC 7292][1]).
+//!
+//! This is a thin wrapper around [`p12_keystore`], that converts between its
+//! types and the rustls types we use everywhere else. Written files store the
+//! key encrypted with PBES2 (PBKDF2 with SHA-256 and AES-256-CBC). Files
+//! using the legacy RC2 or 3DES encryption can be read too.
+//!
+//! [1]: https://datatracker.ietf.org/doc/html/rfc7292
+
+use aws_lc_rs::digest;
+use p12_keystore::{
+    Certificate,
+    KeyStore,
+    KeyStoreEntry,
+    PrivateKeyChain,
+};
+use rustls::pki_types::{
+    CertificateDer,
+    PrivateKeyDer,
+    PrivatePkcs8KeyDer,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid PKCS#12 file")]
+    Invalid(#[source] p12_keystore::error::Error),
+
+    #[error("wrong password or corrupted PKCS#12 file")]
+    Mac,
+
+    #[error("PKCS#12 file doesn't contain a private key")]
+    NoKey,
+
+    #[error("PKCS#12 file doesn't contain a certificate")]
+    NoCertificate,
+}
+
+impl From<p12_keystore::error::Error> for Error {
+    fn from(error: p12_keystore::error::Error) -> Self {
+        match error {
+            p12_keystore::error::Error::MacError(_) => Self::Mac,
+            error => Self::Invalid(error),
+        }
+    }
+}
+
+/// Certificate chain and private key read from a PKCS#12 file.
+#[derive(Debug)]
+pub struct Pkcs12 {
+    /// The certificate chain of the key, starting with the end-entity
+    /// certificate.
+    pub certs: Vec<CertificateDer<'static>>,
+
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Pkcs12 {
+    /// Parses a DER encoded PKCS#12 file, decrypting it with `password`.
+    ///
+    /// If the file contains multiple keys, the first one is used.
+    pub fn parse(der: &[u8], password: &str) -> Result<Self, Error> {
+        let key_store = KeyStore::from_pkcs12(der, password)?;
+        let (_, key_chain) = key_store.private_key_chain().ok_or(Error::NoKey)?;
+
+        let certs = key_chain
+            .chain()
+            .iter()
+            .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            return Err(Error::NoCertificate);
+        }
+
+        Ok(Self {
+            certs,
+            key: PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_chain.key().to_vec())),
+        })
+    }
+}
+
+/// Encodes `certs`, and optionally `key`, as a DER encoded PKCS#12 file,
+/// protected by `password`.
+///
+/// With a `key`, `certs` is its chain, starting with the end-entity
+/// certificate. Without a key, the certificates are stored as trusted
+/// certificates.
+pub fn encode(
+    certs: &[CertificateDer<'_>],
+    key: Option<&PrivateKeyDer<'_>>,
+    password: &str,
+) -> Result<Vec<u8>, Error> {
+    let certs = certs
+        .iter()
+        .map(|cert| Certificate::from_der(cert))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut key_store = KeyStore::new();
+    if let Some(key) = key {
+        let first = certs.first().ok_or(Error::NoCertificate)?;
+        // the key and its certificate are linked by a local key ID. like OpenSSL we use
+        // the SHA-1 hash of the certificate.
+        let local_key_id = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, first.as_der());
+        let alias = first.subject().to_owned();
+        key_store.add_entry(
+            &alias,
+            KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(
+                key.secret_der(),
+                local_key_id,
+                certs,
+            )),
+        );
+    }
+    else {
+        for cert in certs {
+            let alias = cert.subject().to_owned();
+            key_store.add_entry(&alias, KeyStoreEntry::Certificate(cert));
+        }
+    }
+
+    Ok(key_store.writer(password).write()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // created with:
+    // openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+    //     -subj /CN=client -keyout client.key -out client.pem
+    // openssl pkcs12 -export -inkey client.key -in client.pem -out client.p12 \
+    //     -passout pass:hunter2
+    const CLIENT_P12: &[u8] = include_bytes!("testdata/client.p12");
+    const CLIENT_PEM: &[u8] = include_bytes!("testdata/client.pem");
+
+    #[test]
+    fn it_reads_encrypted_files() {
+        let pkcs12 = Pkcs12::parse(CLIENT_P12, "hunter2").unwrap();
+        let expected = rustls_pemfile::certs(&mut &CLIENT_PEM[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(pkcs12.certs, expected);
+        assert!(matches!(pkcs12.key, PrivateKeyDer::Pkcs8(_)));
+        rcgen::KeyPair::try_from(&pkcs12.key).unwrap();
+    }
+
+    #[test]
+    fn it_reads_written_files() {
+        let pkcs12 = Pkcs12::parse(CLIENT_P12, "hunter2").unwrap();
+        let written = encode(&pkcs12.certs, Some(&pkcs12.key), "hunter3").unwrap();
+
+        let read = Pkcs12::parse(&written, "hunter3").unwrap();
+        assert_eq!(read.certs, pkcs12.certs);
+        assert_eq!(read.key.secret_der(), pkcs12.key.secret_der());
+    }
+
+    #[test]
+    fn it_writes_certificates_without_key() {
+        let pkcs12 = Pkcs12::parse(CLIENT_P12, "hunter2").unwrap();
+        let written = encode(&pkcs12.certs, None, "hunter3").unwrap();
+
+        let key_store = KeyStore::from_pkcs12(&written, "hunter3").unwrap();
+        let certs = key_store
+            .entries()
+            .map(|(_, entry)| entry.clone())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            certs.as_slice(),
+            [KeyStoreEntry::Certificate(cert)] if cert.as_der() == pkcs12.certs[0].as_ref()
+        ));
+        assert!(matches!(
+            Pkcs12::parse(&written, "hunter3"),
+            Err(Error::NoKey)
+        ));
+    }
+
+    #[test]
+    fn it_rejects_wrong_passwords() {
+        assert!(matches!(
+            Pkcs12::parse(CLIENT_P12, "hunter3"),
+            Err(Error::Mac)
+        ));
+    }
+}
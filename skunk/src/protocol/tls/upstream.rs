@@ -0,0 +1,561 @@
+//! Verification of the certificates presented by the servers we connect to.
+//!
+//! By default server certificates are verified with the natively installed
+//! root certificates. With [`UpstreamPolicies`] this can be changed per host,
+//! e.g. to trust an internal CA, pin a certificate, or accept any certificate.
+//! In any case the outcome is available as [`Verification`] from the
+//! connection.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use rustls::{
+    client::{
+        danger::{
+            HandshakeSignatureValid,
+            ServerCertVerified,
+            ServerCertVerifier,
+        },
+        WebPkiServerVerifier,
+    },
+    pki_types::{
+        CertificateDer,
+        ServerName,
+        UnixTime,
+    },
+    CertificateError,
+    ClientConfig,
+    DigitallySignedStruct,
+    RootCertStore,
+    SignatureScheme,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use super::{
+    cert_cache::Fingerprint,
+    client_auth::ClientCert,
+    native_certificates,
+    Error,
+};
+use crate::{
+    rule::regex::Regex,
+    util::hex,
+};
+
+/// How the certificate of a server is verified.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerifyMode {
+    /// Verify with the natively installed root certificates.
+    #[default]
+    Native,
+
+    /// Verify with the natively installed root certificates and the
+    /// certificates in [`UpstreamPolicy::ca_bundle`].
+    CaBundle,
+
+    /// Only accept certificates with one of the
+    /// [`UpstreamPolicy::fingerprints`]. The chain is not verified.
+    Fingerprint,
+
+    /// Accept any certificate. The connection is flagged with
+    /// [`Verification::Insecure`].
+    Insecure,
+}
+
+/// Verification settings for a server.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpstreamPolicy {
+    pub verify: VerifyMode,
+
+    /// PEM file with additional CA certificates, for [`VerifyMode::CaBundle`].
+    pub ca_bundle: Option<PathBuf>,
+
+    /// SHA-256 fingerprints of the accepted certificates, for
+    /// [`VerifyMode::Fingerprint`].
+    pub fingerprints: Vec<Fingerprint>,
+
+    /// Client certificate that is presented to servers that ask for one.
+    pub client_cert: Option<ClientCert>,
+}
+
+/// Verification settings per host.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UpstreamPolicies {
+    /// The policy for hosts that don't match any of [`hosts`](Self::hosts).
+    #[serde(flatten)]
+    pub default: UpstreamPolicy,
+
+    /// Policies for specific hosts. The first matching policy is used.
+    #[serde(default)]
+    pub hosts: Vec<HostUpstreamPolicy>,
+}
+
+/// Verification settings for hosts matching a regex.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostUpstreamPolicy {
+    pub host: Regex,
+
+    #[serde(flatten)]
+    pub policy: UpstreamPolicy,
+}
+
+/// Outcome of the verification of a server's certificate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "kebab-case")]
+pub enum Verification {
+    /// The certificate chain was verified.
+    Verified,
+
+    /// The certificate matched a pinned fingerprint.
+    Pinned { fingerprint: Fingerprint },
+
+    /// The certificate was accepted without verification. If the certificate
+    /// would have been rejected, `error` contains the reason.
+    Insecure { error: Option<String> },
+}
+
+impl Verification {
+    pub fn is_insecure(&self) -> bool {
+        matches!(self, Self::Insecure { .. })
+    }
+}
+
+/// Information about a certificate presented by a server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CertificateInfo {
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    pub serial: Option<String>,
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+    pub subject_alt_names: Vec<String>,
+    pub fingerprint: Fingerprint,
+
+    /// DER encoded certificate, hex-encoded.
+    pub der: String,
+}
+
+impl CertificateInfo {
+    /// Extracts information from `cert`. If the certificate can't be parsed,
+    /// only the fingerprint and DER encoding are set.
+    pub fn new(cert: &CertificateDer<'_>) -> Self {
+        let mut info = Self {
+            subject: None,
+            issuer: None,
+            serial: None,
+            not_before: None,
+            not_after: None,
+            subject_alt_names: vec![],
+            fingerprint: Fingerprint::of(cert),
+            der: hex::encode(cert),
+        };
+
+        if let Ok((_, parsed)) = x509_parser::parse_x509_certificate(cert) {
+            info.subject = Some(parsed.subject().to_string());
+            info.issuer = Some(parsed.issuer().to_string());
+            info.serial = Some(parsed.raw_serial_as_string());
+            info.not_before = Some(parsed.validity().not_before.to_string());
+            info.not_after = Some(parsed.validity().not_after.to_string());
+            if let Ok(Some(san)) = parsed.subject_alternative_name() {
+                info.subject_alt_names = san
+                    .value
+                    .general_names
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+            }
+        }
+
+        info
+    }
+
+    /// Extracts information from all certificates in `chain`.
+    pub fn chain(chain: &[CertificateDer<'_>]) -> Vec<Self> {
+        chain.iter().map(Self::new).collect()
+    }
+}
+
+/// [`UpstreamPolicies`] with the certificate verifiers built.
+#[derive(Clone, Debug)]
+pub(super) struct Verifiers {
+    default: Verifier,
+    hosts: Vec<(Regex, Verifier)>,
+}
+
+impl Verifiers {
+    pub fn new(policies: &UpstreamPolicies) -> Result<Self, Error> {
+        Ok(Self {
+            default: Verifier::new(&policies.default)?,
+            hosts: policies
+                .hosts
+                .iter()
+                .map(|policy| Ok((policy.host.clone(), Verifier::new(&policy.policy)?)))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    pub fn default(&self) -> &Verifier {
+        &self.default
+    }
+
+    pub fn for_host(&self, host: &str) -> &Verifier {
+        self.hosts
+            .iter()
+            .find(|(regex, _)| regex.is_match(host))
+            .map_or(&self.default, |(_, verifier)| verifier)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Verifier {
+    mode: VerifyMode,
+    webpki: Arc<WebPkiServerVerifier>,
+    client_config: Arc<ClientConfig>,
+    ip_address_client_config: Arc<ClientConfig>,
+}
+
+impl Verifier {
+    fn new(policy: &UpstreamPolicy) -> Result<Self, Error> {
+        let roots = match policy.verify {
+            VerifyMode::CaBundle => {
+                let path = policy.ca_bundle.as_ref().ok_or(Error::MissingCaBundle)?;
+                let mut roots = RootCertStore::clone(&*native_certificates()?);
+                let mut reader = BufReader::new(File::open(path)?);
+                let mut added = 0;
+                for cert in rustls_pemfile::certs(&mut reader) {
+                    roots.add(cert?)?;
+                    added += 1;
+                }
+                if added == 0 {
+                    return Err(Error::NoCertificate { path: path.clone() });
+                }
+                Arc::new(roots)
+            }
+            _ => native_certificates()?,
+        };
+        let webpki = WebPkiServerVerifier::builder(roots).build()?;
+
+        let verifier: Arc<dyn ServerCertVerifier> = match policy.verify {
+            VerifyMode::Native | VerifyMode::CaBundle => webpki.clone(),
+            VerifyMode::Fingerprint => {
+                Arc::new(PinFingerprints {
+                    fingerprints: policy.fingerprints.clone(),
+                    webpki: webpki.clone(),
+                })
+            }
+            VerifyMode::Insecure => Arc::new(AcceptAny(webpki.clone())),
+        };
+
+        let client_cert = policy
+            .client_cert
+            .as_ref()
+            .map(ClientCert::load)
+            .transpose()?;
+        let client_config = |verifier| {
+            let builder = ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(verifier);
+            let client_config = match &client_cert {
+                Some((certs, key)) => {
+                    builder.with_client_auth_cert(certs.clone(), key.clone_key())?
+                }
+                None => builder.with_no_client_auth(),
+            };
+            Ok::<_, Error>(Arc::new(client_config))
+        };
+
+        Ok(Self {
+            mode: policy.verify,
+            webpki,
+            client_config: client_config(verifier.clone())?,
+            ip_address_client_config: client_config(Arc::new(IgnoreServerName(verifier)))?,
+        })
+    }
+
+    /// The TLS client config. If `ignore_server_name` is set, the certificate
+    /// is not checked against the server name, since we connect by IP
+    /// address.
+    pub fn client_config(&self, ignore_server_name: bool) -> &Arc<ClientConfig> {
+        if ignore_server_name {
+            &self.ip_address_client_config
+        }
+        else {
+            &self.client_config
+        }
+    }
+
+    /// Determines the outcome of the verification for a connection that was
+    /// established with this verifier.
+    pub fn verification(
+        &self,
+        chain: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ignore_server_name: bool,
+    ) -> Verification {
+        match self.mode {
+            VerifyMode::Native | VerifyMode::CaBundle => Verification::Verified,
+            VerifyMode::Fingerprint => {
+                Verification::Pinned {
+                    fingerprint: chain
+                        .first()
+                        .map(Fingerprint::of)
+                        .expect("server certificate was pinned"),
+                }
+            }
+            VerifyMode::Insecure => {
+                // check whether the certificate would have been accepted.
+                let result = match chain.split_first() {
+                    Some((end_entity, intermediates)) => {
+                        let result = self.webpki.verify_server_cert(
+                            end_entity,
+                            intermediates,
+                            server_name,
+                            &[],
+                            UnixTime::now(),
+                        );
+                        if ignore_server_name {
+                            ignore_name_error(result)
+                        }
+                        else {
+                            result
+                        }
+                    }
+                    None => Err(rustls::Error::NoCertificatesPresented),
+                };
+                let error = result.err().map(|error| error.to_string());
+                if let Some(error) = &error {
+                    tracing::warn!(?server_name, %error, "Accepted invalid server certificate");
+                }
+                Verification::Insecure { error }
+            }
+        }
+    }
+}
+
+fn ignore_name_error(
+    result: Result<ServerCertVerified, rustls::Error>,
+) -> Result<ServerCertVerified, rustls::Error> {
+    match result {
+        Err(rustls::Error::InvalidCertificate(
+            CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+        )) => Ok(ServerCertVerified::assertion()),
+        result => result,
+    }
+}
+
+/// Certificate verifier that accepts certificates that are otherwise valid,
+/// but not for the server name.
+#[derive(Debug)]
+struct IgnoreServerName(Arc<dyn ServerCertVerifier>);
+
+impl ServerCertVerifier for IgnoreServerName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // the server name is only checked after the chain was verified.
+        ignore_name_error(self.0.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// Certificate verifier that accepts certificates with the given fingerprints.
+#[derive(Debug)]
+struct PinFingerprints {
+    fingerprints: Vec<Fingerprint>,
+    webpki: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for PinFingerprints {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.fingerprints.contains(&Fingerprint::of(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        }
+        else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/// Certificate verifier that accepts any certificate. The handshake signatures
+/// are still verified.
+#[derive(Debug)]
+struct AcceptAny(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{
+        CertificateParams,
+        KeyPair,
+    };
+
+    use super::*;
+
+    fn cert(name: &str) -> CertificateDer<'static> {
+        let params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().to_owned()
+    }
+
+    #[test]
+    fn it_accepts_pinned_certificates() {
+        let pinned = cert("example.com");
+        let mut roots = RootCertStore::empty();
+        roots.add(pinned.clone()).unwrap();
+        let verifier = PinFingerprints {
+            fingerprints: vec![Fingerprint::of(&pinned)],
+            webpki: WebPkiServerVerifier::builder(Arc::new(roots))
+                .build()
+                .unwrap(),
+        };
+        let server_name = ServerName::try_from("example.com").unwrap();
+
+        assert!(verifier
+            .verify_server_cert(&pinned, &[], &server_name, &[], UnixTime::now())
+            .is_ok());
+        assert!(verifier
+            .verify_server_cert(
+                &cert("example.com"),
+                &[],
+                &server_name,
+                &[],
+                UnixTime::now()
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn it_parses_policies() {
+        let policies: UpstreamPolicies = serde_yml::from_str(
+            r#"
+            hosts:
+              - host: "\\.staging\\."
+                verify: insecure
+              - host: "^internal\\."
+                verify: ca-bundle
+                ca_bundle: internal-ca.pem
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(policies.default.verify, VerifyMode::Native);
+        assert_eq!(policies.hosts[0].policy.verify, VerifyMode::Insecure);
+        assert_eq!(
+            policies.hosts[1].policy.ca_bundle,
+            Some(PathBuf::from("internal-ca.pem"))
+        );
+    }
+
+    #[test]
+    fn it_extracts_certificate_info() {
+        let info = CertificateInfo::new(&cert("example.com"));
+        assert_eq!(info.subject_alt_names, vec!["DNSName(example.com)"]);
+        assert!(info.not_before.is_some());
+    }
+}
@@ -0,0 +1,461 @@
+//! WebSocket protocol ([RFC 6455](https://www.rfc-editor.org/rfc/rfc6455)).
+//!
+//! This only implements the framing, which is what we need to inspect
+//! WebSocket connections after the HTTP/1.1 upgrade handshake. Frames are
+//! passed through as-is, i.e. fragmented messages are not reassembled and
+//! extensions (e.g. `permessage-deflate`) are not decoded. Compressed frames
+//! are therefore never treated as text.
+
+use std::fmt::Display;
+
+use bytes::{
+    Buf,
+    BufMut,
+    Bytes,
+    BytesMut,
+};
+use futures::Future;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
+
+/// Maximum payload length of a single frame that we accept.
+pub const MAX_PAYLOAD_LENGTH: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+
+    #[error("frame payload too large: {length} bytes")]
+    PayloadTooLarge { length: u64 },
+
+    #[error("control frame is invalid")]
+    InvalidControlFrame,
+}
+
+/// Frame opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl Opcode {
+    pub fn is_control(&self) -> bool {
+        u8::from(*self) & 0x8 != 0
+    }
+}
+
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
+        match value & 0xf {
+            0x0 => Self::Continuation,
+            0x1 => Self::Text,
+            0x2 => Self::Binary,
+            0x8 => Self::Close,
+            0x9 => Self::Ping,
+            0xa => Self::Pong,
+            value => Self::Reserved(value),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(value: Opcode) -> Self {
+        match value {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+            Opcode::Reserved(value) => value & 0xf,
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Continuation => write!(f, "continuation"),
+            Self::Text => write!(f, "text"),
+            Self::Binary => write!(f, "binary"),
+            Self::Close => write!(f, "close"),
+            Self::Ping => write!(f, "ping"),
+            Self::Pong => write!(f, "pong"),
+            Self::Reserved(value) => write!(f, "reserved({value:#x})"),
+        }
+    }
+}
+
+/// A WebSocket frame.
+///
+/// The payload is always stored unmasked. If the frame has a `mask`, it is
+/// applied when the frame is written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+
+    /// The `RSV1`, `RSV2` and `RSV3` bits, in the lower 3 bits.
+    pub rsv: u8,
+
+    pub opcode: Opcode,
+
+    pub mask: Option<[u8; 4]>,
+
+    pub payload: Bytes,
+}
+
+impl Frame {
+    /// Creates an unfragmented, unmasked frame.
+    pub fn new(opcode: Opcode, payload: impl Into<Bytes>) -> Self {
+        Self {
+            fin: true,
+            rsv: 0,
+            opcode,
+            mask: None,
+            payload: payload.into(),
+        }
+    }
+
+    /// Creates a text frame.
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::new(Opcode::Text, text.into())
+    }
+
+    /// Creates a binary frame.
+    pub fn binary(data: impl Into<Bytes>) -> Self {
+        Self::new(Opcode::Binary, data)
+    }
+
+    /// Returns whether the `RSV1` bit is set. With the `permessage-deflate`
+    /// extension this marks the frame as compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.rsv & 0b100 != 0
+    }
+
+    /// Returns the payload as text, if this is an uncompressed text frame with
+    /// valid UTF-8.
+    pub fn as_text(&self) -> Option<&str> {
+        (self.opcode == Opcode::Text && !self.is_compressed())
+            .then(|| std::str::from_utf8(&self.payload).ok())
+            .flatten()
+    }
+
+    /// Parses a frame from the start of `buf`.
+    ///
+    /// Returns `Ok(None)` if `buf` doesn't contain a full frame yet. Otherwise
+    /// the frame is removed from `buf`.
+    pub fn parse(buf: &mut BytesMut) -> Result<Option<Self>, Error> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buf[0] & 0x80 != 0;
+        let rsv = (buf[0] >> 4) & 0x7;
+        let opcode = Opcode::from(buf[0]);
+        let masked = buf[1] & 0x80 != 0;
+
+        let (length, mut header_length) = match buf[1] & 0x7f {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4)
+            }
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                let mut length = [0; 8];
+                length.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(length), 10)
+            }
+            length => (u64::from(length), 2),
+        };
+
+        if length > MAX_PAYLOAD_LENGTH {
+            return Err(Error::PayloadTooLarge { length });
+        }
+        if opcode.is_control() && (length > 125 || !fin) {
+            return Err(Error::InvalidControlFrame);
+        }
+        let length = length as usize;
+
+        let mask = if masked {
+            if buf.len() < header_length + 4 {
+                return Ok(None);
+            }
+            let mut mask = [0; 4];
+            mask.copy_from_slice(&buf[header_length..header_length + 4]);
+            header_length += 4;
+            Some(mask)
+        }
+        else {
+            None
+        };
+
+        if buf.len() < header_length + length {
+            buf.reserve(header_length + length - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(header_length);
+        let mut payload = buf.split_to(length);
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Some(Self {
+            fin,
+            rsv,
+            opcode,
+            mask,
+            payload: payload.freeze(),
+        }))
+    }
+
+    /// Writes the frame to `buf`.
+    pub fn write(&self, buf: &mut BytesMut) {
+        let length = self.payload.len();
+
+        buf.put_u8((u8::from(self.fin) << 7) | ((self.rsv & 0x7) << 4) | u8::from(self.opcode));
+
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        if length < 126 {
+            buf.put_u8(mask_bit | length as u8);
+        }
+        else if let Ok(length) = u16::try_from(length) {
+            buf.put_u8(mask_bit | 126);
+            buf.put_u16(length);
+        }
+        else {
+            buf.put_u8(mask_bit | 127);
+            buf.put_u64(length as u64);
+        }
+
+        if let Some(mask) = self.mask {
+            buf.put_slice(&mask);
+            let start = buf.len();
+            buf.put_slice(&self.payload);
+            apply_mask(&mut buf[start..], mask);
+        }
+        else {
+            buf.put_slice(&self.payload);
+        }
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Reads frames from a stream.
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Reads the next frame. Returns `Ok(None)` if the stream was closed.
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        loop {
+            if let Some(frame) = Frame::parse(&mut self.buf)? {
+                return Ok(Some(frame));
+            }
+
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                else {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+        }
+    }
+}
+
+/// Writes a frame to a stream.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), Error> {
+    let mut buf = BytesMut::with_capacity(frame.payload.len() + 14);
+    frame.write(&mut buf);
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The direction in which a frame is sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The frame was sent by the client.
+    FromClient,
+
+    /// The frame was sent by the server.
+    FromServer,
+}
+
+/// Proxies WebSocket frames between `incoming` (the client) and `outgoing`
+/// (the server).
+///
+/// Each frame is passed to `f`, which returns the frames that should be
+/// forwarded in its place. It can return the frame unchanged, modify it, drop
+/// it by returning no frames, or inject additional frames.
+///
+/// Frames sent to the server must be masked. Frames without a mask that are
+/// forwarded to the server, e.g. injected frames, get a random mask.
+pub async fn proxy<I, O, F, Fut>(incoming: I, outgoing: O, f: F) -> Result<(), crate::Error>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(Direction, Frame) -> Fut,
+    Fut: Future<Output = Result<Vec<Frame>, crate::Error>>,
+{
+    let (incoming_read, incoming_write) = tokio::io::split(incoming);
+    let (outgoing_read, outgoing_write) = tokio::io::split(outgoing);
+
+    tokio::try_join!(
+        forward(Direction::FromClient, incoming_read, outgoing_write, &f),
+        forward(Direction::FromServer, outgoing_read, incoming_write, &f),
+    )?;
+
+    Ok(())
+}
+
+async fn forward<R, W, F, Fut>(
+    direction: Direction,
+    reader: R,
+    mut writer: W,
+    f: &F,
+) -> Result<(), crate::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(Direction, Frame) -> Fut,
+    Fut: Future<Output = Result<Vec<Frame>, crate::Error>>,
+{
+    let mut reader = FrameReader::new(reader);
+
+    while let Some(frame) = reader.read_frame().await? {
+        for mut frame in f(direction, frame).await? {
+            match direction {
+                Direction::FromClient => {
+                    frame.mask.get_or_insert_with(rand::random);
+                }
+                Direction::FromServer => frame.mask = None,
+            }
+            write_frame(&mut writer, &frame).await?;
+        }
+    }
+
+    writer.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_masked_frames() {
+        let frame = Frame {
+            mask: Some([1, 2, 3, 4]),
+            ..Frame::text("Hello World")
+        };
+
+        let mut buf = BytesMut::new();
+        frame.write(&mut buf);
+        assert_ne!(&buf[6..], b"Hello World");
+
+        let parsed = Frame::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(parsed, frame);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn it_parses_extended_payload_lengths() {
+        let frame = Frame::binary(vec![0x42; 1000]);
+
+        let mut buf = BytesMut::new();
+        frame.write(&mut buf);
+        assert_eq!(buf[1], 126);
+
+        let mut partial = BytesMut::from(&buf[..100]);
+        assert!(Frame::parse(&mut partial).unwrap().is_none());
+
+        let parsed = Frame::parse(&mut buf).unwrap().unwrap();
+        assert_eq!(parsed.payload.len(), 1000);
+    }
+
+    #[test]
+    fn it_doesnt_read_compressed_frames_as_text() {
+        let frame = Frame {
+            rsv: 0b100,
+            ..Frame::text("Hello World")
+        };
+        assert!(frame.is_compressed());
+        assert_eq!(frame.as_text(), None);
+    }
+
+    #[tokio::test]
+    async fn it_masks_injected_frames_with_random_masks() {
+        let (mut client, incoming) = tokio::io::duplex(1024);
+        let (outgoing, server) = tokio::io::duplex(1024);
+
+        let proxy = tokio::spawn(proxy(incoming, outgoing, |_, frame| {
+            async move { Ok(vec![frame, Frame::text("foo"), Frame::text("bar")]) }
+        }));
+
+        let frame = Frame {
+            mask: Some([1, 2, 3, 4]),
+            ..Frame::text("Hello World")
+        };
+        write_frame(&mut client, &frame).await.unwrap();
+
+        let mut server = FrameReader::new(server);
+        assert_eq!(server.read_frame().await.unwrap().unwrap(), frame);
+        let foo = server.read_frame().await.unwrap().unwrap();
+        let bar = server.read_frame().await.unwrap().unwrap();
+        assert_eq!(foo.as_text(), Some("foo"));
+        assert_eq!(bar.as_text(), Some("bar"));
+        assert!(foo.mask.is_some());
+        assert!(bar.mask.is_some());
+        assert_ne!(foo.mask, bar.mask);
+
+        drop(client);
+        drop(server);
+        proxy.abort();
+    }
+
+    #[test]
+    fn it_rejects_fragmented_control_frames() {
+        let mut buf = BytesMut::from(&[0x09, 0x00][..]);
+        assert!(matches!(
+            Frame::parse(&mut buf),
+            Err(Error::InvalidControlFrame)
+        ));
+    }
+}
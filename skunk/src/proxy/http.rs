@@ -1,56 +1,153 @@
+//! HTTP proxy implementation.
+//!
+//! This provides an explicit HTTP proxy, i.e. the kind of proxy you configure
+//! with `HTTP_PROXY` and `HTTPS_PROXY`. `CONNECT` requests are tunneled to the
+//! destination. Requests in absolute-form (e.g. `GET http://example.com/
+//! HTTP/1.1`) are rewritten to origin-form, such that the connection that is
+//! passed to the [`Proxy`] looks exactly like a connection that was made
+//! directly to the destination server.
+//!
+//! Only the first request on a connection is rewritten, so it's sent with
+//! `connection: close`. This way the client has to open a new connection for
+//! the next request, which might go to a different host.
+
 use std::{
-    convert::Infallible,
     net::SocketAddr,
-    sync::Arc,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
 };
 
-use http_body_util::Empty;
-use hyper::{
-    body::Incoming,
-    service::service_fn,
-    Method,
-    Request,
-    Response,
-    StatusCode,
+use bytes::{
+    BufMut,
+    Bytes,
+    BytesMut,
 };
-use hyper_util::rt::TokioIo;
-use tokio::net::{
-    TcpListener,
-    TcpStream,
+use skunk_util::error::ResultExt;
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
+        ReadBuf,
+    },
+    net::{
+        TcpListener,
+        TcpStream,
+    },
 };
 use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
+use url::{
+    Host,
+    Position,
+    Url,
+};
 
+use super::{
+    DestinationAddress,
+    Passthrough,
+    Proxy,
+};
 use crate::{
+    address::{
+        HostAddress,
+        TcpAddress,
+    },
     connect::{
         Connect,
         ConnectTcp,
     },
-    layer::{
-        Layer,
-        Passthrough,
-    },
-    util::error::ResultExt,
+    util::io::Rewind,
 };
 
-pub const DEFAULT_PORT: u16 = 8080;
+/// The default port to use for the server.
+pub const DEFAULT_PORT: u16 = 8888;
+
+/// Max size of a request head (request line and headers) we accept.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Max number of headers we accept in a request.
+const MAX_HEADERS: usize = 128;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("io error")]
     Io(#[from] std::io::Error),
 
-    #[error("hyper error")]
-    Hyper(#[from] hyper::Error),
+    #[error("invalid request")]
+    InvalidRequest(#[from] httparse::Error),
+
+    #[error("request head too large")]
+    RequestHeadTooLarge,
+
+    #[error("invalid request target: {target}")]
+    InvalidTarget { target: String },
+
+    #[error("unsupported scheme: {scheme}")]
+    UnsupportedScheme { scheme: String },
+}
+
+/// An incoming connection.
+///
+/// For `CONNECT` requests this is the tunneled connection. For requests in
+/// absolute-form, this is the connection with the first request rewritten to
+/// origin-form, and marked as the last request on the connection.
+#[derive(Debug)]
+pub struct Incoming {
+    inner: Rewind<TcpStream>,
+    destination_address: TcpAddress,
+}
+
+impl DestinationAddress for Incoming {
+    fn destination_address(&self) -> &TcpAddress {
+        &self.destination_address
+    }
 }
 
-pub struct Builder<C, L> {
+impl AsyncRead for Incoming {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Incoming {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Builder used to create a HTTP proxy server.
+pub struct Builder<C, P> {
     bind_address: SocketAddr,
     shutdown: CancellationToken,
-    #[cfg(feature = "tls")]
-    tls_client_config: Option<Arc<rustls::ClientConfig>>,
     connect: C,
-    layer: L,
+    proxy: P,
 }
 
 impl Default for Builder<ConnectTcp, Passthrough> {
@@ -58,137 +155,453 @@ impl Default for Builder<ConnectTcp, Passthrough> {
         Self {
             bind_address: ([127, 0, 0, 1], DEFAULT_PORT).into(),
             shutdown: Default::default(),
-            #[cfg(feature = "tls")]
-            tls_client_config: None,
             connect: ConnectTcp,
-            layer: Passthrough,
+            proxy: Passthrough,
         }
     }
 }
 
-impl<C, L> Builder<C, L> {
+impl<C, P> Builder<C, P> {
+    /// Specify a bind address. Defaults to `127.0.0.1:8888`.
     pub fn with_bind_address(mut self, bind_address: impl Into<SocketAddr>) -> Self {
         self.bind_address = bind_address.into();
         self
     }
 
+    /// Stop the server, when the `shutdown` token is cancelled.
     pub fn with_graceful_shutdown(mut self, shutdown: CancellationToken) -> Self {
         self.shutdown = shutdown;
         self
     }
 
-    #[cfg(feature = "tls")]
-    pub fn with_tls_client(
-        mut self,
-        tls_client_config: impl Into<Arc<rustls::ClientConfig>>,
-    ) -> Self {
-        self.tls_client_config = Some(tls_client_config.into());
-        self
-    }
-
-    pub fn with_connect<D>(self, connect: D) -> Builder<D, L> {
+    /// Specify how connections to the destination are established. Defaults
+    /// to [`ConnectTcp`].
+    pub fn with_connect<D>(self, connect: D) -> Builder<D, P> {
         Builder {
             bind_address: self.bind_address,
             shutdown: self.shutdown,
-            #[cfg(feature = "tls")]
-            tls_client_config: self.tls_client_config,
             connect,
-            layer: self.layer,
+            proxy: self.proxy,
         }
     }
 
-    pub fn with_layer<M>(self, layer: M) -> Builder<C, M> {
+    /// Specify the [`Proxy`] that handles the connections. Defaults to
+    /// [`Passthrough`].
+    pub fn with_proxy<Q>(self, proxy: Q) -> Builder<C, Q> {
         Builder {
             bind_address: self.bind_address,
             shutdown: self.shutdown,
-            #[cfg(feature = "tls")]
-            tls_client_config: self.tls_client_config,
             connect: self.connect,
-            layer,
+            proxy,
         }
     }
 
+    /// Run the HTTP proxy server until the shutdown token is cancelled.
     pub async fn serve(self) -> Result<(), Error>
     where
-        C: Connect + Clone + Send + 'static,
-        L: for<'s, 't> Layer<&'s mut (), &'t mut <C as Connect>::Connection>
-            + Clone
-            + Send
-            + 'static,
+        C: Connect + Clone + Send + Sync + 'static,
+        P: Proxy<Incoming, C::Connection> + Clone + Send + Sync + 'static,
     {
-        run(self.bind_address, self.shutdown, self.connect, self.layer).await?;
+        let listener = TcpListener::bind(self.bind_address).await?;
+
+        loop {
+            let (connection, address) = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                result = listener.accept() => result?,
+            };
+
+            let shutdown = self.shutdown.clone();
+            let connect = self.connect.clone();
+            let proxy = self.proxy.clone();
+            let span = tracing::info_span!("http-proxy", %address);
+
+            tokio::spawn(
+                async move {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => {},
+                        result = handle_connection(connection, connect, proxy) => {
+                            let _ = result.log_error();
+                        }
+                    }
+                }
+                .instrument(span),
+            );
+        }
+
         Ok(())
     }
 }
 
-async fn run<C, L>(
-    bind_address: SocketAddr,
-    shutdown: CancellationToken,
+/// Handle a single connection
+async fn handle_connection<C, P>(
+    mut connection: TcpStream,
     connect: C,
-    layer: L,
+    proxy: P,
 ) -> Result<(), Error>
 where
-    C: Connect + Clone + Send + 'static,
-    L: for<'s, 't> Layer<&'s mut (), &'t mut <C as Connect>::Connection> + Clone + Send + 'static,
+    C: Connect,
+    P: Proxy<Incoming, C::Connection>,
 {
-    let listener = TcpListener::bind(bind_address).await?;
+    let (head, rest) = read_request_head(&mut connection).await?;
 
-    loop {
-        tokio::select! {
-            result = listener.accept() => {
-                let (connection, address) = result?;
-                let shutdown = shutdown.clone();
-                let connect = connect.clone();
-                let layer = layer.clone();
-                let span = tracing::info_span!("http-proxy", ?address);
-
-                tokio::spawn(async move {
-                    tokio::select!{
-                        result = handle_connection(connection, connect, layer) => {
-                            let _ = result.log_error();
-                        },
-                        _ = shutdown.cancelled() => {},
-                    }
-                }.instrument(span));
-            },
-            _ = shutdown.cancelled() => {},
+    let target = match Target::from_request_head(&head) {
+        Ok(target) => target,
+        Err(e) => {
+            send_error_response(&mut connection, "400 Bad Request").await?;
+            return Err(e);
+        }
+    };
+
+    let outgoing = match connect.connect(&target.destination_address).await {
+        Ok(outgoing) => outgoing,
+        Err(e) => {
+            send_error_response(&mut connection, "502 Bad Gateway").await?;
+            return Err(e.into());
         }
+    };
+
+    let buf = if head.is_connect() {
+        connection
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        connection.flush().await?;
+        rest
     }
+    else {
+        // put the rewritten request head in front of whatever the client already sent
+        // after it.
+        let mut buf = BytesMut::new();
+        head.write_origin_form(&target, &mut buf);
+        buf.put(rest);
+        buf.freeze()
+    };
+
+    let incoming = Incoming {
+        inner: Rewind::new(connection, buf),
+        destination_address: target.destination_address,
+    };
+
+    let _ = proxy.proxy(incoming, outgoing).await.log_error();
+
+    Ok(())
 }
 
-async fn handle_connection<C, L>(connection: TcpStream, connect: C, layer: L) -> Result<(), Error>
-where
-    C: Connect + Clone,
-    L: for<'s, 't> Layer<&'s mut (), &'t mut <C as Connect>::Connection> + Clone,
-{
-    hyper::server::conn::http1::Builder::new()
-        .serve_connection(
-            TokioIo::new(connection),
-            service_fn(move |request: Request<Incoming>| {
-                let _connect = connect.clone();
-                let _layer = layer.clone();
-                async move {
-                    match request.method() {
-                        &Method::CONNECT => {
-                            tokio::spawn(async move {
-                                let _upgraded = hyper::upgrade::on(request).await?;
-                                Ok::<(), Error>(())
-                            });
-
-                            let response = Response::builder()
-                                .status(StatusCode::SWITCHING_PROTOCOLS)
-                                .body(Empty::<&[u8]>::new())
-                                .expect("build invalid response");
-
-                            Ok::<_, Infallible>(response)
-                        }
-                        _ => {
-                            todo!();
-                        }
-                    }
-                }
-            }),
+async fn send_error_response(connection: &mut TcpStream, status: &str) -> Result<(), Error> {
+    connection
+        .write_all(
+            format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .as_bytes(),
         )
         .await?;
+    connection.shutdown().await?;
     Ok(())
 }
+
+/// The parsed request line and headers of the first request on a connection.
+#[derive(Debug)]
+struct RequestHead {
+    method: String,
+    target: String,
+    version: u8,
+    headers: Vec<(String, Bytes)>,
+}
+
+impl RequestHead {
+    fn is_connect(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+    }
+
+    fn header(&self, name: &str) -> Option<&Bytes> {
+        self.headers
+            .iter()
+            .find_map(|(key, value)| key.eq_ignore_ascii_case(name).then_some(value))
+    }
+
+    /// Writes the request head with the request target in origin-form.
+    ///
+    /// Hop-by-hop headers that are only meant for the proxy are removed, and
+    /// the connection is marked to be closed after this request. Other options
+    /// in the `connection` header (e.g. `upgrade`) are kept.
+    fn write_origin_form(&self, target: &Target, buf: &mut BytesMut) {
+        let path = target.path.as_deref().unwrap_or("/");
+        buf.put_slice(format!("{} {path} HTTP/1.{}\r\n", self.method, self.version).as_bytes());
+
+        if self.header("host").is_none() {
+            buf.put_slice(format!("host: {}\r\n", target.destination_address).as_bytes());
+        }
+
+        let mut connection_options = vec![];
+
+        for (name, value) in &self.headers {
+            if name.eq_ignore_ascii_case("connection") {
+                connection_options.extend(
+                    value
+                        .split(|c| *c == b',')
+                        .map(<[u8]>::trim_ascii)
+                        .filter(|option| {
+                            !option.is_empty()
+                                && !option.eq_ignore_ascii_case(b"keep-alive")
+                                && !option.eq_ignore_ascii_case(b"close")
+                        }),
+                );
+                continue;
+            }
+            if name.eq_ignore_ascii_case("proxy-connection")
+                || name.eq_ignore_ascii_case("proxy-authorization")
+                || name.eq_ignore_ascii_case("keep-alive")
+            {
+                continue;
+            }
+            buf.put_slice(name.as_bytes());
+            buf.put_slice(b": ");
+            buf.put_slice(value);
+            buf.put_slice(b"\r\n");
+        }
+
+        buf.put_slice(b"connection: ");
+        for option in connection_options {
+            buf.put_slice(option);
+            buf.put_slice(b", ");
+        }
+        buf.put_slice(b"close\r\n");
+
+        buf.put_slice(b"\r\n");
+    }
+}
+
+/// Reads the request head from the connection.
+///
+/// Returns the parsed request head, and any bytes that were read after it.
+async fn read_request_head(connection: &mut TcpStream) -> Result<(RequestHead, Bytes), Error> {
+    let mut buf = BytesMut::with_capacity(4096);
+
+    loop {
+        if connection.read_buf(&mut buf).await? == 0 {
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+
+        if let httparse::Status::Complete(head_length) = request.parse(&buf)? {
+            let head = RequestHead {
+                method: request.method.unwrap_or_default().to_owned(),
+                target: request.path.unwrap_or_default().to_owned(),
+                version: request.version.unwrap_or(1),
+                headers: request
+                    .headers
+                    .iter()
+                    .map(|header| (header.name.to_owned(), Bytes::copy_from_slice(header.value)))
+                    .collect(),
+            };
+            let rest = buf.split_off(head_length).freeze();
+            return Ok((head, rest));
+        }
+
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Err(Error::RequestHeadTooLarge);
+        }
+    }
+}
+
+/// Destination of a proxy request.
+#[derive(Debug)]
+struct Target {
+    destination_address: TcpAddress,
+
+    /// Path and query in origin-form. This is `None` for `CONNECT` requests.
+    path: Option<String>,
+}
+
+impl Target {
+    fn from_request_head(head: &RequestHead) -> Result<Self, Error> {
+        let invalid_target = || {
+            Error::InvalidTarget {
+                target: head.target.clone(),
+            }
+        };
+
+        if head.is_connect() {
+            // the target is in authority-form (i.e. `host:port`). we let `Url` parse this,
+            // so that IPv6 addresses are handled correctly.
+            let url =
+                Url::parse(&format!("https://{}", head.target)).map_err(|_| invalid_target())?;
+            let host = url.host().ok_or_else(invalid_target)?;
+            let port = url.port_or_known_default().ok_or_else(invalid_target)?;
+
+            Ok(Self {
+                destination_address: TcpAddress::new(host_address(host), port),
+                path: None,
+            })
+        }
+        else {
+            // the target must be in absolute-form (i.e. `http://host:port/path?query`).
+            let url = Url::parse(&head.target).map_err(|_| invalid_target())?;
+            if url.scheme() != "http" {
+                return Err(Error::UnsupportedScheme {
+                    scheme: url.scheme().to_owned(),
+                });
+            }
+            let host = url.host().ok_or_else(invalid_target)?;
+            let port = url.port_or_known_default().ok_or_else(invalid_target)?;
+
+            Ok(Self {
+                destination_address: TcpAddress::new(host_address(host), port),
+                path: Some(url[Position::BeforePath..Position::AfterQuery].to_owned()),
+            })
+        }
+    }
+}
+
+fn host_address(host: Host<&str>) -> HostAddress {
+    match host {
+        Host::Domain(domain) => HostAddress::DnsName(domain.to_owned()),
+        Host::Ipv4(ip_address) => ip_address.into(),
+        Host::Ipv6(ip_address) => ip_address.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{
+        Ipv4Addr,
+        Ipv6Addr,
+    };
+
+    use super::*;
+
+    fn request_head(method: &str, target: &str, headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            version: 1,
+            headers: headers
+                .iter()
+                .map(|(name, value)| ((*name).to_owned(), Bytes::copy_from_slice(value.as_bytes())))
+                .collect(),
+        }
+    }
+
+    fn parse_target(method: &str, target: &str) -> Result<Target, Error> {
+        Target::from_request_head(&request_head(method, target, &[]))
+    }
+
+    #[test]
+    fn it_parses_authority_form_connect_targets() {
+        let target = parse_target("CONNECT", "example.com:8443").unwrap();
+        assert_eq!(
+            target.destination_address,
+            TcpAddress::new(HostAddress::DnsName("example.com".to_owned()), 8443)
+        );
+        assert_eq!(target.path, None);
+
+        // the port defaults to 443.
+        let target = parse_target("CONNECT", "example.com").unwrap();
+        assert_eq!(target.destination_address.port, 443);
+    }
+
+    #[test]
+    fn it_parses_ipv6_literals() {
+        let target = parse_target("CONNECT", "[::1]:443").unwrap();
+        assert_eq!(
+            target.destination_address,
+            TcpAddress::new(Ipv6Addr::LOCALHOST.into(), 443)
+        );
+
+        let target = parse_target("GET", "http://[2001:db8::1]/").unwrap();
+        assert_eq!(
+            target.destination_address,
+            TcpAddress::new("2001:db8::1".parse::<Ipv6Addr>().unwrap().into(), 80)
+        );
+    }
+
+    #[test]
+    fn it_parses_absolute_form_targets() {
+        let target = parse_target("GET", "http://127.0.0.1:8080/foo?bar=baz#fragment").unwrap();
+        assert_eq!(
+            target.destination_address,
+            TcpAddress::new(Ipv4Addr::LOCALHOST.into(), 8080)
+        );
+        assert_eq!(target.path.as_deref(), Some("/foo?bar=baz"));
+
+        // the port defaults to 80, and the path to `/`.
+        let target = parse_target("GET", "http://example.com").unwrap();
+        assert_eq!(target.destination_address.port, 80);
+        assert_eq!(target.path.as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn it_rejects_other_schemes() {
+        assert!(matches!(
+            parse_target("GET", "ftp://example.com/"),
+            Err(Error::UnsupportedScheme { scheme }) if scheme == "ftp"
+        ));
+    }
+
+    #[test]
+    fn it_rejects_origin_form_targets() {
+        assert!(matches!(
+            parse_target("GET", "/index.html"),
+            Err(Error::InvalidTarget { .. })
+        ));
+    }
+
+    fn origin_form(head: &RequestHead) -> String {
+        let target = Target::from_request_head(head).unwrap();
+        let mut buf = BytesMut::new();
+        head.write_origin_form(&target, &mut buf);
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn it_writes_origin_form() {
+        let head = request_head(
+            "GET",
+            "http://example.com/index.html",
+            &[
+                ("Host", "example.com"),
+                ("Proxy-Connection", "keep-alive"),
+                ("Proxy-Authorization", "Basic Zm9vOmJhcg=="),
+                ("Connection", "keep-alive"),
+                ("Keep-Alive", "timeout=5"),
+                ("Accept", "*/*"),
+            ],
+        );
+        assert_eq!(
+            origin_form(&head),
+            "GET /index.html HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Accept: */*\r\n\
+             connection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn it_adds_a_missing_host_header() {
+        let head = request_head("GET", "http://example.com:8080/", &[]);
+        assert_eq!(
+            origin_form(&head),
+            "GET / HTTP/1.1\r\nhost: example.com:8080\r\nconnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn it_keeps_other_connection_options() {
+        let head = request_head(
+            "GET",
+            "http://example.com/chat",
+            &[
+                ("Host", "example.com"),
+                ("Connection", "keep-alive, Upgrade"),
+                ("Upgrade", "websocket"),
+            ],
+        );
+        assert_eq!(
+            origin_form(&head),
+            "GET /chat HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Upgrade: websocket\r\n\
+             connection: Upgrade, close\r\n\r\n"
+        );
+    }
+}
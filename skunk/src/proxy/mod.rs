@@ -1,7 +1,7 @@
 //! Proxy implementations.
 
-//#[cfg(feature = "http")]
-//pub mod http;
+#[cfg(feature = "http")]
+pub mod http;
 #[cfg(feature = "pcap")]
 pub mod pcap;
 #[cfg(feature = "socks")]
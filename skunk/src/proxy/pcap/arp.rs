@@ -32,6 +32,7 @@ use super::{
 pub use crate::protocol::inet::arp::Packet;
 use crate::protocol::inet::{
     arp::Operation,
+    ethernet,
     MacAddress,
 };
 
@@ -52,13 +53,30 @@ pub struct Sender {
 }
 
 impl Sender {
-    pub async fn send(&self, _packet: &Packet) -> Result<(), SendError> {
-        //self.sock_tx.send(packet).await
-        // todo: don't just send the ARP packet! we need to wrap it into an Ethernet
-        // frame too lol
-        //todo!();
-        tracing::debug!("TODO: implement arp::Sender::send");
-        Ok(())
+    pub async fn send(&self, packet: &Packet) -> Result<(), SendError> {
+        // requests for unknown hardware addresses are broadcast.
+        let destination = if packet.target_hardware_address.is_unspecified() {
+            MacAddress::BROADCAST
+        }
+        else {
+            packet.target_hardware_address
+        };
+
+        let frame = ethernet::Frame {
+            header: ethernet::Header {
+                destination,
+                source: packet.sender_hardware_address,
+                vlan_tags: SmallVec::new(),
+                ether_type: ethernet::EtherType::ARP,
+            },
+            payload: packet.clone(),
+            frame_check_sequence: ethernet::FrameCheckSequence::Absent,
+        };
+
+        self.sock_tx.send(&frame).await.map_err(|error| {
+            tracing::warn!(?error, "Failed to send ARP packet");
+            SendError
+        })
     }
 }
 
@@ -1,137 +1,93 @@
-/// A minimal (not complete) DHCP server implementation.
-///
-/// # Notes
-///
-/// - [RFC 8910](https://datatracker.ietf.org/doc/html/rfc8910)
-/// - [inject arp](https://github.com/bluecatengineering/dora/blob/master/dora-core/src/server/ioctl.rs)
+//! A minimal (not complete) DHCP server for the virtual network.
+//!
+//! # Notes
+//!
+//! - [RFC 2131](https://datatracker.ietf.org/doc/html/rfc2131)
+//! - [RFC 8910](https://datatracker.ietf.org/doc/html/rfc8910)
+
 use std::{
     collections::{
+        hash_map::Entry,
         BTreeSet,
         HashMap,
     },
     fmt::Debug,
-    io::Write,
-    net::Ipv4Addr,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+    },
     ops::RangeInclusive,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
-use bytes::Bytes;
-pub use dhcproto::v4::{
-    CLIENT_PORT,
-    SERVER_PORT,
-};
-use dhcproto::{
-    v4::{
-        DecodeError,
-        DhcpOption,
-        HType,
-        Message,
-        MessageType,
-        Opcode,
-        OptionCode,
+use byst::{
+    io::{
+        ReaderExt,
+        Write,
+        Writer,
+        WriterExt,
     },
-    Decodable,
-    Encodable,
-    Encoder,
-};
-use futures::Future;
-use indexmap::{
-    map::Entry,
-    IndexMap,
-};
-use tokio::{
-    io::ReadBuf,
-    net::UdpSocket,
+    Buf,
+    Bytes,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
 
 use super::{
-    Interface,
+    udp,
+    AddressTriple,
+    NetworkConfig,
+    VirtualNetwork,
+};
+use crate::protocol::inet::{
+    dhcp::{
+        options::{
+            self,
+            DhcpMessageType,
+        },
+        Flags,
+        Message,
+        Opcode,
+        OptionCode,
+        Options,
+    },
     MacAddress,
 };
 
-const BUF_SIZE: usize = 1024;
-const LEASE_TIME: u32 = 24 * 60 * 60;
-
-#[derive(Debug, thiserror::Error)]
-#[error("dhcp error")]
-pub enum Error {
-    Io(#[from] std::io::Error),
-    InvalidRequest(#[from] InvalidRequest),
-    DecodeError(#[from] DecodeError),
-    Send(#[from] super::packet::SendError),
-}
-
-#[derive(Debug, thiserror::Error)]
-#[error("invalid request")]
-pub enum InvalidRequest {
-    #[error("DHCPREQUEST without IP address")]
-    NoRequestedIpAddress,
-}
-
-pub struct Packet(pub dhcproto::v4::Message);
-
-impl Packet {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
-        Ok(Self(Message::from_bytes(bytes)?))
-    }
-
-    pub fn to_bytes(&self) -> Result<Vec<u8>, super::packet::EncodeError> {
-        // since we need to allocate a buffer here anyway, we might as well return it.
-        let mut buf = Vec::with_capacity(512);
-        let mut encoder = Encoder::new(&mut buf);
-        self.0
-            .encode(&mut encoder)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-        Ok(buf)
-    }
-}
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
 
-impl WritePacket for Packet {
-    fn write_packet(&self, mut writer: impl Write) -> Result<(), super::packet::EncodeError> {
-        // allocating here is less than ideal :(
-        writer.write_all(&self.to_bytes()?)?;
-        Ok(())
-    }
-}
+const LEASE_TIME: u32 = 24 * 60 * 60;
+const DOMAIN_NAME: &str = "skunk.local";
+const INTERFACE_MTU: u16 = 1500;
 
-/// Run a DHCP server on the given interface.
+/// Runs a DHCP server on the virtual network.
+///
+/// The server answers with the address [`NetworkConfig::dhcp_server`] and
+/// hands out leases from [`NetworkConfig::pool`].
 pub async fn run(
-    interface: &Interface,
-    shutdown: CancellationToken,
+    network: VirtualNetwork,
     network_config: NetworkConfig,
-) -> Result<(), Error> {
+    shutdown: CancellationToken,
+) {
     let span = tracing::info_span!("dhcpd");
     tracing::debug!(parent: &span, port = %SERVER_PORT, "starting DHCP server");
 
     tokio::select! {
-        result = async move {
-            Server::new(interface, network_config).await?
-                .serve().await?;
-            Ok::<(), Error>(())
-        }.instrument(span) => result?,
+        _ = async move {
+            Server::new(&network, network_config).await.serve().await;
+        }.instrument(span) => {},
         _ = shutdown.cancelled() => {}
     };
-
-    Ok(())
-}
-
-macro_rules! get_opt {
-    ($message:expr, $opt:ident) => {{
-        if let Some(DhcpOption::$opt(value)) = $message.opts().get(OptionCode::$opt) {
-            Some(value)
-        }
-        else {
-            None
-        }
-    }};
 }
 
 /// A socket-agnostic DHCP service implementation.
 ///
-/// This implementation only supports the most basic transactions (`DHCPOFFER`
-/// and `DHCPREQUEST`).
+/// This implementation only supports the most basic transactions
+/// (`DHCPDISCOVER`, `DHCPREQUEST`, `DHCPDECLINE` and `DHCPRELEASE`).
 pub struct Service {
     network_config: NetworkConfig,
     leases: Leases,
@@ -151,188 +107,201 @@ impl Service {
         &self.network_config
     }
 
-    pub async fn handle_message(
-        &mut self,
-        packet: &Packet,
-        sender: impl Sender,
-    ) -> Result<(), Error> {
-        let message = &packet.0;
-
-        if message.opcode() != Opcode::BootRequest
-            || message.htype() != HType::Eth
-            || message.hlen() != 6
-        {
+    /// Handles a message from a client and returns the reply, if any.
+    pub fn handle_message(&mut self, message: &Message, options: &Options) -> Option<Reply> {
+        if message.op != Opcode::BOOTREQUEST {
             tracing::warn!("received invalid dhcp request");
-            return Ok(());
+            return None;
         }
 
-        tracing::debug!(message = ?MessageDebug(&message), "received");
+        let Some(hardware_address) = message.client_hardware_address()
+        else {
+            tracing::warn!(htype = ?message.htype, hlen = message.hlen, "received dhcp request with unsupported hardware address");
+            return None;
+        };
 
-        if let Some(message_type) = get_opt!(message, MessageType) {
-            match message_type {
-                MessageType::Discover => {
-                    self.handle_discover(message, sender).await?;
-                }
-                MessageType::Request => {
-                    self.handle_request(message, sender).await?;
-                }
-                _ => {
-                    tracing::debug!(r#type = ?message_type, "unhandled message");
+        tracing::debug!(message = ?MessageDebug(message, options), "received");
+
+        let Some(message_type) = options.get::<DhcpMessageType>()
+        else {
+            tracing::debug!("received BOOTP request without DHCP message type");
+            return None;
+        };
+
+        let client_identifier = ClientIdentifier::from_message(message, options);
+
+        match *message_type {
+            DhcpMessageType::DHCPDISCOVER => {
+                self.handle_discover(message, options, &client_identifier, hardware_address)
+            }
+            DhcpMessageType::DHCPREQUEST => {
+                self.handle_request(message, options, client_identifier, hardware_address)
+            }
+            DhcpMessageType::DHCPDECLINE => {
+                if let Some(requested) = options.get::<options::RequestedIpAddress>() {
+                    tracing::warn!(ip_address = %requested.0, "client declined address");
+                    self.leases.decline(&client_identifier, requested.0);
                 }
+                None
+            }
+            DhcpMessageType::DHCPRELEASE => {
+                self.leases.release(&client_identifier, message.ciaddr);
+                None
+            }
+            _ => {
+                tracing::debug!(r#type = ?message_type, "unhandled message");
+                None
             }
         }
-
-        Ok(())
     }
 
-    async fn handle_discover(
+    fn handle_discover(
         &mut self,
         message: &Message,
-        mut sender: impl Sender,
-    ) -> Result<(), Error> {
-        let requested_ip_address = get_opt!(message, RequestedIpAddress).copied();
-
-        let target = SendTo::from_message(&message);
-
-        if let Some(offer) = self.leases.get_offer(
-            ClientIdentifier::from_message(&message),
-            requested_ip_address,
-        ) {
-            let mut offer_reply = self.create_reply(&message, MessageType::Offer);
-            offer_reply.set_yiaddr(offer.ip_address);
-            sender.send(&Packet(offer_reply), target).await?;
-
-            let ack = self.create_reply(&message, MessageType::Ack);
-            sender.send(&Packet(ack), target).await?;
-        }
+        options: &Options,
+        client_identifier: &ClientIdentifier,
+        hardware_address: MacAddress,
+    ) -> Option<Reply> {
+        let requested_ip_address = options.get::<options::RequestedIpAddress>().map(|o| o.0);
+
+        let Some(ip_address) = self
+            .leases
+            .get_offer(client_identifier, requested_ip_address)
         else {
-            let nack = self.create_reply(&message, MessageType::Nak);
-            sender.send(&Packet(nack), target).await?;
-        }
-
-        Ok(())
+            // we're not supposed to answer with a DHCPNAK here, so the client will just
+            // time out.
+            tracing::warn!("address pool exhausted");
+            return None;
+        };
+
+        Some(self.create_reply(
+            message,
+            options,
+            DhcpMessageType::DHCPOFFER,
+            ip_address,
+            hardware_address,
+        ))
     }
 
-    async fn handle_request(
+    fn handle_request(
         &mut self,
         message: &Message,
-        mut sender: impl Sender,
-    ) -> Result<(), Error> {
-        if let Some(server_identifier) = get_opt!(message, ServerIdentifier).copied() {
-            if server_identifier != self.network_config.dhcp_server {
-                // not for us.
-                return Ok(());
+        options: &Options,
+        client_identifier: ClientIdentifier,
+        hardware_address: MacAddress,
+    ) -> Option<Reply> {
+        if let Some(server_identifier) = options.get::<options::ServerIdentifier>() {
+            if server_identifier.0 != self.network_config.dhcp_server {
+                // the client accepted an offer from another server.
+                self.leases.release(&client_identifier, None);
+                return None;
             }
         }
 
-        let requested_ip_address = get_opt!(message, RequestedIpAddress)
-            .copied()
-            .ok_or_else(|| InvalidRequest::NoRequestedIpAddress)?;
-
-        let target = SendTo::from_message(&message);
-
-        if let Some(lease) = self.leases.request_lease(
-            ClientIdentifier::from_message(&message),
-            message.chaddr().try_into().unwrap(),
-            requested_ip_address,
-        ) {
-            let lease_ip_address = lease.ip_address;
-            let mut ack = self.create_reply(&message, MessageType::Ack);
-            ack.set_yiaddr(lease_ip_address);
-            let opts = ack.opts_mut();
-            opts.insert(DhcpOption::AddressLeaseTime(LEASE_TIME));
-            opts.insert(DhcpOption::SubnetMask(
-                self.network_config.subnet.full_netmask(),
-            ));
-            opts.insert(DhcpOption::Router(vec![self.network_config.router]));
-            opts.insert(DhcpOption::DomainNameServer(
-                self.network_config.dns_servers.clone(),
-            ));
-            sender.send(&Packet(ack), target).await?;
+        // if the client is renewing or rebinding its lease, the address is in `ciaddr`.
+        let requested_ip_address = options
+            .get::<options::RequestedIpAddress>()
+            .map(|o| o.0)
+            .or_else(|| (!message.ciaddr.is_unspecified()).then_some(message.ciaddr));
+
+        let lease = requested_ip_address.and_then(|requested_ip_address| {
+            self.leases
+                .request_lease(client_identifier, hardware_address, requested_ip_address)
+        });
+
+        if let Some(lease) = lease {
+            let ip_address = lease.ip_address;
+            Some(self.create_reply(
+                message,
+                options,
+                DhcpMessageType::DHCPACK,
+                ip_address,
+                hardware_address,
+            ))
         }
         else {
-            let nack = self.create_reply(&message, MessageType::Nak);
-            sender.send(&Packet(nack), target).await?;
+            tracing::debug!(?requested_ip_address, "rejecting request");
+            Some(self.create_nak(message))
         }
-
-        Ok(())
     }
 
-    fn create_reply(&self, request: &Message, message_type: MessageType) -> Message {
-        let mut reply = Message::default();
-        reply.set_opcode(Opcode::BootReply);
-        reply.set_htype(HType::Eth);
-        reply.set_xid(request.xid());
-        reply.set_siaddr(self.network_config.router);
-        reply.set_chaddr(request.chaddr());
-
-        let opts = reply.opts_mut();
-        opts.insert(DhcpOption::MessageType(message_type));
-        opts.insert(DhcpOption::ServerIdentifier(
-            self.network_config.dhcp_server,
+    fn create_reply(
+        &self,
+        request: &Message,
+        request_options: &Options,
+        message_type: DhcpMessageType,
+        ip_address: Ipv4Addr,
+        hardware_address: MacAddress,
+    ) -> Reply {
+        let mut message = reply_message(request);
+        message.yiaddr = ip_address;
+        if message_type == DhcpMessageType::DHCPACK {
+            message.ciaddr = request.ciaddr;
+        }
+
+        let mut options = Options::default();
+        options.insert(message_type);
+        options.insert(options::ServerIdentifier(self.network_config.dhcp_server));
+        options.insert(options::IpAddressLeaseTime(LEASE_TIME));
+        options.insert(options::SubnetMask(
+            self.network_config.subnet.full_netmask(),
+        ));
+        options.insert(options::Gateway(vec![self.network_config.router]));
+        options.insert(options::DomainNameServer(
+            self.network_config.dns_servers.clone(),
         ));
 
-        for request in get_opt!(request, ParameterRequestList)
-            .map(|v| v.into_iter())
+        for code in request_options
+            .get::<options::ParameterRequestList>()
             .into_iter()
-            .flatten()
+            .flat_map(|list| &list.0)
         {
-            match request {
-                OptionCode::SubnetMask => {
-                    opts.insert(DhcpOption::SubnetMask(
-                        self.network_config.subnet.full_netmask(),
-                    ));
-                }
-                OptionCode::Router => {
-                    opts.insert(DhcpOption::Router(vec![self.network_config.router]));
-                }
-                OptionCode::DomainNameServer => {
-                    opts.insert(DhcpOption::DomainNameServer(
-                        self.network_config.dns_servers.clone(),
-                    ));
-                }
-                OptionCode::AddressLeaseTime => {
-                    opts.insert(DhcpOption::AddressLeaseTime(LEASE_TIME));
-                }
-                OptionCode::DomainName => {
-                    opts.insert(DhcpOption::DomainName("skunk.local".to_owned()));
+            if options.contains(*code) {
+                continue;
+            }
+
+            match *code {
+                OptionCode::DOMAIN_NAME => {
+                    options.insert(options::DomainName(DOMAIN_NAME.to_owned()));
                 }
-                OptionCode::InterfaceMtu => {
-                    opts.insert(DhcpOption::InterfaceMtu(1500));
+                OptionCode::INTERFACE_MTU => {
+                    options.insert(options::InterfaceMtu(INTERFACE_MTU));
                 }
-                OptionCode::BroadcastAddr => {
-                    opts.insert(DhcpOption::BroadcastAddr(
+                OptionCode::BROADCAST_ADDRESS => {
+                    options.insert(options::BroadcastAddress(
                         self.network_config.subnet.broadcast_address(),
                     ));
                 }
-                OptionCode::CaptivePortal => {
-                    // ignore these
-                }
                 _ => {
-                    tracing::debug!("client requested unavailable parameter: {request:?}");
+                    tracing::trace!(?code, "client requested unavailable parameter");
                 }
             }
         }
 
-        reply
+        Reply {
+            to: SendTo::for_reply(request, ip_address, hardware_address),
+            message,
+            options,
+        }
     }
-}
 
-pub trait Sender {
-    fn send(
-        &mut self,
-        response: &Packet,
-        to: SendTo,
-    ) -> impl Future<Output = Result<(), super::packet::SendError>>;
-}
+    fn create_nak(&self, request: &Message) -> Reply {
+        let mut options = Options::default();
+        options.insert(DhcpMessageType::DHCPNAK);
+        options.insert(options::ServerIdentifier(self.network_config.dhcp_server));
 
-impl<T: Sender> Sender for &mut T {
-    fn send(
-        &mut self,
-        response: &Packet,
-        to: SendTo,
-    ) -> impl Future<Output = Result<(), super::packet::SendError>> {
-        (*self).send(response, to)
+        let to = if request.giaddr.is_unspecified() {
+            SendTo::Broadcast
+        }
+        else {
+            SendTo::Relay(request.giaddr)
+        };
+
+        Reply {
+            message: reply_message(request),
+            options,
+            to,
+        }
     }
 }
 
@@ -345,230 +314,447 @@ impl Debug for Service {
     }
 }
 
-/// A standalone DHCP server using Tokio's [`UdpSocket`].
+fn reply_message(request: &Message) -> Message {
+    Message {
+        op: Opcode::BOOTREPLY,
+        htype: request.htype,
+        hlen: request.hlen,
+        hops: 0,
+        xid: request.xid,
+        secs: 0,
+        flags: request.flags,
+        ciaddr: Ipv4Addr::UNSPECIFIED,
+        yiaddr: Ipv4Addr::UNSPECIFIED,
+        siaddr: Ipv4Addr::UNSPECIFIED,
+        giaddr: request.giaddr,
+        chaddr: request.chaddr,
+        sname: [0; 64],
+        file: [0; 128],
+        options: (),
+    }
+}
+
+/// A reply to a DHCP message.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub message: Message,
+    pub options: Options,
+    pub to: SendTo,
+}
+
+/// Where to send a reply to.
+#[derive(Clone, Copy, Debug)]
+pub enum SendTo {
+    Relay(Ipv4Addr),
+    Client {
+        ip_address: Ipv4Addr,
+        hardware_address: MacAddress,
+    },
+    Broadcast,
+}
+
+impl SendTo {
+    /// Determines where to send a `DHCPOFFER` or `DHCPACK` to, as described in
+    /// [RFC 2131 section 4.1][1].
+    ///
+    /// [1]: https://datatracker.ietf.org/doc/html/rfc2131#section-4.1
+    fn for_reply(request: &Message, yiaddr: Ipv4Addr, hardware_address: MacAddress) -> Self {
+        if !request.giaddr.is_unspecified() {
+            Self::Relay(request.giaddr)
+        }
+        else if !request.ciaddr.is_unspecified() {
+            Self::Client {
+                ip_address: request.ciaddr,
+                hardware_address,
+            }
+        }
+        else if Flags::from_bits_retain(request.flags).contains(Flags::BROADCAST) {
+            Self::Broadcast
+        }
+        else {
+            // since we send raw ethernet frames, we can send the reply directly to the
+            // client's hardware address, even though it doesn't have an IP address yet.
+            Self::Client {
+                ip_address: yiaddr,
+                hardware_address,
+            }
+        }
+    }
+
+    /// Destination address for a reply to a request that was received from
+    /// `source`.
+    fn destination(&self, source: &AddressTriple) -> AddressTriple {
+        match self {
+            Self::Relay(ip_address) => {
+                AddressTriple {
+                    mac_address: source.mac_address,
+                    ip_address: (*ip_address).into(),
+                    port: SERVER_PORT,
+                }
+            }
+            Self::Client {
+                ip_address,
+                hardware_address,
+            } => {
+                AddressTriple {
+                    mac_address: *hardware_address,
+                    ip_address: (*ip_address).into(),
+                    port: CLIENT_PORT,
+                }
+            }
+            Self::Broadcast => {
+                AddressTriple {
+                    mac_address: MacAddress::BROADCAST,
+                    ip_address: Ipv4Addr::BROADCAST.into(),
+                    port: CLIENT_PORT,
+                }
+            }
+        }
+    }
+}
+
+/// A DHCP server on the [`VirtualNetwork`].
 #[derive(Debug)]
 pub struct Server {
-    socket: UdpSocket,
+    socket: udp::Socket,
+    hardware_address: MacAddress,
     service: Service,
-    buf: Vec<u8>,
 }
 
 impl Server {
-    pub async fn new(interface: &Interface, network_config: NetworkConfig) -> Result<Self, Error> {
-        let socket = UdpSocket::bind((Ipv4Addr::BROADCAST, SERVER_PORT)).await?;
-        socket.bind_device(Some(interface.name().as_bytes()))?;
-        socket.set_broadcast(true)?;
+    pub async fn new(network: &VirtualNetwork, network_config: NetworkConfig) -> Self {
+        let hardware_address = network.interface.hardware_address();
+
+        // this also makes sure that ARP requests for the server address are answered.
+        let host = network
+            .clone()
+            .host(hardware_address, network_config.dhcp_server.into())
+            .await;
+        let socket = host
+            .udp_socket(Some((Ipv4Addr::UNSPECIFIED.into(), SERVER_PORT)))
+            .await;
 
-        let buf = Vec::with_capacity(BUF_SIZE);
-
-        let service = Service::new(network_config);
-
-        Ok(Self {
+        Self {
             socket,
-            service,
-            buf,
-        })
+            hardware_address,
+            service: Service::new(network_config),
+        }
     }
 
-    pub async fn serve(mut self) -> Result<(), Error> {
-        loop {
-            assert!(self.buf.len() >= BUF_SIZE);
-            let mut read_buf = ReadBuf::new(&mut self.buf);
-
-            let (_, client_address) = self.socket.recv_buf_from(&mut read_buf).await?;
-            if client_address.port() != CLIENT_PORT {
-                tracing::warn!(
-                    port = client_address.port(),
-                    "received message from unusual port"
-                );
+    pub async fn serve(mut self) {
+        while let Some(packet) = self.socket.receive().await {
+            let Some((message, options)) = parse_message(&packet.data)
+            else {
+                tracing::debug!(source = ?packet.source, "received invalid dhcp message");
                 continue;
-            }
+            };
 
-            let request = Packet::from_bytes(read_buf.filled())?;
-
-            self.service
-                .handle_message(
-                    &request,
-                    ServerSender {
-                        socket: &self.socket,
-                        buf: &mut self.buf,
-                    },
-                )
-                .await?;
+            if let Some(reply) = self.service.handle_message(&message, &options) {
+                self.send(&reply, &packet.source).await;
+            }
         }
     }
-}
 
-struct ServerSender<'a> {
-    socket: &'a UdpSocket,
-    buf: &'a mut Vec<u8>,
+    async fn send(&self, reply: &Reply, request_source: &AddressTriple) {
+        tracing::debug!(message = ?MessageDebug(&reply.message, &reply.options), to = ?reply.to, "sending");
+
+        let source = AddressTriple {
+            mac_address: self.hardware_address,
+            ip_address: IpAddr::V4(self.service.network_config.dhcp_server),
+            port: SERVER_PORT,
+        };
+        let destination = reply.to.destination(request_source);
+
+        let options = reply.options.to_vec();
+        let payload = Payload {
+            message: &reply.message,
+            options: &options,
+        };
+
+        // errors are already logged by the sender, and the client will retry anyway.
+        let _ = self
+            .socket
+            .send(
+                source,
+                destination,
+                payload,
+                Message::LENGTH + options.len(),
+            )
+            .await;
+    }
 }
 
-impl<'a> Sender for ServerSender<'a> {
-    async fn send(
-        &mut self,
-        response: &Packet,
-        to: SendTo,
-    ) -> Result<(), super::packet::SendError> {
-        let mut encoder = Encoder::new(self.buf);
-        response
-            .0
-            .encode(&mut encoder)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-
-        tracing::debug!(message = ?MessageDebug(&response.0), ?to, "sending");
-
-        self.socket
-            .send_to(encoder.buffer_filled(), to.to_socket_address())
-            .await?;
-        Ok(())
-    }
+fn parse_message(data: &Bytes) -> Option<(Message, Options)> {
+    let mut reader = data.reader();
+    let message = reader.read::<Message>().ok()?;
+    let options = reader.read::<Options>().ok()?;
+    Some((message, options))
 }
 
-#[derive(Clone, Copy, Debug)]
-pub enum SendTo {
-    Relay(Ipv4Addr),
-    Client(Ipv4Addr),
-    Broadcast,
+/// A message with pre-encoded options.
+struct Payload<'a> {
+    message: &'a Message,
+    options: &'a [u8],
 }
 
-impl SendTo {
-    fn from_message(message: &Message) -> Self {
-        if !message.giaddr().is_unspecified() {
-            Self::Relay(message.giaddr())
-        }
-        else if !message.ciaddr().is_unspecified() {
-            Self::Client(message.ciaddr())
-        }
-        else {
-            // ideally we would check the broadcast bit here. but it's kind of difficult to
-            // send an UDP packet to a mac address.
-            Self::Broadcast
-        }
-    }
+impl<'a, W: Writer> Write<W, ()> for Payload<'a>
+where
+    Message: Write<W, (), Error = W::Error>,
+{
+    type Error = W::Error;
 
-    pub fn to_socket_address(&self) -> (Ipv4Addr, u16) {
-        match self {
-            Self::Relay(ip_address) => (*ip_address, SERVER_PORT),
-            Self::Client(ip_address) => (*ip_address, CLIENT_PORT),
-            Self::Broadcast => (Ipv4Addr::BROADCAST, CLIENT_PORT),
-        }
+    fn write(&self, writer: &mut W, _context: ()) -> Result<(), Self::Error> {
+        writer.write(self.message)?;
+        writer.write_buf(self.options)?;
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 struct Leases {
-    leases: IndexMap<ClientIdentifier, Lease>,
-    by_mac_address: HashMap<MacAddress, usize>,
-    by_ip_address: HashMap<Ipv4Addr, usize>,
+    leases: HashMap<ClientIdentifier, Lease>,
     available: BTreeSet<Ipv4Addr>,
 }
 
 impl Leases {
     pub fn new(pool: RangeInclusive<Ipv4Addr>) -> Self {
         let available: BTreeSet<Ipv4Addr> = pool.into_iter().collect();
-        let n = available.len();
         Self {
-            leases: IndexMap::with_capacity(n),
-            by_mac_address: HashMap::with_capacity(n),
-            by_ip_address: HashMap::with_capacity(n),
+            leases: HashMap::with_capacity(available.len()),
             available,
         }
     }
 
+    /// Returns the address we would offer to the client.
+    ///
+    /// This is the client's current lease, if it has one. Otherwise it's the
+    /// requested address, if it's available, or any available address.
     pub fn get_offer(
-        &self,
-        client_identifier: ClientIdentifier,
+        &mut self,
+        client_identifier: &ClientIdentifier,
         requested_ip_address: Option<Ipv4Addr>,
-    ) -> Option<Offer> {
+    ) -> Option<Ipv4Addr> {
+        if let Some(lease) = self.leases.get(client_identifier) {
+            return Some(lease.ip_address);
+        }
+
         if let Some(requested) = requested_ip_address {
-            if let Some(index) = self.by_ip_address.get(&requested) {
-                let (_, lease) = self.leases.get_index(*index).unwrap();
-                if lease.client_identifier == client_identifier {
-                    return Some(Offer {
-                        ip_address: requested,
-                    });
-                }
+            if self.available.contains(&requested) {
+                return Some(requested);
             }
         }
 
-        Some(Offer {
-            ip_address: self.available.iter().next().copied()?,
-        })
+        if self.available.is_empty() {
+            self.reclaim_expired();
+        }
+
+        self.available.first().copied()
     }
 
+    /// Assigns the requested address to the client.
+    ///
+    /// Returns `None` if the address is not available, or the client already
+    /// has a lease for a different address.
     pub fn request_lease(
         &mut self,
         client_identifier: ClientIdentifier,
-        mac_address: MacAddress,
+        hardware_address: MacAddress,
         requested_ip_address: Ipv4Addr,
     ) -> Option<&Lease> {
-        match self.leases.entry(client_identifier.clone()) {
+        if !self.available.contains(&requested_ip_address) {
+            self.reclaim_expired();
+        }
+
+        let expires_at = Instant::now() + Duration::from_secs(LEASE_TIME.into());
+
+        match self.leases.entry(client_identifier) {
             Entry::Occupied(entry) => {
                 let lease = entry.into_mut();
-                (lease.client_identifier == client_identifier).then_some(lease)
+                if lease.ip_address != requested_ip_address {
+                    return None;
+                }
+                lease.hardware_address = hardware_address;
+                lease.expires_at = expires_at;
+                Some(lease)
             }
             Entry::Vacant(entry) => {
-                let index = entry.index();
-                let lease = entry.insert(Lease {
-                    mac_address,
+                if !self.available.remove(&requested_ip_address) {
+                    return None;
+                }
+                Some(entry.insert(Lease {
+                    hardware_address,
                     ip_address: requested_ip_address,
-                    client_identifier,
-                });
-                self.by_ip_address.insert(requested_ip_address, index);
-                self.by_mac_address.insert(mac_address, index);
-                Some(lease)
+                    expires_at,
+                }))
+            }
+        }
+    }
+
+    /// Releases the client's lease and returns the address to the pool.
+    ///
+    /// If `ip_address` is given, the lease is only released if it is for this
+    /// address.
+    pub fn release(
+        &mut self,
+        client_identifier: &ClientIdentifier,
+        ip_address: impl Into<Option<Ipv4Addr>>,
+    ) {
+        let ip_address = ip_address.into();
+        if let Entry::Occupied(entry) = self.leases.entry(client_identifier.clone()) {
+            if ip_address.is_some() && ip_address != Some(entry.get().ip_address) {
+                return;
+            }
+            let lease = entry.remove();
+            self.available.insert(lease.ip_address);
+        }
+    }
+
+    /// The client found that the address is already in use, so we remove its
+    /// lease without returning the address to the pool.
+    pub fn decline(&mut self, client_identifier: &ClientIdentifier, ip_address: Ipv4Addr) {
+        if let Entry::Occupied(entry) = self.leases.entry(client_identifier.clone()) {
+            if entry.get().ip_address == ip_address {
+                entry.remove();
             }
         }
+        self.available.remove(&ip_address);
+    }
+
+    fn reclaim_expired(&mut self) {
+        let now = Instant::now();
+        let available = &mut self.available;
+        self.leases.retain(|_, lease| {
+            if lease.expires_at <= now {
+                available.insert(lease.ip_address);
+                false
+            }
+            else {
+                true
+            }
+        });
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 enum ClientIdentifier {
-    Bytes(Bytes),
-    MacAddress(MacAddress),
+    Opaque { kind: u8, data: Vec<u8> },
+    HardwareAddress(MacAddress),
 }
 
 impl ClientIdentifier {
-    pub fn from_bytes(bytes: impl Into<Bytes>) -> Self {
-        Self::Bytes(bytes.into())
-    }
-
-    pub fn from_mac_address(mac_address: MacAddress) -> Self {
-        Self::MacAddress(mac_address)
-    }
-
-    pub fn from_message(message: &Message) -> Self {
-        get_opt!(message, ClientIdentifier)
-            .map(|ident| Self::from_bytes(Bytes::copy_from_slice(ident)))
-            .unwrap_or_else(|| Self::from_mac_address(message.chaddr().try_into().unwrap()))
+    /// The client identifier option, or the client's hardware address, if it
+    /// didn't send one.
+    pub fn from_message(message: &Message, options: &Options) -> Self {
+        if let Some(client_identifier) = options.get::<options::ClientIdentifier>() {
+            Self::Opaque {
+                kind: client_identifier.0,
+                data: client_identifier.1.clone(),
+            }
+        }
+        else {
+            // we already checked that it's an ethernet address.
+            Self::HardwareAddress(message.client_hardware_address().unwrap_or_default())
+        }
     }
 }
 
 #[derive(Debug)]
 struct Lease {
-    mac_address: MacAddress,
-    ip_address: Ipv4Addr,
-    client_identifier: ClientIdentifier,
-}
-
-#[derive(Debug)]
-struct Offer {
+    hardware_address: MacAddress,
     ip_address: Ipv4Addr,
+    expires_at: Instant,
 }
 
-struct MessageDebug<'a>(&'a Message);
+struct MessageDebug<'a>(&'a Message, &'a Options);
 
 impl<'a> Debug for MessageDebug<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Message")
-            .field("type", get_opt!(&self.0, MessageType).unwrap())
-            .field("xid", &self.0.xid())
-            .field("ciaddr", &self.0.ciaddr())
-            .field("yiaddr", &self.0.yiaddr())
-            .field("siaddr", &self.0.siaddr())
-            .field("giaddr", &self.0.giaddr())
-            .field("chaddr", &MacAddress::try_from(self.0.chaddr()).unwrap())
+            .field("type", &self.1.get::<DhcpMessageType>())
+            .field("xid", &self.0.xid)
+            .field("ciaddr", &self.0.ciaddr)
+            .field("yiaddr", &self.0.yiaddr)
+            .field("siaddr", &self.0.siaddr)
+            .field("giaddr", &self.0.giaddr)
+            .field("chaddr", &self.0.client_hardware_address())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(hardware_address: MacAddress, message_type: DhcpMessageType) -> (Message, Options) {
+        let mut chaddr = [0; 16];
+        chaddr[..6].copy_from_slice(&hardware_address.0);
+
+        let message = Message {
+            op: Opcode::BOOTREQUEST,
+            htype: crate::protocol::inet::dhcp::HardwareType::ETHER,
+            hlen: 6,
+            hops: 0,
+            xid: 0x1234,
+            secs: 0,
+            flags: 0,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            sname: [0; 64],
+            file: [0; 128],
+            options: (),
+        };
+
+        let mut options = Options::default();
+        options.insert(message_type);
+
+        (message, options)
+    }
+
+    #[test]
+    fn it_leases_an_address() {
+        let network_config = NetworkConfig::default();
+        let mut service = Service::new(NetworkConfig::default());
+        let hardware_address = MacAddress([0x02, 0, 0, 0, 0, 1]);
+
+        let (message, options) = request(hardware_address, DhcpMessageType::DHCPDISCOVER);
+        let offer = service.handle_message(&message, &options).unwrap();
+        assert_eq!(
+            offer.options.get::<DhcpMessageType>(),
+            Some(&DhcpMessageType::DHCPOFFER)
+        );
+        assert!(network_config.pool.contains(&offer.message.yiaddr));
+        assert_eq!(
+            offer.options.get::<options::Gateway>(),
+            Some(&options::Gateway(vec![network_config.router]))
+        );
+        assert!(matches!(
+            offer.to,
+            SendTo::Client { ip_address, hardware_address: to } if ip_address == offer.message.yiaddr && to == hardware_address
+        ));
+
+        let (message, mut options) = request(hardware_address, DhcpMessageType::DHCPREQUEST);
+        options.insert(options::RequestedIpAddress(offer.message.yiaddr));
+        options.insert(options::ServerIdentifier(network_config.dhcp_server));
+        let ack = service.handle_message(&message, &options).unwrap();
+        assert_eq!(
+            ack.options.get::<DhcpMessageType>(),
+            Some(&DhcpMessageType::DHCPACK)
+        );
+        assert_eq!(ack.message.yiaddr, offer.message.yiaddr);
+
+        // another client can't have the same address.
+        let (message, mut options) = request(
+            MacAddress([0x02, 0, 0, 0, 0, 2]),
+            DhcpMessageType::DHCPREQUEST,
+        );
+        options.insert(options::RequestedIpAddress(offer.message.yiaddr));
+        let nak = service.handle_message(&message, &options).unwrap();
+        assert_eq!(
+            nak.options.get::<DhcpMessageType>(),
+            Some(&DhcpMessageType::DHCPNAK)
+        );
+    }
+}
@@ -0,0 +1,647 @@
+//! A DNS server for the virtual network.
+//!
+//! Queries from clients are passed to a [`Handler`], which decides how they're
+//! answered. Usually it forwards them to the [`Upstream`] resolver, but it can
+//! also point names at other addresses, or pretend they don't exist.
+//!
+//! Addresses from answers are remembered in the network's [`Hostnames`], so
+//! that TCP connections to them get the name as destination address.
+//!
+//! # Notes
+//!
+//! - Only queries with exactly one question are supported, which is what all
+//!   common resolvers send.
+//! - DNS over TCP isn't supported. Truncated answers are passed to the client
+//!   as-is.
+
+use std::{
+    future::Future,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    },
+    sync::Arc,
+    time::Duration,
+};
+
+use byst::{
+    io::{
+        BufReader,
+        ReaderExt,
+    },
+    Buf,
+    Bytes,
+};
+use smallvec::smallvec;
+use tokio::{
+    net::UdpSocket,
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use super::{
+    udp,
+    AddressTriple,
+    Hostnames,
+    NetworkConfig,
+    VirtualNetwork,
+};
+use crate::protocol::inet::{
+    dns::{
+        Flags,
+        Header,
+        Message,
+        Opcode,
+        Qr,
+        QuestionClass,
+        QuestionType,
+        RecordClass,
+        RecordData,
+        RecordType,
+        ResourceRecord,
+        ResponseCode,
+    },
+    udp::RawPayload,
+    MacAddress,
+};
+
+pub const SERVER_PORT: u16 = 53;
+
+/// How long we wait for the upstream resolver.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TTL for answers that we make up ourselves.
+const DEFAULT_TTL: u32 = 60;
+
+/// Runs a DNS server on the virtual network.
+///
+/// The server answers queries sent to any address on port 53, so clients
+/// with hard-coded DNS servers are served too. Replies are sent from the
+/// address the client sent the query to.
+pub async fn run<H>(
+    network: VirtualNetwork,
+    network_config: NetworkConfig,
+    upstream: Upstream,
+    handler: H,
+    shutdown: CancellationToken,
+) where
+    H: Handler + Send + Sync + 'static,
+{
+    let span = tracing::info_span!("dnsd");
+    tracing::debug!(parent: &span, port = %SERVER_PORT, ?upstream, "starting DNS server");
+
+    tokio::select! {
+        _ = async move {
+            Server::new(&network, &network_config, upstream, handler).await.serve().await;
+        }.instrument(span) => {},
+        _ = shutdown.cancelled() => {}
+    };
+}
+
+/// Trait for things that decide how DNS queries are answered.
+pub trait Handler {
+    /// Answers `query`. Use `resolver` to get the answer from the upstream
+    /// resolver.
+    fn handle(&self, query: Query, resolver: Resolver) -> impl Future<Output = Answer> + Send;
+}
+
+/// A [`Handler`] that answers all queries using the upstream resolver.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Forward;
+
+impl Handler for Forward {
+    async fn handle(&self, query: Query, resolver: Resolver) -> Answer {
+        resolver.resolve(&query).await
+    }
+}
+
+/// Create a [`Handler`] using a function or closure.
+pub fn fn_handler<F, Fut>(func: F) -> FnHandler<F>
+where
+    F: Fn(Query, Resolver) -> Fut,
+    Fut: Future<Output = Answer>,
+{
+    FnHandler { func }
+}
+
+/// A [`Handler`] created from a function or closure.
+#[derive(Copy, Clone)]
+pub struct FnHandler<F> {
+    func: F,
+}
+
+impl<F, Fut> Handler for FnHandler<F>
+where
+    F: Fn(Query, Resolver) -> Fut,
+    Fut: Future<Output = Answer> + Send,
+{
+    fn handle(&self, query: Query, resolver: Resolver) -> impl Future<Output = Answer> + Send {
+        (self.func)(query, resolver)
+    }
+}
+
+/// The resolver that queries are forwarded to.
+#[derive(Clone, Debug)]
+pub enum Upstream {
+    /// Forward queries to a DNS server.
+    Server(SocketAddr),
+
+    /// Resolve names with the resolver of the operating system. This only
+    /// answers `A` and `AAAA` queries.
+    System,
+}
+
+impl Upstream {
+    /// Uses the first name server from `/etc/resolv.conf`.
+    pub fn from_resolv_conf() -> Option<Self> {
+        let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+        resolv_conf.lines().find_map(|line| {
+            let mut parts = line.split_whitespace();
+            if parts.next()? != "nameserver" {
+                return None;
+            }
+            let ip_address: IpAddr = parts.next()?.parse().ok()?;
+            Some(Self::Server((ip_address, SERVER_PORT).into()))
+        })
+    }
+}
+
+impl Default for Upstream {
+    /// The name server from `/etc/resolv.conf`, or the system resolver if
+    /// there is none.
+    fn default() -> Self {
+        Self::from_resolv_conf().unwrap_or(Self::System)
+    }
+}
+
+/// A DNS query from a client on the virtual network.
+#[derive(Clone, Debug)]
+pub struct Query {
+    /// The address of the client.
+    pub client: IpAddr,
+
+    /// The queried name, without the trailing dot.
+    pub name: String,
+
+    pub query_type: QuestionType,
+
+    message: Message,
+    data: Bytes,
+}
+
+/// The answer to a [`Query`].
+#[derive(Clone, Debug)]
+pub struct Answer {
+    response_code: ResponseCode,
+    addresses: Vec<IpAddr>,
+    ttl: u32,
+
+    spoofed: bool,
+    response: Option<Vec<u8>>,
+}
+
+impl Answer {
+    /// An answer that points the queried name at `addresses`.
+    ///
+    /// Only the addresses that match the query type are sent to the client.
+    pub fn spoofed(addresses: Vec<IpAddr>) -> Self {
+        Self {
+            response_code: ResponseCode::NO_ERROR,
+            addresses,
+            ttl: DEFAULT_TTL,
+            spoofed: true,
+            response: None,
+        }
+    }
+
+    /// An answer that tells the client that the queried name doesn't exist.
+    pub fn name_error() -> Self {
+        Self::error(ResponseCode::NAME_ERROR, true)
+    }
+
+    fn error(response_code: ResponseCode, spoofed: bool) -> Self {
+        Self {
+            response_code,
+            addresses: vec![],
+            ttl: DEFAULT_TTL,
+            spoofed,
+            response: None,
+        }
+    }
+
+    pub fn response_code(&self) -> ResponseCode {
+        self.response_code
+    }
+
+    /// The addresses from the `A` and `AAAA` records of the answer.
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.addresses
+    }
+
+    /// The lowest TTL of the address records.
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// Whether this answer was made up, instead of coming from the upstream
+    /// resolver.
+    pub fn is_spoofed(&self) -> bool {
+        self.spoofed
+    }
+
+    fn to_vec(&self, query: &Query) -> Vec<u8> {
+        if let Some(response) = &self.response {
+            return response.clone();
+        }
+
+        let question = &query.message.questions[0];
+        let answers = self
+            .addresses
+            .iter()
+            .filter_map(|address| {
+                let (r#type, rdata) = match (query.query_type, address) {
+                    (QuestionType::A | QuestionType::ANY, IpAddr::V4(address)) => {
+                        (RecordType::A, RecordData::A { address: *address })
+                    }
+                    (QuestionType::AAAA | QuestionType::ANY, IpAddr::V6(address)) => {
+                        (RecordType::AAAA, RecordData::Aaaa { address: *address })
+                    }
+                    _ => return None,
+                };
+                Some(ResourceRecord {
+                    name: question.qname.clone(),
+                    r#type,
+                    class: RecordClass::IN,
+                    ttl: self.ttl.min(i32::MAX as u32) as i32,
+                    rdata,
+                })
+            })
+            .collect();
+
+        let message = Message {
+            header: Header {
+                transaction_id: query.message.header.transaction_id,
+                flags: Flags {
+                    qr: Qr::Reply,
+                    opcode: Opcode::QUERY,
+                    aa: false,
+                    tc: false,
+                    rd: query.message.header.flags.rd,
+                    ra: true,
+                    z: 0,
+                    rcode: self.response_code,
+                },
+                num_questions: 0,
+                num_answers: 0,
+                num_authority: 0,
+                num_additional: 0,
+            },
+            questions: smallvec![question.clone()],
+            answers,
+            authority: vec![],
+            additional: vec![],
+        };
+
+        message.to_vec()
+    }
+}
+
+/// Resolves queries with the [`Upstream`] resolver.
+#[derive(Clone, Debug)]
+pub struct Resolver {
+    upstream: Upstream,
+}
+
+impl Resolver {
+    pub fn new(upstream: Upstream) -> Self {
+        Self { upstream }
+    }
+
+    /// Resolves `query`.
+    ///
+    /// If the upstream resolver fails, this returns a `SERVFAIL` answer.
+    pub async fn resolve(&self, query: &Query) -> Answer {
+        let result = match &self.upstream {
+            Upstream::Server(address) => self.forward(query, *address).await,
+            Upstream::System => Ok(self.lookup(query).await),
+        };
+
+        result.unwrap_or_else(|error| {
+            tracing::debug!(name = %query.name, ?error, "upstream resolver failed");
+            Answer::error(ResponseCode::SERVER_FAILURE, false)
+        })
+    }
+
+    async fn forward(&self, query: &Query, address: SocketAddr) -> Result<Answer, std::io::Error> {
+        let bind_address: IpAddr = match address {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((bind_address, 0)).await?;
+        socket.connect(address).await?;
+        socket.send(&buf_to_vec(&query.data)).await?;
+
+        let receive = async {
+            let mut buf = vec![0; 0x10000];
+            loop {
+                let n_read = socket.recv(&mut buf).await?;
+                let response = buf[..n_read].to_vec();
+                if let Some(message) = parse_message(&Bytes::from(response.clone())) {
+                    if message.header.transaction_id == query.message.header.transaction_id
+                        && message.header.flags.qr == Qr::Reply
+                    {
+                        break Ok::<_, std::io::Error>((message, response));
+                    }
+                }
+            }
+        };
+        let (message, response) = tokio::time::timeout(UPSTREAM_TIMEOUT, receive).await??;
+
+        let (addresses, ttl) = message.answer_addresses();
+
+        Ok(Answer {
+            response_code: message.header.flags.rcode,
+            addresses,
+            ttl: ttl.unwrap_or(DEFAULT_TTL),
+            spoofed: false,
+            response: Some(response),
+        })
+    }
+
+    async fn lookup(&self, query: &Query) -> Answer {
+        let is_wanted: fn(&IpAddr) -> bool = match query.query_type {
+            QuestionType::A => IpAddr::is_ipv4,
+            QuestionType::AAAA => IpAddr::is_ipv6,
+            QuestionType::ANY => |_| true,
+            _ => {
+                // we can't answer this, so we tell the client there is no such data.
+                return Answer::error(ResponseCode::NO_ERROR, false);
+            }
+        };
+
+        match tokio::net::lookup_host((query.name.as_str(), 0)).await {
+            Ok(addresses) => {
+                let mut addresses = addresses
+                    .map(|address| address.ip())
+                    .filter(is_wanted)
+                    .collect::<Vec<_>>();
+                addresses.dedup();
+                Answer {
+                    response_code: ResponseCode::NO_ERROR,
+                    addresses,
+                    ttl: DEFAULT_TTL,
+                    spoofed: false,
+                    response: None,
+                }
+            }
+            Err(_) => Answer::error(ResponseCode::NAME_ERROR, false),
+        }
+    }
+}
+
+/// A DNS server on the [`VirtualNetwork`].
+#[derive(Debug)]
+pub struct Server<H> {
+    socket: udp::Socket,
+    hardware_address: MacAddress,
+    hostnames: Hostnames,
+    resolver: Resolver,
+    handler: Arc<H>,
+}
+
+impl<H> Server<H>
+where
+    H: Handler + Send + Sync + 'static,
+{
+    pub async fn new(
+        network: &VirtualNetwork,
+        network_config: &NetworkConfig,
+        upstream: Upstream,
+        handler: H,
+    ) -> Self {
+        let hardware_address = network.interface.hardware_address();
+
+        // make sure that ARP requests for the server addresses are answered.
+        for dns_server in &network_config.dns_servers {
+            network
+                .clone()
+                .host(hardware_address, (*dns_server).into())
+                .await;
+        }
+
+        let host = network
+            .clone()
+            .host(hardware_address, network_config.dhcp_server.into())
+            .await;
+        let socket = host
+            .udp_socket(Some((Ipv4Addr::UNSPECIFIED.into(), SERVER_PORT)))
+            .await;
+
+        Self {
+            socket,
+            hardware_address,
+            hostnames: network.hostnames().clone(),
+            resolver: Resolver::new(upstream),
+            handler: Arc::new(handler),
+        }
+    }
+
+    pub async fn serve(self) {
+        let (sender, receiver) = self.socket.split();
+        let Some(mut receiver) = receiver
+        else {
+            return;
+        };
+
+        // queries are answered concurrently, since the upstream resolver might take a
+        // while.
+        let mut join_set = JoinSet::new();
+
+        while let Some(packet) = receiver.receive().await {
+            while join_set.try_join_next().is_some() {}
+
+            let Some(query) = parse_query(&packet)
+            else {
+                tracing::debug!(source = ?packet.source, "received invalid dns query");
+                continue;
+            };
+
+            let sender = sender.clone();
+            let hostnames = self.hostnames.clone();
+            let resolver = self.resolver.clone();
+            let handler = self.handler.clone();
+            let source = AddressTriple {
+                mac_address: self.hardware_address,
+                ..packet.destination
+            };
+            let destination = packet.source;
+
+            join_set.spawn(async move {
+                tracing::debug!(name = %query.name, query_type = ?query.query_type, client = %query.client, "query");
+
+                let answer = handler.handle(query.clone(), resolver).await;
+                tracing::debug!(name = %query.name, ?answer.response_code, ?answer.addresses, spoofed = answer.spoofed, "answer");
+
+                let ttl = Duration::from_secs(answer.ttl.into());
+                for address in &answer.addresses {
+                    hostnames.insert(*address, query.name.clone(), ttl);
+                }
+
+                let response = answer.to_vec(&query);
+
+                // errors are already logged by the sender, and the client will retry anyway.
+                let _ = sender
+                    .send(source, destination, RawPayload(&response), response.len())
+                    .await;
+            });
+        }
+    }
+}
+
+fn parse_message(data: &Bytes) -> Option<Message> {
+    data.reader().read::<Message>().ok()
+}
+
+fn parse_query(packet: &udp::Packet) -> Option<Query> {
+    let message = parse_message(&packet.data)?;
+
+    if message.header.flags.qr != Qr::Query || message.header.flags.opcode != Opcode::QUERY {
+        return None;
+    }
+    let [question] = &message.questions[..]
+    else {
+        return None;
+    };
+    if question.qclass != QuestionClass::IN {
+        return None;
+    }
+
+    Some(Query {
+        client: packet.source.ip_address,
+        name: question.qname.as_str().to_owned(),
+        query_type: question.qtype,
+        data: packet.data.clone(),
+        message,
+    })
+}
+
+fn buf_to_vec(data: &Bytes) -> Vec<u8> {
+    let mut buf = vec![];
+    let mut reader = data.reader();
+    while let Some(chunk) = reader.peek_chunk() {
+        buf.extend(chunk);
+        reader.advance(chunk.len()).unwrap();
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::inet::mac_address;
+
+    /// Query for the `A` record of `example.com`, with recursion desired.
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65, 0x78,
+        0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    fn packet(data: &[u8]) -> udp::Packet {
+        udp::Packet {
+            source: AddressTriple {
+                mac_address: mac_address!("02:00:00:00:00:02"),
+                ip_address: Ipv4Addr::new(10, 0, 69, 2).into(),
+                port: 51234,
+            },
+            destination: AddressTriple {
+                mac_address: mac_address!("02:00:00:00:00:01"),
+                ip_address: Ipv4Addr::new(10, 0, 69, 1).into(),
+                port: SERVER_PORT,
+            },
+            data: Bytes::from(data.to_vec()),
+        }
+    }
+
+    fn query() -> Query {
+        parse_query(&packet(&QUERY)).unwrap()
+    }
+
+    #[test]
+    fn it_parses_a_query() {
+        let query = query();
+        assert_eq!(query.client, IpAddr::from(Ipv4Addr::new(10, 0, 69, 2)));
+        assert_eq!(query.name, "example.com");
+        assert_eq!(query.query_type, QuestionType::A);
+    }
+
+    #[test]
+    fn it_ignores_replies() {
+        let mut data = QUERY;
+        data[2] |= 0x80;
+        assert!(parse_query(&packet(&data)).is_none());
+    }
+
+    #[test]
+    fn it_ignores_queries_with_multiple_questions() {
+        let mut data = QUERY.to_vec();
+        data[5] = 2;
+        data.extend_from_slice(&QUERY[12..]);
+        assert!(parse_query(&packet(&data)).is_none());
+    }
+
+    #[test]
+    fn it_encodes_spoofed_answers() {
+        let query = query();
+        let answer = Answer::spoofed(vec![
+            Ipv4Addr::new(10, 0, 69, 1).into(),
+            Ipv6Addr::LOCALHOST.into(),
+        ]);
+        let message = parse_message(&Bytes::from(answer.to_vec(&query))).unwrap();
+
+        assert_eq!(message.header.transaction_id, 0x1234);
+        assert_eq!(message.header.flags.qr, Qr::Reply);
+        assert!(message.header.flags.rd);
+        assert!(message.header.flags.ra);
+        assert_eq!(message.header.flags.rcode, ResponseCode::NO_ERROR);
+        assert_eq!(message.questions.len(), 1);
+        assert_eq!(message.questions[0].qname.as_str(), "example.com");
+
+        // only the address matching the query type is sent.
+        let [record] = &message.answers[..]
+        else {
+            panic!("expected one answer: {:?}", message.answers);
+        };
+        assert_eq!(record.r#type, RecordType::A);
+        assert_eq!(record.ttl, DEFAULT_TTL as i32);
+        assert!(
+            matches!(record.rdata, RecordData::A { address } if address == Ipv4Addr::new(10, 0, 69, 1))
+        );
+    }
+
+    #[test]
+    fn it_encodes_name_errors() {
+        let query = query();
+        let message = parse_message(&Bytes::from(Answer::name_error().to_vec(&query))).unwrap();
+
+        assert_eq!(message.header.transaction_id, 0x1234);
+        assert_eq!(message.header.flags.qr, Qr::Reply);
+        assert_eq!(message.header.flags.rcode, ResponseCode::NAME_ERROR);
+        assert!(message.answers.is_empty());
+    }
+
+    #[test]
+    fn it_passes_upstream_responses_through() {
+        let response = vec![0x12, 0x34, 0x81, 0x80];
+        let answer = Answer {
+            response_code: ResponseCode::NO_ERROR,
+            addresses: vec![],
+            ttl: DEFAULT_TTL,
+            spoofed: false,
+            response: Some(response.clone()),
+        };
+        assert_eq!(answer.to_vec(&query()), response);
+    }
+}
@@ -1,9 +1,11 @@
 pub mod ap;
 pub mod arp;
-//pub mod dhcp;
+pub mod dhcp;
+pub mod dns;
 pub mod interface;
 mod os;
 pub mod socket;
+pub mod tcp;
 pub mod udp;
 
 use std::{
@@ -12,8 +14,12 @@ use std::{
     net::{
         IpAddr,
         Ipv4Addr,
+        SocketAddr,
+        SocketAddrV4,
     },
     ops::RangeInclusive,
+    sync::Arc,
+    time::Duration,
 };
 
 use byst::{
@@ -24,13 +30,18 @@ use byst::{
     Buf,
     Bytes,
 };
+use indexmap::IndexMap;
 use ip_network::Ipv4Network;
+use parking_lot::Mutex;
 use skunk_macros::{
     ipv4_address,
     ipv4_network,
 };
 use skunk_util::error::ResultExt;
-use tokio::sync::mpsc;
+use tokio::{
+    sync::mpsc,
+    time::Instant,
+};
 use tracing::Instrument;
 
 use self::{
@@ -40,13 +51,20 @@ use self::{
         ReceiveError,
     },
 };
-use crate::protocol::inet::{
-    ethernet,
-    ipv4,
-    MacAddress,
+use crate::{
+    address::{
+        HostAddress,
+        TcpAddress,
+    },
+    protocol::inet::{
+        self,
+        ethernet,
+        ipv4,
+        MacAddress,
+    },
 };
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct NetworkConfig {
     pub subnet: Ipv4Network,
     pub dhcp_server: Ipv4Addr,
@@ -74,10 +92,7 @@ pub enum Error {
     Io(#[from] std::io::Error),
     InvalidPacket(
         #[from]
-        ethernet::InvalidFrame<
-            End,
-            ethernet::AnyPayloadError<End, ipv4::AnyPayloadError<Infallible>>,
-        >,
+        ethernet::InvalidFrame<End, ethernet::AnyPayloadError<End, ipv4::AnyPayloadError<End>>>,
     ),
 }
 
@@ -96,6 +111,7 @@ pub struct VirtualNetwork {
     sock_tx: socket::Sender,
     interface: Interface,
     arp: arp::Service,
+    hostnames: Hostnames,
 }
 
 impl VirtualNetwork {
@@ -111,6 +127,8 @@ impl VirtualNetwork {
             },
         });
 
+        let hostnames = Hostnames::default();
+
         let reactor = Reactor {
             interface: interface.clone(),
             sock_tx: sock_tx.clone(),
@@ -118,6 +136,9 @@ impl VirtualNetwork {
             command_rx,
             arp_listener: arp_tx,
             udp_listeners: HashMap::new(),
+            tcp_listener: None,
+            tcp_connections: HashMap::new(),
+            hostnames: hostnames.clone(),
         };
 
         reactor.spawn();
@@ -127,6 +148,7 @@ impl VirtualNetwork {
             sock_tx,
             interface: interface.clone(),
             arp,
+            hostnames,
         })
     }
 
@@ -134,6 +156,25 @@ impl VirtualNetwork {
         self.command_tx.send(command).await.expect("Reactor died");
     }
 
+    /// Creates a listener for TCP connections from clients on the virtual
+    /// network.
+    ///
+    /// All connections are accepted, regardless of their destination address.
+    /// Only one listener can be active at a time. Creating a new one replaces
+    /// the previous one.
+    pub async fn tcp_listener(&self) -> tcp::Listener {
+        let (stream_tx, stream_rx) = mpsc::channel(16);
+        self.send_command(Command::RegisterTcpListener { stream_tx })
+            .await;
+        tcp::Listener { stream_rx }
+    }
+
+    /// The host names that clients on the network resolved with the [DNS
+    /// server][dns].
+    pub fn hostnames(&self) -> &Hostnames {
+        &self.hostnames
+    }
+
     pub async fn host(&mut self, hardware_address: MacAddress, ip_address: IpAddr) -> VirtualHost {
         self.arp.insert(ip_address, hardware_address, true).await;
         VirtualHost {
@@ -152,6 +193,9 @@ struct Reactor {
     command_rx: mpsc::Receiver<Command>,
     arp_listener: mpsc::Sender<arp::Packet>,
     udp_listeners: HashMap<(IpAddr, u16), mpsc::Sender<udp::Packet>>,
+    tcp_listener: Option<mpsc::Sender<tcp::TcpStream>>,
+    tcp_connections: HashMap<tcp::ConnectionKey, mpsc::Sender<tcp::Segment>>,
+    hostnames: Hostnames,
 }
 
 impl Reactor {
@@ -206,8 +250,17 @@ impl Reactor {
             ethernet::AnyPayload::Ipv4(ip_packet) => {
                 tracing::debug!("IPv4: {:#?}", ip_packet.header);
 
-                if let ipv4::AnyPayload::Udp(udp_packet) = ip_packet.payload {
-                    tracing::debug!("UDP: {:#?}", udp_packet.header);
+                match ip_packet.payload {
+                    ipv4::AnyPayload::Udp(udp_packet) => {
+                        tracing::debug!("UDP: {:#?}", udp_packet.header);
+                        self.handle_udp(&frame.header, &ip_packet.header, udp_packet);
+                    }
+                    ipv4::AnyPayload::Tcp(tcp_packet) => {
+                        tracing::debug!("TCP: {:#?}", tcp_packet.header);
+                        self.handle_tcp(frame.header.source, &ip_packet.header, tcp_packet)
+                            .await;
+                    }
+                    _ => {}
                 }
             }
             _ => {}
@@ -216,6 +269,131 @@ impl Reactor {
         Ok(())
     }
 
+    fn handle_udp(
+        &mut self,
+        ethernet_header: &ethernet::Header,
+        ip_header: &ipv4::Header,
+        packet: inet::udp::Packet,
+    ) {
+        let destination_port = packet.header.destination_port;
+        let Some(bind_address) = [
+            (
+                IpAddr::from(ip_header.destination_address),
+                destination_port,
+            ),
+            (IpAddr::from(Ipv4Addr::UNSPECIFIED), destination_port),
+        ]
+        .into_iter()
+        .find(|bind_address| self.udp_listeners.contains_key(bind_address))
+        else {
+            return;
+        };
+
+        let packet = udp::Packet {
+            source: AddressTriple {
+                mac_address: ethernet_header.source,
+                ip_address: ip_header.source_address.into(),
+                port: packet.header.source_port,
+            },
+            destination: AddressTriple {
+                mac_address: ethernet_header.destination,
+                ip_address: ip_header.destination_address.into(),
+                port: destination_port,
+            },
+            data: packet.payload,
+        };
+
+        match self.udp_listeners[&bind_address].try_send(packet) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::debug!(?bind_address, "UDP packet dropped");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                self.udp_listeners.remove(&bind_address);
+            }
+        }
+    }
+
+    async fn handle_tcp(
+        &mut self,
+        client_hardware_address: MacAddress,
+        ip_header: &ipv4::Header,
+        packet: inet::tcp::Packet,
+    ) {
+        let key = tcp::ConnectionKey {
+            client: SocketAddrV4::new(ip_header.source_address, packet.header.source_port),
+            server: SocketAddrV4::new(
+                ip_header.destination_address,
+                packet.header.destination_port,
+            ),
+        };
+        let segment = tcp::Segment::new(packet.header, packet.payload);
+
+        let segment = if let Some(segment_tx) = self.tcp_connections.get(&key) {
+            match segment_tx.try_send(segment) {
+                Ok(()) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    // the client will retransmit it.
+                    tracing::debug!(client = %key.client, server = %key.server, "TCP segment dropped");
+                    return;
+                }
+                Err(mpsc::error::TrySendError::Closed(segment)) => {
+                    self.tcp_connections.remove(&key);
+                    segment
+                }
+            }
+        }
+        else {
+            segment
+        };
+
+        let link = tcp::Link {
+            sock_tx: self.sock_tx.clone(),
+            hardware_address: self.interface.hardware_address(),
+            client_hardware_address,
+        };
+
+        let flags = segment.header.flags;
+        if flags.contains(inet::tcp::Flags::SYN) && !flags.contains(inet::tcp::Flags::ACK) {
+            if let Some(stream_tx) = &self.tcp_listener {
+                // if the client resolved the address with our DNS server, we know which host
+                // it wants to connect to.
+                let destination_address = match self.hostnames.get(&(*key.server.ip()).into()) {
+                    Some(hostname) => {
+                        TcpAddress::new(HostAddress::DnsName(hostname), key.server.port())
+                    }
+                    None => SocketAddr::from(key.server).into(),
+                };
+
+                let (connection, stream, segment_tx) =
+                    tcp::Connection::new(key, destination_address, link.clone(), &segment);
+
+                match stream_tx.try_send(stream) {
+                    Ok(()) => {
+                        tracing::debug!(client = %key.client, server = %key.server, "TCP connection");
+                        connection.spawn();
+                        self.tcp_connections
+                            .retain(|_, segment_tx| !segment_tx.is_closed());
+                        self.tcp_connections.insert(key, segment_tx);
+                        return;
+                    }
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        // the client will retransmit the SYN.
+                        return;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => {
+                        // the listener was dropped, so we reset the connection.
+                        self.tcp_listener = None;
+                    }
+                }
+            }
+        }
+
+        if !flags.contains(inet::tcp::Flags::RST) {
+            tcp::reset(&link, &key, &segment).await;
+        }
+    }
+
     async fn handle_command(&mut self, command: Command) -> Result<(), Error> {
         match command {
             Command::RegisterUdpListener {
@@ -224,6 +402,9 @@ impl Reactor {
             } => {
                 self.udp_listeners.insert(bind_address, packet_tx);
             }
+            Command::RegisterTcpListener { stream_tx } => {
+                self.tcp_listener = Some(stream_tx);
+            }
         }
         Ok(())
     }
@@ -244,6 +425,9 @@ enum Command {
         bind_address: (IpAddr, u16),
         packet_tx: mpsc::Sender<udp::Packet>,
     },
+    RegisterTcpListener {
+        stream_tx: mpsc::Sender<tcp::TcpStream>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -281,3 +465,107 @@ impl VirtualHost {
 #[derive(Debug, thiserror::Error)]
 #[error("Send error")]
 pub struct SendError;
+
+/// Host names by IP address.
+///
+/// If multiple names resolve to the same address, the name that was resolved
+/// last wins. Entries expire with the TTL of the DNS answer, and only the
+/// [`Hostnames::MAX_ENTRIES`] most recently used addresses are kept.
+#[derive(Clone, Debug, Default)]
+pub struct Hostnames {
+    inner: Arc<Mutex<IndexMap<IpAddr, Hostname>>>,
+}
+
+#[derive(Debug)]
+struct Hostname {
+    name: String,
+    expires_at: Instant,
+}
+
+impl Hostnames {
+    /// Maximum number of addresses that are remembered.
+    pub const MAX_ENTRIES: usize = 4096;
+
+    /// Entries are kept at least this long, since clients often connect a
+    /// bit after the TTL ran out.
+    pub const MIN_TTL: Duration = Duration::from_secs(60);
+
+    pub fn insert(&self, ip_address: IpAddr, hostname: String, ttl: Duration) {
+        let mut inner = self.inner.lock();
+
+        // remove it first, so it moves to the end.
+        inner.shift_remove(&ip_address);
+        inner.insert(
+            ip_address,
+            Hostname {
+                name: hostname,
+                expires_at: Instant::now() + ttl.max(Self::MIN_TTL),
+            },
+        );
+
+        while inner.len() > Self::MAX_ENTRIES {
+            inner.shift_remove_index(0);
+        }
+    }
+
+    pub fn get(&self, ip_address: &IpAddr) -> Option<String> {
+        let mut inner = self.inner.lock();
+
+        let index = inner.get_index_of(ip_address)?;
+        if inner[index].expires_at <= Instant::now() {
+            inner.shift_remove_index(index);
+            return None;
+        }
+
+        let last = inner.len() - 1;
+        inner.move_index(index, last);
+        Some(inner[last].name.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34));
+
+    #[tokio::test(start_paused = true)]
+    async fn it_expires_hostnames() {
+        let hostnames = Hostnames::default();
+        hostnames.insert(ADDRESS, "example.com".to_owned(), Duration::from_secs(300));
+        assert_eq!(hostnames.get(&ADDRESS).as_deref(), Some("example.com"));
+
+        tokio::time::advance(Duration::from_secs(301)).await;
+        assert_eq!(hostnames.get(&ADDRESS), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_keeps_hostnames_for_the_minimum_ttl() {
+        let hostnames = Hostnames::default();
+        hostnames.insert(ADDRESS, "example.com".to_owned(), Duration::ZERO);
+
+        tokio::time::advance(Hostnames::MIN_TTL - Duration::from_secs(1)).await;
+        assert_eq!(hostnames.get(&ADDRESS).as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn it_evicts_the_least_recently_used_hostname() {
+        let hostnames = Hostnames::default();
+        let ttl = Duration::from_secs(300);
+        hostnames.insert(ADDRESS, "example.com".to_owned(), ttl);
+        for i in 1..Hostnames::MAX_ENTRIES {
+            hostnames.insert(
+                Ipv4Addr::from(i as u32).into(),
+                "example.org".to_owned(),
+                ttl,
+            );
+        }
+
+        // using it keeps it around.
+        assert!(hostnames.get(&ADDRESS).is_some());
+        hostnames.insert(Ipv4Addr::LOCALHOST.into(), "localhost".to_owned(), ttl);
+        assert!(hostnames.get(&ADDRESS).is_some());
+        assert!(hostnames.get(&Ipv4Addr::from(1).into()).is_none());
+        assert!(hostnames.get(&Ipv4Addr::from(2).into()).is_some());
+    }
+}
@@ -176,17 +176,17 @@ pub struct Sender {
 }
 
 impl Sender {
-    pub async fn send<T>(&self, _packet: &T) -> Result<(), SendError<()>>
+    /// Encodes `packet` and sends it on the interface.
+    pub async fn send<T>(&self, packet: &T) -> Result<(), SendError<T::Error>>
     where
         T: Write<ArcBufMut, ()>,
     {
-        //let mut buf = self.shared.get_buf();
+        let mut buf = self.shared.get_buf();
+        packet.write(&mut buf, ()).map_err(SendError::Encode)?;
 
-        //self.socket.send(buf.filled()).await?;
+        self.shared.socket.send(buf.filled()).await?;
 
-        //Ok(())
-
-        todo!();
+        Ok(())
     }
 
     #[inline]
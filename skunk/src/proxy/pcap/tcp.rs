@@ -0,0 +1,937 @@
+//! Userspace TCP for the virtual network.
+//!
+//! Clients on the virtual network connect to arbitrary hosts, but all their
+//! traffic is routed through us. We terminate these connections ourselves, i.e.
+//! we complete the handshake as if we were the destination, and yield them as
+//! [`TcpStream`]s. These can then be proxied like connections accepted by the
+//! SOCKS server.
+//!
+//! This is a deliberately small TCP implementation: Segments that arrive out of
+//! order are dropped (the client will retransmit them), there is no congestion
+//! control, and window scaling and SACK are not negotiated.
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::BuildHasher,
+    net::{
+        SocketAddr,
+        SocketAddrV4,
+    },
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Duration,
+};
+
+use byst::{
+    io::BufReader,
+    Buf,
+};
+use bytes::{
+    Bytes,
+    BytesMut,
+};
+use smallvec::SmallVec;
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
+        DuplexStream,
+        ReadBuf,
+        ReadHalf,
+        WriteHalf,
+    },
+    sync::mpsc,
+    time::{
+        sleep_until,
+        Instant,
+    },
+};
+use tracing::Instrument;
+
+use super::socket;
+use crate::{
+    address::TcpAddress,
+    protocol::inet::{
+        checksum::AddressPair,
+        ethernet,
+        ipv4,
+        tcp::{
+            self,
+            Flags,
+        },
+        MacAddress,
+    },
+    proxy::DestinationAddress,
+};
+
+/// The maximum segment size we announce. This is the Ethernet MTU minus the
+/// IPv4 and TCP headers.
+pub const MAXIMUM_SEGMENT_SIZE: u16 = (ethernet::MTU - 40) as u16;
+
+/// The maximum segment size to assume, if the client doesn't announce one.
+const DEFAULT_MAXIMUM_SEGMENT_SIZE: u16 = 536;
+
+/// The receive window we announce.
+const RECEIVE_WINDOW: u16 = u16::MAX;
+
+/// How long to wait for an acknowledgment, before retransmitting a segment.
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often to retransmit a segment, before the connection is reset.
+const MAX_RETRANSMISSIONS: usize = 8;
+
+/// Buffer size of the pipe between the connection and the [`TcpStream`].
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Number of segments that are queued for a connection, before they're
+/// dropped.
+const SEGMENT_QUEUE_SIZE: usize = 64;
+
+/// Identifies a connection by the addresses of both ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct ConnectionKey {
+    pub client: SocketAddrV4,
+    pub server: SocketAddrV4,
+}
+
+/// A segment received from a client.
+#[derive(Debug)]
+pub(super) struct Segment {
+    pub header: tcp::Header,
+    pub payload: Bytes,
+}
+
+impl Segment {
+    pub fn new(header: tcp::Header, payload: impl Buf) -> Self {
+        // copy the payload out of the packet buffer, so that the buffer can be reused.
+        let mut reader = payload.reader();
+        let mut buf = BytesMut::with_capacity(reader.remaining());
+        while let Some(chunk) = reader.peek_chunk() {
+            buf.extend_from_slice(chunk);
+            reader.advance(chunk.len()).unwrap();
+        }
+
+        Self {
+            header,
+            payload: buf.freeze(),
+        }
+    }
+
+    /// Length of the segment in sequence space.
+    fn length(&self) -> u32 {
+        let mut length = self.payload.len() as u32;
+        if self.header.flags.contains(Flags::SYN) {
+            length += 1;
+        }
+        if self.header.flags.contains(Flags::FIN) {
+            length += 1;
+        }
+        length
+    }
+}
+
+/// Sends segments to a client.
+pub(super) trait Transmit {
+    /// Sends a segment from `key.server` to `key.client`. The checksum in
+    /// `header` is calculated when the segment is sent.
+    ///
+    /// Errors are only logged, since the segment is retransmitted anyway, or
+    /// the client will retransmit its segment.
+    fn transmit(
+        &self,
+        key: &ConnectionKey,
+        header: tcp::Header,
+        payload: &[u8],
+    ) -> impl Future<Output = ()> + Send;
+}
+
+/// Link-layer addresses used to send segments to a client.
+#[derive(Clone, Debug)]
+pub(super) struct Link {
+    pub sock_tx: socket::Sender,
+    pub hardware_address: MacAddress,
+    pub client_hardware_address: MacAddress,
+}
+
+impl Transmit for Link {
+    async fn transmit(&self, key: &ConnectionKey, header: tcp::Header, payload: &[u8]) {
+        let total_length = ipv4::Header::LENGTH + header.length() + payload.len();
+
+        let mut segment = tcp::Packet { header, payload };
+        segment.header.checksum = segment.calculate_checksum(AddressPair::V4 {
+            source: *key.server.ip(),
+            destination: *key.client.ip(),
+        });
+
+        let mut ip_header = ipv4::Header {
+            version: 4,
+            internet_header_length: 5,
+            differentiated_service_code_point: 0,
+            explicit_congestion_notification: 0,
+            total_length: total_length as u16,
+            identification: 0,
+            flags: ipv4::Flags::DONT_FRAGMENT,
+            fragment_offset: 0,
+            time_to_live: 64,
+            protocol: ipv4::Protocol::TCP,
+            header_checksum: 0,
+            source_address: *key.server.ip(),
+            destination_address: *key.client.ip(),
+        };
+        ip_header.header_checksum = ip_header.calculate_checksum();
+
+        let frame = ethernet::Frame {
+            header: ethernet::Header {
+                destination: self.client_hardware_address,
+                source: self.hardware_address,
+                vlan_tags: SmallVec::new(),
+                ether_type: ethernet::EtherType::IPV4,
+            },
+            payload: ipv4::Packet {
+                header: ip_header,
+                payload: segment,
+            },
+            frame_check_sequence: ethernet::FrameCheckSequence::Absent,
+        };
+
+        if let Err(error) = self.sock_tx.send(&frame).await {
+            tracing::warn!(?error, "Failed to send TCP segment");
+        }
+    }
+}
+
+/// Accepts TCP connections from clients on the virtual network.
+///
+/// This can be created with [`VirtualNetwork::tcp_listener`][1].
+///
+/// [1]: super::VirtualNetwork::tcp_listener
+#[derive(Debug)]
+pub struct Listener {
+    pub(super) stream_rx: mpsc::Receiver<TcpStream>,
+}
+
+impl Listener {
+    /// Waits for the next connection. Returns `None` if the virtual network
+    /// has shut down.
+    pub async fn accept(&mut self) -> Option<TcpStream> {
+        self.stream_rx.recv().await
+    }
+}
+
+/// A TCP connection from a client on the virtual network.
+///
+/// The [`DestinationAddress`] is the address the client tried to connect to.
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: DuplexStream,
+    source_address: SocketAddr,
+    destination_address: TcpAddress,
+}
+
+impl TcpStream {
+    /// The address of the client.
+    pub fn source_address(&self) -> SocketAddr {
+        self.source_address
+    }
+}
+
+impl DestinationAddress for TcpStream {
+    fn destination_address(&self) -> &TcpAddress {
+        &self.destination_address
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// We sent our SYN-ACK, but it hasn't been acknowledged yet.
+    SynReceived,
+    Established,
+}
+
+/// The state of a single connection.
+///
+/// This runs in its own task and receives segments for this connection from
+/// the reactor.
+#[derive(Debug)]
+pub(super) struct Connection<L = Link> {
+    key: ConnectionKey,
+    link: L,
+    segment_rx: mpsc::Receiver<Segment>,
+    stream_rx: ReadHalf<DuplexStream>,
+    stream_tx: WriteHalf<DuplexStream>,
+    state: State,
+
+    /// Our initial sequence number.
+    initial_sequence_number: u32,
+
+    /// The oldest sequence number that hasn't been acknowledged yet.
+    send_unacknowledged: u32,
+
+    /// The client's receive window.
+    send_window: usize,
+
+    /// The client's maximum segment size.
+    maximum_segment_size: usize,
+
+    /// Data that was sent, but hasn't been acknowledged yet. This starts at
+    /// `send_unacknowledged`.
+    unacknowledged: BytesMut,
+
+    /// The next sequence number we expect from the client.
+    receive_next: u32,
+
+    /// The stream was shut down, and we sent a FIN.
+    fin_sent: bool,
+
+    /// Our FIN was acknowledged.
+    fin_acknowledged: bool,
+
+    /// We received a FIN from the client.
+    fin_received: bool,
+
+    retransmission_deadline: Option<Instant>,
+    retransmissions: usize,
+}
+
+impl<L> Connection<L>
+where
+    L: Transmit + Send + Sync + 'static,
+{
+    /// Creates a connection for a SYN segment sent by a client.
+    ///
+    /// `destination_address` is the address the [`TcpStream`] reports as
+    /// destination.
+    ///
+    /// Returns the connection, the stream for the user, and the sender used to
+    /// pass further segments to the connection.
+    pub fn new(
+        key: ConnectionKey,
+        destination_address: TcpAddress,
+        link: L,
+        syn: &Segment,
+    ) -> (Self, TcpStream, mpsc::Sender<Segment>) {
+        let (inner, outer) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+        let (stream_rx, stream_tx) = tokio::io::split(inner);
+        let (segment_tx, segment_rx) = mpsc::channel(SEGMENT_QUEUE_SIZE);
+
+        let initial_sequence_number = RandomState::new().hash_one((key, Instant::now())) as u32;
+
+        let connection = Self {
+            key,
+            link,
+            segment_rx,
+            stream_rx,
+            stream_tx,
+            state: State::SynReceived,
+            initial_sequence_number,
+            send_unacknowledged: initial_sequence_number,
+            send_window: syn.header.window_size.into(),
+            maximum_segment_size: syn
+                .header
+                .options
+                .maximum_segment_size()
+                .unwrap_or(DEFAULT_MAXIMUM_SEGMENT_SIZE)
+                .min(MAXIMUM_SEGMENT_SIZE)
+                .into(),
+            unacknowledged: BytesMut::new(),
+            receive_next: syn.header.sequence_number.wrapping_add(1),
+            fin_sent: false,
+            fin_acknowledged: false,
+            fin_received: false,
+            retransmission_deadline: None,
+            retransmissions: 0,
+        };
+
+        let stream = TcpStream {
+            inner: outer,
+            source_address: key.client.into(),
+            destination_address,
+        };
+
+        (connection, stream, segment_tx)
+    }
+
+    /// Spawns the task for this connection.
+    pub fn spawn(self) {
+        let span =
+            tracing::debug_span!("tcp", client = %self.key.client, server = %self.key.server);
+        tokio::spawn(
+            async move {
+                tracing::debug!("connection established");
+                self.run().await;
+                tracing::debug!("connection closed");
+            }
+            .instrument(span),
+        );
+    }
+
+    async fn run(mut self) {
+        self.send_syn_ack().await;
+
+        let mut buf = vec![0; MAXIMUM_SEGMENT_SIZE.into()];
+
+        loop {
+            if self.fin_sent && self.fin_acknowledged && self.fin_received {
+                break;
+            }
+
+            let send_capacity = self.send_capacity();
+            let deadline = self.retransmission_deadline;
+
+            tokio::select! {
+                segment_opt = self.segment_rx.recv() => {
+                    let Some(segment) = segment_opt else { break; };
+                    if !self.handle_segment(segment).await {
+                        break;
+                    }
+                }
+                read_result = self.stream_rx.read(&mut buf[..send_capacity]), if send_capacity > 0 => {
+                    match read_result {
+                        Ok(0) | Err(_) => self.send_fin().await,
+                        Ok(n) => self.send_data(&buf[..n]).await,
+                    }
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if !self.retransmit().await {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// How many bytes we can read from the stream and send right now.
+    fn send_capacity(&self) -> usize {
+        if self.state != State::Established || self.fin_sent {
+            return 0;
+        }
+        self.send_window
+            .saturating_sub(self.unacknowledged.len())
+            .min(self.maximum_segment_size)
+    }
+
+    /// The sequence number of the next byte we send.
+    fn send_next(&self) -> u32 {
+        self.send_unacknowledged
+            .wrapping_add(self.unacknowledged.len() as u32)
+    }
+
+    /// Handles a segment from the client. Returns `false` if the connection
+    /// should be closed.
+    async fn handle_segment(&mut self, segment: Segment) -> bool {
+        let flags = segment.header.flags;
+
+        if flags.contains(Flags::RST) {
+            tracing::debug!("reset by client");
+            return false;
+        }
+
+        if flags.contains(Flags::SYN) {
+            if self.state == State::SynReceived {
+                // our SYN-ACK was probably lost.
+                self.send_syn_ack().await;
+            }
+            return true;
+        }
+
+        if flags.contains(Flags::ACK) {
+            self.handle_acknowledgment(&segment).await;
+        }
+
+        if self.state != State::Established {
+            return true;
+        }
+
+        let mut acknowledge = false;
+        let sequence_number = segment.header.sequence_number;
+
+        if !segment.payload.is_empty() {
+            acknowledge = true;
+
+            // skip data we already received, in case of a retransmission.
+            let offset = self.receive_next.wrapping_sub(sequence_number) as usize;
+            if offset < segment.payload.len() && !self.fin_received {
+                let data = &segment.payload[offset..];
+                if self.stream_tx.write_all(data).await.is_err() {
+                    // the stream was dropped.
+                    self.send_reset().await;
+                    return false;
+                }
+                self.receive_next = self.receive_next.wrapping_add(data.len() as u32);
+            }
+        }
+
+        if flags.contains(Flags::FIN) {
+            acknowledge = true;
+
+            let fin_sequence_number = sequence_number.wrapping_add(segment.payload.len() as u32);
+            if fin_sequence_number == self.receive_next && !self.fin_received {
+                self.fin_received = true;
+                self.receive_next = self.receive_next.wrapping_add(1);
+                let _ = self.stream_tx.shutdown().await;
+            }
+        }
+
+        if acknowledge {
+            self.send(Flags::ACK, self.send_next(), tcp::Options::default(), &[])
+                .await;
+        }
+
+        true
+    }
+
+    async fn handle_acknowledgment(&mut self, segment: &Segment) {
+        let acknowledgment_number = segment.header.acknowledgment_number;
+
+        if self.state == State::SynReceived {
+            if acknowledgment_number != self.initial_sequence_number.wrapping_add(1) {
+                return;
+            }
+            self.state = State::Established;
+            self.send_unacknowledged = acknowledgment_number;
+            self.retransmission_deadline = None;
+            self.retransmissions = 0;
+        }
+
+        self.send_window = segment.header.window_size.into();
+
+        let acknowledged = acknowledgment_number.wrapping_sub(self.send_unacknowledged) as usize;
+        let outstanding = self.unacknowledged.len() + usize::from(self.fin_sent);
+        if acknowledged == 0 || acknowledged > outstanding {
+            // duplicate, or acknowledges something we didn't send.
+            return;
+        }
+
+        let data_acknowledged = acknowledged.min(self.unacknowledged.len());
+        let _ = self.unacknowledged.split_to(data_acknowledged);
+        if acknowledged > data_acknowledged {
+            self.fin_acknowledged = true;
+        }
+        self.send_unacknowledged = acknowledgment_number;
+
+        self.retransmissions = 0;
+        self.retransmission_deadline = (!self.unacknowledged.is_empty()
+            || (self.fin_sent && !self.fin_acknowledged))
+            .then(|| Instant::now() + RETRANSMISSION_TIMEOUT);
+    }
+
+    async fn send_syn_ack(&mut self) {
+        let options = [tcp::Option::MaximumSegmentSize(MAXIMUM_SEGMENT_SIZE)]
+            .into_iter()
+            .collect();
+        self.send(
+            Flags::SYN | Flags::ACK,
+            self.initial_sequence_number,
+            options,
+            &[],
+        )
+        .await;
+        self.start_retransmission_timer();
+    }
+
+    async fn send_data(&mut self, data: &[u8]) {
+        let sequence_number = self.send_next();
+        self.unacknowledged.extend_from_slice(data);
+        self.send(
+            Flags::ACK | Flags::PSH,
+            sequence_number,
+            tcp::Options::default(),
+            data,
+        )
+        .await;
+        self.start_retransmission_timer();
+    }
+
+    async fn send_fin(&mut self) {
+        self.fin_sent = true;
+        self.send(
+            Flags::ACK | Flags::FIN,
+            self.send_next(),
+            tcp::Options::default(),
+            &[],
+        )
+        .await;
+        self.start_retransmission_timer();
+    }
+
+    async fn send_reset(&mut self) {
+        self.send(
+            Flags::ACK | Flags::RST,
+            self.send_next(),
+            tcp::Options::default(),
+            &[],
+        )
+        .await;
+    }
+
+    fn start_retransmission_timer(&mut self) {
+        if self.retransmission_deadline.is_none() {
+            self.retransmission_deadline = Some(Instant::now() + RETRANSMISSION_TIMEOUT);
+        }
+    }
+
+    /// Retransmits the oldest unacknowledged segment. Returns `false` if we
+    /// gave up and reset the connection.
+    async fn retransmit(&mut self) -> bool {
+        self.retransmissions += 1;
+        if self.retransmissions > MAX_RETRANSMISSIONS {
+            tracing::debug!("too many retransmissions");
+            self.send_reset().await;
+            return false;
+        }
+
+        // exponential backoff
+        let backoff = 2u32.pow(self.retransmissions.min(6) as u32);
+        self.retransmission_deadline = Some(Instant::now() + RETRANSMISSION_TIMEOUT * backoff);
+
+        if self.state == State::SynReceived {
+            let options = [tcp::Option::MaximumSegmentSize(MAXIMUM_SEGMENT_SIZE)]
+                .into_iter()
+                .collect();
+            self.send(
+                Flags::SYN | Flags::ACK,
+                self.initial_sequence_number,
+                options,
+                &[],
+            )
+            .await;
+        }
+        else if !self.unacknowledged.is_empty() {
+            let length = self.unacknowledged.len().min(self.maximum_segment_size);
+            self.send(
+                Flags::ACK | Flags::PSH,
+                self.send_unacknowledged,
+                tcp::Options::default(),
+                &self.unacknowledged[..length],
+            )
+            .await;
+        }
+        else if self.fin_sent && !self.fin_acknowledged {
+            self.send(
+                Flags::ACK | Flags::FIN,
+                self.send_unacknowledged,
+                tcp::Options::default(),
+                &[],
+            )
+            .await;
+        }
+        else {
+            self.retransmission_deadline = None;
+        }
+
+        true
+    }
+
+    async fn send(
+        &self,
+        flags: Flags,
+        sequence_number: u32,
+        options: tcp::Options,
+        payload: &[u8],
+    ) {
+        let mut header = tcp::Header::new(self.key.server.port(), self.key.client.port(), flags);
+        header.sequence_number = sequence_number;
+        header.acknowledgment_number = self.receive_next;
+        header.window_size = RECEIVE_WINDOW;
+        header.options = options;
+
+        self.link.transmit(&self.key, header, payload).await;
+    }
+}
+
+/// Sends a reset in response to a segment that doesn't belong to any
+/// connection.
+pub(super) async fn reset(link: &impl Transmit, key: &ConnectionKey, segment: &Segment) {
+    let mut header = tcp::Header::new(key.server.port(), key.client.port(), Flags::RST);
+
+    if segment.header.flags.contains(Flags::ACK) {
+        header.sequence_number = segment.header.acknowledgment_number;
+    }
+    else {
+        header.flags |= Flags::ACK;
+        header.acknowledgment_number = segment
+            .header
+            .sequence_number
+            .wrapping_add(segment.length());
+    }
+
+    link.transmit(key, header, &[]).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::address::HostAddress;
+
+    /// Passes the segments a connection sends to the test.
+    #[derive(Clone, Debug)]
+    struct Channel(mpsc::UnboundedSender<(tcp::Header, Bytes)>);
+
+    impl Transmit for Channel {
+        async fn transmit(&self, _key: &ConnectionKey, header: tcp::Header, payload: &[u8]) {
+            let _ = self.0.send((header, Bytes::copy_from_slice(payload)));
+        }
+    }
+
+    const CLIENT_INITIAL_SEQUENCE_NUMBER: u32 = 0xfffffff0;
+
+    fn key() -> ConnectionKey {
+        ConnectionKey {
+            client: SocketAddrV4::new(Ipv4Addr::new(10, 0, 69, 2), 51234),
+            server: SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 443),
+        }
+    }
+
+    /// The address the client resolved with the DNS server.
+    fn destination_address() -> TcpAddress {
+        TcpAddress::new(HostAddress::DnsName("example.com".to_owned()), 443)
+    }
+
+    fn segment(flags: Flags, sequence_number: u32, acknowledgment_number: u32) -> Segment {
+        let mut header = tcp::Header::new(51234, 443, flags);
+        header.sequence_number = sequence_number;
+        header.acknowledgment_number = acknowledgment_number;
+        header.window_size = u16::MAX;
+        Segment {
+            header,
+            payload: Bytes::new(),
+        }
+    }
+
+    /// The client end of a connection.
+    struct Client {
+        segment_tx: mpsc::Sender<Segment>,
+        segment_rx: mpsc::UnboundedReceiver<(tcp::Header, Bytes)>,
+        stream: TcpStream,
+        send_next: u32,
+        receive_next: u32,
+    }
+
+    impl Client {
+        /// Sends a SYN, and returns the SYN-ACK.
+        fn open() -> (Self, tcp::Header) {
+            let (link_tx, segment_rx) = mpsc::unbounded_channel();
+            let syn = segment(Flags::SYN, CLIENT_INITIAL_SEQUENCE_NUMBER, 0);
+            let (connection, stream, segment_tx) =
+                Connection::new(key(), destination_address(), Channel(link_tx), &syn);
+            connection.spawn();
+
+            let client = Self {
+                segment_tx,
+                segment_rx,
+                stream,
+                send_next: CLIENT_INITIAL_SEQUENCE_NUMBER.wrapping_add(1),
+                receive_next: 0,
+            };
+            (client, syn.header)
+        }
+
+        /// Completes the handshake.
+        async fn connect() -> Self {
+            let (mut client, _) = Self::open();
+            let (syn_ack, _) = client.receive().await;
+            client.receive_next = syn_ack.sequence_number.wrapping_add(1);
+            client.send(Flags::ACK, b"").await;
+            client
+        }
+
+        async fn send(&mut self, flags: Flags, payload: &[u8]) {
+            let mut segment = segment(flags, self.send_next, self.receive_next);
+            segment.payload = Bytes::copy_from_slice(payload);
+            self.send_next = self.send_next.wrapping_add(segment.length());
+            self.segment_tx.send(segment).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> (tcp::Header, Bytes) {
+            self.segment_rx.recv().await.unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn it_completes_the_handshake() {
+        let (mut client, _) = Client::open();
+        assert_eq!(client.stream.destination_address(), &destination_address());
+
+        let (syn_ack, _) = client.receive().await;
+        assert_eq!(syn_ack.flags, Flags::SYN | Flags::ACK);
+        assert_eq!(syn_ack.acknowledgment_number, client.send_next);
+        assert_eq!(
+            syn_ack.options.maximum_segment_size(),
+            Some(MAXIMUM_SEGMENT_SIZE)
+        );
+
+        // a retransmitted SYN is answered with the same SYN-ACK.
+        client
+            .segment_tx
+            .send(segment(Flags::SYN, CLIENT_INITIAL_SEQUENCE_NUMBER, 0))
+            .await
+            .unwrap();
+        let (retransmitted, _) = client.receive().await;
+        assert_eq!(retransmitted.sequence_number, syn_ack.sequence_number);
+
+        // data is only sent once the handshake is complete.
+        client.receive_next = syn_ack.sequence_number.wrapping_add(1);
+        client.send(Flags::ACK, b"").await;
+        client.stream.write_all(b"hello").await.unwrap();
+        let (header, payload) = client.receive().await;
+        assert_eq!(header.sequence_number, client.receive_next);
+        assert_eq!(&payload[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn it_receives_data_in_order() {
+        let mut client = Client::connect().await;
+
+        client.send(Flags::ACK | Flags::PSH, b"hello").await;
+        let mut buf = [0; 5];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        let (ack, _) = client.receive().await;
+        assert_eq!(ack.acknowledgment_number, client.send_next);
+
+        // a segment that arrives early is dropped, and the client is told what we
+        // expect next.
+        let expected = client.send_next;
+        client.send_next = client.send_next.wrapping_add(6);
+        client.send(Flags::ACK | Flags::PSH, b"world").await;
+        let (ack, _) = client.receive().await;
+        assert_eq!(ack.acknowledgment_number, expected);
+
+        // data that was already received is skipped.
+        client.send_next = expected.wrapping_sub(2);
+        client.send(Flags::ACK | Flags::PSH, b"lo, world").await;
+        let mut buf = [0; 7];
+        client.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b", world");
+        let (ack, _) = client.receive().await;
+        assert_eq!(ack.acknowledgment_number, client.send_next);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_retransmits_unacknowledged_data() {
+        let mut client = Client::connect().await;
+
+        client.stream.write_all(b"hello").await.unwrap();
+        let (header, payload) = client.receive().await;
+        let (retransmitted, retransmitted_payload) = client.receive().await;
+        assert_eq!(retransmitted.sequence_number, header.sequence_number);
+        assert_eq!(retransmitted_payload, payload);
+
+        // once the data is acknowledged, it isn't sent again.
+        client.receive_next = header.sequence_number.wrapping_add(5);
+        client.send(Flags::ACK, b"").await;
+        assert!(
+            tokio::time::timeout(RETRANSMISSION_TIMEOUT * 4, client.receive())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_resets_after_too_many_retransmissions() {
+        let (mut client, _) = Client::open();
+
+        for _ in 0..=MAX_RETRANSMISSIONS {
+            let (syn_ack, _) = client.receive().await;
+            assert_eq!(syn_ack.flags, Flags::SYN | Flags::ACK);
+        }
+        let (reset, _) = client.receive().await;
+        assert!(reset.flags.contains(Flags::RST));
+        client.segment_tx.closed().await;
+    }
+
+    #[tokio::test]
+    async fn it_closes_the_connection() {
+        let mut client = Client::connect().await;
+
+        client.send(Flags::ACK | Flags::FIN, b"").await;
+        let mut buf = vec![];
+        client.stream.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        let (ack, _) = client.receive().await;
+        assert_eq!(ack.acknowledgment_number, client.send_next);
+
+        client.stream.shutdown().await.unwrap();
+        let (fin, _) = client.receive().await;
+        assert!(fin.flags.contains(Flags::FIN));
+
+        client.receive_next = fin.sequence_number.wrapping_add(1);
+        client.send(Flags::ACK, b"").await;
+        client.segment_tx.closed().await;
+    }
+
+    #[tokio::test]
+    async fn it_is_reset_by_the_client() {
+        let mut client = Client::connect().await;
+
+        client.send(Flags::RST, b"").await;
+        client.segment_tx.closed().await;
+
+        let mut buf = vec![];
+        client.stream.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_resets_unknown_connections() {
+        let (link_tx, mut segment_rx) = mpsc::unbounded_channel();
+        let link = Channel(link_tx);
+
+        let syn = segment(Flags::SYN, CLIENT_INITIAL_SEQUENCE_NUMBER, 0);
+        reset(&link, &key(), &syn).await;
+        let (header, _) = segment_rx.recv().await.unwrap();
+        assert_eq!(header.flags, Flags::RST | Flags::ACK);
+        assert_eq!(
+            header.acknowledgment_number,
+            CLIENT_INITIAL_SEQUENCE_NUMBER.wrapping_add(1)
+        );
+
+        let ack = segment(Flags::ACK, CLIENT_INITIAL_SEQUENCE_NUMBER, 1234);
+        reset(&link, &key(), &ack).await;
+        let (header, _) = segment_rx.recv().await.unwrap();
+        assert_eq!(header.flags, Flags::RST);
+        assert_eq!(header.sequence_number, 1234);
+    }
+}
@@ -1,4 +1,17 @@
-use byst::Bytes;
+use std::{
+    convert::Infallible,
+    net::IpAddr,
+};
+
+use byst::{
+    buf::arc_buf::ArcBufMut,
+    io::{
+        Write,
+        Writer,
+    },
+    Bytes,
+};
+use smallvec::SmallVec;
 use tokio::sync::mpsc;
 
 use super::{
@@ -6,6 +19,12 @@ use super::{
     AddressTriple,
     SendError,
 };
+use crate::protocol::inet::{
+    checksum::Checksum,
+    ethernet,
+    ipv4,
+    udp,
+};
 
 #[derive(Clone, Debug)]
 pub struct Packet {
@@ -14,17 +33,93 @@ pub struct Packet {
     pub data: Bytes,
 }
 
+#[derive(Clone, Debug)]
 pub struct Sender {
     pub(super) sock_tx: socket::Sender,
 }
 
 impl Sender {
-    pub async fn send(&mut self) -> Result<(), SendError> {
-        tracing::debug!("TODO: implement udp::Sender::send");
-        Ok(())
+    /// Sends a UDP datagram.
+    ///
+    /// The datagram is sent as a raw Ethernet frame, so the hardware
+    /// addresses of `source` and `destination` are used as-is. This allows
+    /// sending datagrams to hosts that don't have an IP address yet, or to the
+    /// broadcast address.
+    ///
+    /// `payload_length` must be the number of bytes `payload` writes. Only IPv4
+    /// is supported at the moment.
+    pub async fn send<P>(
+        &self,
+        source: AddressTriple,
+        destination: AddressTriple,
+        payload: P,
+        payload_length: usize,
+    ) -> Result<(), SendError>
+    where
+        P: Write<ArcBufMut, (), Error = <ArcBufMut as Writer>::Error>,
+        P: Write<Checksum, (), Error = Infallible>,
+    {
+        let (IpAddr::V4(source_address), IpAddr::V4(destination_address)) =
+            (source.ip_address, destination.ip_address)
+        else {
+            tracing::warn!(?source, ?destination, "UDP over IPv6 is not supported");
+            return Err(SendError);
+        };
+
+        let udp_length = udp::Header::LENGTH + payload_length;
+        let total_length = ipv4::Header::LENGTH + udp_length;
+
+        let mut datagram = udp::Packet {
+            header: udp::Header {
+                source_port: source.port,
+                destination_port: destination.port,
+                length: udp_length as u16,
+                checksum: 0,
+            },
+            payload,
+        };
+        datagram.header.checksum =
+            datagram.calculate_checksum((source_address, destination_address).into());
+
+        let mut ip_header = ipv4::Header {
+            version: 4,
+            internet_header_length: 5,
+            differentiated_service_code_point: 0,
+            explicit_congestion_notification: 0,
+            total_length: total_length as u16,
+            identification: 0,
+            flags: ipv4::Flags::empty(),
+            fragment_offset: 0,
+            time_to_live: 64,
+            protocol: ipv4::Protocol::UDP,
+            header_checksum: 0,
+            source_address,
+            destination_address,
+        };
+        ip_header.header_checksum = ip_header.calculate_checksum();
+
+        let frame = ethernet::Frame {
+            header: ethernet::Header {
+                destination: destination.mac_address,
+                source: source.mac_address,
+                vlan_tags: SmallVec::new(),
+                ether_type: ethernet::EtherType::IPV4,
+            },
+            payload: ipv4::Packet {
+                header: ip_header,
+                payload: datagram,
+            },
+            frame_check_sequence: ethernet::FrameCheckSequence::Absent,
+        };
+
+        self.sock_tx.send(&frame).await.map_err(|error| {
+            tracing::warn!(?error, "Failed to send UDP datagram");
+            SendError
+        })
     }
 }
 
+#[derive(Debug)]
 pub struct Receiver {
     pub(super) packet_rx: mpsc::Receiver<Packet>,
 }
@@ -35,6 +130,7 @@ impl Receiver {
     }
 }
 
+#[derive(Debug)]
 pub struct Socket {
     pub(super) tx: Sender,
     pub(super) rx: Option<Receiver>,
@@ -50,8 +146,20 @@ impl Socket {
         }
     }
 
-    pub async fn send(&mut self) -> Result<(), SendError> {
-        self.tx.send().await
+    pub async fn send<P>(
+        &self,
+        source: AddressTriple,
+        destination: AddressTriple,
+        payload: P,
+        payload_length: usize,
+    ) -> Result<(), SendError>
+    where
+        P: Write<ArcBufMut, (), Error = <ArcBufMut as Writer>::Error>,
+        P: Write<Checksum, (), Error = Infallible>,
+    {
+        self.tx
+            .send(source, destination, payload, payload_length)
+            .await
     }
 
     pub fn split(self) -> (Sender, Option<Receiver>) {
@@ -0,0 +1,199 @@
+//! SOCKS client implementation.
+//!
+//! This provides a [`Connect`] implementation that tunnels connections through
+//! an upstream SOCKS4a/5 server.
+
+use std::fmt::Debug;
+
+use super::{
+    error::Error,
+    v4,
+    v5,
+};
+use crate::{
+    address::{
+        HostAddress,
+        TcpAddress,
+    },
+    connect::{
+        Connect,
+        ConnectTcp,
+    },
+};
+
+/// SOCKS protocol version spoken with the upstream server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Version {
+    /// SOCKS4 with the SOCKS4a extension for DNS names.
+    V4a,
+
+    #[default]
+    V5,
+}
+
+/// Username and password to authenticate with.
+///
+/// SOCKS4a has no authentication, so only the username is sent as the user id.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Connect through an upstream SOCKS server.
+///
+/// DNS names are resolved by the SOCKS server, unless local resolution is
+/// enabled with [`with_local_resolution`][Self::with_local_resolution].
+#[derive(Clone, Debug)]
+pub struct ConnectSocks<C = ConnectTcp> {
+    server_address: TcpAddress,
+    version: Version,
+    credentials: Option<Credentials>,
+    resolve_locally: bool,
+    connect: C,
+}
+
+impl ConnectSocks {
+    /// Connect through the SOCKS server at `server_address`, speaking SOCKS5
+    /// without authentication.
+    pub fn new(server_address: TcpAddress) -> Self {
+        Self {
+            server_address,
+            version: Version::default(),
+            credentials: None,
+            resolve_locally: false,
+            connect: ConnectTcp,
+        }
+    }
+}
+
+impl<C> ConnectSocks<C> {
+    /// Specify the SOCKS version. Defaults to SOCKS5.
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Authenticate with username and password.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Resolve DNS names before sending them to the SOCKS server. With SOCKS4a,
+    /// only IPv4 addresses are used. Defaults to `false`.
+    pub fn with_local_resolution(mut self, resolve_locally: bool) -> Self {
+        self.resolve_locally = resolve_locally;
+        self
+    }
+
+    /// Specify how the connection to the SOCKS server is established.
+    /// Defaults to [`ConnectTcp`].
+    pub fn with_connect<D>(self, connect: D) -> ConnectSocks<D> {
+        ConnectSocks {
+            server_address: self.server_address,
+            version: self.version,
+            credentials: self.credentials,
+            resolve_locally: self.resolve_locally,
+            connect,
+        }
+    }
+
+    pub fn server_address(&self) -> &TcpAddress {
+        &self.server_address
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn resolves_locally(&self) -> bool {
+        self.resolve_locally
+    }
+
+    /// Resolves a DNS name to an address the SOCKS version supports.
+    async fn resolve(&self, address: &TcpAddress) -> Result<TcpAddress, Error> {
+        let HostAddress::DnsName(name) = &address.host
+        else {
+            return Ok(address.clone());
+        };
+
+        tokio::net::lookup_host((name.as_str(), address.port))
+            .await?
+            .find(|resolved| self.version == Version::V5 || resolved.is_ipv4())
+            .map(|resolved| TcpAddress::new(resolved.ip().into(), address.port))
+            .ok_or_else(|| {
+                Error::UnsupportedAddress {
+                    address: address.clone(),
+                }
+            })
+    }
+}
+
+impl<C> Connect for ConnectSocks<C>
+where
+    C: Connect + Sync,
+{
+    type Connection = C::Connection;
+
+    async fn connect(&self, address: &TcpAddress) -> Result<Self::Connection, std::io::Error> {
+        let resolved;
+        let address = if self.resolve_locally {
+            resolved = self.resolve(address).await?;
+            &resolved
+        }
+        else {
+            address
+        };
+
+        let socket = self.connect.connect(&self.server_address).await?;
+
+        let result = match self.version {
+            Version::V4a => {
+                let user_id = self
+                    .credentials
+                    .as_ref()
+                    .map_or("", |credentials| &credentials.username);
+                v4::client::connect(socket, address, user_id).await
+            }
+            Version::V5 => v5::client::connect(socket, address, self.credentials.as_ref()).await,
+        };
+
+        result.map_err(|error: Error| {
+            tracing::debug!(server_address = %self.server_address, %address, ?error, "SOCKS request failed");
+            error.into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_resolves_dns_names_locally() {
+        let connect = ConnectSocks::new("127.0.0.1:1080".parse().unwrap())
+            .with_version(Version::V4a)
+            .with_local_resolution(true);
+
+        let address = connect
+            .resolve(&"localhost:443".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(address, TcpAddress::new(Ipv4Addr::LOCALHOST.into(), 443));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::address::TcpAddress;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("io error")]
@@ -23,6 +25,27 @@ pub enum Error {
 
     #[error("invalid address type")]
     InvalidAddressType(#[from] super::v5::InvalidAddressType),
+
+    #[error("invalid reply")]
+    InvalidReply(#[from] InvalidReply),
+
+    #[error("address not supported by SOCKS version: {address}")]
+    UnsupportedAddress { address: TcpAddress },
+
+    #[error("request rejected: {}", .reason.message())]
+    RejectedV4 { reason: super::v4::Reply },
+
+    #[error("request rejected: {}", .reason.message())]
+    RejectedV5 { reason: super::v5::RejectReason },
+}
+
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Io(error) => error,
+            _ => std::io::Error::other(value),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -3,17 +3,27 @@
 //! This provides a SOCKS4a/5 server that can be used to inspect traffic.
 
 use std::{
-    net::SocketAddr,
+    collections::HashMap,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        SocketAddr,
+    },
     pin::Pin,
+    sync::Arc,
     task::{
         Context,
         Poll,
     },
+    time::Duration,
 };
 
+use bytes::Bytes;
 use tokio::{
     io::{
+        AsyncBufReadExt,
         AsyncRead,
+        AsyncReadExt,
         AsyncWrite,
         BufStream,
         ReadBuf,
@@ -21,6 +31,7 @@ use tokio::{
     net::{
         TcpListener,
         TcpStream,
+        UdpSocket,
     },
     sync::{
         mpsc,
@@ -31,14 +42,19 @@ use tracing::Instrument;
 
 use super::{
     error::Error,
+    v4,
     v5::{
+        self,
         server::{
+            read_password_request,
+            send_password_status,
             serve,
+            Accept,
             AuthProvider,
             AuthResult,
             Connected,
-            Request,
         },
+        udp::UdpHeader,
         AuthMethod,
         RejectReason,
         SelectedAuthMethod,
@@ -46,10 +62,21 @@ use super::{
     },
 };
 use crate::{
-    address::TcpAddress,
+    address::{
+        HostAddress,
+        TcpAddress,
+        UdpAddress,
+    },
     proxy::DestinationAddress,
 };
 
+/// Max size of a datagram we receive from the client: the max UDP payload
+/// size.
+const MAX_DATAGRAM_SIZE: usize = 0xffff;
+
+/// How long a [`Binding`] waits for the peer to connect.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(120);
+
 /// An incoming connection.
 ///
 /// # Buffering
@@ -61,8 +88,16 @@ use crate::{
 /// [`flush`]: tokio::io::AsyncWriteExt::flush
 #[derive(Debug)]
 pub struct Incoming {
-    inner: Connected<BufStream<TcpStream>, MaybeAuth>,
+    inner: IncomingInner,
     destination_address: TcpAddress,
+    username: Option<String>,
+}
+
+impl Incoming {
+    /// The user that authenticated, if authentication is enabled.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
 }
 
 impl DestinationAddress for Incoming {
@@ -77,7 +112,10 @@ impl AsyncRead for Incoming {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        match &mut self.inner {
+            IncomingInner::V4(inner) => Pin::new(inner).poll_read(cx, buf),
+            IncomingInner::V5(inner) => Pin::new(inner).poll_read(cx, buf),
+        }
     }
 }
 
@@ -87,24 +125,40 @@ impl AsyncWrite for Incoming {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        match &mut self.inner {
+            IncomingInner::V4(inner) => Pin::new(inner).poll_write(cx, buf),
+            IncomingInner::V5(inner) => Pin::new(inner).poll_write(cx, buf),
+        }
     }
 
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.inner).poll_flush(cx)
+        match &mut self.inner {
+            IncomingInner::V4(inner) => Pin::new(inner).poll_flush(cx),
+            IncomingInner::V5(inner) => Pin::new(inner).poll_flush(cx),
+        }
     }
 
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.inner).poll_shutdown(cx)
+        match &mut self.inner {
+            IncomingInner::V4(inner) => Pin::new(inner).poll_shutdown(cx),
+            IncomingInner::V5(inner) => Pin::new(inner).poll_shutdown(cx),
+        }
     }
 }
 
+/// The client's connection, after the SOCKS4 or SOCKS5 handshake.
+#[derive(Debug)]
+enum IncomingInner {
+    V4(v4::server::Connected<BufStream<TcpStream>>),
+    V5(Connected<BufStream<TcpStream>, MaybeAuth>),
+}
+
 /// Builder used to create a SOCKS server.
 pub struct Builder {
     bind_address: SocketAddr,
@@ -127,17 +181,29 @@ impl Builder {
         self
     }
 
-    /// Specify username and password for authentication. By default no
-    /// authentication is used.
-    ///
-    /// # TODO
-    ///
-    /// This is not implemented yet.
+    /// Add a user that can authenticate with username and password. Once a
+    /// user is added, clients must authenticate. By default no authentication
+    /// is used.
     pub fn with_password(mut self, username: String, password: String) -> Self {
-        self.auth = MaybeAuth::Password {
-            username: username.into_bytes(),
-            password: password.into_bytes(),
-        };
+        match &mut self.auth {
+            MaybeAuth::NoAuth => {
+                self.auth = MaybeAuth::Password {
+                    users: Arc::new([(username, password)].into()),
+                };
+            }
+            MaybeAuth::Password { users } => {
+                Arc::make_mut(users).insert(username, password);
+            }
+        }
+        self
+    }
+
+    /// Add users that can authenticate with username and password. See
+    /// [`with_password`](Self::with_password).
+    pub fn with_users(mut self, users: impl IntoIterator<Item = (String, String)>) -> Self {
+        for (username, password) in users {
+            self = self.with_password(username, password);
+        }
         self
     }
 
@@ -192,11 +258,11 @@ impl Builder {
 /// Stream of connection requests
 #[derive(Debug)]
 pub struct ConnectionRequests {
-    connection_requests_rx: mpsc::Receiver<Result<ConnectionRequest, Error>>,
+    connection_requests_rx: mpsc::Receiver<Result<Request, Error>>,
 }
 
 impl ConnectionRequests {
-    pub async fn next(&mut self) -> Result<ConnectionRequest, Error> {
+    pub async fn next(&mut self) -> Result<Request, Error> {
         if let Some(result) = self.connection_requests_rx.recv().await {
             result
         }
@@ -206,6 +272,30 @@ impl ConnectionRequests {
     }
 }
 
+/// A request from a client.
+#[derive(Debug)]
+pub enum Request {
+    /// Connect to a destination over TCP.
+    Connect(ConnectionRequest),
+
+    /// Listen for a connection from a peer.
+    Bind(BindRequest),
+
+    /// Relay UDP datagrams.
+    Associate(AssociateRequest),
+}
+
+impl Request {
+    /// The user that authenticated, if authentication is enabled.
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            Self::Connect(request) => request.username(),
+            Self::Bind(request) => request.username(),
+            Self::Associate(request) => request.username(),
+        }
+    }
+}
+
 /// A request to connect to a destination address
 ///
 /// Either [`accept`] or [`reject`] the request, taking into account the
@@ -218,6 +308,7 @@ impl ConnectionRequests {
 #[derive(Debug)]
 pub struct ConnectionRequest {
     destination_address: TcpAddress,
+    username: Option<String>,
     ack_tx: oneshot::Sender<Result<TcpAddress, RejectReason>>,
     connection_rx: oneshot::Receiver<Result<Incoming, Error>>,
 }
@@ -227,6 +318,11 @@ impl ConnectionRequest {
         &self.destination_address
     }
 
+    /// The user that authenticated, if authentication is enabled.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
     pub async fn accept(self, bind_address: TcpAddress) -> Result<Incoming, Error> {
         let _ = self.ack_tx.send(Ok(bind_address));
         let connection = self
@@ -243,31 +339,481 @@ impl ConnectionRequest {
     }
 }
 
+/// A request to listen for a connection from a peer.
+///
+/// Either [`accept`] or [`reject`] the request. If this is dropped, the request
+/// will be rejected with a generic reason.
+///
+/// [`accept`]: [Self::accept]
+/// [`reject`]: [Self::reject]
+#[derive(Debug)]
+pub struct BindRequest {
+    destination_address: TcpAddress,
+    client_address: SocketAddr,
+    username: Option<String>,
+    ack_tx: oneshot::Sender<Result<(), RejectReason>>,
+    binding_rx: oneshot::Receiver<Result<Binding, Error>>,
+}
+
+impl BindRequest {
+    /// The address of the peer the client expects to connect.
+    pub fn destination_address(&self) -> &TcpAddress {
+        &self.destination_address
+    }
+
+    /// The address of the client's control connection.
+    pub fn client_address(&self) -> &SocketAddr {
+        &self.client_address
+    }
+
+    /// The user that authenticated, if authentication is enabled.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Accepts the request. This binds a TCP listener for the peer, and tells
+    /// the client its address.
+    pub async fn accept(self) -> Result<Binding, Error> {
+        let _ = self.ack_tx.send(Ok(()));
+        let binding = self
+            .binding_rx
+            .await
+            .expect("binding_tx dropped without error")?;
+        Ok(binding)
+    }
+
+    pub fn reject(self, reason: impl Into<Option<RejectReason>>) {
+        let _ = self.ack_tx.send(Err(reason
+            .into()
+            .unwrap_or(RejectReason::ConnectionRefused)));
+    }
+}
+
+/// An accepted BIND request that waits for the peer to connect.
+///
+/// Only the first connection from the expected peer is
+/// [accepted][Self::accept]. If the client didn't specify the peer's IP
+/// address, connections from any address are accepted.
+#[derive(Debug)]
+pub struct Binding {
+    control: BindingControl,
+    listener: TcpListener,
+    destination_address: TcpAddress,
+    peer_ip_address: Option<IpAddr>,
+    username: Option<String>,
+}
+
+impl Binding {
+    /// The address we listen on for the peer.
+    pub fn bind_address(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// The address of the peer the client expects to connect.
+    pub fn destination_address(&self) -> &TcpAddress {
+        &self.destination_address
+    }
+
+    /// The user that authenticated, if authentication is enabled.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Waits for the peer to connect and tells the client about it.
+    ///
+    /// Returns the client's connection, with the peer as its destination, and
+    /// the peer's connection. Returns `None` if the client closes the
+    /// connection before the peer connects, or if the peer doesn't connect
+    /// within [`PEER_TIMEOUT`].
+    pub async fn accept(mut self) -> Result<Option<(Incoming, TcpStream)>, Error> {
+        let timeout = tokio::time::sleep(PEER_TIMEOUT);
+        tokio::pin!(timeout);
+        let mut watch_control = true;
+
+        loop {
+            let result = tokio::select! {
+                result = self.control.get_mut().fill_buf(), if watch_control => {
+                    if result?.is_empty() {
+                        return Ok(None);
+                    }
+                    // the client already sent data. it's relayed to the peer once it's connected.
+                    // we can't notice the client closing the connection anymore without reading
+                    // the data, so only the timeout ends the binding now.
+                    watch_control = false;
+                    continue;
+                }
+                result = self.listener.accept() => result,
+                _ = &mut timeout => {
+                    tracing::debug!("Peer didn't connect");
+                    self.control.reject(RejectReason::TTLExpired).await?;
+                    return Ok(None);
+                }
+            };
+
+            let (peer, peer_address) = match result {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    self.control.reject(RejectReason::GeneralFailure).await?;
+                    return Err(error.into());
+                }
+            };
+
+            if self
+                .peer_ip_address
+                .is_some_and(|ip_address| ip_address != peer_address.ip())
+            {
+                tracing::debug!(%peer_address, "Dropping connection from unexpected peer");
+                continue;
+            }
+
+            let destination_address = TcpAddress::from(peer_address);
+            let inner = match self.control {
+                BindingControl::V4(control) => {
+                    IncomingInner::V4(control.accept(ipv4_address(&destination_address)).await?)
+                }
+                BindingControl::V5(control) => {
+                    IncomingInner::V5(control.accept(&destination_address).await?)
+                }
+            };
+            tracing::debug!(%peer_address, "Peer connected");
+
+            let incoming = Incoming {
+                inner,
+                destination_address,
+                username: self.username,
+            };
+            return Ok(Some((incoming, peer)));
+        }
+    }
+}
+
+/// The client's control connection of a [`Binding`].
+#[derive(Debug)]
+enum BindingControl {
+    V4(v4::server::Accept<BufStream<TcpStream>>),
+    V5(Accept<BufStream<TcpStream>, MaybeAuth>),
+}
+
+impl BindingControl {
+    fn get_mut(&mut self) -> &mut BufStream<TcpStream> {
+        match self {
+            Self::V4(control) => control.get_mut(),
+            Self::V5(control) => control.get_mut(),
+        }
+    }
+
+    /// Rejects the binding. SOCKS4 has no reasons, so `reason` is only sent to
+    /// SOCKS5 clients.
+    async fn reject(self, reason: RejectReason) -> Result<(), Error> {
+        match self {
+            Self::V4(control) => control.reject().await,
+            Self::V5(control) => control.reject(reason).await,
+        }
+    }
+}
+
+/// A request to relay UDP datagrams for the client.
+///
+/// Either [`accept`] or [`reject`] the request. If this is dropped, the request
+/// will be rejected with a generic reason.
+///
+/// [`accept`]: [Self::accept]
+/// [`reject`]: [Self::reject]
+#[derive(Debug)]
+pub struct AssociateRequest {
+    client_address: SocketAddr,
+    username: Option<String>,
+    ack_tx: oneshot::Sender<Result<(), RejectReason>>,
+    association_rx: oneshot::Receiver<Result<Association, Error>>,
+}
+
+impl AssociateRequest {
+    /// The address of the client's control connection.
+    pub fn client_address(&self) -> &SocketAddr {
+        &self.client_address
+    }
+
+    /// The user that authenticated, if authentication is enabled.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Accepts the request. This binds a UDP socket for the association,
+    /// which the client is told to send its datagrams to.
+    pub async fn accept(self) -> Result<Association, Error> {
+        let _ = self.ack_tx.send(Ok(()));
+        let association = self
+            .association_rx
+            .await
+            .expect("association_tx dropped without error")?;
+        Ok(association)
+    }
+
+    pub fn reject(self, reason: impl Into<Option<RejectReason>>) {
+        let _ = self.ack_tx.send(Err(reason
+            .into()
+            .unwrap_or(RejectReason::ConnectionRefused)));
+    }
+}
+
+/// A datagram from the client.
+#[derive(Clone, Debug)]
+pub struct Datagram {
+    /// The address the client wants the datagram to be sent to.
+    pub destination_address: UdpAddress,
+
+    pub data: Bytes,
+}
+
+/// A UDP association.
+///
+/// Datagrams from the client are [received][Self::receive] without the SOCKS
+/// header, and datagrams to the client are [sent][Self::send] with it.
+/// Datagrams from other hosts than the client, and fragmented datagrams are
+/// dropped.
+///
+/// The association ends when the client closes the control connection.
+#[derive(Debug)]
+pub struct Association {
+    control: Connected<BufStream<TcpStream>, MaybeAuth>,
+    socket: UdpSocket,
+    client_ip_address: IpAddr,
+    client_port: Option<u16>,
+    username: Option<String>,
+}
+
+impl Association {
+    /// The address of the UDP socket the client sends its datagrams to.
+    pub fn bind_address(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// The user that authenticated, if authentication is enabled.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Receives the next datagram from the client. Returns `None` when the
+    /// association has ended.
+    ///
+    /// This is cancel safe.
+    pub async fn receive(&mut self) -> Result<Option<Datagram>, Error> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut control_buf = [0; 64];
+
+        loop {
+            tokio::select! {
+                // the client doesn't send anything on the control connection, so this only
+                // completes when it's closed.
+                result = self.control.read(&mut control_buf) => {
+                    if result? == 0 {
+                        return Ok(None);
+                    }
+                }
+                result = self.socket.recv_from(&mut buf) => {
+                    let (n_read, source) = result?;
+                    if source.ip() != self.client_ip_address
+                        || self.client_port.is_some_and(|port| port != source.port())
+                    {
+                        tracing::debug!(%source, "Dropping datagram from unexpected source");
+                        continue;
+                    }
+
+                    match UdpHeader::parse(&buf[..n_read]) {
+                        Ok((header, _)) if header.fragment != 0 => {
+                            tracing::debug!(fragment = header.fragment, "Dropping fragmented datagram");
+                        }
+                        Ok((header, data)) => {
+                            // datagrams to the client are sent to where its first datagram came
+                            // from.
+                            self.client_port = Some(source.port());
+                            return Ok(Some(Datagram {
+                                destination_address: header.address,
+                                data: Bytes::copy_from_slice(data),
+                            }));
+                        }
+                        Err(error) => {
+                            tracing::debug!(?error, "Dropping invalid datagram");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a datagram from `source_address` to the client.
+    ///
+    /// The datagram is dropped if the client didn't send any datagram yet,
+    /// since we don't know its port then.
+    pub async fn send(&self, source_address: &UdpAddress, data: &[u8]) -> Result<(), Error> {
+        let Some(client_port) = self.client_port
+        else {
+            tracing::debug!(source = %source_address, "Dropping datagram to client with unknown port");
+            return Ok(());
+        };
+
+        let mut datagram = Vec::with_capacity(data.len() + 32);
+        UdpHeader::new(source_address.clone()).write_to(&mut datagram)?;
+        datagram.extend_from_slice(data);
+        self.socket
+            .send_to(&datagram, (self.client_ip_address, client_port))
+            .await?;
+
+        Ok(())
+    }
+}
+
 /// Handle a single connection
 async fn handle_connection(
     connection: TcpStream,
     auth: MaybeAuth,
-    connection_requests_tx: mpsc::Sender<Result<ConnectionRequest, Error>>,
+    connection_requests_tx: mpsc::Sender<Result<Request, Error>>,
 ) -> Result<(), Error> {
-    let connection = BufStream::new(connection);
+    let client_address = connection.peer_addr()?;
+    let local_address = connection.local_addr()?;
+    let mut connection = BufStream::new(connection);
+
+    // both versions start with the version number.
+    if connection.fill_buf().await?.first() == Some(&4) {
+        return handle_v4_connection(
+            connection,
+            client_address,
+            local_address,
+            &auth,
+            connection_requests_tx,
+        )
+        .await;
+    }
+
     let request = serve(connection, &auth).await?;
 
     match request {
-        Request::Associate(request) => request.reject(RejectReason::CommandNotSupported).await?,
-        Request::Bind(request) => request.reject(RejectReason::CommandNotSupported).await?,
-        Request::Connect(request) => {
+        v5::server::Request::Associate(request) => {
+            let username = request.auth_data().clone();
+
+            // the client tells us where it'll send datagrams from, but it might not know
+            // its address or port yet.
+            let expected_address = request.destination_address();
+            let client_ip_address = match &expected_address.host {
+                HostAddress::IpAddress(ip_address) if !ip_address.is_unspecified() => *ip_address,
+                _ => client_address.ip(),
+            };
+            let client_port = (expected_address.port != 0).then_some(expected_address.port);
+
+            let (ack_tx, ack_rx) = oneshot::channel();
+            let (association_tx, association_rx) = oneshot::channel();
+
+            // doesn't matter if receiver was dropped, since the ACK will fail
+            let _ = connection_requests_tx
+                .send(Ok(Request::Associate(AssociateRequest {
+                    client_address,
+                    username: username.clone(),
+                    ack_tx,
+                    association_rx,
+                })))
+                .await;
+
+            match ack_rx.await {
+                Ok(Ok(())) => {
+                    // association accepted. the client sends its datagrams to the same address
+                    // it connected to.
+                    let result = async {
+                        let socket = UdpSocket::bind((local_address.ip(), 0)).await?;
+                        let bind_address = socket.local_addr()?.into();
+                        let control = request.accept(&bind_address).await?;
+                        tracing::debug!(%bind_address, "UDP association");
+                        Ok(Association {
+                            control,
+                            socket,
+                            client_ip_address,
+                            client_port,
+                            username,
+                        })
+                    }
+                    .await;
+                    let _ = association_tx.send(result);
+                }
+                Ok(Err(reason)) => {
+                    // association rejected with reason
+                    request.reject(reason).await?;
+                }
+                Err(_) => {
+                    // ACK sender dropped
+                    request.reject(RejectReason::ConnectionRefused).await?;
+                }
+            }
+        }
+        v5::server::Request::Bind(request) => {
             let destination_address = request.destination_address().clone();
+            let username = request.auth_data().clone();
+
+            // the client might not know the peer's address yet.
+            let peer_ip_address = match &destination_address.host {
+                HostAddress::IpAddress(ip_address) if !ip_address.is_unspecified() => {
+                    Some(*ip_address)
+                }
+                _ => None,
+            };
+
+            let (ack_tx, ack_rx) = oneshot::channel();
+            let (binding_tx, binding_rx) = oneshot::channel();
+
+            // doesn't matter if receiver was dropped, since the ACK will fail
+            let _ = connection_requests_tx
+                .send(Ok(Request::Bind(BindRequest {
+                    destination_address: destination_address.clone(),
+                    client_address,
+                    username: username.clone(),
+                    ack_tx,
+                    binding_rx,
+                })))
+                .await;
+
+            match ack_rx.await {
+                Ok(Ok(())) => {
+                    // bind request accepted. the peer connects to the same address the client
+                    // connected to.
+                    let result = async {
+                        let listener = TcpListener::bind((local_address.ip(), 0)).await?;
+                        let bind_address = listener.local_addr()?.into();
+                        let control = request.accept(&bind_address).await?;
+                        tracing::debug!(%bind_address, "Listening for peer");
+                        Ok(Binding {
+                            control: BindingControl::V5(control),
+                            listener,
+                            destination_address,
+                            peer_ip_address,
+                            username,
+                        })
+                    }
+                    .await;
+                    let _ = binding_tx.send(result);
+                }
+                Ok(Err(reason)) => {
+                    // bind request rejected with reason
+                    request.reject(reason).await?;
+                }
+                Err(_) => {
+                    // ACK sender dropped
+                    request.reject(RejectReason::ConnectionRefused).await?;
+                }
+            }
+        }
+        v5::server::Request::Connect(request) => {
+            let destination_address = request.destination_address().clone();
+            let username = request.auth_data().clone();
 
             let (ack_tx, ack_rx) = oneshot::channel();
             let (connection_tx, connection_rx) = oneshot::channel();
 
             // doesn't matter if receiver was dropped, since the ACK will fail
             let _ = connection_requests_tx
-                .send(Ok(ConnectionRequest {
+                .send(Ok(Request::Connect(ConnectionRequest {
                     destination_address: destination_address.clone(),
+                    username: username.clone(),
                     ack_tx,
                     connection_rx,
-                }))
+                })))
                 .await;
 
             match ack_rx.await {
@@ -275,8 +821,9 @@ async fn handle_connection(
                     // connection request accepted
                     let result = request.accept(&bind_address).await.map(|connection| {
                         Incoming {
-                            inner: connection,
+                            inner: IncomingInner::V5(connection),
                             destination_address,
+                            username,
                         }
                     });
                     let _ = connection_tx.send(result);
@@ -296,18 +843,155 @@ async fn handle_connection(
     Ok(())
 }
 
+/// Handle a SOCKS4(a) connection.
+///
+/// SOCKS4 only has a user ID, which isn't authenticated, so requests are
+/// rejected if authentication is enabled.
+async fn handle_v4_connection(
+    connection: BufStream<TcpStream>,
+    client_address: SocketAddr,
+    local_address: SocketAddr,
+    auth: &MaybeAuth,
+    connection_requests_tx: mpsc::Sender<Result<Request, Error>>,
+) -> Result<(), Error> {
+    let request = v4::server::serve(connection).await?;
+
+    if let MaybeAuth::Password { .. } = auth {
+        tracing::debug!("Rejecting SOCKS4 request, since authentication is required");
+        match request {
+            v4::server::Request::Connect(request) => request.reject().await?,
+            v4::server::Request::Bind(request) => request.reject().await?,
+        }
+        return Ok(());
+    }
+
+    match request {
+        v4::server::Request::Bind(request) => {
+            let destination_address = request.destination_address().clone();
+
+            // the client might not know the peer's address yet.
+            let peer_ip_address = match &destination_address.host {
+                HostAddress::IpAddress(ip_address) if !ip_address.is_unspecified() => {
+                    Some(*ip_address)
+                }
+                _ => None,
+            };
+
+            let (ack_tx, ack_rx) = oneshot::channel();
+            let (binding_tx, binding_rx) = oneshot::channel();
+
+            // doesn't matter if receiver was dropped, since the ACK will fail
+            let _ = connection_requests_tx
+                .send(Ok(Request::Bind(BindRequest {
+                    destination_address: destination_address.clone(),
+                    client_address,
+                    username: None,
+                    ack_tx,
+                    binding_rx,
+                })))
+                .await;
+
+            if let Ok(Ok(())) = ack_rx.await {
+                // bind request accepted. the peer connects to the same address the client
+                // connected to, which has to be an IPv4 address to fit in the reply.
+                let result = async {
+                    let listener = TcpListener::bind((local_address.ip(), 0)).await?;
+                    let bind_address = listener.local_addr()?;
+                    let Some(ip_address) = to_ipv4(bind_address.ip())
+                    else {
+                        return Err(Error::UnsupportedAddress {
+                            address: bind_address.into(),
+                        });
+                    };
+                    let control = request.accept((ip_address, bind_address.port())).await?;
+                    tracing::debug!(%bind_address, "Listening for peer");
+                    Ok(Binding {
+                        control: BindingControl::V4(control),
+                        listener,
+                        destination_address,
+                        peer_ip_address,
+                        username: None,
+                    })
+                }
+                .await;
+                let _ = binding_tx.send(result);
+            }
+            else {
+                // bind request rejected, or ACK sender dropped. SOCKS4 has no reasons.
+                request.reject().await?;
+            }
+        }
+        v4::server::Request::Connect(request) => {
+            let destination_address = request.destination_address().clone();
+
+            let (ack_tx, ack_rx) = oneshot::channel();
+            let (connection_tx, connection_rx) = oneshot::channel();
+
+            // doesn't matter if receiver was dropped, since the ACK will fail
+            let _ = connection_requests_tx
+                .send(Ok(Request::Connect(ConnectionRequest {
+                    destination_address: destination_address.clone(),
+                    username: None,
+                    ack_tx,
+                    connection_rx,
+                })))
+                .await;
+
+            if let Ok(Ok(bind_address)) = ack_rx.await {
+                // connection request accepted
+                let result = request
+                    .accept(ipv4_address(&bind_address))
+                    .await
+                    .map(|connection| {
+                        Incoming {
+                            inner: IncomingInner::V4(connection),
+                            destination_address,
+                            username: None,
+                        }
+                    });
+                let _ = connection_tx.send(result);
+            }
+            else {
+                // connection request rejected, or ACK sender dropped. SOCKS4 has no reasons.
+                request.reject().await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the address to send in a SOCKS4 reply. Clients ignore the address
+/// in CONNECT replies, so anything that isn't IPv4 is sent as `0.0.0.0`.
+fn ipv4_address(address: &TcpAddress) -> (Ipv4Addr, u16) {
+    let ip_address = match &address.host {
+        HostAddress::IpAddress(ip_address) => to_ipv4(*ip_address),
+        HostAddress::DnsName(_) => None,
+    };
+    (ip_address.unwrap_or(Ipv4Addr::UNSPECIFIED), address.port)
+}
+
+fn to_ipv4(ip_address: IpAddr) -> Option<Ipv4Addr> {
+    match ip_address.to_canonical() {
+        IpAddr::V4(ip_address) => Some(ip_address),
+        IpAddr::V6(_) => None,
+    }
+}
+
 /// Authentication configuration.
 #[derive(Clone, Debug)]
 pub enum MaybeAuth {
     NoAuth,
+    /// Username/password authentication against a list of users, mapping
+    /// usernames to passwords.
     Password {
-        username: Vec<u8>,
-        password: Vec<u8>,
+        users: Arc<HashMap<String, String>>,
     },
 }
 
 impl AuthProvider for MaybeAuth {
-    type Data = ();
+    /// The username, if the client authenticated with username and password.
+    type Data = Option<String>;
     type Socket<S> = S
     where S: AsyncRead + AsyncWrite + Unpin;
 
@@ -327,28 +1011,382 @@ impl AuthProvider for MaybeAuth {
     async fn authenticate<S>(
         &self,
         _auth_method: AuthMethod,
-        socket: S,
+        mut socket: S,
     ) -> Result<AuthResult<S, Self>, Error>
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
-        let accept = match self {
-            MaybeAuth::NoAuth => true,
-            MaybeAuth::Password {
-                username: _,
-                password: _,
-            } => {
-                todo!("Implement SOCKS authentication");
+        let username = match self {
+            MaybeAuth::NoAuth => return Ok(AuthResult::Success { socket, data: None }),
+            MaybeAuth::Password { users } => {
+                let (username, password) = read_password_request(&mut socket).await?;
+
+                // usernames that aren't valid UTF-8 can't be in the list.
+                let username = String::from_utf8(username).ok().filter(|username| {
+                    users
+                        .get(username)
+                        .is_some_and(|expected| expected.as_bytes() == password)
+                });
+
+                send_password_status(&mut socket, username.is_some()).await?;
+                username
             }
         };
 
-        let result = if accept {
-            AuthResult::Success { socket, data: () }
+        let result = if let Some(username) = username {
+            tracing::debug!(%username, "User authenticated");
+            AuthResult::Success {
+                socket,
+                data: Some(username),
+            }
         }
         else {
+            tracing::debug!("Authentication failed");
             AuthResult::Failed { socket }
         };
 
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{
+        duplex,
+        AsyncReadExt,
+        AsyncWriteExt,
+    };
+
+    use super::*;
+    use crate::proxy::socks::{
+        client::Credentials,
+        v5::client::connect,
+    };
+
+    fn auth() -> MaybeAuth {
+        Builder::default()
+            .with_users([
+                ("alice".to_owned(), "secret".to_owned()),
+                ("bob".to_owned(), "hunter2".to_owned()),
+            ])
+            .auth
+    }
+
+    #[tokio::test]
+    async fn it_authenticates_users() {
+        let (client, server) = duplex(1024);
+
+        let server = tokio::spawn(async move {
+            let v5::server::Request::Connect(request) = serve(server, &auth()).await.unwrap()
+            else {
+                panic!("expected connect request");
+            };
+            let username = request.auth_data().clone();
+            let mut connected = request
+                .accept(&"127.0.0.1:1234".parse().unwrap())
+                .await
+                .unwrap();
+            connected.write_all(b"hello").await.unwrap();
+            connected.flush().await.unwrap();
+            username
+        });
+
+        let credentials = Credentials {
+            username: "bob".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        let mut client = connect(
+            client,
+            &"example.com:443".parse().unwrap(),
+            Some(&credentials),
+        )
+        .await
+        .unwrap();
+
+        let mut data = [0; 5];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+        assert_eq!(server.await.unwrap().as_deref(), Some("bob"));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_wrong_passwords() {
+        let (client, server) = duplex(1024);
+
+        let server = tokio::spawn(async move { serve(server, &auth()).await.err() });
+
+        let credentials = Credentials {
+            username: "alice".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        let error = connect(
+            client,
+            &"example.com:443".parse().unwrap(),
+            Some(&credentials),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, Error::AuthenticationFailed));
+        assert!(matches!(
+            server.await.unwrap(),
+            Some(Error::AuthenticationFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_relays_datagrams() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (connection, _) = listener.accept().await.unwrap();
+
+        let (connection_requests_tx, mut connection_requests_rx) = mpsc::channel(1);
+        tokio::spawn(handle_connection(
+            connection,
+            MaybeAuth::NoAuth,
+            connection_requests_tx,
+        ));
+
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0; 2];
+        control.read_exact(&mut reply).await.unwrap();
+        control
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        let Some(Ok(Request::Associate(request))) = connection_requests_rx.recv().await
+        else {
+            panic!("expected associate request");
+        };
+        let mut association = request.accept().await.unwrap();
+
+        let mut reply = [0; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], &[5, 0, 0, 1]);
+        let bind_address = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[8], reply[9]]),
+        ));
+        assert_eq!(bind_address, association.bind_address().unwrap());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(bind_address).await.unwrap();
+
+        // fragmented datagrams are dropped.
+        client
+            .send(b"\x00\x00\x01\x03\x0bexample.com\x00\x35dropped")
+            .await
+            .unwrap();
+        client
+            .send(b"\x00\x00\x00\x03\x0bexample.com\x00\x35query")
+            .await
+            .unwrap();
+
+        let datagram = association.receive().await.unwrap().unwrap();
+        assert_eq!(
+            datagram.destination_address,
+            "example.com:53".parse().unwrap()
+        );
+        assert_eq!(&datagram.data[..], b"query");
+
+        association
+            .send(&"192.0.2.1:53".parse().unwrap(), b"answer")
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        let n_read = client.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            &buf[..n_read],
+            b"\x00\x00\x00\x01\xc0\x00\x02\x01\x00\x35answer"
+        );
+
+        // the association ends with the control connection.
+        drop(control);
+        assert!(association.receive().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn it_binds() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (connection, _) = listener.accept().await.unwrap();
+
+        let (connection_requests_tx, mut connection_requests_rx) = mpsc::channel(1);
+        tokio::spawn(handle_connection(
+            connection,
+            MaybeAuth::NoAuth,
+            connection_requests_tx,
+        ));
+
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0; 2];
+        control.read_exact(&mut reply).await.unwrap();
+        control
+            .write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 21])
+            .await
+            .unwrap();
+
+        let Some(Ok(Request::Bind(request))) = connection_requests_rx.recv().await
+        else {
+            panic!("expected bind request");
+        };
+        assert_eq!(
+            request.destination_address(),
+            &"127.0.0.1:21".parse().unwrap()
+        );
+        let binding = request.accept().await.unwrap();
+
+        // first reply: the address we listen on.
+        let mut reply = [0; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], &[5, 0, 0, 1]);
+        let bind_address = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[8], reply[9]]),
+        ));
+        assert_eq!(bind_address, binding.bind_address().unwrap());
+
+        let accepted = tokio::spawn(binding.accept());
+        let mut peer = TcpStream::connect(bind_address).await.unwrap();
+        let (mut incoming, mut outgoing) = accepted.await.unwrap().unwrap().unwrap();
+        assert_eq!(
+            incoming.destination_address(),
+            &peer.local_addr().unwrap().into()
+        );
+
+        // second reply: the address of the peer.
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], &[5, 0, 0, 1]);
+        let peer_address = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[8], reply[9]]),
+        ));
+        assert_eq!(peer_address, peer.local_addr().unwrap());
+
+        // data from the peer reaches the client through the control connection.
+        peer.write_all(b"hello").await.unwrap();
+        let mut data = [0; 5];
+        outgoing.read_exact(&mut data).await.unwrap();
+        incoming.write_all(&data).await.unwrap();
+        incoming.flush().await.unwrap();
+        control.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn it_binds_for_socks4_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (connection, _) = listener.accept().await.unwrap();
+
+        let (connection_requests_tx, mut connection_requests_rx) = mpsc::channel(1);
+        tokio::spawn(handle_connection(
+            connection,
+            MaybeAuth::NoAuth,
+            connection_requests_tx,
+        ));
+
+        control
+            .write_all(&[4, 2, 0, 21, 127, 0, 0, 1, b'a', 0])
+            .await
+            .unwrap();
+
+        let Some(Ok(Request::Bind(request))) = connection_requests_rx.recv().await
+        else {
+            panic!("expected bind request");
+        };
+        assert_eq!(
+            request.destination_address(),
+            &"127.0.0.1:21".parse().unwrap()
+        );
+        let binding = request.accept().await.unwrap();
+
+        // first reply: the address we listen on.
+        let mut reply = [0; 8];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..2], &[0, 90]);
+        let bind_address = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[2], reply[3]]),
+        ));
+        assert_eq!(bind_address, binding.bind_address().unwrap());
+
+        let accepted = tokio::spawn(binding.accept());
+        let peer = TcpStream::connect(bind_address).await.unwrap();
+        let (mut incoming, _outgoing) = accepted.await.unwrap().unwrap().unwrap();
+
+        // second reply: the address of the peer.
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..2], &[0, 90]);
+        let peer_address = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[2], reply[3]]),
+        ));
+        assert_eq!(peer_address, peer.local_addr().unwrap());
+
+        incoming.write_all(b"hello").await.unwrap();
+        incoming.flush().await.unwrap();
+        let mut data = [0; 5];
+        control.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn it_stops_waiting_for_the_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut control = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (connection, _) = listener.accept().await.unwrap();
+
+        let (connection_requests_tx, mut connection_requests_rx) = mpsc::channel(1);
+        tokio::spawn(handle_connection(
+            connection,
+            MaybeAuth::NoAuth,
+            connection_requests_tx,
+        ));
+
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0; 2];
+        control.read_exact(&mut reply).await.unwrap();
+        control
+            .write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 21])
+            .await
+            .unwrap();
+
+        let Some(Ok(Request::Bind(request))) = connection_requests_rx.recv().await
+        else {
+            panic!("expected bind request");
+        };
+        let binding = request.accept().await.unwrap();
+        let mut reply = [0; 10];
+        control.read_exact(&mut reply).await.unwrap();
+
+        // once the client sent data, closing the connection isn't noticed anymore, but
+        // the peer has to connect in time.
+        control.write_all(b"early").await.unwrap();
+        tokio::time::pause();
+        assert!(binding.accept().await.unwrap().is_none());
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..2], &[5, 6]);
+    }
+
+    #[tokio::test]
+    async fn it_requires_authentication() {
+        let (client, server) = duplex(1024);
+
+        tokio::spawn(async move { serve(server, &auth()).await.err() });
+
+        let error = connect(client, &"example.com:443".parse().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::AuthenticationFailed));
+    }
+}
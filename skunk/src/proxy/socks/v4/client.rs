@@ -1 +1,141 @@
+//! SOCKS4a client
+//!
+//! [SOCKS4](https://www.openssh.com/txt/socks4.protocol),
+//! [SOCKS4a](https://www.openssh.com/txt/socks4a.protocol)
 
+use std::net::IpAddr;
+
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
+
+use super::{
+    Command,
+    Reply,
+};
+use crate::{
+    address::{
+        HostAddress,
+        TcpAddress,
+    },
+    proxy::socks::error::Error,
+};
+
+/// Asks the SOCKS server on `socket` to connect to `destination_address`.
+///
+/// DNS names are sent to the server to resolve (SOCKS4a). IPv6 addresses are
+/// not supported by SOCKS4.
+///
+/// On success the socket is returned, which is then connected to the
+/// destination.
+pub async fn connect<S>(
+    mut socket: S,
+    destination_address: &TcpAddress,
+    user_id: &str,
+) -> Result<S, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![4, Command::Connect.into()];
+    request.extend_from_slice(&destination_address.port.to_be_bytes());
+
+    match &destination_address.host {
+        HostAddress::IpAddress(IpAddr::V4(ip_address)) => {
+            request.extend_from_slice(&ip_address.octets());
+            push_nul_terminated(&mut request, user_id)?;
+        }
+        HostAddress::DnsName(name) => {
+            // an invalid IP address of the form 0.0.0.x tells the server that a DNS name
+            // follows the user id.
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            push_nul_terminated(&mut request, user_id)?;
+            push_nul_terminated(&mut request, name).map_err(|_| Error::InvalidHostName)?;
+        }
+        HostAddress::IpAddress(IpAddr::V6(_)) => {
+            return Err(Error::UnsupportedAddress {
+                address: destination_address.clone(),
+            });
+        }
+    }
+
+    socket.write_all(&request).await?;
+    socket.flush().await?;
+
+    // the reply starts with a null byte instead of the version.
+    let version = socket.read_u8().await?;
+    if version != 0 {
+        return Err(Error::InvalidVersion(version));
+    }
+
+    let reply = Reply::try_from(socket.read_u8().await?)?;
+
+    // the bound address is only meaningful for `BIND` requests.
+    let mut bind_address = [0; 6];
+    socket.read_exact(&mut bind_address).await?;
+
+    match reply {
+        Reply::Granted => Ok(socket),
+        reason => Err(Error::RejectedV4 { reason }),
+    }
+}
+
+fn push_nul_terminated(buf: &mut Vec<u8>, s: &str) -> Result<(), Error> {
+    if s.contains('\0') {
+        return Err(Error::InvalidRequest);
+    }
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_sends_dns_names_to_the_server() {
+        let (client, mut server) = duplex(1024);
+
+        let server = tokio::spawn(async move {
+            let mut request = [0; 26];
+            server.read_exact(&mut request).await.unwrap();
+            server.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            request
+        });
+
+        connect(client, &"example.com:443".parse().unwrap(), "alice")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            &server.await.unwrap(),
+            b"\x04\x01\x01\xbb\x00\x00\x00\x01alice\x00example.com\x00"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_fails_if_rejected() {
+        let (client, mut server) = duplex(1024);
+
+        tokio::spawn(async move {
+            let mut request = [0; 9];
+            server.read_exact(&mut request).await.unwrap();
+            server.write_all(&[0, 91, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        });
+
+        let error = connect(client, &"192.0.2.1:80".parse().unwrap(), "")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::RejectedV4 {
+                reason: Reply::Failed
+            }
+        ));
+    }
+}
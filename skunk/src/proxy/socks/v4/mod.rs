@@ -33,6 +33,7 @@ impl From<Command> for u8 {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Reply {
     Granted,
     Failed,
@@ -64,3 +65,14 @@ impl From<Reply> for u8 {
         }
     }
 }
+
+impl Reply {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Granted => "request granted",
+            Self::Failed => "request rejected or failed",
+            Self::IdentdConnectionRefused => "identd not reachable",
+            Self::IdentdMismatch => "user id not confirmed by identd",
+        }
+    }
+}
@@ -135,6 +135,63 @@ impl<S> Bind<S> {
     }
 }
 
+impl<S> Bind<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    /// Sends the first reply, telling the client the address we listen on for
+    /// the peer.
+    pub async fn accept(mut self, bind_address: (Ipv4Addr, u16)) -> Result<Accept<S>, Error> {
+        self.inner
+            .send_reply(Reply::Granted, Some(bind_address))
+            .await?;
+        Ok(Accept { inner: self.inner })
+    }
+
+    pub async fn reject(mut self) -> Result<(), Error> {
+        self.inner.send_reply(Reply::Failed, None).await?;
+        self.inner.socket.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// A BIND request that was accepted and is waiting for the peer to connect.
+#[derive(Debug)]
+pub struct Accept<S> {
+    inner: RequestInner<S>,
+}
+
+impl<S> Accept<S> {
+    /// Returns the underlying socket, e.g. to notice when the client closes
+    /// the connection while waiting for the peer.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner.socket
+    }
+}
+
+impl<S> Accept<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    /// Sends the second reply, telling the client that `peer_address`
+    /// connected.
+    pub async fn accept(mut self, peer_address: (Ipv4Addr, u16)) -> Result<Connected<S>, Error> {
+        self.inner
+            .send_reply(Reply::Granted, Some(peer_address))
+            .await?;
+        Ok(Connected {
+            socket: self.inner.socket,
+        })
+    }
+
+    pub async fn reject(mut self) -> Result<(), Error> {
+        self.inner.send_reply(Reply::Failed, None).await?;
+        self.inner.socket.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
 pub struct Connected<S> {
     socket: S,
 }
@@ -176,6 +233,7 @@ where
     }
 }
 
+#[derive(Debug)]
 struct RequestInner<S> {
     socket: S,
     dest_address: TcpAddress,
@@ -1 +1,243 @@
+//! SOCKS Protocol Version 5 client
+//!
+//! [RFC 1928](https://datatracker.ietf.org/doc/html/rfc1928),
+//! [RFC 1929](https://datatracker.ietf.org/doc/html/rfc1929)
 
+use std::net::IpAddr;
+
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+};
+
+use super::{
+    AddressType,
+    AuthMethod,
+    Command,
+    Reply,
+    SelectedAuthMethod,
+};
+use crate::{
+    address::{
+        HostAddress,
+        TcpAddress,
+    },
+    proxy::socks::{
+        client::Credentials,
+        error::Error,
+    },
+};
+
+/// Asks the SOCKS server on `socket` to connect to `destination_address`.
+///
+/// If `credentials` are given, the server may ask for username/password
+/// authentication. Otherwise only no authentication is offered. DNS names are
+/// sent to the server to resolve.
+///
+/// On success the socket is returned, which is then connected to the
+/// destination.
+pub async fn connect<S>(
+    mut socket: S,
+    destination_address: &TcpAddress,
+    credentials: Option<&Credentials>,
+) -> Result<S, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut auth_methods = vec![AuthMethod::NoAuthentication];
+    if credentials.is_some() {
+        auth_methods.push(AuthMethod::UsernamePassword);
+    }
+
+    socket.write_u8(5).await?;
+    socket.write_u8(auth_methods.len() as u8).await?;
+    for auth_method in &auth_methods {
+        socket.write_u8((*auth_method).into()).await?;
+    }
+    socket.flush().await?;
+
+    let version = socket.read_u8().await?;
+    if version != 5 {
+        return Err(Error::InvalidVersion(version));
+    }
+
+    match SelectedAuthMethod::from(socket.read_u8().await?) {
+        SelectedAuthMethod::Selected(AuthMethod::NoAuthentication) => {}
+        SelectedAuthMethod::Selected(AuthMethod::UsernamePassword) => {
+            let credentials = credentials.ok_or(Error::AuthenticationFailed)?;
+            authenticate(&mut socket, credentials).await?;
+        }
+        SelectedAuthMethod::Selected(auth_method) => {
+            return Err(Error::InvalidAuthMethod(super::InvalidAuthMethod {
+                value: auth_method.into(),
+            }));
+        }
+        SelectedAuthMethod::NoAcceptable => return Err(Error::AuthenticationFailed),
+    }
+
+    let mut request = vec![5, Command::Connect.into(), 0];
+    match &destination_address.host {
+        HostAddress::IpAddress(IpAddr::V4(ip_address)) => {
+            request.push(AddressType::IpV4.into());
+            request.extend_from_slice(&ip_address.octets());
+        }
+        HostAddress::IpAddress(IpAddr::V6(ip_address)) => {
+            request.push(AddressType::IpV6.into());
+            request.extend_from_slice(&ip_address.octets());
+        }
+        HostAddress::DnsName(name) => {
+            request.push(AddressType::DomainName.into());
+            request.push(name.len().try_into().map_err(|_| Error::InvalidHostName)?);
+            request.extend_from_slice(name.as_bytes());
+        }
+    }
+    request.extend_from_slice(&destination_address.port.to_be_bytes());
+    socket.write_all(&request).await?;
+    socket.flush().await?;
+
+    let version = socket.read_u8().await?;
+    if version != 5 {
+        return Err(Error::InvalidVersion(version));
+    }
+
+    let reply = Reply::try_from(socket.read_u8().await?)?;
+
+    let reserved = socket.read_u8().await?;
+    if reserved != 0 {
+        return Err(Error::InvalidRequest);
+    }
+
+    // the bound address is only meaningful for `BIND` requests, but we need to
+    // read it anyway.
+    let address_length = match AddressType::try_from(socket.read_u8().await?)? {
+        AddressType::IpV4 => 4,
+        AddressType::DomainName => socket.read_u8().await?.into(),
+        AddressType::IpV6 => 16,
+    };
+    let mut bind_address = vec![0; address_length + 2];
+    socket.read_exact(&mut bind_address).await?;
+
+    match reply {
+        Reply::Succeeded => Ok(socket),
+        Reply::Failure(reason) => Err(Error::RejectedV5 { reason }),
+    }
+}
+
+/// Username/password authentication ([RFC 1929][1]).
+///
+/// [1]: https://datatracker.ietf.org/doc/html/rfc1929
+async fn authenticate<S>(mut socket: S, credentials: &Credentials) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let username: u8 = credentials
+        .username
+        .len()
+        .try_into()
+        .map_err(|_| Error::InvalidRequest)?;
+    let password: u8 = credentials
+        .password
+        .len()
+        .try_into()
+        .map_err(|_| Error::InvalidRequest)?;
+
+    let mut request = vec![1, username];
+    request.extend_from_slice(credentials.username.as_bytes());
+    request.push(password);
+    request.extend_from_slice(credentials.password.as_bytes());
+    socket.write_all(&request).await?;
+    socket.flush().await?;
+
+    let version = socket.read_u8().await?;
+    if version != 1 {
+        return Err(Error::InvalidVersion(version));
+    }
+
+    let status = socket.read_u8().await?;
+    if status != 0 {
+        return Err(Error::AuthenticationFailed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::proxy::socks::v5::RejectReason;
+
+    #[tokio::test]
+    async fn it_authenticates_and_connects() {
+        let (client, mut server) = duplex(1024);
+
+        let server = tokio::spawn(async move {
+            let mut greeting = [0; 4];
+            server.read_exact(&mut greeting).await.unwrap();
+            server.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0; 14];
+            server.read_exact(&mut auth).await.unwrap();
+            server.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 18];
+            server.read_exact(&mut request).await.unwrap();
+            server
+                .write_all(&[5, 0, 0, 1, 192, 0, 2, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+
+            (greeting, auth, request)
+        });
+
+        let credentials = Credentials {
+            username: "alice".to_owned(),
+            password: "secret".to_owned(),
+        };
+        connect(
+            client,
+            &"example.com:443".parse().unwrap(),
+            Some(&credentials),
+        )
+        .await
+        .unwrap();
+
+        let (greeting, auth, request) = server.await.unwrap();
+        assert_eq!(&greeting, b"\x05\x02\x00\x02");
+        assert_eq!(&auth, b"\x01\x05alice\x06secret");
+        assert_eq!(&request, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+    }
+
+    #[tokio::test]
+    async fn it_fails_if_rejected() {
+        let (client, mut server) = duplex(1024);
+
+        tokio::spawn(async move {
+            let mut greeting = [0; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            server.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 22];
+            server.read_exact(&mut request).await.unwrap();
+            server
+                .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let destination_address =
+            TcpAddress::new("2001:db8::1".parse::<IpAddr>().unwrap().into(), 80);
+        let error = connect(client, &destination_address, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::RejectedV5 {
+                reason: RejectReason::ConnectionRefused
+            }
+        ));
+    }
+}
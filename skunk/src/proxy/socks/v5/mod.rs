@@ -1,5 +1,6 @@
 pub mod client;
 pub mod server;
+pub mod udp;
 
 use super::error::{
     InvalidCommand,
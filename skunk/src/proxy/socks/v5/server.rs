@@ -1,6 +1,7 @@
 //! SOCKS Protocol Version 5
 //!
-//! [RFC 1928](https://datatracker.ietf.org/doc/html/rfc1928)
+//! [RFC 1928](https://datatracker.ietf.org/doc/html/rfc1928),
+//! [RFC 1929](https://datatracker.ietf.org/doc/html/rfc1929)
 
 use std::{
     net::{
@@ -62,7 +63,7 @@ where
 {
     let n_methods = socket.read_u8().await?;
 
-    let mut auth_methods = vec![AuthMethod::NoAuthentication; n_methods.into()];
+    let mut auth_methods = Vec::with_capacity(n_methods.into());
     for _ in 0..n_methods {
         auth_methods.push(AuthMethod::try_from(socket.read_u8().await?)?);
     }
@@ -214,6 +215,11 @@ where
     }
 }
 
+/// A request to listen for a connection from a peer, e.g. for active-mode FTP.
+///
+/// The client gets a reply with the address we listen on, and another one
+/// once the peer connected. After that the connection is relayed to the peer.
+#[derive(Debug)]
 pub struct Bind<S, A>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -237,6 +243,8 @@ where
         &self.destination_address
     }
 
+    /// Sends the first reply, telling the client the address we listen on for
+    /// the peer.
     pub async fn accept(mut self, bind_address: &TcpAddress) -> Result<Accept<S, A>, Error> {
         send_reply(
             &mut self.socket,
@@ -256,9 +264,10 @@ where
     }
 }
 
-/// # TODO
+/// A request to relay UDP datagrams for the client.
 ///
-/// implement this.
+/// The association lasts as long as the control connection this request was
+/// sent on.
 #[derive(Debug)]
 pub struct Associate<S, A>
 where
@@ -279,10 +288,26 @@ where
         &self.auth_data
     }
 
+    /// The address the client will send datagrams from. Either part can be
+    /// zero, if the client doesn't know it yet.
     pub fn destination_address(&self) -> &UdpAddress {
         &self.destination_address
     }
 
+    /// Accepts the request, telling the client to send its datagrams to
+    /// `bind_address`. Returns the control connection.
+    pub async fn accept(mut self, bind_address: &UdpAddress) -> Result<Connected<S, A>, Error> {
+        send_reply(
+            &mut self.socket,
+            Reply::Succeeded,
+            Some((&bind_address.host, bind_address.port)),
+        )
+        .await?;
+        Ok(Connected {
+            socket: self.socket,
+        })
+    }
+
     pub async fn reject(mut self, failure: RejectReason) -> Result<(), Error> {
         send_reply(&mut self.socket, failure.into(), None).await?;
         self.socket.shutdown().await?;
@@ -338,6 +363,8 @@ where
     }
 }
 
+/// A BIND request that was accepted and is waiting for the peer to connect.
+#[derive(Debug)]
 pub struct Accept<S, A>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -351,6 +378,14 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
     A: AuthProvider,
 {
+    /// Returns the underlying socket, e.g. to notice when the client closes
+    /// the connection while waiting for the peer.
+    pub fn get_mut(&mut self) -> &mut A::Socket<S> {
+        &mut self.socket
+    }
+
+    /// Sends the second reply, telling the client that `peer_address`
+    /// connected.
     pub async fn accept(mut self, peer_address: &TcpAddress) -> Result<Connected<S, A>, Error> {
         send_reply(
             &mut self.socket,
@@ -362,6 +397,12 @@ where
             socket: self.socket,
         })
     }
+
+    pub async fn reject(mut self, failure: RejectReason) -> Result<(), Error> {
+        send_reply(&mut self.socket, failure.into(), None).await?;
+        self.socket.shutdown().await?;
+        Ok(())
+    }
 }
 
 async fn send_reply<S>(
@@ -409,6 +450,44 @@ where
     Ok(())
 }
 
+/// Reads a username/password request ([RFC 1929][1]) after the
+/// `UsernamePassword` method was selected.
+///
+/// Returns the username and password.
+///
+/// [1]: https://datatracker.ietf.org/doc/html/rfc1929
+pub async fn read_password_request<S>(mut socket: S) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let version = socket.read_u8().await?;
+    if version != 1 {
+        return Err(Error::InvalidVersion(version));
+    }
+
+    let n = socket.read_u8().await?;
+    let mut username = vec![0; n.into()];
+    socket.read_exact(&mut username).await?;
+
+    let n = socket.read_u8().await?;
+    let mut password = vec![0; n.into()];
+    socket.read_exact(&mut password).await?;
+
+    Ok((username, password))
+}
+
+/// Sends the status of a username/password authentication. If it failed, the
+/// client must close the connection.
+pub async fn send_password_status<S>(mut socket: S, success: bool) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    socket.write_u8(1).await?;
+    socket.write_u8(if success { 0 } else { 1 }).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
 pub enum AuthResult<S, A>
 where
     S: AsyncRead + AsyncWrite + Unpin,
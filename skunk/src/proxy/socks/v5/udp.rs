@@ -0,0 +1,140 @@
+//! Header of UDP datagrams that are relayed by the server.
+//!
+//! [RFC 1928 Section 7](https://datatracker.ietf.org/doc/html/rfc1928#section-7)
+
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+
+use super::AddressType;
+use crate::{
+    address::{
+        HostAddress,
+        UdpAddress,
+    },
+    proxy::socks::error::Error,
+};
+
+/// The header that precedes the data of each relayed UDP datagram.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpHeader {
+    /// Fragment number. `0` means the datagram is standalone. Fragmentation is
+    /// optional and we don't support it.
+    pub fragment: u8,
+
+    /// The destination address of datagrams from the client, or the source
+    /// address of datagrams to the client.
+    pub address: UdpAddress,
+}
+
+impl UdpHeader {
+    pub fn new(address: UdpAddress) -> Self {
+        Self {
+            fragment: 0,
+            address,
+        }
+    }
+
+    /// Parses the header of `datagram`. Returns the header and the data that
+    /// follows it.
+    pub fn parse(datagram: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let truncated = || Error::InvalidRequest;
+
+        let [reserved_1, reserved_2, fragment, address_type, rest @ ..] = datagram
+        else {
+            return Err(truncated());
+        };
+        if *reserved_1 != 0 || *reserved_2 != 0 {
+            return Err(Error::InvalidRequest);
+        }
+
+        let (host, rest) = match AddressType::try_from(*address_type)? {
+            AddressType::IpV4 => {
+                let (octets, rest) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+                (IpAddr::from(Ipv4Addr::from(*octets)).into(), rest)
+            }
+            AddressType::DomainName => {
+                let (n, rest) = rest.split_first().ok_or_else(truncated)?;
+                let n = usize::from(*n);
+                if rest.len() < n {
+                    return Err(truncated());
+                }
+                let (name, rest) = rest.split_at(n);
+                let name = std::str::from_utf8(name).map_err(|_| Error::InvalidHostName)?;
+                (HostAddress::DnsName(name.to_owned()), rest)
+            }
+            AddressType::IpV6 => {
+                let (octets, rest) = rest.split_first_chunk::<16>().ok_or_else(truncated)?;
+                (IpAddr::from(Ipv6Addr::from(*octets)).into(), rest)
+            }
+        };
+
+        let (port, data) = rest.split_first_chunk::<2>().ok_or_else(truncated)?;
+        let header = Self {
+            fragment: *fragment,
+            address: UdpAddress::new(host, u16::from_be_bytes(*port)),
+        };
+
+        Ok((header, data))
+    }
+
+    /// Appends the encoded header to `buf`.
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.extend_from_slice(&[0, 0, self.fragment]);
+
+        match &self.address.host {
+            HostAddress::IpAddress(IpAddr::V4(ip_address)) => {
+                buf.push(AddressType::IpV4.into());
+                buf.extend_from_slice(&ip_address.octets());
+            }
+            HostAddress::IpAddress(IpAddr::V6(ip_address)) => {
+                buf.push(AddressType::IpV6.into());
+                buf.extend_from_slice(&ip_address.octets());
+            }
+            HostAddress::DnsName(name) => {
+                buf.push(AddressType::DomainName.into());
+                buf.push(name.len().try_into().map_err(|_| Error::InvalidHostName)?);
+                buf.extend_from_slice(name.as_bytes());
+            }
+        }
+
+        buf.extend_from_slice(&self.address.port.to_be_bytes());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_datagrams() {
+        let datagram = b"\x00\x00\x00\x03\x0bexample.com\x00\x35hello";
+        let (header, data) = UdpHeader::parse(datagram).unwrap();
+        assert_eq!(header.fragment, 0);
+        assert_eq!(header.address, "example.com:53".parse().unwrap());
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn it_writes_headers() {
+        let mut buf = vec![];
+        UdpHeader::new("192.0.2.1:443".parse().unwrap())
+            .write_to(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"\x00\x00\x00\x01\xc0\x00\x02\x01\x01\xbb");
+
+        let (header, data) = UdpHeader::parse(&buf).unwrap();
+        assert_eq!(header.address, "192.0.2.1:443".parse().unwrap());
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_truncated_datagrams() {
+        assert!(UdpHeader::parse(b"\x00\x00\x00\x04\x20\x01").is_err());
+        assert!(UdpHeader::parse(b"\x00\x00\x00\x03\x0bexample").is_err());
+    }
+}
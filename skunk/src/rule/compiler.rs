@@ -65,7 +65,7 @@ where
         condition: Option<ExpressionId>,
         scope: &mut B::Scope,
     ) -> Result<(), Error<B>> {
-        if !rule.then.is_empty() || rule.alt.is_empty() {
+        if !rule.then.is_empty() || !rule.alt.is_empty() {
             let mut cond_expressions =
                 Vec::with_capacity(rule.condition.0.len() + condition.map_or(0, |_| 1));
             cond_expressions.extend(condition);
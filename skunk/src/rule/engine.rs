@@ -0,0 +1,969 @@
+//! Rule engine for the [default filters][DefaultFilters] and [default
+//! effects][DefaultEffects].
+//!
+//! A [`RulesFile`] is compiled into an [`eval::Graph`]. Each connection then
+//! gets its own [`RulesEvaluator`], which is fed the facts about the connection
+//! as they become known (the destination address, the TLS handshake, HTTP
+//! requests and responses, WebSocket frames). DNS queries from the virtual
+//! network get their own evaluator. After each update it returns the
+//! effects whose conditions became true.
+
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    path::Path,
+    sync::Arc,
+};
+
+use super::{
+    compiler::{
+        self,
+        Compiler,
+        Config,
+    },
+    eval::{
+        self,
+        Extractor,
+        Match,
+    },
+    file::{
+        self,
+        DefaultEffects,
+        DefaultFilters,
+        Direction,
+        DnsFilter,
+        DnsQueryType,
+        HttpFilter,
+        RulesFile,
+        TcpFilter,
+        TlsFilter,
+        WebSocketFilter,
+        WebSocketOpcode,
+    },
+    regex::Regex,
+};
+use crate::{
+    address::{
+        HostAddress,
+        TcpAddress,
+    },
+    util::boolean::{
+        ExpressionId,
+        Maybe,
+        ModifyGraph,
+        VariableId,
+    },
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("could not load rules file")]
+    File(#[from] file::Error),
+
+    #[error("the filter {name} requires user interaction")]
+    RequiresUserInteraction { name: Cow<'static, str> },
+}
+
+impl From<compiler::Error<Backend>> for Error {
+    fn from(error: compiler::Error<Backend>) -> Self {
+        match error {
+            compiler::Error::Backend(error) => match error {},
+            compiler::Error::RequiresUserInteraction { name } => {
+                Self::RequiresUserInteraction { name }
+            }
+        }
+    }
+}
+
+/// Compiled rules.
+#[derive(Clone, Debug)]
+pub struct Rules {
+    graph: eval::Graph,
+    effects: Arc<[(ExpressionId, DefaultEffects)]>,
+}
+
+impl Rules {
+    pub fn compile(rules: &RulesFile, config: &Config) -> Result<Self, Error> {
+        let mut backend = Backend {
+            builder: Default::default(),
+            effects: vec![],
+            with_user_interaction: config.with_user_interaction,
+        };
+
+        Compiler::new(config, &mut backend).compile_block(&rules.rules, None, None)?;
+
+        Ok(Self {
+            graph: backend.builder.build(),
+            effects: backend.effects.into(),
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>, config: &Config) -> Result<Self, Error> {
+        let rules = file::from_file(path)?;
+        Self::compile(&rules, config)
+    }
+
+    /// Creates an evaluator for a single connection.
+    pub fn evaluator(&self) -> RulesEvaluator {
+        RulesEvaluator {
+            evaluator: self.graph.evaluator(),
+            effects: self.effects.clone(),
+            fired: vec![false; self.effects.len()],
+        }
+    }
+}
+
+/// Evaluates [`Rules`] for a connection.
+///
+/// Each kind of fact must only be set once. To evaluate multiple HTTP requests
+/// on the same connection, clone the evaluator after the connection-level facts
+/// (TCP, TLS) have been set, and use one clone per request, response and
+/// WebSocket frame.
+#[derive(Clone, Debug)]
+pub struct RulesEvaluator {
+    evaluator: eval::Evaluator,
+    effects: Arc<[(ExpressionId, DefaultEffects)]>,
+    fired: Vec<bool>,
+}
+
+impl RulesEvaluator {
+    /// Sets the destination address of the connection, and returns effects
+    /// that fire because of it.
+    pub fn set_tcp(&mut self, destination_address: &TcpAddress) -> Vec<DefaultEffects> {
+        self.evaluator
+            .update()
+            .for_each(|_: &TcpExtractor| destination_address);
+        self.fire()
+    }
+
+    /// Sets information about the TLS connection, and returns effects that fire
+    /// because of it.
+    ///
+    /// For connections that are not TLS encrypted, pass
+    /// [`TlsInfo::default()`].
+    pub fn set_tls(&mut self, tls_info: &TlsInfo) -> Vec<DefaultEffects> {
+        self.evaluator
+            .update()
+            .for_each(|_: &TlsExtractor| tls_info);
+        self.fire()
+    }
+
+    /// Sets information about a HTTP request or response, and returns effects
+    /// that fire because of it.
+    pub fn set_http(&mut self, http_info: &HttpInfo) -> Vec<DefaultEffects> {
+        self.evaluator
+            .update()
+            .for_each(|_: &HttpExtractor| http_info);
+        self.fire()
+    }
+
+    /// Sets information about a WebSocket frame, and returns effects that fire
+    /// because of it.
+    pub fn set_websocket(&mut self, websocket_info: &WebSocketInfo) -> Vec<DefaultEffects> {
+        self.evaluator
+            .update()
+            .for_each(|_: &WebSocketExtractor| websocket_info);
+        self.fire()
+    }
+
+    /// Sets information about a DNS query, and returns effects that fire
+    /// because of it.
+    pub fn set_dns(&mut self, dns_info: &DnsInfo) -> Vec<DefaultEffects> {
+        self.evaluator
+            .update()
+            .for_each(|_: &DnsExtractor| dns_info);
+        self.fire()
+    }
+
+    /// Creates an evaluator for the response to a request that was evaluated
+    /// with `request`.
+    ///
+    /// `self` must be the evaluator the request evaluator was cloned from, i.e.
+    /// without any HTTP facts set. Effects that already fired for the request
+    /// don't fire again for the response, so rules without a `direction`
+    /// filter apply only once per request/response exchange.
+    pub fn for_response(&self, request: &RulesEvaluator) -> RulesEvaluator {
+        let mut response = self.clone();
+        response.fired.clone_from(&request.fired);
+        response
+    }
+
+    fn fire(&mut self) -> Vec<DefaultEffects> {
+        let mut effects = vec![];
+
+        for ((condition, effect), fired) in self.effects.iter().zip(&mut self.fired) {
+            if !*fired && self.evaluator.get(*condition) == true {
+                *fired = true;
+                effects.push(effect.clone());
+            }
+        }
+
+        effects
+    }
+}
+
+/// Information about a TLS connection.
+#[derive(Clone, Debug, Default)]
+pub struct TlsInfo {
+    /// The server name the client sent in its `CLIENT_HELLO`.
+    pub server_name: Option<String>,
+
+    /// The common name of the server's certificate.
+    pub common_name: Option<String>,
+
+    /// The distinguished name of the server's certificate, in the form
+    /// `CN=example.com, O=Example`.
+    pub distinguished_name: Option<String>,
+
+    /// The JA3 fingerprint of the client's `CLIENT_HELLO`.
+    pub ja3: Option<String>,
+
+    /// The JA4 fingerprint of the client's `CLIENT_HELLO`.
+    pub ja4: Option<String>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsInfo {
+    /// Extracts the information from the decrypted connection pair.
+    pub fn from_connections(
+        incoming: &rustls::ServerConnection,
+        outgoing: &rustls::ClientConnection,
+    ) -> Self {
+        use rcgen::{
+            CertificateParams,
+            DnType,
+            DnValue,
+        };
+
+        fn dn_value(value: &DnValue) -> Option<&str> {
+            match value {
+                DnValue::Ia5String(value) => Some(value.as_str()),
+                DnValue::PrintableString(value) => Some(value.as_str()),
+                DnValue::TeletexString(value) => Some(value.as_str()),
+                DnValue::Utf8String(value) => Some(value),
+                _ => None,
+            }
+        }
+
+        let mut tls_info = Self {
+            server_name: incoming.server_name().map(ToOwned::to_owned),
+            ..Default::default()
+        };
+
+        let certificate_params = outgoing
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| CertificateParams::from_ca_cert_der(cert).ok());
+
+        if let Some(certificate_params) = certificate_params {
+            let distinguished_name = &certificate_params.distinguished_name;
+
+            tls_info.common_name = distinguished_name
+                .get(&DnType::CommonName)
+                .and_then(dn_value)
+                .map(ToOwned::to_owned);
+
+            let parts = distinguished_name
+                .iter()
+                .filter_map(|(ty, value)| {
+                    let key = match ty {
+                        DnType::CountryName => "C",
+                        DnType::LocalityName => "L",
+                        DnType::StateOrProvinceName => "ST",
+                        DnType::OrganizationName => "O",
+                        DnType::OrganizationalUnitName => "OU",
+                        DnType::CommonName => "CN",
+                        _ => return None,
+                    };
+                    Some(format!("{key}={}", dn_value(value)?))
+                })
+                .collect::<Vec<_>>();
+            tls_info.distinguished_name = Some(parts.join(", "));
+        }
+
+        tls_info
+    }
+
+    /// Adds the fingerprints of the client's `CLIENT_HELLO`.
+    pub fn with_client_hello(
+        mut self,
+        client_hello: &crate::protocol::tls::client_hello::ClientHello,
+    ) -> Self {
+        self.ja3 = Some(client_hello.ja3());
+        self.ja4 = Some(client_hello.ja4());
+        self
+    }
+}
+
+/// Information about a HTTP request or response.
+///
+/// For responses `method` and `url` are those of the corresponding request,
+/// and `headers` are the response headers.
+#[derive(Clone, Debug)]
+pub struct HttpInfo {
+    pub direction: Direction,
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpInfo {
+    fn headers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers.iter().filter_map(move |(key, value)| {
+            key.eq_ignore_ascii_case(name).then_some(value.as_str())
+        })
+    }
+
+    fn host(&self) -> Option<&str> {
+        self.headers("host").next().map(|host| {
+            // strip port
+            host.rsplit_once(':')
+                .filter(|(_, port)| port.bytes().all(|c| c.is_ascii_digit()))
+                .map_or(host, |(host, _)| host)
+        })
+    }
+}
+
+/// Information about a WebSocket frame.
+#[derive(Clone, Debug)]
+pub struct WebSocketInfo {
+    /// [`Direction::Request`] for frames sent by the client,
+    /// [`Direction::Response`] for frames sent by the server.
+    pub direction: Direction,
+
+    /// The frame's opcode. This is `None` for reserved opcodes.
+    pub opcode: Option<WebSocketOpcode>,
+
+    /// The payload of text frames.
+    pub text: Option<String>,
+}
+
+#[cfg(feature = "http")]
+impl WebSocketInfo {
+    pub fn from_frame(
+        direction: crate::protocol::websocket::Direction,
+        frame: &crate::protocol::websocket::Frame,
+    ) -> Self {
+        use crate::protocol::websocket::{
+            Direction as FrameDirection,
+            Opcode,
+        };
+
+        let direction = match direction {
+            FrameDirection::FromClient => Direction::Request,
+            FrameDirection::FromServer => Direction::Response,
+        };
+
+        let opcode = match frame.opcode {
+            Opcode::Continuation => Some(WebSocketOpcode::Continuation),
+            Opcode::Text => Some(WebSocketOpcode::Text),
+            Opcode::Binary => Some(WebSocketOpcode::Binary),
+            Opcode::Close => Some(WebSocketOpcode::Close),
+            Opcode::Ping => Some(WebSocketOpcode::Ping),
+            Opcode::Pong => Some(WebSocketOpcode::Pong),
+            Opcode::Reserved(_) => None,
+        };
+
+        Self {
+            direction,
+            opcode,
+            text: frame.as_text().map(ToOwned::to_owned),
+        }
+    }
+}
+
+/// Information about a DNS query.
+#[derive(Clone, Debug)]
+pub struct DnsInfo {
+    /// The queried name, without the trailing dot.
+    pub name: String,
+
+    /// The query type. This is `None` for types without a [`DnsQueryType`].
+    pub query_type: Option<DnsQueryType>,
+}
+
+#[cfg(feature = "pcap")]
+impl DnsInfo {
+    pub fn from_query(query: &crate::proxy::pcap::dns::Query) -> Self {
+        use crate::protocol::inet::dns::QuestionType;
+
+        let query_type = match query.query_type {
+            QuestionType::A => Some(DnsQueryType::A),
+            QuestionType::AAAA => Some(DnsQueryType::Aaaa),
+            QuestionType::CNAME => Some(DnsQueryType::Cname),
+            QuestionType::MX => Some(DnsQueryType::Mx),
+            QuestionType::NS => Some(DnsQueryType::Ns),
+            QuestionType::PTR => Some(DnsQueryType::Ptr),
+            QuestionType::SOA => Some(DnsQueryType::Soa),
+            QuestionType::SRV => Some(DnsQueryType::Srv),
+            QuestionType::TXT => Some(DnsQueryType::Txt),
+            QuestionType::HTTPS => Some(DnsQueryType::Https),
+            QuestionType::ANY => Some(DnsQueryType::Any),
+            _ => None,
+        };
+
+        Self {
+            name: query.name.clone(),
+            query_type,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct TcpExtractor;
+
+impl Extractor for TcpExtractor {
+    type Data<'d> = &'d TcpAddress;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct TlsExtractor;
+
+impl Extractor for TlsExtractor {
+    type Data<'d> = &'d TlsInfo;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct HttpExtractor;
+
+impl Extractor for HttpExtractor {
+    type Data<'d> = &'d HttpInfo;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct WebSocketExtractor;
+
+impl Extractor for WebSocketExtractor {
+    type Data<'d> = &'d WebSocketInfo;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct DnsExtractor;
+
+impl Extractor for DnsExtractor {
+    type Data<'d> = &'d DnsInfo;
+}
+
+fn any_match(regexes: &[Regex], haystack: &str) -> bool {
+    regexes.iter().any(|regex| regex.is_match(haystack))
+}
+
+fn host_name(host: &HostAddress) -> Cow<'_, str> {
+    match host {
+        HostAddress::DnsName(name) => Cow::Borrowed(name),
+        HostAddress::IpAddress(ip_address) => Cow::Owned(ip_address.to_string()),
+    }
+}
+
+/// Matcher for the `host` filter. This matches the destination address, the
+/// TLS server name and the HTTP `Host` header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct HostMatcher(Vec<Regex>);
+
+impl Match<TcpExtractor> for HostMatcher {
+    fn matches(&self, input: &&TcpAddress) -> Maybe {
+        any_match(&self.0, &host_name(&input.host)).into()
+    }
+}
+
+impl Match<TlsExtractor> for HostMatcher {
+    fn matches(&self, input: &&TlsInfo) -> Maybe {
+        input
+            .server_name
+            .as_ref()
+            .is_some_and(|server_name| any_match(&self.0, server_name))
+            .into()
+    }
+}
+
+impl Match<HttpExtractor> for HostMatcher {
+    fn matches(&self, input: &&HttpInfo) -> Maybe {
+        input
+            .host()
+            .is_some_and(|host| any_match(&self.0, host))
+            .into()
+    }
+}
+
+impl Match<HttpExtractor> for Direction {
+    fn matches(&self, input: &&HttpInfo) -> Maybe {
+        match self {
+            Direction::Both => true.into(),
+            direction => (*direction == input.direction).into(),
+        }
+    }
+}
+
+impl Match<TcpExtractor> for TcpFilter {
+    fn matches(&self, input: &&TcpAddress) -> Maybe {
+        let matches = match self {
+            TcpFilter::HostPort(host_ports) => {
+                host_ports.iter().any(|host_port| {
+                    host_port.host.is_match(&host_name(&input.host))
+                        && host_port.port.range().contains(&input.port)
+                })
+            }
+            TcpFilter::Hostname(regexes) => any_match(regexes, &host_name(&input.host)),
+            TcpFilter::DnsName(regexes) => {
+                match &input.host {
+                    HostAddress::DnsName(name) => any_match(regexes, name),
+                    HostAddress::IpAddress(_) => false,
+                }
+            }
+            TcpFilter::IpAddress(networks) => {
+                match &input.host {
+                    HostAddress::IpAddress(ip_address) => {
+                        networks.iter().any(|network| network.contains(*ip_address))
+                    }
+                    HostAddress::DnsName(_) => false,
+                }
+            }
+            TcpFilter::Port(ports) => {
+                ports
+                    .iter()
+                    .any(|ports| ports.range().contains(&input.port))
+            }
+        };
+        matches.into()
+    }
+}
+
+impl Match<TlsExtractor> for TlsFilter {
+    fn matches(&self, input: &&TlsInfo) -> Maybe {
+        let (regexes, value) = match self {
+            TlsFilter::ServerName(regexes) => (regexes, &input.server_name),
+            TlsFilter::CommonName(regexes) => (regexes, &input.common_name),
+            TlsFilter::DistinguishedName(regexes) => (regexes, &input.distinguished_name),
+            TlsFilter::Ja3(regexes) => (regexes, &input.ja3),
+            TlsFilter::Ja4(regexes) => (regexes, &input.ja4),
+        };
+        value
+            .as_ref()
+            .is_some_and(|value| any_match(regexes, value))
+            .into()
+    }
+}
+
+impl Match<HttpExtractor> for HttpFilter {
+    fn matches(&self, input: &&HttpInfo) -> Maybe {
+        let matches = match self {
+            HttpFilter::Method(regexes) => any_match(regexes, &input.method),
+            HttpFilter::Url(regexes) => any_match(regexes, &input.url),
+            HttpFilter::Header { name, value } => {
+                input
+                    .headers
+                    .iter()
+                    .any(|(key, val)| name.is_match(key) && value.is_match(val))
+            }
+            HttpFilter::ContentType(regexes) => {
+                input
+                    .headers("content-type")
+                    .any(|value| any_match(regexes, value))
+            }
+            HttpFilter::Cookie(regexes) => {
+                input
+                    .headers("cookie")
+                    .chain(input.headers("set-cookie"))
+                    .any(|value| any_match(regexes, value))
+            }
+            HttpFilter::Host(regexes) => input.host().is_some_and(|host| any_match(regexes, host)),
+        };
+        matches.into()
+    }
+}
+
+impl Match<WebSocketExtractor> for WebSocketFilter {
+    fn matches(&self, input: &&WebSocketInfo) -> Maybe {
+        let matches = match self {
+            WebSocketFilter::Direction(Direction::Both) => true,
+            WebSocketFilter::Direction(direction) => *direction == input.direction,
+            WebSocketFilter::Opcode(opcodes) => {
+                input.opcode.is_some_and(|opcode| opcodes.contains(&opcode))
+            }
+            WebSocketFilter::Text(regexes) => {
+                input
+                    .text
+                    .as_ref()
+                    .is_some_and(|text| any_match(regexes, text))
+            }
+        };
+        matches.into()
+    }
+}
+
+impl Match<DnsExtractor> for DnsFilter {
+    fn matches(&self, input: &&DnsInfo) -> Maybe {
+        let matches = match self {
+            DnsFilter::Name(regexes) => any_match(regexes, &input.name),
+            DnsFilter::Type(query_types) => {
+                input
+                    .query_type
+                    .is_some_and(|query_type| query_types.contains(&query_type))
+            }
+        };
+        matches.into()
+    }
+}
+
+/// Compiler backend that compiles into an [`eval::Graph`].
+#[derive(Debug)]
+pub struct Backend {
+    builder: eval::Builder,
+    effects: Vec<(ExpressionId, DefaultEffects)>,
+    with_user_interaction: bool,
+}
+
+impl Backend {
+    fn input<E, M>(&mut self, extractor: E, matcher: M) -> ExpressionId
+    where
+        E: Extractor + Eq + std::hash::Hash + Send + Sync + 'static,
+        M: Match<E> + Eq + std::hash::Hash + Send + Sync + 'static,
+    {
+        self.builder.input(extractor, matcher).into()
+    }
+
+    fn all<T, F>(&mut self, filters: &[T], mut f: F) -> ExpressionId
+    where
+        F: FnMut(&mut Self, &T) -> ExpressionId,
+    {
+        let expressions = filters
+            .iter()
+            .map(|filter| f(self, filter))
+            .collect::<Vec<_>>();
+        self.and(&expressions)
+    }
+}
+
+#[derive(Debug)]
+pub struct Scope {
+    condition: Option<ExpressionId>,
+}
+
+impl ModifyGraph for Backend {
+    #[inline]
+    fn literal(&self, value: bool) -> ExpressionId {
+        ModifyGraph::literal(&self.builder, value)
+    }
+
+    #[inline]
+    fn variable(&mut self) -> VariableId {
+        ModifyGraph::variable(&mut self.builder)
+    }
+
+    #[inline]
+    fn not(&mut self, input: ExpressionId) -> ExpressionId {
+        ModifyGraph::not(&mut self.builder, input)
+    }
+
+    #[inline]
+    fn and(&mut self, inputs: &[ExpressionId]) -> ExpressionId {
+        ModifyGraph::and(&mut self.builder, inputs)
+    }
+
+    #[inline]
+    fn or(&mut self, inputs: &[ExpressionId]) -> ExpressionId {
+        ModifyGraph::or(&mut self.builder, inputs)
+    }
+
+    #[inline]
+    fn pin(&mut self, expression_id: ExpressionId) {
+        ModifyGraph::pin(&mut self.builder, expression_id)
+    }
+
+    #[inline]
+    fn unpin(&mut self, expression_id: ExpressionId) {
+        ModifyGraph::unpin(&mut self.builder, expression_id)
+    }
+}
+
+impl compiler::Backend for Backend {
+    type Filter = DefaultFilters;
+    type Effect = DefaultEffects;
+    type Scope = Scope;
+    type Error = Infallible;
+
+    fn scope(
+        &mut self,
+        _parent: Option<&mut Self::Scope>,
+        condition: Option<ExpressionId>,
+    ) -> Self::Scope {
+        Scope { condition }
+    }
+
+    fn compile_filter(
+        &mut self,
+        _scope: &mut Self::Scope,
+        filter: &Self::Filter,
+    ) -> Result<ExpressionId, compiler::Error<Self>> {
+        let expression = match filter {
+            DefaultFilters::Direction(direction) => self.input(HttpExtractor, *direction),
+            DefaultFilters::Host(regexes) => {
+                let matcher = HostMatcher(regexes.clone());
+                let inputs = [
+                    self.input(TcpExtractor, matcher.clone()),
+                    self.input(TlsExtractor, matcher.clone()),
+                    self.input(HttpExtractor, matcher),
+                ];
+                self.or(&inputs)
+            }
+            DefaultFilters::Tcp(filters) => {
+                self.all(filters, |this, filter| {
+                    this.input(TcpExtractor, filter.clone())
+                })
+            }
+            DefaultFilters::Tls(filters) => {
+                self.all(filters, |this, filter| {
+                    this.input(TlsExtractor, filter.clone())
+                })
+            }
+            DefaultFilters::Http(filters) => {
+                self.all(filters, |this, filter| {
+                    this.input(HttpExtractor, filter.clone())
+                })
+            }
+            DefaultFilters::WebSocket(filters) => {
+                self.all(filters, |this, filter| {
+                    this.input(WebSocketExtractor, filter.clone())
+                })
+            }
+            DefaultFilters::Dns(filters) => {
+                self.all(filters, |this, filter| {
+                    this.input(DnsExtractor, filter.clone())
+                })
+            }
+        };
+
+        Ok(expression)
+    }
+
+    fn compile_effect(
+        &mut self,
+        scope: &mut Self::Scope,
+        effect: &Self::Effect,
+    ) -> Result<(), compiler::Error<Self>> {
+        if matches!(effect, DefaultEffects::Interrupt(_)) && !self.with_user_interaction {
+            return Err(compiler::Error::RequiresUserInteraction {
+                name: "interrupt".into(),
+            });
+        }
+
+        let condition = scope
+            .condition
+            .unwrap_or_else(|| ModifyGraph::literal(self, true));
+        self.pin(condition);
+        self.effects.push((condition, effect.clone()));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(yaml: &str) -> Rules {
+        let rules = file::from_reader(yaml.as_bytes()).unwrap();
+        Rules::compile(
+            &rules,
+            &Config {
+                with_user_interaction: true,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn effects_fire_when_facts_become_known() {
+        let rules = compile(
+            r#"
+            rules:
+              - if:
+                  - tcp:
+                      - port: [443]
+                  - tls:
+                      - server-name: ["^example\\.com$"]
+                then:
+                  effects:
+                    - drop
+            "#,
+        );
+
+        let mut evaluator = rules.evaluator();
+        assert!(evaluator
+            .set_tcp(&"example.com:443".parse().unwrap())
+            .is_empty());
+
+        let effects = evaluator.set_tls(&TlsInfo {
+            server_name: Some("example.com".to_owned()),
+            ..Default::default()
+        });
+        assert!(matches!(effects.as_slice(), [DefaultEffects::Drop]));
+    }
+
+    #[test]
+    fn tls_filters_match_client_fingerprints() {
+        let rules = compile(
+            r#"
+            rules:
+              - if:
+                  - tls:
+                      - ja4: ["^t13d1516h2_8daaf6152771_"]
+                then:
+                  effects:
+                    - drop
+            "#,
+        );
+
+        let mut evaluator = rules.evaluator();
+        assert!(evaluator
+            .set_tls(&TlsInfo {
+                ja4: Some("t12d1209h2_d34a8e72043a_b39be8c56a14".to_owned()),
+                ..Default::default()
+            })
+            .is_empty());
+
+        let mut evaluator = rules.evaluator();
+        let effects = evaluator.set_tls(&TlsInfo {
+            ja3: Some("cd08e31494f9531f560d64c695473da9".to_owned()),
+            ja4: Some("t13d1516h2_8daaf6152771_02713d6af862".to_owned()),
+            ..Default::default()
+        });
+        assert!(matches!(effects.as_slice(), [DefaultEffects::Drop]));
+    }
+
+    #[test]
+    fn else_branch_fires() {
+        let rules = compile(
+            r#"
+            rules:
+              - if:
+                  - http:
+                      - method: ["^POST$"]
+                else:
+                  effects:
+                    - drop
+            "#,
+        );
+
+        let mut evaluator = rules.evaluator();
+        let effects = evaluator.set_http(&HttpInfo {
+            direction: Direction::Request,
+            method: "GET".to_owned(),
+            url: "http://example.com/".to_owned(),
+            headers: vec![],
+        });
+        assert!(matches!(effects.as_slice(), [DefaultEffects::Drop]));
+    }
+
+    #[test]
+    fn http_effects_fire_once_per_exchange() {
+        let rules = compile(
+            r#"
+            rules:
+              - if:
+                  - http:
+                      - method: ["^GET$"]
+                then:
+                  effects:
+                    - drop
+              - if:
+                  - direction: response
+                  - http:
+                      - method: ["^GET$"]
+                then:
+                  effects:
+                    - log: {}
+            "#,
+        );
+        let http_info = |direction| {
+            HttpInfo {
+                direction,
+                method: "GET".to_owned(),
+                url: "http://example.com/".to_owned(),
+                headers: vec![],
+            }
+        };
+
+        let connection = rules.evaluator();
+
+        let mut request = connection.clone();
+        let effects = request.set_http(&http_info(Direction::Request));
+        assert!(matches!(effects.as_slice(), [DefaultEffects::Drop]));
+
+        let mut response = connection.for_response(&request);
+        let effects = response.set_http(&http_info(Direction::Response));
+        assert!(matches!(effects.as_slice(), [DefaultEffects::Log(_)]));
+    }
+
+    #[test]
+    fn websocket_filters_match_frames() {
+        let rules = compile(
+            r#"
+            rules:
+              - if:
+                  - websocket:
+                      - direction: request
+                      - text: ["secret"]
+                then:
+                  effects:
+                    - replace:
+                        pattern: "secret"
+                        with: "public"
+            "#,
+        );
+
+        let mut evaluator = rules.evaluator();
+        let effects = evaluator.set_websocket(&WebSocketInfo {
+            direction: Direction::Response,
+            opcode: Some(WebSocketOpcode::Text),
+            text: Some("a secret".to_owned()),
+        });
+        assert!(effects.is_empty());
+
+        let mut evaluator = rules.evaluator();
+        let effects = evaluator.set_websocket(&WebSocketInfo {
+            direction: Direction::Request,
+            opcode: Some(WebSocketOpcode::Text),
+            text: Some("a secret".to_owned()),
+        });
+        assert!(matches!(effects.as_slice(), [DefaultEffects::Replace(_)]));
+    }
+
+    #[test]
+    fn dns_filters_match_queries() {
+        let rules = compile(
+            r#"
+            rules:
+              - if:
+                  - dns:
+                      - name: ["^example\\.com$"]
+                      - type: [a]
+                then:
+                  effects:
+                    - resolve:
+                        addresses: ["10.0.69.1"]
+            "#,
+        );
+
+        let mut evaluator = rules.evaluator();
+        let effects = evaluator.set_dns(&DnsInfo {
+            name: "example.com".to_owned(),
+            query_type: Some(DnsQueryType::Aaaa),
+        });
+        assert!(effects.is_empty());
+
+        let mut evaluator = rules.evaluator();
+        let effects = evaluator.set_dns(&DnsInfo {
+            name: "example.com".to_owned(),
+            query_type: Some(DnsQueryType::A),
+        });
+        let [DefaultEffects::Resolve(resolve)] = effects.as_slice()
+        else {
+            panic!("unexpected effects: {effects:?}");
+        };
+        assert_eq!(
+            resolve.addresses,
+            ["10.0.69.1".parse::<std::net::IpAddr>().unwrap()]
+        );
+    }
+}
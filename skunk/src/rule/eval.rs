@@ -36,6 +36,19 @@ pub struct Builder {
     inner: GraphInner,
 }
 
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            inner: GraphInner {
+                graph: Default::default(),
+                inputs: Inputs {
+                    inputs: HashMap::new(),
+                },
+            },
+        }
+    }
+}
+
 impl Builder {
     #[inline]
     pub fn literal(&mut self, value: bool) -> ExpressionId {
@@ -45,8 +58,8 @@ impl Builder {
     #[inline]
     pub fn input<E, M>(&mut self, extractor: E, matcher: M) -> VariableId
     where
-        E: Extractor + Eq + Hash + 'static,
-        M: Match<E> + Eq + Hash + 'static,
+        E: Extractor + Eq + Hash + Send + Sync + 'static,
+        M: Match<E> + Eq + Hash + Send + Sync + 'static,
     {
         self.inner
             .inputs
@@ -69,7 +82,6 @@ impl Builder {
     }
 
     #[inline]
-    #[allow(clippy::arc_with_non_send_sync)] // fixme
     pub fn build(self) -> Graph {
         Graph {
             inner: Arc::new(RwLock::new(Arc::new(RwLock::new(self.inner)))),
@@ -82,6 +94,43 @@ impl Builder {
     }
 }
 
+impl ModifyGraph for Builder {
+    #[inline]
+    fn literal(&self, value: bool) -> ExpressionId {
+        self.inner.graph.literal(value)
+    }
+
+    #[inline]
+    fn variable(&mut self) -> VariableId {
+        self.inner.graph.variable()
+    }
+
+    #[inline]
+    fn not(&mut self, input: ExpressionId) -> ExpressionId {
+        self.inner.graph.not(input)
+    }
+
+    #[inline]
+    fn and(&mut self, inputs: &[ExpressionId]) -> ExpressionId {
+        self.inner.graph.and(inputs)
+    }
+
+    #[inline]
+    fn or(&mut self, inputs: &[ExpressionId]) -> ExpressionId {
+        self.inner.graph.or(inputs)
+    }
+
+    #[inline]
+    fn pin(&mut self, expression_id: ExpressionId) {
+        self.inner.graph.pin(expression_id)
+    }
+
+    #[inline]
+    fn unpin(&mut self, expression_id: ExpressionId) {
+        self.inner.graph.unpin(expression_id)
+    }
+}
+
 pub struct Modify {
     inner: ArcRwLockWriteGuard<RawRwLock, GraphInner>,
 }
@@ -95,8 +144,8 @@ impl Modify {
     #[inline]
     pub fn input<E, M>(&mut self, extractor: E, matcher: M) -> VariableId
     where
-        E: Extractor + Eq + Hash + 'static,
-        M: Match<E> + Eq + Hash + 'static,
+        E: Extractor + Eq + Hash + Send + Sync + 'static,
+        M: Match<E> + Eq + Hash + Send + Sync + 'static,
     {
         let inner = self.inner.deref_mut();
         inner
@@ -126,7 +175,7 @@ struct GraphInner {
     inputs: Inputs,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Graph {
     // this nestedness of `Arc`s and `RwLock` is intentional. We want to share this graph. But we
     // can also swap out the inner `Arc`. The inner `Arc` is then shared with `Evaluator`s.
@@ -142,7 +191,6 @@ impl Graph {
     }
 
     #[inline]
-    #[allow(clippy::arc_with_non_send_sync)] // fixme
     pub fn replace(&self, builder: Builder) {
         let mut inner = self.inner.write();
         *inner = Arc::new(RwLock::new(builder.inner));
@@ -162,15 +210,17 @@ pub struct Evaluator {
 }
 
 impl Evaluator {
-    pub fn update<E, F>(&mut self) -> UpdateInputs
-    where
-        E: Extractor,
-    {
+    pub fn update(&mut self) -> UpdateInputs<'_> {
         UpdateInputs {
             eval: &mut self.eval,
             inner: self.inner.read(),
         }
     }
+
+    #[inline]
+    pub fn get(&self, expression_id: ExpressionId) -> Maybe {
+        self.eval.get(expression_id)
+    }
 }
 
 pub struct UpdateInputs<'a> {
@@ -181,7 +231,7 @@ pub struct UpdateInputs<'a> {
 impl<'a> UpdateInputs<'a> {
     pub fn for_each<'d, E, F>(&mut self, mut f: F)
     where
-        E: Extractor + 'static,
+        E: Extractor + Send + Sync + 'static,
         F: FnMut(&E) -> E::Data<'d>,
     {
         if let Some(set) = self.inner.inputs.input_set::<E>() {
@@ -196,7 +246,7 @@ impl<'a> UpdateInputs<'a> {
 
     pub fn fold_map<'d, E, S, F, M>(&mut self, mut init: S, mut fold: F, mut map: M)
     where
-        E: Extractor + 'static,
+        E: Extractor + Send + Sync + 'static,
         F: FnMut(&mut S, &E),
         M: FnMut(S) -> E::Data<'d>,
     {
@@ -216,7 +266,7 @@ impl<'a> UpdateInputs<'a> {
 
 #[derive(Debug)]
 struct Inputs {
-    inputs: HashMap<TypeId, Box<dyn Any>>,
+    inputs: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
 }
 
 impl Inputs {
@@ -227,8 +277,8 @@ impl Inputs {
         create_variable: impl FnOnce() -> VariableId,
     ) -> VariableId
     where
-        E: Extractor + Eq + Hash + 'static,
-        M: Match<E> + Eq + Hash + 'static,
+        E: Extractor + Eq + Hash + Send + Sync + 'static,
+        M: Match<E> + Eq + Hash + Send + Sync + 'static,
     {
         let set = self
             .inputs
@@ -242,7 +292,7 @@ impl Inputs {
 
     pub fn input_set<E>(&self) -> Option<&InputSet<E>>
     where
-        E: Extractor + 'static,
+        E: Extractor + Send + Sync + 'static,
     {
         self.inputs
             .get(&TypeId::of::<E>())
@@ -252,7 +302,7 @@ impl Inputs {
 
 pub struct Input<E: Extractor> {
     extractor: E,
-    matcher: Box<dyn Match<E>>,
+    matcher: Box<dyn Match<E> + Send + Sync>,
     hash: u64,
     variable: VariableId,
 }
@@ -294,8 +344,8 @@ where
         create_variable: impl FnOnce() -> VariableId,
     ) -> VariableId
     where
-        M: Match<E> + Eq + Hash + 'static,
-        E: Eq + Hash + 'static,
+        M: Match<E> + Eq + Hash + Send + Sync + 'static,
+        E: Eq + Hash + Send + Sync + 'static,
     {
         let mut hasher = DefaultHasher::new();
         extractor.hash(&mut hasher);
@@ -341,7 +391,7 @@ pub trait Match<E: Extractor> {
     fn matches(&self, input: &E::Data<'_>) -> Maybe;
 }
 
-impl<E: Extractor> Match<E> for Box<dyn Match<E>> {
+impl<E: Extractor> Match<E> for Box<dyn Match<E> + Send + Sync> {
     fn matches(&self, input: &E::Data<'_>) -> Maybe {
         self.deref().matches(input)
     }
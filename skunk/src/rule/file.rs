@@ -6,6 +6,7 @@ use std::{
         Read,
         Write,
     },
+    net::IpAddr,
     path::Path,
 };
 
@@ -158,9 +159,12 @@ pub enum DefaultFilters {
     Tcp(Vec<TcpFilter>),
     Tls(Vec<TlsFilter>),
     Http(Vec<HttpFilter>),
+    #[serde(rename = "websocket")]
+    WebSocket(Vec<WebSocketFilter>),
+    Dns(Vec<DnsFilter>),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Direction {
     Request,
@@ -180,7 +184,7 @@ impl Direction {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum TcpFilter {
     HostPort(Vec<HostPort>),
@@ -190,14 +194,14 @@ pub enum TcpFilter {
     Port(Vec<Ports>),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct HostPort {
-    host: Regex,
-    port: Ports,
+    pub host: Regex,
+    pub port: Ports,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum TlsFilter {
     ServerName(Vec<Regex>),
@@ -205,9 +209,14 @@ pub enum TlsFilter {
     CommonName(Vec<Regex>),
     #[serde(alias = "dn")]
     DistinguishedName(Vec<Regex>),
+    /// JA3 fingerprint of the client, as hex-encoded MD5 hash.
+    Ja3(Vec<Regex>),
+    /// JA4 fingerprint of the client, e.g.
+    /// `t13d1516h2_8daaf6152771_02713d6af862`.
+    Ja4(Vec<Regex>),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub enum HttpFilter {
     Method(Vec<Regex>),
@@ -219,30 +228,82 @@ pub enum HttpFilter {
     Host(Vec<Regex>),
 }
 
+/// Filters for WebSocket frames.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum WebSocketFilter {
+    /// `request` matches frames sent by the client, `response` matches frames
+    /// sent by the server.
+    Direction(Direction),
+    Opcode(Vec<WebSocketOpcode>),
+    Text(Vec<Regex>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebSocketOpcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+/// Filters for DNS queries from clients on the virtual network.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum DnsFilter {
+    /// The queried name, without the trailing dot.
+    Name(Vec<Regex>),
+    Type(Vec<DnsQueryType>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsQueryType {
+    A,
+    Aaaa,
+    Cname,
+    Mx,
+    Ns,
+    Ptr,
+    Soa,
+    Srv,
+    Txt,
+    Https,
+    Any,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum DefaultEffects {
     Log(LogEffect),
     Interrupt(InterruptEffect),
     Drop,
+    Replace(ReplaceEffect),
+    Inject(InjectEffect),
+    Resolve(ResolveEffect),
+    Upstream(UpstreamEffect),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct LogEffect {
-    #[serde(skip_serializing_if = "LogTarget::is_user")]
-    target: LogTarget,
+    #[serde(default, skip_serializing_if = "LogTarget::is_user")]
+    pub target: LogTarget,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    name: Option<String>,
+    pub name: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    message: Option<String>,
+    pub message: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum LogTarget {
+    #[default]
     User,
     File,
 }
@@ -261,7 +322,46 @@ impl LogTarget {
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct InterruptEffect {
     #[serde(skip_serializing_if = "Option::is_none")]
-    prompt: Option<String>,
+    pub prompt: Option<String>,
+}
+
+/// Replaces text in WebSocket text frames. Compressed frames are left as they
+/// are.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ReplaceEffect {
+    pub pattern: Regex,
+
+    /// Replacement text. This can refer to capture groups, e.g. `$1`.
+    pub with: String,
+}
+
+/// Injects a WebSocket text frame after the matching frame, in the same
+/// direction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct InjectEffect {
+    pub text: String,
+}
+
+/// Answers a DNS query with the given addresses, instead of asking the
+/// upstream resolver.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ResolveEffect {
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Connects to the destination through the named upstream proxy from the
+/// configuration, instead of the default one. The name `direct` connects
+/// without a proxy.
+///
+/// This only applies to effects that fire for the destination address, since
+/// the connection is made before anything else is known.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UpstreamEffect {
+    pub proxy: String,
 }
 
 #[derive(Debug, thiserror::Error)]
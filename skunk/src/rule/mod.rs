@@ -3,6 +3,7 @@
 //! This is undocumented as it is likely to change a lot.
 
 pub mod compiler;
+pub mod engine;
 pub mod eval;
 pub mod file;
 pub mod filter;
@@ -28,6 +28,23 @@ pub struct RegexParseError {
     string: Arc<str>,
 }
 
+impl Regex {
+    #[inline]
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.regex.is_match(haystack)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+
+    /// Replaces all matches in `haystack` with `replacement`, which can refer
+    /// to capture groups, e.g. `$1`.
+    pub fn replace_all<'h>(&self, haystack: &'h str, replacement: &str) -> Cow<'h, str> {
+        self.regex.replace_all(haystack, replacement)
+    }
+}
+
 impl FromStr for Regex {
     type Err = RegexParseError;
 
@@ -127,11 +127,17 @@ fn propagate(
             }
         });
 
-        dependant_state
+        dependant_state.num_inputs_not_evaluated = dependant_state
             .num_inputs_not_evaluated
             .checked_sub(1)
             .expect("node received more inputs than expected");
 
+        if let Maybe::Definite(_) = dependant_state.value {
+            // the value was already determined by an earlier input (e.g. a `false` into an
+            // `and`), so the remaining inputs don't change it.
+            continue;
+        }
+
         // compute new value
         let dependant_new_value = match (
             new_value,
@@ -184,3 +190,58 @@ fn propagate(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::util::boolean::{
+        Graph,
+        Maybe,
+        ModifyGraph,
+    };
+
+    #[test]
+    fn and_is_true_when_all_inputs_are_true() {
+        let mut graph = Graph::default();
+        let a = graph.variable();
+        let b = graph.variable();
+        let and = graph.and(&[a.into(), b.into()]);
+
+        let mut evaluator = graph.evaluator();
+        evaluator.set(&graph, a, true);
+        assert!(matches!(evaluator.get(and), Maybe::Indefinite));
+        evaluator.set(&graph, b, true);
+        assert_eq!(evaluator.get(and), true);
+    }
+
+    #[test]
+    fn and_short_circuits() {
+        let mut graph = Graph::default();
+        let a = graph.variable();
+        let b = graph.variable();
+        let and = graph.and(&[a.into(), b.into()]);
+        let not = graph.not(and);
+
+        let mut evaluator = graph.evaluator();
+        evaluator.set(&graph, a, false);
+        assert_eq!(evaluator.get(and), false);
+        assert_eq!(evaluator.get(not), true);
+
+        // setting the other input must not change the result
+        evaluator.set(&graph, b, true);
+        assert_eq!(evaluator.get(and), false);
+    }
+
+    #[test]
+    fn or_is_false_when_all_inputs_are_false() {
+        let mut graph = Graph::default();
+        let a = graph.variable();
+        let b = graph.variable();
+        let or = graph.or(&[a.into(), b.into()]);
+
+        let mut evaluator = graph.evaluator();
+        evaluator.set(&graph, a, false);
+        assert!(matches!(evaluator.get(or), Maybe::Indefinite));
+        evaluator.set(&graph, b, false);
+        assert_eq!(evaluator.get(or), false);
+    }
+}
@@ -2,6 +2,8 @@
 
 pub(crate) mod boolean;
 pub mod crc;
+#[cfg(feature = "tls")]
+pub(crate) mod hex;
 pub mod io;
 
 use std::{